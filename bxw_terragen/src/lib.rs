@@ -1,3 +1,4 @@
 pub mod continent;
 pub mod math;
 pub mod supersimplex;
+pub mod worldgen;
@@ -0,0 +1,423 @@
+use crate::continent::*;
+use crate::math::*;
+use bxw_util::lru::LruCache;
+use bxw_util::parking_lot::Mutex;
+use bxw_util::TracedMutex;
+use bxw_world::generation::{
+    parse_generator_settings, serialize_generator_settings, WorldGenerator, WorldGeneratorError,
+};
+use bxw_world::stdgen::{StdGenerator, STD_GENERATOR_NAME};
+use bxw_world::storage::{
+    WorldStorageBackend, META_GENERATOR_NAME, META_GENERATOR_SETTINGS, META_GENERATOR_VERSION,
+    META_WORLD_SEED,
+};
+use bxw_world::testgen::*;
+use bxw_world::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Number of biome centroids blended together to get the height of a single column
+const HEIGHT_INTERPOLATION_POINTS: usize = 6;
+/// Generated continent tiles kept in memory, a tile is large enough that only a few are needed at once
+const TILE_CACHE_SIZE: usize = 4;
+/// Columns at or above this height are covered in snow
+const SNOW_LINE: i32 = 600;
+
+/// Name of the [`ContinentGenerator`] in save files
+pub const CONTINENT_GENERATOR_NAME: &str = "continent";
+
+/// The seed-independent part of [`ContinentGenSettings`] stored with the world
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContinentGeneratorSettings {
+    pub biome_avg_distance: f64,
+    pub continent_size: u32,
+}
+
+impl Default for ContinentGeneratorSettings {
+    fn default() -> Self {
+        let defaults = ContinentGenSettings::new();
+        Self {
+            biome_avg_distance: defaults.biome_avg_distance,
+            continent_size: defaults.continent_size,
+        }
+    }
+}
+
+/// A cached tile, empty until the task that inserted it finishes generating it
+type TileSlot = Arc<Mutex<Option<Arc<ContinentTile>>>>;
+
+/// Generates terrain based on the biome map of continent tiles from [`generate_continent_tile`]
+pub struct ContinentGenerator {
+    settings: ContinentGenSettings,
+    tile_cache: Mutex<LruCache<ContinentTilePosition, TileSlot>>,
+}
+
+impl ContinentGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self::with_settings(ContinentGenSettings::with_seed(seed))
+    }
+
+    pub fn with_settings(settings: ContinentGenSettings) -> Self {
+        Self {
+            settings,
+            tile_cache: Mutex::new(LruCache::new(TILE_CACHE_SIZE)),
+        }
+    }
+
+    pub fn from_settings(seed: u64, settings: &str) -> Result<Self, WorldGeneratorError> {
+        let settings: ContinentGeneratorSettings = parse_generator_settings(settings)?;
+        if settings.continent_size < CHUNK_DIM as u32 || settings.biome_avg_distance < 1.0 {
+            return Err(WorldGeneratorError::InvalidSettings(String::from(
+                "Continent size or biome distance too small",
+            )));
+        }
+        Ok(Self::with_settings(ContinentGenSettings {
+            seed,
+            biome_avg_distance: settings.biome_avg_distance,
+            continent_size: settings.continent_size,
+        }))
+    }
+
+    pub fn gen_settings(&self) -> &ContinentGenSettings {
+        &self.settings
+    }
+
+    /// Returns the tile from the cache, generating it if necessary.
+    /// Only the slot of the tile is locked during generation, so that concurrent chunk tasks
+    /// don't generate the same tile twice, but can still use the other cached tiles.
+    pub fn get_tile(&self, tile_position: ContinentTilePosition) -> Arc<ContinentTile> {
+        let slot = {
+            let mut cache = self
+                .tile_cache
+                .lock_traced("continent tile cache", file!(), line!());
+            if let Some(slot) = cache.get(&tile_position) {
+                slot.clone()
+            } else {
+                let slot = TileSlot::default();
+                cache.put(tile_position, slot.clone());
+                slot
+            }
+        };
+        let mut slot = slot.lock_traced("continent tile slot", file!(), line!());
+        if let Some(tile) = slot.as_ref() {
+            return tile.clone();
+        }
+        let _p_zone = bxw_util::tracy_client::Span::new(
+            "Generate continent tile",
+            "ContinentGenerator",
+            file!(),
+            line!(),
+            4,
+        );
+        let tile = Arc::new(generate_continent_tile(&self.settings, tile_position));
+        *slot = Some(tile.clone());
+        tile
+    }
+
+    /// Inverse distance weighted blend of the nearest biome heights, each extrapolated along its gradient
+    pub fn column_height(tile: &ContinentTile, inner_pos: ContinentBlockInnerPosition) -> f64 {
+        let p: Vector2<f64> = inner_pos.map(f64::from);
+        let mut total_height = 0.0;
+        let mut total_weight = 0.0;
+        for nearest in tile
+            .biome_point_tree
+            .nearest_neighbor_iter(&[p.x, p.y])
+            .take(HEIGHT_INTERPOLATION_POINTS)
+        {
+            let biome = &tile.biome_points[nearest.data];
+            let offset = p - biome.position;
+            let dist2 = offset.dot(&offset);
+            let (height, gradient) = biome.height;
+            let extrapolated = height + gradient.dot(&offset);
+            if dist2 < 1.0e-6 {
+                return extrapolated;
+            }
+            let weight = 1.0 / dist2;
+            total_height += extrapolated * weight;
+            total_weight += weight;
+        }
+        if total_weight > 0.0 {
+            total_height / total_weight
+        } else {
+            0.0
+        }
+    }
+}
+
+impl WorldGenerator for ContinentGenerator {
+    fn name(&self) -> &'static str {
+        CONTINENT_GENERATOR_NAME
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn settings(&self) -> String {
+        serialize_generator_settings(&ContinentGeneratorSettings {
+            biome_avg_distance: self.settings.biome_avg_distance,
+            continent_size: self.settings.continent_size,
+        })
+    }
+
+    fn generate_chunk(&self, chunk: &mut UncompressedChunk, registry: &VoxelRegistry) {
+        let _p_zone = bxw_util::tracy_client::Span::new(
+            "Generate chunk",
+            "ContinentGenerator",
+            file!(),
+            line!(),
+            4,
+        );
+        let i_air = registry
+            .get_definition_from_name("core:void")
+            .expect("No standard air block definition found")
+            .id;
+        let i_grass = registry
+            .get_definition_from_name("core:grass")
+            .expect("No standard grass block definition found")
+            .id;
+        let i_dirt = registry
+            .get_definition_from_name("core:dirt")
+            .expect("No standard dirt block definition found")
+            .id;
+        let i_stone = registry
+            .get_definition_from_name("core:stone")
+            .expect("No standard stone block definition found")
+            .id;
+        let i_snow_grass = registry
+            .get_definition_from_name("core:snow_grass")
+            .expect("No standard snow grass block definition found")
+            .id;
+
+        const VCD: i32 = CHUNK_DIM as i32;
+        let mut heights = [0i32; CHUNK_DIM * CHUNK_DIM];
+        let mut tile: Option<Arc<ContinentTile>> = None;
+        for (i, h) in heights.iter_mut().enumerate() {
+            let x = (i % CHUNK_DIM) as i32 + (chunk.position.0.x * VCD);
+            let z = (i / CHUNK_DIM) as i32 + (chunk.position.0.z * VCD);
+            let bpos = BlockPosition::new(x, 0, z);
+            let tpos = continent_tilepos_from_blockpos(&self.settings, bpos);
+            // a chunk only straddles tile boundaries if the continent size is not a multiple of the chunk size
+            let ctile = match tile {
+                Some(ref t) if t.position == tpos => t.clone(),
+                _ => {
+                    let t = self.get_tile(tpos);
+                    tile = Some(t.clone());
+                    t
+                }
+            };
+            let inner = continent_tile_inner_pos(&self.settings, bpos);
+            *h = Self::column_height(&ctile, inner).round() as i32;
+        }
+
+        for (vidx, vox) in chunk.blocks_yzx.iter_mut().enumerate() {
+            let xc = vidx % CHUNK_DIM;
+            let zc = (vidx / CHUNK_DIM) % CHUNK_DIM;
+            let yc = ((vidx / CHUNK_DIM / CHUNK_DIM) % CHUNK_DIM) as i32;
+            let y = chunk.position.0.y * VCD + yc;
+            let h = heights[xc + zc * CHUNK_DIM];
+
+            *vox = if y == h {
+                if h >= SNOW_LINE {
+                    VoxelDatum::new(i_snow_grass, 0)
+                } else if h >= 0 {
+                    VoxelDatum::new(i_grass, 0)
+                } else {
+                    VoxelDatum::new(i_dirt, 0)
+                }
+            } else if y < h - 5 {
+                VoxelDatum::new(i_stone, 0)
+            } else if y < h {
+                VoxelDatum::new(i_dirt, 0)
+            } else {
+                VoxelDatum::new(i_air, 0)
+            };
+        }
+    }
+}
+
+/// Names of all the generators that can be created by [`create_world_generator`]
+pub const WORLD_GENERATOR_NAMES: [&str; 5] = [
+    STD_GENERATOR_NAME,
+    CONTINENT_GENERATOR_NAME,
+    FLAT_GENERATOR_NAME,
+    VOID_GENERATOR_NAME,
+    DEBUG_GENERATOR_NAME,
+];
+
+/// Creates a world generator by its name from serialized settings (empty for the defaults)
+pub fn create_world_generator(
+    name: &str,
+    seed: u64,
+    settings: &str,
+) -> Result<Arc<dyn WorldGenerator>, WorldGeneratorError> {
+    Ok(match name {
+        STD_GENERATOR_NAME => Arc::new(StdGenerator::new(seed)),
+        CONTINENT_GENERATOR_NAME => Arc::new(ContinentGenerator::from_settings(seed, settings)?),
+        FLAT_GENERATOR_NAME => Arc::new(FlatGenerator::from_settings(settings)?),
+        VOID_GENERATOR_NAME => Arc::new(VoidGenerator),
+        DEBUG_GENERATOR_NAME => Arc::new(DebugPatternGenerator::from_settings(settings)?),
+        _ => return Err(WorldGeneratorError::UnknownGenerator(String::from(name))),
+    })
+}
+
+#[derive(Debug)]
+pub enum WorldGeneratorLoadError {
+    StorageError(bxw_world::storage::rusqlite::Error),
+    GeneratorError(WorldGeneratorError),
+}
+
+/// Generator choice for a newly created world, ignored when opening an existing one
+#[derive(Clone, Debug, Default)]
+pub struct WorldGeneratorRequest {
+    pub name: String,
+    /// TOML settings, empty for the defaults
+    pub settings: String,
+    /// Random if not given
+    pub seed: Option<u64>,
+}
+
+/// Recreates the generator stored in the save metadata, or for a new world creates the requested one and stores it.
+/// Returns the generator and the world seed.
+pub fn load_or_create_world_generator(
+    storage: &mut dyn WorldStorageBackend,
+    request: &WorldGeneratorRequest,
+) -> Result<(Arc<dyn WorldGenerator>, u64), WorldGeneratorLoadError> {
+    use WorldGeneratorLoadError::*;
+    if let Some(name) = storage
+        .read_meta(META_GENERATOR_NAME)
+        .map_err(StorageError)?
+    {
+        let settings = storage
+            .read_meta(META_GENERATOR_SETTINGS)
+            .map_err(StorageError)?
+            .unwrap_or_default();
+        let version: u32 = storage
+            .read_meta(META_GENERATOR_VERSION)
+            .map_err(StorageError)?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        // worlds saved before the seed was stored were all generated with seed 0
+        let seed: u64 = match storage.read_meta(META_WORLD_SEED).map_err(StorageError)? {
+            Some(seed) => seed.parse().map_err(|_| {
+                GeneratorError(WorldGeneratorError::InvalidSettings(format!(
+                    "Invalid world seed: {}",
+                    seed
+                )))
+            })?,
+            None => 0,
+        };
+        let generator = create_world_generator(&name, seed, &settings).map_err(GeneratorError)?;
+        if version > generator.version() {
+            return Err(GeneratorError(WorldGeneratorError::UnsupportedVersion {
+                name,
+                version,
+            }));
+        }
+        Ok((generator, seed))
+    } else {
+        let seed = request.seed.unwrap_or_else(bxw_util::rand::random);
+        let generator = create_world_generator(&request.name, seed, &request.settings)
+            .map_err(GeneratorError)?;
+        storage
+            .write_meta(META_GENERATOR_NAME, generator.name())
+            .map_err(StorageError)?;
+        storage
+            .write_meta(META_GENERATOR_VERSION, &generator.version().to_string())
+            .map_err(StorageError)?;
+        storage
+            .write_meta(META_GENERATOR_SETTINGS, &generator.settings())
+            .map_err(StorageError)?;
+        storage
+            .write_meta(META_WORLD_SEED, &seed.to_string())
+            .map_err(StorageError)?;
+        Ok((generator, seed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_registry() -> VoxelRegistry {
+        let mut reg = VoxelRegistry::new();
+        for name in &["core:grass", "core:dirt", "core:stone", "core:snow_grass"] {
+            reg.build_definition().name(name).finish().unwrap();
+        }
+        reg
+    }
+
+    fn small_settings(seed: u64) -> ContinentGenSettings {
+        ContinentGenSettings {
+            seed,
+            biome_avg_distance: 64.0,
+            continent_size: 512,
+        }
+    }
+
+    fn generate(gen: &ContinentGenerator, reg: &VoxelRegistry, cpos: ChunkPosition) -> Vec<u32> {
+        let mut chunk = UncompressedChunk::new();
+        chunk.position = cpos;
+        gen.generate_chunk(&mut chunk, reg);
+        chunk.blocks_yzx.iter().map(|v| v.repr()).collect()
+    }
+
+    #[test]
+    fn continent_determinism_test() {
+        let reg = Arc::new(test_registry());
+        let positions = [
+            ChunkPosition::new(0, 0, 0),
+            ChunkPosition::new(3, -1, 5),
+            ChunkPosition::new(-7, 0, 2),
+            ChunkPosition::new(20, 1, -20),
+        ];
+        let gen_a = ContinentGenerator::with_settings(small_settings(1234));
+        let expected = positions
+            .iter()
+            .map(|&cpos| generate(&gen_a, &reg, cpos))
+            .collect::<Vec<_>>();
+
+        // a fresh generator with the same seed, with tiles generated by concurrent tasks
+        let gen_b = Arc::new(ContinentGenerator::with_settings(small_settings(1234)));
+        let threads = positions
+            .iter()
+            .map(|&cpos| {
+                let gen_b = gen_b.clone();
+                let reg = reg.clone();
+                std::thread::spawn(move || generate(&gen_b, &reg, cpos))
+            })
+            .collect::<Vec<_>>();
+        for (thread, expected) in threads.into_iter().zip(expected.iter()) {
+            assert_eq!(&thread.join().unwrap(), expected);
+        }
+
+        let tile_a = gen_a.get_tile(vec2(0, 0));
+        let tile_b = gen_b.get_tile(vec2(0, 0));
+        assert_eq!(tile_a.biome_points.len(), tile_b.biome_points.len());
+    }
+
+    #[test]
+    fn generator_settings_roundtrip_test() {
+        for &name in WORLD_GENERATOR_NAMES.iter() {
+            let gen = create_world_generator(name, 77, "").unwrap();
+            assert_eq!(gen.name(), name);
+            let recreated = create_world_generator(name, 77, &gen.settings()).unwrap();
+            assert_eq!(recreated.name(), name);
+            assert_eq!(recreated.settings(), gen.settings());
+        }
+        let flat = create_world_generator(
+            FLAT_GENERATOR_NAME,
+            0,
+            "base_height = 5\n[[layers]]\nblock = \"core:stone\"\nthickness = 2\n",
+        )
+        .unwrap();
+        let recreated = create_world_generator(FLAT_GENERATOR_NAME, 0, &flat.settings()).unwrap();
+        assert_eq!(recreated.settings(), flat.settings());
+        assert!(flat.settings().contains("base_height = 5"));
+        assert!(matches!(
+            create_world_generator("nonexistent", 0, ""),
+            Err(WorldGeneratorError::UnknownGenerator(_))
+        ));
+    }
+}
@@ -1,5 +1,7 @@
+use serde::{Deserialize, Serialize};
+
 /// A type for implementing idempotent change tracking mechanics
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Change<T: Clone + PartialEq> {
     Unchanged,
     Create { new: T },
@@ -1,7 +1,8 @@
 use crate::math::*;
+use serde::{Deserialize, Serialize};
 
 /// Axis-Aligned Bounding Box
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AABB {
     pub mins: Vector3<f64>,
     pub maxs: Vector3<f64>,
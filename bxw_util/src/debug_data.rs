@@ -15,6 +15,7 @@ pub struct DebugData {
     pub fps: AtomicU32,
     pub frame_times: TimingRing,
     pub wgen_times: TimingRing,
+    pub wlight_times: TimingRing,
     pub wmesh_times: TimingRing,
     pub phys_times: TimingRing,
     pub taskpool_active_tasks: AtomicI32,
@@ -73,6 +74,7 @@ impl DebugData {
             r#"FPS: {fps}
 FT: {ft}
 Generate: {gen}
+Light: {light}
 Mesh: {mesh}
 Physics: {phys}
 Pos: {lpx:.1} {lpy:.1} {lpz:.1}
@@ -85,6 +87,7 @@ GPU heap usage: {gpuheap}
             fps = self.fps.load(Ordering::Acquire),
             ft = &self.frame_times,
             gen = &self.wgen_times,
+            light = &self.wlight_times,
             mesh = &self.wmesh_times,
             phys = &self.phys_times,
             tasks = self.taskpool_active_tasks.load(Ordering::Acquire),
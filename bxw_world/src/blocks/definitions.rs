@@ -0,0 +1,225 @@
+//! Loading of voxel definitions from TOML files, see res/blocks.toml for the format
+
+use crate::voxregistry::{VoxelDefinitionError, VoxelRegistry, VOXEL_CUBE_SHAPE};
+use crate::{TextureMapping, VoxelMesh};
+use bxw_util::collider::AABB;
+use bxw_util::math::*;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockDefinitionsFile {
+    #[serde(default, rename = "block")]
+    blocks: Vec<BlockDefinitionEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockDefinitionEntry {
+    name: String,
+    texture: Option<TextureEntry>,
+    mesh: Option<String>,
+    collision: Option<ShapeEntry>,
+    selection: Option<ShapeEntry>,
+    debug_color: Option<[f32; 3]>,
+    light_emission: Option<u8>,
+    light_opacity: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextureEntry {
+    Single(String),
+    TopSideBottom {
+        top: String,
+        side: String,
+        bottom: String,
+    },
+    /// In the order of left, right, down, up, front, back
+    Faces([String; 6]),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ShapeEntry {
+    /// "cube" or "none"
+    Named(String),
+    Box {
+        mins: [f64; 3],
+        maxs: [f64; 3],
+    },
+}
+
+fn parse_shape(shape: &Option<ShapeEntry>) -> Result<Option<AABB>, VoxelDefinitionError> {
+    match shape {
+        None => Ok(Some(*VOXEL_CUBE_SHAPE)),
+        Some(ShapeEntry::Named(name)) => match name.as_str() {
+            "cube" => Ok(Some(*VOXEL_CUBE_SHAPE)),
+            "none" => Ok(None),
+            _ => Err(VoxelDefinitionError::UnknownKind(name.clone())),
+        },
+        Some(ShapeEntry::Box { mins, maxs }) => Ok(Some(AABB::from_min_max(
+            Vector3::from(*mins),
+            Vector3::from(*maxs),
+        ))),
+    }
+}
+
+fn parse_mesh(mesh: &Option<String>) -> Result<VoxelMesh, VoxelDefinitionError> {
+    match mesh.as_deref() {
+        None | Some("cube_and_slopes") => Ok(VoxelMesh::CubeAndSlopes),
+        Some("none") => Ok(VoxelMesh::None),
+        Some(other) => Err(VoxelDefinitionError::UnknownKind(String::from(other))),
+    }
+}
+
+fn register_block(
+    vxreg: &mut VoxelRegistry,
+    texmapper: &dyn Fn(&str) -> u32,
+    entry: &BlockDefinitionEntry,
+) -> Result<(), VoxelDefinitionError> {
+    let texture_mapping = match &entry.texture {
+        None => TextureMapping::new_single(texmapper("unknown")),
+        Some(TextureEntry::Single(t)) => TextureMapping::new_single(t.as_str()).map(texmapper),
+        Some(TextureEntry::TopSideBottom { top, side, bottom }) => {
+            TextureMapping::new_tsb(top.as_str(), side.as_str(), bottom.as_str()).map(texmapper)
+        }
+        Some(TextureEntry::Faces(faces)) => {
+            TextureMapping::new(faces.clone()).map(|t| texmapper(&t))
+        }
+    };
+    let mut builder = vxreg
+        .build_definition()
+        .name(&entry.name)
+        .set_mesh(parse_mesh(&entry.mesh)?)
+        .set_collision_shape(parse_shape(&entry.collision)?)
+        .set_selection_shape(parse_shape(&entry.selection)?)
+        .texture_mapping(texture_mapping);
+    if let Some([r, g, b]) = entry.debug_color {
+        builder = builder.debug_color(r, g, b);
+    }
+    if let Some(level) = entry.light_emission {
+        builder = builder.light_emission(level);
+    }
+    if let Some(level) = entry.light_opacity {
+        builder = builder.light_opacity(level);
+    }
+    builder.finish()
+}
+
+/// Registers all the blocks defined in the given TOML text, in the order they're listed
+pub fn register_blocks_from_toml(
+    vxreg: &mut VoxelRegistry,
+    texmapper: &dyn Fn(&str) -> u32,
+    toml_text: &str,
+) -> Result<(), VoxelDefinitionError> {
+    let file: BlockDefinitionsFile = bxw_util::toml::from_str(toml_text)
+        .map_err(|e| VoxelDefinitionError::InvalidFile(e.to_string()))?;
+    for entry in file.blocks.iter() {
+        register_block(vxreg, texmapper, entry).map_err(|e| {
+            VoxelDefinitionError::InDefinition {
+                name: entry.name.clone(),
+                error: Box::new(e),
+            }
+        })?;
+    }
+    Ok(())
+}
+
+pub fn register_blocks_from_file(
+    vxreg: &mut VoxelRegistry,
+    texmapper: &dyn Fn(&str) -> u32,
+    path: &Path,
+) -> Result<(), VoxelDefinitionError> {
+    let toml_text = std::fs::read_to_string(path).map_err(|e| {
+        VoxelDefinitionError::InvalidFile(format!("{}: {}", path.to_string_lossy(), e))
+    })?;
+    register_blocks_from_toml(vxreg, texmapper, &toml_text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn standard_blocks_file_test() {
+        let mut reg = VoxelRegistry::new();
+        register_blocks_from_toml(&mut reg, &|_| 0, include_str!("../../../res/blocks.toml"))
+            .expect("Invalid res/blocks.toml");
+        assert!(reg.get_definition_from_name("core:stone").is_some());
+        let lava = reg.get_definition_from_name("core:lava").unwrap();
+        assert!(lava.light_emission > 0);
+    }
+
+    #[test]
+    fn block_definition_test() {
+        let textures = ["a", "b", "c", "d", "e", "f"];
+        let texmapper = |t: &str| textures.iter().position(|&x| x == t).unwrap_or(99) as u32;
+        let mut reg = VoxelRegistry::new();
+        register_blocks_from_toml(
+            &mut reg,
+            &texmapper,
+            r#"
+            [[block]]
+            name = "test:tsb"
+            texture = { top = "a", side = "b", bottom = "c" }
+            debug_color = [0.5, 0.25, 1.0]
+
+            [[block]]
+            name = "test:faces"
+            texture = ["a", "b", "c", "d", "e", "f"]
+            mesh = "none"
+            collision = "none"
+            selection = { mins = [-0.5, -0.5, -0.5], maxs = [0.5, 0.0, 0.5] }
+            "#,
+        )
+        .unwrap();
+        let tsb = reg.get_definition_from_name("test:tsb").unwrap();
+        assert_eq!(
+            tsb.texture_mapping.at_direction(crate::Direction::YPlus),
+            &0
+        );
+        assert_eq!(
+            tsb.texture_mapping.at_direction(crate::Direction::XMinus),
+            &1
+        );
+        assert_eq!(tsb.debug_color, [0.5, 0.25, 1.0]);
+        assert_eq!(tsb.collision_shape, Some(*VOXEL_CUBE_SHAPE));
+        let faces = reg.get_definition_from_name("test:faces").unwrap();
+        assert_eq!(faces.mesh, VoxelMesh::None);
+        assert_eq!(faces.collision_shape, None);
+        assert_eq!(faces.selection_shape.unwrap().maxs.y, 0.0);
+    }
+
+    #[test]
+    fn block_definition_error_test() {
+        let load = |text: &str| register_blocks_from_toml(&mut VoxelRegistry::new(), &|_| 0, text);
+        assert!(matches!(
+            load("[[block]]\nname = 1"),
+            Err(VoxelDefinitionError::InvalidFile(_))
+        ));
+        let in_definition = |text: &str| match load(text) {
+            Err(VoxelDefinitionError::InDefinition { error, .. }) => *error,
+            other => panic!("Unexpected result {:?}", other),
+        };
+        assert_eq!(
+            in_definition("[[block]]\nname = \"core:void\""),
+            VoxelDefinitionError::AlreadyExists
+        );
+        assert_eq!(
+            in_definition("[[block]]\nname = \"a:b\"\nmesh = \"sphere\""),
+            VoxelDefinitionError::UnknownKind(String::from("sphere"))
+        );
+        assert_eq!(
+            in_definition("[[block]]\nname = \"a:b\"\nlight_emission = 16"),
+            VoxelDefinitionError::InvalidLightLevel
+        );
+        assert_eq!(
+            in_definition(
+                "[[block]]\nname = \"a:b\"\ncollision = { mins = [0, 0, 0], maxs = [1, -1, 1] }"
+            ),
+            VoxelDefinitionError::InvalidShape
+        );
+    }
+}
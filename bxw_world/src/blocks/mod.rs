@@ -1,65 +1,24 @@
+pub mod definitions;
 pub mod stdshapes;
 
-use crate::voxregistry::VoxelRegistry;
+use crate::storage::palette::MISSING_VOXEL_NAME;
+use crate::voxregistry::{VoxelDefinitionError, VoxelRegistry};
 use crate::TextureMapping;
+use std::path::Path;
 
-pub fn register_standard_blocks(vxreg: &mut VoxelRegistry, texmapper: &dyn Fn(&str) -> u32) {
-    vxreg
-        .build_definition()
-        .name("core:grass")
-        .texture_names(
-            texmapper,
-            TextureMapping::new_tsb("grass_top", "dirt_grass", "dirt"),
-        )
-        .finish()
-        .unwrap();
-    vxreg
-        .build_definition()
-        .name("core:snow_grass")
-        .texture_names(
-            texmapper,
-            TextureMapping::new_tsb("snow", "dirt_snow", "dirt"),
-        )
-        .finish()
-        .unwrap();
-    vxreg
-        .build_definition()
-        .name("core:dirt")
-        .texture_names(texmapper, TextureMapping::new_single("dirt"))
-        .finish()
-        .unwrap();
-    vxreg
-        .build_definition()
-        .name("core:stone")
-        .texture_names(texmapper, TextureMapping::new_single("stone"))
-        .finish()
-        .unwrap();
-    vxreg
-        .build_definition()
-        .name("core:diamond_ore")
-        .texture_names(texmapper, TextureMapping::new_single("stone_diamond"))
-        .finish()
-        .unwrap();
-    vxreg
-        .build_definition()
-        .name("core:debug")
-        .texture_names(
-            texmapper,
-            TextureMapping::new([
-                "dbg_left",
-                "dbg_right",
-                "dbg_down",
-                "dbg_up",
-                "dbg_front",
-                "dbg_back",
-            ]),
-        )
-        .finish()
-        .unwrap();
+/// Definitions of the standard blocks, relative to the working directory like the rest of res/
+pub const STANDARD_BLOCKS_PATH: &str = "res/blocks.toml";
+
+pub fn register_standard_blocks(
+    vxreg: &mut VoxelRegistry,
+    texmapper: &dyn Fn(&str) -> u32,
+) -> Result<(), VoxelDefinitionError> {
+    definitions::register_blocks_from_file(vxreg, texmapper, Path::new(STANDARD_BLOCKS_PATH))?;
+    // placeholder for saved voxels that are no longer registered
     vxreg
         .build_definition()
-        .name("core:table")
-        .texture_names(texmapper, TextureMapping::new_tsb("table", "wood", "table"))
+        .name(MISSING_VOXEL_NAME)
+        .texture_names(texmapper, TextureMapping::new_single("dbg_front"))
+        .debug_color(1.0, 0.0, 1.0)
         .finish()
-        .unwrap();
 }
@@ -3,6 +3,7 @@ use bxw_util::collider::AABB;
 use bxw_util::fnv::*;
 use bxw_util::math::*;
 use bxw_util::sparsevec::*;
+use serde::{Deserialize, Serialize};
 use std::cell::*;
 use std::marker::PhantomData;
 
@@ -21,7 +22,7 @@ impl EntityDomain {
     }
 }
 
-#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct ValidEntityID(u64);
 
 pub type EntityID = Option<ValidEntityID>;
@@ -64,7 +65,7 @@ pub trait Component: Clone + PartialEq {
     fn entity_id(&self) -> ValidEntityID;
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum BoundingShape {
     Point { offset: Vector3<f64> },
     AxisAlignedBox(AABB),
@@ -81,7 +82,7 @@ impl BoundingShape {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CLocation {
     id: ValidEntityID,
     pub position: Vector3<f64>,
@@ -112,7 +113,7 @@ impl Component for CLocation {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CPhysics {
     id: ValidEntityID,
     pub frozen: bool,
@@ -149,7 +150,7 @@ impl Component for CPhysics {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CDebugInfo {
     id: ValidEntityID,
     pub ent_name: String,
@@ -171,7 +172,7 @@ impl Component for CDebugInfo {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CLoadAnchor {
     id: ValidEntityID,
     pub radius: u32,
@@ -225,20 +226,28 @@ pub struct Entity {
     pub inventory: Option<ComponentId<CInventory>>,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub enum EntityChangeKind {
     NewEntity(ValidEntityID),
     UpdateEntity(ValidEntityID),
     DeleteEntity(ValidEntityID),
 }
 
+impl EntityChangeKind {
+    pub fn entity_id(self) -> ValidEntityID {
+        match self {
+            Self::NewEntity(id) | Self::UpdateEntity(id) | Self::DeleteEntity(id) => id,
+        }
+    }
+}
+
 impl Default for EntityChangeKind {
     fn default() -> Self {
         Self::NewEntity(ValidEntityID(0))
     }
 }
 
-#[derive(Default, Clone, PartialEq)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct EntityChange {
     pub kind: EntityChangeKind,
     pub location: Change<CLocation>,
@@ -248,6 +257,93 @@ pub struct EntityChange {
     pub inventory: Change<CInventory>,
 }
 
+fn component_change<T: Component>(old: Option<&T>, new: Option<&T>) -> Change<T> {
+    match (old, new) {
+        (None, None) => Change::Unchanged,
+        (None, Some(new)) => Change::Create { new: new.clone() },
+        (Some(old), None) => Change::Destroy { old: old.clone() },
+        (Some(old), Some(new)) if old == new => Change::Unchanged,
+        (Some(old), Some(new)) => Change::Update {
+            old: old.clone(),
+            new: new.clone(),
+        },
+    }
+}
+
+fn component_change_id_matches<T: Component>(change: &Change<T>, id: ValidEntityID) -> bool {
+    match change {
+        Change::Unchanged => true,
+        Change::Create { new } => new.entity_id() == id,
+        Change::Update { old, new } => old.entity_id() == id && new.entity_id() == id,
+        Change::Destroy { old } => old.entity_id() == id,
+    }
+}
+
+impl EntityChange {
+    /// The change that turns the `old` state of an entity into `new`, `None` if they're the same
+    pub fn between_snapshots(
+        old: Option<&EntitySnapshot>,
+        new: Option<&EntitySnapshot>,
+    ) -> Option<Self> {
+        let kind = match (old, new) {
+            (None, None) => return None,
+            (None, Some(new)) => EntityChangeKind::NewEntity(ValidEntityID::from_raw(new.raw_id)?),
+            (Some(old), None) => {
+                return Some(Self {
+                    kind: EntityChangeKind::DeleteEntity(ValidEntityID::from_raw(old.raw_id)?),
+                    ..Default::default()
+                })
+            }
+            (Some(_), Some(new)) => {
+                EntityChangeKind::UpdateEntity(ValidEntityID::from_raw(new.raw_id)?)
+            }
+        };
+        let change = Self {
+            kind,
+            location: component_change(
+                old.and_then(|s| s.location.as_ref()),
+                new.and_then(|s| s.location.as_ref()),
+            ),
+            physics: component_change(
+                old.and_then(|s| s.physics.as_ref()),
+                new.and_then(|s| s.physics.as_ref()),
+            ),
+            debug_info: component_change(
+                old.and_then(|s| s.debug_info.as_ref()),
+                new.and_then(|s| s.debug_info.as_ref()),
+            ),
+            load_anchor: component_change(
+                old.and_then(|s| s.load_anchor.as_ref()),
+                new.and_then(|s| s.load_anchor.as_ref()),
+            ),
+            inventory: component_change(
+                old.and_then(|s| s.inventory.as_ref()),
+                new.and_then(|s| s.inventory.as_ref()),
+            ),
+        };
+        let unchanged = change.location == Change::Unchanged
+            && change.physics == Change::Unchanged
+            && change.debug_info == Change::Unchanged
+            && change.load_anchor == Change::Unchanged
+            && change.inventory == Change::Unchanged;
+        if unchanged && matches!(kind, EntityChangeKind::UpdateEntity(_)) {
+            None
+        } else {
+            Some(change)
+        }
+    }
+
+    /// Whether all the component values in the change belong to the changed entity
+    pub fn component_ids_match(&self) -> bool {
+        let id = self.kind.entity_id();
+        component_change_id_matches(&self.location, id)
+            && component_change_id_matches(&self.physics, id)
+            && component_change_id_matches(&self.debug_info, id)
+            && component_change_id_matches(&self.load_anchor, id)
+            && component_change_id_matches(&self.inventory, id)
+    }
+}
+
 impl Entity {
     fn new(id: ValidEntityID) -> Self {
         Self {
@@ -278,6 +374,18 @@ pub struct ECS {
 pub enum AddEntityError {
     AlreadyExists,
     InvalidRawID,
+    MismatchedComponentID,
+}
+
+/// All components of a single entity, in the form that gets written to world storage
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    pub raw_id: u64,
+    pub location: Option<CLocation>,
+    pub physics: Option<CPhysics>,
+    pub debug_info: Option<CDebugInfo>,
+    pub load_anchor: Option<CLoadAnchor>,
+    pub inventory: Option<CInventory>,
 }
 
 impl ECS {
@@ -308,6 +416,19 @@ impl ECS {
         ValidEntityID::from_parts(domain, sub_id).unwrap()
     }
 
+    /// The highest sub-ID taken so far in each domain, indexed by [`EntityDomain::number`].
+    /// Saved with the world, so that entities of chunks not loaded yet keep their IDs to themselves.
+    pub fn id_high_water_marks(&self) -> [u64; 4] {
+        self.last_nonfree_ids.get()
+    }
+
+    /// Makes sure no sub-IDs up to the given marks get allocated, see [`ECS::id_high_water_marks`]
+    pub fn reserve_ids_up_to(&mut self, marks: [u64; 4]) {
+        for (nfi, &mark) in self.last_nonfree_ids.get_mut().iter_mut().zip(marks.iter()) {
+            *nfi = (*nfi).max(mark);
+        }
+    }
+
     pub fn add_new_entity(&mut self, domain: EntityDomain) -> ValidEntityID {
         let id = self.allocate_id(domain);
         let iret = self.entities.insert(id, Entity::new(id));
@@ -354,6 +475,74 @@ impl ECS {
         }
     }
 
+    pub fn snapshot_entity(&self, id: ValidEntityID) -> Option<EntitySnapshot> {
+        if !self.entities.contains_key(&id) {
+            return None;
+        }
+        Some(EntitySnapshot {
+            raw_id: id.u64(),
+            location: self.get_component(id).cloned(),
+            physics: self.get_component(id).cloned(),
+            debug_info: self.get_component(id).cloned(),
+            load_anchor: self.get_component(id).cloned(),
+            inventory: self.get_component(id).cloned(),
+        })
+    }
+
+    /// Re-creates an entity from a snapshot, keeping its original ID
+    pub fn restore_entity(
+        &mut self,
+        snapshot: EntitySnapshot,
+    ) -> Result<ValidEntityID, AddEntityError> {
+        let id = ValidEntityID::from_raw(snapshot.raw_id).ok_or(AddEntityError::InvalidRawID)?;
+        let ids_match = snapshot.location.iter().all(|c| c.entity_id() == id)
+            && snapshot.physics.iter().all(|c| c.entity_id() == id)
+            && snapshot.debug_info.iter().all(|c| c.entity_id() == id)
+            && snapshot.load_anchor.iter().all(|c| c.entity_id() == id)
+            && snapshot.inventory.iter().all(|c| c.entity_id() == id);
+        if !ids_match {
+            return Err(AddEntityError::MismatchedComponentID);
+        }
+        let id = self.add_entity_with_id(snapshot.raw_id)?;
+        if let Some(c) = snapshot.location {
+            self.set_component(id, c);
+        }
+        if let Some(c) = snapshot.physics {
+            self.set_component(id, c);
+        }
+        if let Some(c) = snapshot.debug_info {
+            self.set_component(id, c);
+        }
+        if let Some(c) = snapshot.load_anchor {
+            self.set_component(id, c);
+        }
+        if let Some(c) = snapshot.inventory {
+            self.set_component(id, c);
+        }
+        Ok(id)
+    }
+
+    pub fn has_entity(&self, id: ValidEntityID) -> bool {
+        self.entities.contains_key(&id)
+    }
+
+    /// Whether the change applies to the current state as expected: new entities don't exist yet,
+    /// and the old values of updated or destroyed components match the current ones
+    pub fn is_change_valid(&self, change: &EntityChange) -> bool {
+        match change.kind {
+            EntityChangeKind::NewEntity(id) => !self.has_entity(id),
+            EntityChangeKind::DeleteEntity(id) => self.has_entity(id),
+            EntityChangeKind::UpdateEntity(id) => {
+                self.has_entity(id)
+                    && change.location.is_valid(self.get_component(id))
+                    && change.physics.is_valid(self.get_component(id))
+                    && change.debug_info.is_valid(self.get_component(id))
+                    && change.load_anchor.is_valid(self.get_component(id))
+                    && change.inventory.is_valid(self.get_component(id))
+            }
+        }
+    }
+
     pub fn apply_entity_changes(&mut self, changes: &[EntityChange]) {
         for change in changes {
             let eid = match change.kind {
@@ -471,3 +660,79 @@ impl_ecs_fns!(CPhysics, physics, physicss);
 impl_ecs_fns!(CDebugInfo, debug_info, debug_infos);
 impl_ecs_fns!(CLoadAnchor, load_anchor, load_anchors);
 impl_ecs_fns!(CInventory, inventory, inventories);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_id_high_water_marks() {
+        // an entity stored with an unloaded chunk when the world was saved
+        let mut ecs = ECS::new();
+        ecs.add_new_entity(EntityDomain::SharedChunked);
+        let stored = ecs.add_new_entity(EntityDomain::SharedChunked);
+        let mut location = CLocation::new(stored);
+        location.position = vec3(40.0, 2.0, 3.0);
+        ecs.set_component(stored, location);
+        let snapshot = ecs.snapshot_entity(stored).unwrap();
+        let marks = ecs.id_high_water_marks();
+        assert_eq!(marks[EntityDomain::SharedChunked.number()], 2);
+
+        // reopened world, the chunk loads after a new entity was created
+        let mut reopened = ECS::new();
+        reopened.reserve_ids_up_to(marks);
+        let new = reopened.add_new_entity(EntityDomain::SharedChunked);
+        assert_ne!(new, stored);
+        assert_eq!(reopened.restore_entity(snapshot.clone()), Ok(stored));
+        assert_eq!(reopened.snapshot_entity(stored), Some(snapshot));
+        // other domains are unaffected and marks never go down
+        assert_eq!(
+            reopened.add_new_entity(EntityDomain::LocalChunked).sub_id(),
+            1
+        );
+        reopened.reserve_ids_up_to([0; 4]);
+        assert_eq!(
+            reopened.id_high_water_marks()[EntityDomain::SharedChunked.number()],
+            3
+        );
+    }
+
+    #[test]
+    fn test_snapshot_changes() {
+        let mut ecs = ECS::new();
+        let id = ecs.add_new_entity(EntityDomain::SharedChunked);
+        let mut location = CLocation::new(id);
+        location.position = vec3(1.0, 2.0, 3.0);
+        ecs.set_component(id, location.clone());
+        let before = ecs.snapshot_entity(id).unwrap();
+        assert!(EntityChange::between_snapshots(Some(&before), Some(&before)).is_none());
+
+        let mut replica = ECS::new();
+        let create = EntityChange::between_snapshots(None, Some(&before)).unwrap();
+        assert_eq!(create.kind, EntityChangeKind::NewEntity(id));
+        assert!(create.component_ids_match());
+        assert!(replica.is_change_valid(&create));
+        replica.apply_entity_changes(&[create.clone()]);
+        assert!(!replica.is_change_valid(&create));
+        assert_eq!(replica.snapshot_entity(id).as_ref(), Some(&before));
+
+        location.position.y = 5.0;
+        ecs.set_component(id, location);
+        ecs.set_component(id, CDebugInfo::new(id, String::from("test")));
+        let after = ecs.snapshot_entity(id).unwrap();
+        let update = EntityChange::between_snapshots(Some(&before), Some(&after)).unwrap();
+        assert_eq!(update.kind, EntityChangeKind::UpdateEntity(id));
+        assert!(matches!(update.debug_info, Change::Create { .. }));
+        assert_eq!(update.physics, Change::Unchanged);
+        assert!(replica.is_change_valid(&update));
+        replica.apply_entity_changes(&[update.clone()]);
+        assert_eq!(replica.snapshot_entity(id).as_ref(), Some(&after));
+        // applying the same update twice means the replica diverged
+        assert!(!replica.is_change_valid(&update));
+
+        let delete = EntityChange::between_snapshots(Some(&after), None).unwrap();
+        assert!(replica.is_change_valid(&delete));
+        replica.apply_entity_changes(&[delete]);
+        assert!(!replica.has_entity(id));
+    }
+}
@@ -1,11 +1,16 @@
 use crate::ecs::*;
+use crate::physics::{SMALL_V_CUTOFF, TIMESTEP};
 use bxw_util::change::Change;
 use bxw_util::collider::AABB;
+use bxw_util::glm;
 use bxw_util::math::*;
+use serde::{Deserialize, Serialize};
 
 pub const PLAYER_WIDTH: f64 = 1.2;
 pub const PLAYER_HEIGHT: f64 = 3.90;
 pub const PLAYER_EYE_HEIGHT: f64 = 3.80;
+/// Maximum distance of blocks the player can break or place
+pub const PLAYER_REACH: f64 = 32.0;
 
 pub const PLAYER_INVENTORY_SLOTS_WIDTH: u32 = 9;
 pub const PLAYER_INVENTORY_SLOTS_HEIGHT: u32 = 5;
@@ -39,3 +44,128 @@ pub fn create_player(ecs: &ECS, local: bool, name: String) -> EntityChange {
         inventory: Change::Create { new: inventory },
     }
 }
+
+/// Where new players appear
+pub const PLAYER_SPAWN_POSITION: [f64; 3] = [300.0, 32.0, 28.0];
+
+/// Movement controls of a player for a single physics tick, applied the same way by clients and the server
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    /// X (-Left,Right+) Y (-Back,Forward+), each component in the [-1, 1] range
+    pub walk: [f32; 2],
+    pub pitch: f64,
+    pub yaw: f64,
+    pub jump: bool,
+    pub sprint: bool,
+    /// Debugging aid, moves through blocks without physics
+    pub noclip: bool,
+}
+
+/// The state of a player that carries over between physics ticks, used to correct mispredicted movement
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerMovementState {
+    pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+    pub control_frame_impulse: Vector3<f64>,
+}
+
+impl PlayerMovementState {
+    pub fn of_entity(ecs: &ECS, id: ValidEntityID) -> Option<Self> {
+        let location: &CLocation = ecs.get_component(id)?;
+        let physics: &CPhysics = ecs.get_component(id)?;
+        Some(Self {
+            position: location.position,
+            velocity: location.velocity,
+            control_frame_impulse: physics.control_frame_impulse,
+        })
+    }
+
+    /// The change that moves the entity into this state
+    pub fn change_for(&self, ecs: &ECS, id: ValidEntityID) -> Option<EntityChange> {
+        let location: &CLocation = ecs.get_component(id)?;
+        let physics: &CPhysics = ecs.get_component(id)?;
+        let mut new_location = location.clone();
+        new_location.position = self.position;
+        new_location.velocity = self.velocity;
+        let mut new_physics = physics.clone();
+        new_physics.control_frame_impulse = self.control_frame_impulse;
+        Some(EntityChange {
+            kind: EntityChangeKind::UpdateEntity(id),
+            location: Change::Update {
+                old: location.clone(),
+                new: new_location,
+            },
+            physics: Change::Update {
+                old: physics.clone(),
+                new: new_physics,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// Sets the player's orientation and movement controls for the next physics tick
+pub fn apply_player_input(
+    ecs: &ECS,
+    id: ValidEntityID,
+    input: &PlayerInput,
+) -> Option<EntityChange> {
+    let location: &CLocation = ecs.get_component(id)?;
+    let physics: &CPhysics = ecs.get_component(id)?;
+    let valid = input.walk.iter().all(|c| c.is_finite())
+        && input.pitch.is_finite()
+        && input.yaw.is_finite();
+    if !valid {
+        return None;
+    }
+    let mut new_loc: CLocation = location.clone();
+    let qyaw = Quaternion::from_polar_decomposition(1.0, input.yaw, Vector3::y_axis());
+    let qpitch = Quaternion::from_polar_decomposition(1.0, input.pitch, Vector3::x_axis());
+    new_loc.orientation = UnitQuaternion::new_normalize(qpitch * qyaw);
+
+    let mview = glm::quat_to_mat3(&new_loc.orientation).transpose();
+
+    let mut wvel = Vector3::new(
+        f64::from(input.walk[0].max(-1.0).min(1.0)),
+        0.0,
+        f64::from(input.walk[1].max(-1.0).min(1.0)),
+    );
+    wvel *= 6.0; // Walk 6m/s
+    if input.noclip {
+        wvel *= 10.0;
+    }
+    if input.sprint {
+        wvel *= 3.0;
+    }
+    let mut tvel = mview * wvel;
+    let tspeed = tvel.magnitude();
+
+    if input.noclip {
+        new_loc.position += tvel * TIMESTEP;
+        new_loc.velocity = tvel;
+    } else {
+        tvel.y = 0.0;
+    }
+    let mut new_phys: CPhysics = physics.clone();
+    new_phys.control_target_velocity = if tspeed < SMALL_V_CUTOFF {
+        zero()
+    } else {
+        tvel.normalize() * tspeed
+    };
+    new_phys.frozen = input.noclip;
+    if input.jump && new_phys.against_wall[2] {
+        new_phys.control_frame_impulse.y = 300.0;
+    }
+    Some(EntityChange {
+        kind: EntityChangeKind::UpdateEntity(id),
+        location: Change::Update {
+            old: location.clone(),
+            new: new_loc,
+        },
+        physics: Change::Update {
+            old: physics.clone(),
+            new: new_phys,
+        },
+        ..Default::default()
+    })
+}
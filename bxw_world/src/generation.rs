@@ -1,32 +1,98 @@
 use crate::ecs::CLoadAnchor;
-use crate::stdgen::StdGenerator;
+use crate::storage::palette::VoxelPalette;
 use crate::worldmgr::*;
 use crate::*;
+use bxw_util::fnv::FnvHashMap;
 use bxw_util::itertools::Itertools;
 use bxw_util::taskpool::Task;
 use std::any::Any;
 use std::sync::Arc;
 use std::time::Instant;
 
+/// Fills newly created chunks with terrain, called from worker threads
+pub trait WorldGenerator: Send + Sync {
+    /// Identifier stored in the save file, used to pick the same generator when the world is reopened
+    fn name(&self) -> &'static str;
+    /// Bumped whenever the generator starts producing different terrain for the same seed and settings
+    fn version(&self) -> u32;
+    /// TOML-serialized settings, recreating the generator from them has to produce the same terrain
+    fn settings(&self) -> String;
+    fn generate_chunk(&self, chunk: &mut UncompressedChunk, registry: &VoxelRegistry);
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum WorldGeneratorError {
+    UnknownGenerator(String),
+    InvalidSettings(String),
+    /// The world was created by a newer version of the generator than the one available
+    UnsupportedVersion {
+        name: String,
+        version: u32,
+    },
+}
+
+/// Parses generator settings, an empty string gives the default settings
+pub fn parse_generator_settings<T: serde::de::DeserializeOwned + Default>(
+    settings: &str,
+) -> Result<T, WorldGeneratorError> {
+    if settings.trim().is_empty() {
+        return Ok(T::default());
+    }
+    bxw_util::toml::from_str(settings)
+        .map_err(|e| WorldGeneratorError::InvalidSettings(e.to_string()))
+}
+
+pub fn serialize_generator_settings<T: serde::Serialize>(settings: &T) -> String {
+    bxw_util::toml::to_string(settings).expect("Couldn't serialize world generator settings")
+}
+
 pub struct WorldBlocks {
     pub voxel_registry: Arc<VoxelRegistry>,
-    pub generator: Arc<StdGenerator>,
+    /// `None` for worlds streamed from a server, their chunks are only received over the network
+    pub generator: Option<Arc<dyn WorldGenerator>>,
+    /// Maps the voxel ids of serialized or received chunks to the registry's
+    pub palette: VoxelPalette,
     status_array: Vec<ChunkDataState>,
     compressed_storage: Vec<Option<Arc<VChunk>>>,
+    /// Chunks the server has sent and not unloaded yet, with the data of those not currently loaded locally
+    remote_chunks: FnvHashMap<ChunkPosition, Option<Arc<VChunk>>>,
     cache: RefCell<VCache>,
 }
 
 impl WorldBlocks {
-    pub fn new(voxel_registry: Arc<VoxelRegistry>, seed: u64) -> Self {
+    pub fn new(
+        voxel_registry: Arc<VoxelRegistry>,
+        generator: Arc<dyn WorldGenerator>,
+        palette: VoxelPalette,
+    ) -> Self {
         Self {
             voxel_registry,
-            generator: Arc::new(StdGenerator::new(seed)),
+            generator: Some(generator),
+            palette,
             status_array: Vec::new(),
             compressed_storage: Vec::new(),
+            remote_chunks: Default::default(),
             cache: Default::default(),
         }
     }
 
+    /// Block data of a world streamed from a server, see [`receive_remote_chunk`]
+    pub fn new_remote(voxel_registry: Arc<VoxelRegistry>) -> Self {
+        Self {
+            voxel_registry,
+            generator: None,
+            palette: VoxelPalette::identity(),
+            status_array: Vec::new(),
+            compressed_storage: Vec::new(),
+            remote_chunks: Default::default(),
+            cache: Default::default(),
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.generator.is_none()
+    }
+
     pub fn get_vcache(&self) -> RefMut<'_, VCache> {
         self.cache.borrow_mut()
     }
@@ -86,6 +152,12 @@ impl ChunkDataHandler for WorldBlocks {
     fn swap_data(&mut self, _world: &World, index: usize, new_data: AnyChunkData) -> AnyChunkData {
         let new_data = new_data.map(|d| d.downcast::<VChunk>().unwrap());
         let old_data = std::mem::replace(&mut self.compressed_storage[index], new_data);
+        // the server won't send an unloaded chunk again until it unloads it on its side too
+        if let (None, Some(old)) = (&self.compressed_storage[index], &old_data) {
+            if let Some(kept) = self.remote_chunks.get_mut(&old.position) {
+                *kept = Some(old.clone());
+            }
+        }
         old_data.map(|x| x as AnyChunkDataArc)
     }
 
@@ -99,10 +171,25 @@ impl ChunkDataHandler for WorldBlocks {
         cpos: ChunkPosition,
         index: usize,
     ) -> Option<Task> {
+        let generator = match &self.generator {
+            Some(g) => g,
+            None => {
+                // remote chunks are loaded once the server sends them
+                match self.remote_chunks.get_mut(&cpos).and_then(Option::take) {
+                    Some(chunk) => {
+                        self.status_array[index] = ChunkDataState::Loaded;
+                        self.compressed_storage[index] = Some(chunk);
+                        self.cache.borrow_mut().uncompressed_chunks.pop(&cpos);
+                    }
+                    None => self.status_array[index] = ChunkDataState::Loading,
+                }
+                return None;
+            }
+        };
         self.status_array[index] = ChunkDataState::Loading;
         let registry = self.voxel_registry.clone();
         let submit_channel = world.get_sync_task_channel();
-        let worldgen = Arc::downgrade(&self.generator);
+        let worldgen = Arc::downgrade(generator);
         Some(Task::new(
             move || {
                 let _p_zone = bxw_util::tracy_client::Span::new(
@@ -158,11 +245,23 @@ impl ChunkDataHandler for WorldBlocks {
     }
 
     fn serializable(&self) -> bool {
-        true
+        !self.is_remote()
     }
 
     fn serialize_data(&self, _world: &World, index: usize) -> Option<Vec<u8>> {
         let data = self.compressed_storage.get(index)?.as_ref()?;
+        let remapped;
+        let data = if self.palette.is_identity() {
+            data.as_ref()
+        } else {
+            let mut chunk = data.decompress();
+            self.palette.chunk_to_saved(&mut chunk);
+            let mut vchunk = VChunk::new();
+            vchunk.position = data.position;
+            vchunk.compress(&chunk);
+            remapped = vchunk;
+            &remapped
+        };
         let VChunkData::QuickCompressed { vox } = &data.data;
         let mut out: Vec<u8> = Vec::with_capacity(vox.len() * 4);
         vox.iter()
@@ -186,11 +285,21 @@ impl ChunkDataHandler for WorldBlocks {
                 .tuples()
                 .map(|(&a, &b, &c, &d)| u32::from_le_bytes([a, b, c, d])),
         );
+        let position = world
+            .get_chunk_position(index)
+            .ok_or("Trying to deserialize a chunk without an assigned position")?;
+        let vox = if self.palette.is_identity() {
+            vox
+        } else {
+            let mut chunk =
+                crate::decompress_rle(&vox).map_err(|_| "Invalid compressed voxel data")?;
+            chunk.position = position;
+            self.palette.chunk_from_saved(&mut chunk);
+            crate::compress_rle(chunk.blocks_yzx.iter().copied().map(VoxelDatum::repr))
+        };
         let new_data = VChunk {
             data: VChunkData::QuickCompressed { vox },
-            position: world
-                .get_chunk_position(index)
-                .ok_or("Trying to deserialize a chunk without an assigned position")?,
+            position,
         };
         let old_data = std::mem::replace(
             &mut self.compressed_storage[index],
@@ -208,6 +317,143 @@ impl ChunkDataHandler for WorldBlocks {
     }
 }
 
+/// Stores a chunk received from the server as little-endian RLE words in the server's voxel ids.
+/// If the chunk is already loaded the differences are applied as voxel changes, so that dependent data gets updated.
+pub fn receive_remote_chunk(
+    world: &mut World,
+    cpos: ChunkPosition,
+    vox: Vec<u32>,
+) -> Result<(), &'static str> {
+    let mut blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow_mut();
+    let blocks: &mut WorldBlocks = blocks_ref.as_any_mut().downcast_mut().unwrap();
+    if !blocks.is_remote() {
+        return Err("Received a chunk for a locally generated world");
+    }
+    let mut ucchunk = crate::decompress_rle(&vox).map_err(|_| "Invalid compressed voxel data")?;
+    ucchunk.position = cpos;
+    blocks.palette.chunk_from_saved(&mut ucchunk);
+    if ucchunk.blocks_yzx.iter().any(|v| {
+        blocks
+            .voxel_registry
+            .try_get_definition_from_id(v.id())
+            .is_none()
+    }) {
+        return Err("Unknown voxel id in received chunk");
+    }
+    let index = world.get_chunk_index(cpos);
+    let status = index.map(|i| blocks.status_array[i]);
+    if status == Some(ChunkDataState::Loaded) {
+        blocks.remote_chunks.insert(cpos, None);
+        let old = blocks
+            .get_chunk(world, cpos)
+            .expect("Loaded chunk without data")
+            .decompress();
+        drop(blocks_ref);
+        let origin = cpos.0 * CHUNK_DIM as i32;
+        let changes = old
+            .blocks_yzx
+            .iter()
+            .zip(ucchunk.blocks_yzx.iter())
+            .enumerate()
+            .filter(|(_, (from, to))| from != to)
+            .map(|(bidx, (&from, &to))| VoxelChange {
+                bpos: BlockPosition(origin + BlockPosition::from_blockidx(bidx as u32).0),
+                from,
+                to,
+            })
+            .collect_vec();
+        if !changes.is_empty() {
+            world.apply_voxel_changes(&changes);
+        }
+        return Ok(());
+    }
+    let vox = if blocks.palette.is_identity() {
+        vox
+    } else {
+        crate::compress_rle(ucchunk.blocks_yzx.iter().copied().map(VoxelDatum::repr))
+    };
+    let chunk = Arc::new(VChunk {
+        data: VChunkData::QuickCompressed { vox },
+        position: cpos,
+    });
+    match (index, status) {
+        (Some(index), Some(ChunkDataState::Loading)) => {
+            blocks.remote_chunks.insert(cpos, None);
+            blocks.status_array[index] = ChunkDataState::Loaded;
+            blocks.compressed_storage[index] = Some(chunk);
+            blocks.cache.borrow_mut().uncompressed_chunks.pop(&cpos);
+        }
+        _ => {
+            blocks.remote_chunks.insert(cpos, Some(chunk));
+        }
+    }
+    Ok(())
+}
+
+/// The server stopped tracking the chunk, a copy of it isn't kept for reloading anymore
+pub fn forget_remote_chunk(world: &World, cpos: ChunkPosition) {
+    let mut blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow_mut();
+    let blocks: &mut WorldBlocks = blocks_ref.as_any_mut().downcast_mut().unwrap();
+    blocks.remote_chunks.remove(&cpos);
+}
+
+/// Sets blocks of a world streamed from a server to the server's data, in the server's voxel ids.
+/// Blocks of chunks that weren't received yet are skipped, the server sends them with the whole chunk.
+pub fn set_remote_blocks(
+    world: &mut World,
+    new_blocks: &[(BlockPosition, VoxelDatum)],
+) -> Result<(), &'static str> {
+    let mut blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow_mut();
+    let blocks: &mut WorldBlocks = blocks_ref.as_any_mut().downcast_mut().unwrap();
+    if !blocks.is_remote() {
+        return Err("Received blocks for a locally generated world");
+    }
+    let new_blocks = new_blocks
+        .iter()
+        .map(|&(bpos, datum)| (bpos, blocks.palette.datum_from_saved(datum)))
+        .collect_vec();
+    if new_blocks.iter().any(|(_, datum)| {
+        blocks
+            .voxel_registry
+            .try_get_definition_from_id(datum.id())
+            .is_none()
+    }) {
+        return Err("Unknown voxel id in received blocks");
+    }
+    let mut changes = Vec::new();
+    for (cpos, group) in &new_blocks
+        .iter()
+        .group_by(|(bpos, _)| ChunkPosition::from(*bpos))
+    {
+        let index = world.get_chunk_index(cpos);
+        if index.map(|i| blocks.status_array[i]) == Some(ChunkDataState::Loaded) {
+            let mut vcache = blocks.get_vcache();
+            for &(bpos, to) in group {
+                let from = vcache
+                    .get_block(world, blocks, bpos)
+                    .expect("Loaded chunk without data");
+                if from != to {
+                    changes.push(VoxelChange { bpos, from, to });
+                }
+            }
+        } else if let Some(Some(kept)) = blocks.remote_chunks.get_mut(&cpos) {
+            let mut ucchunk = kept.decompress();
+            for &(bpos, to) in group {
+                ucchunk.blocks_yzx[bpos.as_blockidx()] = to;
+            }
+            let mut vchunk = VChunk::new();
+            vchunk.position = cpos;
+            vchunk.compress(&ucchunk);
+            *kept = Arc::new(vchunk);
+        }
+    }
+    drop(blocks_ref);
+    if !changes.is_empty() {
+        world.apply_voxel_changes(&changes);
+    }
+    Ok(())
+}
+
 pub struct VCache {
     uncompressed_chunks: LruCache<ChunkPosition, Box<UncompressedChunk>>,
 }
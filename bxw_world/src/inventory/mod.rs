@@ -1,7 +1,9 @@
 use crate::ecs::*;
-use crate::itemregistry::ItemID;
+use crate::itemregistry::{ItemDefinition, ItemID, ItemRegistry};
+use bxw_util::change::Change;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SlotType {
     Item,
     Fluid,
@@ -10,7 +12,7 @@ pub enum SlotType {
 pub type StackSize = u32;
 pub const DEFAULT_SLOT_CAPACITY: StackSize = 120;
 
-#[derive(Clone, Debug, PartialEq, Hash)]
+#[derive(Clone, Debug, PartialEq, Hash, Serialize, Deserialize)]
 pub struct InventorySlot {
     name: [u8; 4],
     type_: SlotType,
@@ -19,7 +21,77 @@ pub struct InventorySlot {
     held_count: StackSize,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl InventorySlot {
+    pub fn new(name: [u8; 4], type_: SlotType, capacity: StackSize) -> Self {
+        Self {
+            name,
+            type_,
+            capacity,
+            held_id: ItemID::default(),
+            held_count: 0,
+        }
+    }
+
+    pub fn name(&self) -> [u8; 4] {
+        self.name
+    }
+
+    pub fn slot_type(&self) -> SlotType {
+        self.type_
+    }
+
+    pub fn capacity(&self) -> StackSize {
+        self.capacity
+    }
+
+    pub fn held_id(&self) -> ItemID {
+        self.held_id
+    }
+
+    pub fn held_count(&self) -> StackSize {
+        self.held_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held_count == 0
+    }
+
+    /// How many items of the given kind fit in this slot in total
+    pub fn limit_for(&self, item: &ItemDefinition) -> StackSize {
+        if item.slot_type != self.type_ {
+            0
+        } else {
+            self.capacity.min(item.max_stack)
+        }
+    }
+
+    /// How many more items of the given kind can be put into this slot
+    pub fn space_for(&self, item: &ItemDefinition) -> StackSize {
+        if self.is_empty() {
+            self.limit_for(item)
+        } else if self.held_id == item.id {
+            self.limit_for(item).saturating_sub(self.held_count)
+        } else {
+            0
+        }
+    }
+
+    fn put(&mut self, item: ItemID, count: StackSize) {
+        if count > 0 {
+            self.held_id = item;
+            self.held_count += count;
+        }
+    }
+
+    fn remove(&mut self, count: StackSize) {
+        self.held_count -= count;
+        if self.held_count == 0 {
+            self.held_id = ItemID::default();
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CInventory {
     id: ValidEntityID,
     slots: Vec<InventorySlot>,
@@ -32,16 +104,159 @@ impl CInventory {
             slots: Vec::with_capacity(slot_count as usize),
         };
         for islot in 0..slot_count {
-            inv.slots.push(InventorySlot {
-                name: islot.to_be_bytes(),
-                type_: SlotType::Item,
-                capacity: DEFAULT_SLOT_CAPACITY,
-                held_id: ItemID::default(),
-                held_count: 0,
-            });
+            inv.slots.push(InventorySlot::new(
+                islot.to_be_bytes(),
+                SlotType::Item,
+                DEFAULT_SLOT_CAPACITY,
+            ));
         }
         inv
     }
+
+    pub fn with_slots(id: ValidEntityID, slots: Vec<InventorySlot>) -> Self {
+        Self { id, slots }
+    }
+
+    pub fn slots(&self) -> &[InventorySlot] {
+        &self.slots
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> Result<&mut InventorySlot, InventoryError> {
+        self.slots
+            .get_mut(slot)
+            .ok_or(InventoryError::InvalidSlot(slot))
+    }
+
+    /// Applies the change in place, on error the inventory is left unmodified
+    pub fn apply_change(
+        &mut self,
+        change: &InventoryChange,
+        items: &ItemRegistry,
+    ) -> Result<InventoryChangeOutcome, InventoryError> {
+        let mut new_inv = self.clone();
+        let outcome = new_inv.apply_change_unchecked(change, items)?;
+        *self = new_inv;
+        Ok(outcome)
+    }
+
+    /// Computes the result of the change as an `EntityChange` that can be applied to (or replayed on) the world
+    pub fn change_entity(
+        &self,
+        change: &InventoryChange,
+        items: &ItemRegistry,
+    ) -> Result<(EntityChange, InventoryChangeOutcome), InventoryError> {
+        let mut new_inv = self.clone();
+        let outcome = new_inv.apply_change(change, items)?;
+        Ok((self.entity_change_to(new_inv), outcome))
+    }
+
+    /// An `EntityChange` replacing this inventory with `new`, only valid while this inventory is unchanged
+    pub fn entity_change_to(&self, new: CInventory) -> EntityChange {
+        EntityChange {
+            kind: EntityChangeKind::UpdateEntity(self.id),
+            inventory: Change::Update {
+                old: self.clone(),
+                new,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn apply_change_unchecked(
+        &mut self,
+        change: &InventoryChange,
+        items: &ItemRegistry,
+    ) -> Result<InventoryChangeOutcome, InventoryError> {
+        let mut outcome = InventoryChangeOutcome::default();
+        match *change {
+            InventoryChange::Insert { item, count } => {
+                let idef = items
+                    .try_get_definition_from_id(item)
+                    .ok_or(InventoryError::UnknownItem(item))?;
+                let mut remaining = count;
+                // Top up existing stacks first, then fill empty slots
+                for fill_empty in [false, true].iter().copied() {
+                    for slot in self.slots.iter_mut() {
+                        if remaining == 0 {
+                            break;
+                        }
+                        if slot.is_empty() != fill_empty {
+                            continue;
+                        }
+                        let moved = slot.space_for(idef).min(remaining);
+                        slot.put(item, moved);
+                        remaining -= moved;
+                    }
+                }
+                outcome.overflow = remaining;
+            }
+            InventoryChange::Take { slot, count } => {
+                let slot = self.slot_mut(slot)?;
+                if count == 0 {
+                    return Err(InventoryError::InvalidCount);
+                }
+                if slot.held_count < count {
+                    return Err(InventoryError::NotEnoughItems);
+                }
+                outcome.taken = Some((slot.held_id, count));
+                slot.remove(count);
+            }
+            InventoryChange::Swap { a, b } => {
+                self.slot_mut(a)?;
+                self.slot_mut(b)?;
+                if a == b {
+                    return Ok(outcome);
+                }
+                for &(from, to) in &[(a, b), (b, a)] {
+                    let held = &self.slots[from];
+                    if held.is_empty() {
+                        continue;
+                    }
+                    let idef = items
+                        .try_get_definition_from_id(held.held_id)
+                        .ok_or(InventoryError::UnknownItem(held.held_id))?;
+                    let target = &self.slots[to];
+                    if target.type_ != idef.slot_type {
+                        return Err(InventoryError::SlotTypeMismatch);
+                    }
+                    if target.limit_for(idef) < held.held_count {
+                        return Err(InventoryError::OverCapacity);
+                    }
+                }
+                let (held_a, count_a) = (self.slots[a].held_id, self.slots[a].held_count);
+                let (held_b, count_b) = (self.slots[b].held_id, self.slots[b].held_count);
+                self.slots[a].held_id = held_b;
+                self.slots[a].held_count = count_b;
+                self.slots[b].held_id = held_a;
+                self.slots[b].held_count = count_a;
+            }
+            InventoryChange::Move { from, to, count } => {
+                let source = self.slot_mut(from)?.clone();
+                self.slot_mut(to)?;
+                if source.held_count < count {
+                    return Err(InventoryError::NotEnoughItems);
+                }
+                if from == to || count == 0 {
+                    return Ok(outcome);
+                }
+                let idef = items
+                    .try_get_definition_from_id(source.held_id)
+                    .ok_or(InventoryError::UnknownItem(source.held_id))?;
+                let target = &self.slots[to];
+                if target.type_ != idef.slot_type {
+                    return Err(InventoryError::SlotTypeMismatch);
+                }
+                if !target.is_empty() && target.held_id != source.held_id {
+                    return Err(InventoryError::OccupiedSlot);
+                }
+                let moved = target.space_for(idef).min(count);
+                self.slots[to].put(source.held_id, moved);
+                self.slots[from].remove(moved);
+                outcome.overflow = count - moved;
+            }
+        }
+        Ok(outcome)
+    }
 }
 
 impl Component for CInventory {
@@ -54,5 +269,257 @@ impl Component for CInventory {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum InventoryChange {}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InventoryError {
+    InvalidSlot(usize),
+    UnknownItem(ItemID),
+    SlotTypeMismatch,
+    /// The slot already holds a different item
+    OccupiedSlot,
+    OverCapacity,
+    NotEnoughItems,
+    /// Taking zero items
+    InvalidCount,
+    /// Transfers need two different inventories
+    SameInventory,
+}
+
+/// A single operation on an inventory, see `CInventory::apply_change`
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum InventoryChange {
+    /// Adds items, topping up existing stacks before filling empty slots, whatever doesn't fit overflows
+    Insert { item: ItemID, count: StackSize },
+    /// Removes exactly `count` items from the slot
+    Take { slot: usize, count: StackSize },
+    /// Exchanges the contents of two slots
+    Swap { a: usize, b: usize },
+    /// Moves (splits or merges) up to `count` items onto another slot, whatever doesn't fit stays in place
+    Move {
+        from: usize,
+        to: usize,
+        count: StackSize,
+    },
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct InventoryChangeOutcome {
+    /// Number of items that did not fit for Insert and Move
+    pub overflow: StackSize,
+    /// The item kind and amount removed by Take
+    pub taken: Option<(ItemID, StackSize)>,
+}
+
+/// Moves up to `count` items from a slot of one inventory into another inventory,
+/// returns the changes for both entities and the number of items that did not fit (and stayed in the source slot)
+pub fn transfer_items(
+    from: &CInventory,
+    from_slot: usize,
+    count: StackSize,
+    to: &CInventory,
+    items: &ItemRegistry,
+) -> Result<([EntityChange; 2], StackSize), InventoryError> {
+    if from.id == to.id {
+        return Err(InventoryError::SameInventory);
+    }
+    let source = from
+        .slots
+        .get(from_slot)
+        .ok_or(InventoryError::InvalidSlot(from_slot))?;
+    if source.held_count < count {
+        return Err(InventoryError::NotEnoughItems);
+    }
+    let mut new_to = to.clone();
+    let inserted = new_to.apply_change(
+        &InventoryChange::Insert {
+            item: source.held_id,
+            count,
+        },
+        items,
+    )?;
+    let moved = count - inserted.overflow;
+    let mut new_from = from.clone();
+    // nothing fit, the source stays as it was
+    if moved > 0 {
+        new_from.apply_change(
+            &InventoryChange::Take {
+                slot: from_slot,
+                count: moved,
+            },
+            items,
+        )?;
+    }
+    Ok((
+        [from.entity_change_to(new_from), to.entity_change_to(new_to)],
+        inserted.overflow,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_items() -> (ItemRegistry, ItemID, ItemID) {
+        let mut reg = ItemRegistry::new();
+        reg.build_definition()
+            .name("test:rock")
+            .max_stack(10)
+            .finish()
+            .unwrap();
+        reg.build_definition()
+            .name("test:water")
+            .slot_type(SlotType::Fluid)
+            .finish()
+            .unwrap();
+        let rock = reg.get_definition_from_name("test:rock").unwrap().id;
+        let water = reg.get_definition_from_name("test:water").unwrap().id;
+        (reg, rock, water)
+    }
+
+    fn test_id(sub_id: u64) -> ValidEntityID {
+        ValidEntityID::from_parts(EntityDomain::LocalOmnipresent, sub_id).unwrap()
+    }
+
+    #[test]
+    fn insert_take_test() {
+        let (items, rock, _) = test_items();
+        let mut inv = CInventory::new(test_id(1), 3);
+        let r = inv
+            .apply_change(
+                &InventoryChange::Insert {
+                    item: rock,
+                    count: 25,
+                },
+                &items,
+            )
+            .unwrap();
+        assert_eq!(r.overflow, 0);
+        let counts: Vec<_> = inv.slots().iter().map(InventorySlot::held_count).collect();
+        assert_eq!(counts, vec![10, 10, 5]);
+        let r = inv
+            .apply_change(
+                &InventoryChange::Insert {
+                    item: rock,
+                    count: 8,
+                },
+                &items,
+            )
+            .unwrap();
+        assert_eq!(r.overflow, 3);
+        let r = inv
+            .apply_change(&InventoryChange::Take { slot: 1, count: 4 }, &items)
+            .unwrap();
+        assert_eq!(r.taken, Some((rock, 4)));
+        let before = inv.clone();
+        assert_eq!(
+            inv.apply_change(&InventoryChange::Take { slot: 1, count: 7 }, &items),
+            Err(InventoryError::NotEnoughItems)
+        );
+        assert_eq!(
+            inv.apply_change(&InventoryChange::Take { slot: 1, count: 0 }, &items),
+            Err(InventoryError::InvalidCount)
+        );
+        assert_eq!(inv, before);
+        // an empty slot doesn't give out zero empty items either
+        let mut empty = CInventory::new(test_id(2), 1);
+        assert_eq!(
+            empty.apply_change(&InventoryChange::Take { slot: 0, count: 0 }, &items),
+            Err(InventoryError::InvalidCount)
+        );
+    }
+
+    #[test]
+    fn slot_type_test() {
+        let (items, rock, water) = test_items();
+        let mut inv = CInventory::with_slots(
+            test_id(1),
+            vec![
+                InventorySlot::new(*b"item", SlotType::Item, 5),
+                InventorySlot::new(*b"flui", SlotType::Fluid, 100),
+            ],
+        );
+        let r = inv
+            .apply_change(
+                &InventoryChange::Insert {
+                    item: water,
+                    count: 50,
+                },
+                &items,
+            )
+            .unwrap();
+        assert_eq!(r.overflow, 0);
+        assert_eq!(inv.slots()[1].held_count(), 50);
+        let r = inv
+            .apply_change(
+                &InventoryChange::Insert {
+                    item: rock,
+                    count: 7,
+                },
+                &items,
+            )
+            .unwrap();
+        assert_eq!(r.overflow, 2);
+        assert_eq!(
+            inv.apply_change(&InventoryChange::Swap { a: 0, b: 1 }, &items),
+            Err(InventoryError::SlotTypeMismatch)
+        );
+    }
+
+    #[test]
+    fn transfer_test() {
+        let (items, rock, _) = test_items();
+        let mut ecs = ECS::new();
+        let mut a = CInventory::new(ecs.add_new_entity(EntityDomain::LocalOmnipresent), 2);
+        let b = CInventory::new(ecs.add_new_entity(EntityDomain::LocalOmnipresent), 1);
+        a.apply_change(
+            &InventoryChange::Insert {
+                item: rock,
+                count: 15,
+            },
+            &items,
+        )
+        .unwrap();
+        ecs.set_component(a.entity_id(), a.clone());
+        ecs.set_component(b.entity_id(), b.clone());
+        let (changes, overflow) = transfer_items(&a, 0, 10, &b, &items).unwrap();
+        assert_eq!(overflow, 0);
+        ecs.apply_entity_changes(&changes);
+        // replaying the same changes is a no-op
+        ecs.apply_entity_changes(&changes);
+        let new_a: &CInventory = ecs.get_component(a.entity_id()).unwrap();
+        let new_b: &CInventory = ecs.get_component(b.entity_id()).unwrap();
+        assert_eq!(new_a.slots()[0].held_count(), 0);
+        assert_eq!(new_a.slots()[1].held_count(), 5);
+        assert_eq!(new_b.slots()[0].held_count(), 10);
+        assert_eq!(new_b.slots()[0].held_id(), rock);
+    }
+
+    #[test]
+    fn transfer_into_full_test() {
+        let (items, rock, _) = test_items();
+        let mut ecs = ECS::new();
+        let mut a = CInventory::new(ecs.add_new_entity(EntityDomain::LocalOmnipresent), 1);
+        let mut b = CInventory::new(ecs.add_new_entity(EntityDomain::LocalOmnipresent), 1);
+        for inv in [&mut a, &mut b].iter_mut() {
+            inv.apply_change(
+                &InventoryChange::Insert {
+                    item: rock,
+                    count: 10,
+                },
+                &items,
+            )
+            .unwrap();
+        }
+        let (changes, overflow) = transfer_items(&a, 0, 4, &b, &items).unwrap();
+        assert_eq!(overflow, 4);
+        ecs.set_component(a.entity_id(), a.clone());
+        ecs.set_component(b.entity_id(), b.clone());
+        ecs.apply_entity_changes(&changes);
+        let new_a: &CInventory = ecs.get_component(a.entity_id()).unwrap();
+        let new_b: &CInventory = ecs.get_component(b.entity_id()).unwrap();
+        assert_eq!(new_a, &a);
+        assert_eq!(new_b, &b);
+        // transferring nothing is fine as well
+        let (_, overflow) = transfer_items(&a, 0, 0, &b, &items).unwrap();
+        assert_eq!(overflow, 0);
+    }
+}
@@ -1,12 +1,332 @@
+use crate::inventory::{SlotType, StackSize, DEFAULT_SLOT_CAPACITY};
+use crate::storage::palette::MISSING_VOXEL_NAME;
+use crate::{TextureMapping, VoxelId, VoxelRegistry};
+use std::collections::HashMap;
+
 pub type ItemID = u16;
 
-#[derive(Clone, Debug, Hash)]
+/// Name of the item with ID 0, used for empty inventory slots
+pub const EMPTY_ITEM_NAME: &str = "core:empty";
+
+#[derive(Clone, Debug)]
 pub struct ItemDefinition {
     pub id: ItemID,
+    /// eg. core:stone
     pub name: String,
+    /// Human-readable name shown in the UI, eg. Stone
+    pub display_name: String,
+    /// Maximum number of items of this kind in a single inventory slot
+    pub max_stack: StackSize,
+    /// The kind of inventory slot this item can be stored in
+    pub slot_type: SlotType,
+    pub icon_mapping: TextureMapping<u32>,
+    /// The voxel placed when using this item, for block items
+    pub placed_voxel: Option<VoxelId>,
+}
+
+impl ItemDefinition {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> ItemID {
+        self.id
+    }
+}
+
+pub struct ItemDefinitionBuilder<'a> {
+    registry: &'a mut ItemRegistry,
+    id: ItemID,
+    name: String,
+    display_name: Option<String>,
+    max_stack: StackSize,
+    slot_type: SlotType,
+    icon_mapping: TextureMapping<u32>,
+    placed_voxel: Option<VoxelId>,
 }
 
 #[derive(Clone)]
 pub struct ItemRegistry {
-    //
+    definitions: Vec<Option<ItemDefinition>>,
+    name_lut: HashMap<String, usize>,
+    voxel_lut: HashMap<VoxelId, usize>,
+    last_free_id: ItemID,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum ItemDefinitionError {
+    AlreadyExists,
+    InvalidStackSize,
+}
+
+/// Turns a namespaced name like core:diamond_ore into a display name like Diamond ore
+pub fn default_display_name(name: &str) -> String {
+    let base = name.rsplit(':').next().unwrap_or(name).replace('_', " ");
+    let mut chars = base.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => base,
+    }
+}
+
+impl<'a> ItemDefinitionBuilder<'a> {
+    pub fn name(mut self, v: &str) -> Self {
+        self.name = String::from(v);
+        self
+    }
+
+    pub fn display_name(mut self, v: &str) -> Self {
+        self.display_name = Some(String::from(v));
+        self
+    }
+
+    pub fn max_stack(mut self, v: StackSize) -> Self {
+        self.max_stack = v;
+        self
+    }
+
+    pub fn slot_type(mut self, v: SlotType) -> Self {
+        self.slot_type = v;
+        self
+    }
+
+    pub fn placed_voxel(mut self, v: Option<VoxelId>) -> Self {
+        self.placed_voxel = v;
+        self
+    }
+
+    pub fn icon_mapping(mut self, t: TextureMapping<u32>) -> Self {
+        self.icon_mapping = t;
+        self
+    }
+
+    pub fn icon_names(mut self, mapper_fn: &dyn Fn(&str) -> u32, t: TextureMapping<&str>) -> Self {
+        self.icon_mapping = t.map(mapper_fn);
+        self
+    }
+
+    pub fn finish(self) -> Result<(), ItemDefinitionError> {
+        if self.max_stack == 0 && self.id != 0 {
+            return Err(ItemDefinitionError::InvalidStackSize);
+        }
+        let def = ItemDefinition {
+            id: self.id,
+            display_name: self
+                .display_name
+                .unwrap_or_else(|| default_display_name(&self.name)),
+            name: self.name,
+            max_stack: self.max_stack,
+            slot_type: self.slot_type,
+            icon_mapping: self.icon_mapping,
+            placed_voxel: self.placed_voxel,
+        };
+        let idx = def.id as usize;
+        if self.registry.definitions.len() <= idx {
+            self.registry.definitions.resize(idx * 2 + 1, None);
+        } else if self.registry.definitions[idx].is_some() {
+            return Err(ItemDefinitionError::AlreadyExists);
+        }
+        if self.registry.name_lut.contains_key(&def.name) {
+            return Err(ItemDefinitionError::AlreadyExists);
+        }
+        self.registry.name_lut.insert(def.name.clone(), idx);
+        if let Some(vid) = def.placed_voxel {
+            self.registry.voxel_lut.insert(vid, idx);
+        }
+        self.registry.definitions[idx] = Some(def);
+        Ok(())
+    }
+}
+
+impl Default for ItemRegistry {
+    fn default() -> Self {
+        let mut reg = ItemRegistry {
+            definitions: Default::default(),
+            name_lut: Default::default(),
+            voxel_lut: Default::default(),
+            last_free_id: 0,
+        };
+        reg.build_definition()
+            .name(EMPTY_ITEM_NAME)
+            .display_name("")
+            .max_stack(0)
+            .finish()
+            .unwrap();
+        reg
+    }
+}
+
+impl ItemRegistry {
+    pub fn new() -> ItemRegistry {
+        Default::default()
+    }
+
+    pub fn build_definition(&mut self) -> ItemDefinitionBuilder {
+        ItemDefinitionBuilder {
+            id: {
+                let id = self.last_free_id;
+                self.last_free_id += 1;
+                id
+            },
+            name: String::default(),
+            display_name: None,
+            registry: self,
+            max_stack: DEFAULT_SLOT_CAPACITY,
+            slot_type: SlotType::Item,
+            icon_mapping: TextureMapping::new_single(0),
+            placed_voxel: None,
+        }
+    }
+
+    /// Adds an item with the same name as the voxel for every voxel definition without one yet,
+    /// except core:void and the placeholder for missing voxels
+    pub fn register_block_items(
+        &mut self,
+        voxels: &VoxelRegistry,
+    ) -> Result<(), ItemDefinitionError> {
+        for vdef in voxels.iter() {
+            if vdef.id == 0
+                || vdef.name == MISSING_VOXEL_NAME
+                || self.voxel_lut.contains_key(&vdef.id)
+            {
+                continue;
+            }
+            self.build_definition()
+                .name(&vdef.name)
+                .icon_mapping(vdef.texture_mapping)
+                .placed_voxel(Some(vdef.id))
+                .finish()?;
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn get_definition_from_id(&self, id: ItemID) -> &ItemDefinition {
+        self.definitions[usize::from(id)].as_ref().unwrap()
+    }
+
+    pub fn try_get_definition_from_id(&self, id: ItemID) -> Option<&ItemDefinition> {
+        self.definitions.get(usize::from(id))?.as_ref()
+    }
+
+    pub fn get_definition_from_name(&self, name: &str) -> Option<&ItemDefinition> {
+        self.name_lut
+            .get(name)
+            .and_then(|x| self.definitions.get(*x)?.as_ref())
+    }
+
+    /// The item that places the given voxel
+    pub fn get_block_item(&self, voxel: VoxelId) -> Option<&ItemDefinition> {
+        self.voxel_lut
+            .get(&voxel)
+            .and_then(|x| self.definitions.get(*x)?.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ItemDefinition> {
+        self.definitions.iter().filter_map(Option::as_ref)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_name_test() {
+        assert_eq!(default_display_name("core:diamond_ore"), "Diamond ore");
+        assert_eq!(default_display_name("stone"), "Stone");
+        assert_eq!(default_display_name("core:"), "");
+    }
+
+    #[test]
+    fn builder_test() {
+        let mut reg = ItemRegistry::new();
+        let empty = reg.get_definition_from_id(0);
+        assert_eq!(empty.name(), EMPTY_ITEM_NAME);
+        assert_eq!(empty.max_stack, 0);
+        reg.build_definition()
+            .name("test:flask")
+            .display_name("Glass flask")
+            .max_stack(4)
+            .slot_type(SlotType::Fluid)
+            .finish()
+            .unwrap();
+        let flask = reg.get_definition_from_name("test:flask").unwrap();
+        assert_eq!(flask.id(), 1);
+        assert_eq!(flask.display_name, "Glass flask");
+        assert_eq!(flask.max_stack, 4);
+        assert_eq!(flask.slot_type, SlotType::Fluid);
+        assert_eq!(flask.placed_voxel, None);
+        assert_eq!(reg.get_definition_from_id(1).name(), "test:flask");
+        assert!(reg.try_get_definition_from_id(2).is_none());
+        assert!(reg.get_definition_from_name("test:missing").is_none());
+        assert_eq!(reg.iter().count(), 2);
+    }
+
+    #[test]
+    fn invalid_definitions_test() {
+        let mut reg = ItemRegistry::new();
+        reg.build_definition().name("test:rock").finish().unwrap();
+        assert_eq!(
+            reg.build_definition().name("test:rock").finish(),
+            Err(ItemDefinitionError::AlreadyExists)
+        );
+        assert_eq!(
+            reg.build_definition().name(EMPTY_ITEM_NAME).finish(),
+            Err(ItemDefinitionError::AlreadyExists)
+        );
+        assert_eq!(
+            reg.build_definition()
+                .name("test:nothing")
+                .max_stack(0)
+                .finish(),
+            Err(ItemDefinitionError::InvalidStackSize)
+        );
+        assert!(reg.get_definition_from_name("test:nothing").is_none());
+        assert_eq!(reg.iter().count(), 2);
+    }
+
+    #[test]
+    fn block_items_test() {
+        let mut voxels = VoxelRegistry::new();
+        voxels
+            .build_definition()
+            .name("test:stone")
+            .finish()
+            .unwrap();
+        voxels
+            .build_definition()
+            .name("test:sand")
+            .finish()
+            .unwrap();
+        voxels
+            .build_definition()
+            .name(MISSING_VOXEL_NAME)
+            .finish()
+            .unwrap();
+        let stone = voxels.get_definition_from_name("test:stone").unwrap().id;
+        let sand = voxels.get_definition_from_name("test:sand").unwrap().id;
+
+        let mut reg = ItemRegistry::new();
+        reg.build_definition()
+            .name("test:sand_pile")
+            .placed_voxel(Some(sand))
+            .finish()
+            .unwrap();
+        reg.register_block_items(&voxels).unwrap();
+        let stone_item = reg.get_block_item(stone).unwrap();
+        assert_eq!(stone_item.name(), "test:stone");
+        assert_eq!(stone_item.display_name, "Stone");
+        assert_eq!(stone_item.placed_voxel, Some(stone));
+        // voxels that already have an item keep it
+        assert_eq!(reg.get_block_item(sand).unwrap().name(), "test:sand_pile");
+        assert!(reg.get_definition_from_name("test:sand").is_none());
+        // core:void and the missing voxel placeholder don't get items
+        assert!(reg.get_block_item(0).is_none());
+        assert!(reg.get_definition_from_name(MISSING_VOXEL_NAME).is_none());
+        // registering again doesn't duplicate anything
+        let count = reg.iter().count();
+        reg.register_block_items(&voxels).unwrap();
+        assert_eq!(reg.iter().count(), count);
+    }
 }
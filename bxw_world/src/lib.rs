@@ -6,10 +6,12 @@ pub mod entities;
 pub mod generation;
 pub mod inventory;
 pub mod itemregistry;
+pub mod light;
 pub mod physics;
 pub mod raycast;
 pub mod stdgen;
 pub mod storage;
+pub mod testgen;
 pub mod voxregistry;
 pub mod worldmgr;
 
@@ -93,10 +95,11 @@ impl BlockPosition {
         Self(v)
     }
 
+    /// Inverse of `as_blockidx`, the index is in YZX order
     pub fn from_blockidx(bidx: u32) -> Self {
         let x = bidx % CHUNK_DIM as u32;
-        let y = (bidx / CHUNK_DIM as u32) % CHUNK_DIM as u32;
-        let z = (bidx / CHUNK_DIM2 as u32) % CHUNK_DIM as u32;
+        let z = (bidx / CHUNK_DIM as u32) % CHUNK_DIM as u32;
+        let y = (bidx / CHUNK_DIM2 as u32) % CHUNK_DIM as u32;
         Self::new(x as i32, y as i32, z as i32)
     }
 
@@ -487,9 +490,22 @@ impl<'d> Iterator for RleVoxelIterator<'d> {
 
 #[cfg(test)]
 mod test {
-    use crate::{compress_rle, decompress_rle, RleVoxelIterator, CHUNK_DIM3};
+    use crate::{compress_rle, decompress_rle, BlockPosition, RleVoxelIterator, CHUNK_DIM3};
     use bxw_util::itertools::Itertools;
 
+    #[test]
+    fn blockidx_roundtrip_test() {
+        for bidx in 0..CHUNK_DIM3 {
+            let bpos = BlockPosition::from_blockidx(bidx as u32);
+            assert_eq!(bpos.as_blockidx(), bidx);
+        }
+        let bpos = BlockPosition::new(1, 2, 3);
+        assert_eq!(
+            BlockPosition::from_blockidx(bpos.as_blockidx() as u32),
+            bpos
+        );
+    }
+
     #[test]
     fn rle_compress_zero_test() {
         let zeroes = [0u32; CHUNK_DIM3];
@@ -643,6 +659,10 @@ pub struct VoxelDefinition {
     pub selection_shape: Option<AABB>,
     pub debug_color: [f32; 3],
     pub texture_mapping: TextureMapping<u32>,
+    /// Block light level emitted by this voxel, 0..=MAX_LIGHT_LEVEL
+    pub light_emission: u8,
+    /// How many light levels are absorbed when passing through this voxel, MAX_LIGHT_LEVEL blocks all light
+    pub light_opacity: u8,
 }
 
 impl VoxelDefinition {
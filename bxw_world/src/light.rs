@@ -0,0 +1,457 @@
+use crate::ecs::CLoadAnchor;
+use crate::generation::WorldBlocks;
+use crate::storage::serializer::{storage_zstd_compress, storage_zstd_decompress};
+use crate::worldmgr::*;
+use crate::*;
+use bxw_util::itertools::{iproduct, Itertools};
+use bxw_util::taskpool::Task;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub const MAX_LIGHT_LEVEL: u8 = 15;
+/// Light can't travel further than this, so only this many voxels of the neighbouring chunks affect a chunk
+const LIGHT_MARGIN: i32 = MAX_LIGHT_LEVEL as i32;
+const REGION_DIM: i32 = CHUNK_DIM as i32 + 2 * LIGHT_MARGIN;
+const REGION_DIM2: i32 = REGION_DIM * REGION_DIM;
+const REGION_DIM3: usize = (REGION_DIM * REGION_DIM * REGION_DIM) as usize;
+
+/// Per-voxel light levels of a single chunk
+#[derive(Clone, Debug)]
+pub struct ChunkLight {
+    pub position: ChunkPosition,
+    /// Sky light in the high nibble, block light in the low nibble, in the same YZX order as voxels
+    pub levels: Vec<u8>,
+}
+
+impl ChunkLight {
+    pub fn new(position: ChunkPosition) -> Self {
+        Self {
+            position,
+            levels: vec![0; CHUNK_DIM3],
+        }
+    }
+
+    pub fn sky_light(&self, bpos: BlockPosition) -> u8 {
+        self.levels[bpos.as_blockidx()] >> 4
+    }
+
+    pub fn block_light(&self, bpos: BlockPosition) -> u8 {
+        self.levels[bpos.as_blockidx()] & 0x0F
+    }
+
+    /// The brighter of the sky and block light at the given position
+    pub fn light(&self, bpos: BlockPosition) -> u8 {
+        self.sky_light(bpos).max(self.block_light(bpos))
+    }
+}
+
+/// Chunks whose light can change when the voxel at `bpos` changes.
+/// Shadows cast further down are handled by the light handler marking the chunks below for an update.
+pub fn chunks_affected_by_change(bpos: BlockPosition) -> Vec<ChunkPosition> {
+    let margin = vec3(LIGHT_MARGIN, LIGHT_MARGIN, LIGHT_MARGIN);
+    let cmin = ChunkPosition::from(BlockPosition(bpos.0 - margin)).0;
+    let cmax = ChunkPosition::from(BlockPosition(bpos.0 + margin)).0;
+    iproduct!(cmin.y..=cmax.y, cmin.z..=cmax.z, cmin.x..=cmax.x)
+        .map(|(y, z, x)| ChunkPosition::new(x, y, z))
+        .collect_vec()
+}
+
+/// Index range of the voxel layer that chunks below read the incoming sky light from
+fn sky_seed_layer() -> std::ops::Range<usize> {
+    let seed_y = (REGION_DIM - LIGHT_MARGIN - CHUNK_DIM as i32) as usize;
+    seed_y * CHUNK_DIM2..(seed_y + 1) * CHUNK_DIM2
+}
+
+#[inline(always)]
+fn region_index(rpos: Vector3<i32>) -> usize {
+    (rpos.x + REGION_DIM * rpos.z + REGION_DIM2 * rpos.y) as usize
+}
+
+#[inline(always)]
+fn region_contains(rpos: Vector3<i32>) -> bool {
+    rpos.iter().all(|&c| (0..REGION_DIM).contains(&c))
+}
+
+#[inline(always)]
+fn attenuate(level: u8, opacity: u8) -> u8 {
+    level.saturating_sub(1 + opacity)
+}
+
+/// Breadth-first flood fill of light levels starting from the queued region indices
+fn propagate_light(levels: &mut [u8], opacity: &[u8], queue: &mut VecDeque<u32>) {
+    const STEPS: [(i32, i32, i32); 6] = [
+        (-1, 0, 0),
+        (1, 0, 0),
+        (0, -1, 0),
+        (0, 1, 0),
+        (0, 0, -1),
+        (0, 0, 1),
+    ];
+    while let Some(ri) = queue.pop_front() {
+        let ri = ri as i32;
+        let level = levels[ri as usize];
+        if level <= 1 {
+            continue;
+        }
+        let rpos = vec3(
+            ri % REGION_DIM,
+            ri / REGION_DIM2,
+            (ri / REGION_DIM) % REGION_DIM,
+        );
+        for &(dx, dy, dz) in STEPS.iter() {
+            let npos = rpos + vec3(dx, dy, dz);
+            if !region_contains(npos) {
+                continue;
+            }
+            let ni = region_index(npos);
+            let nlevel = attenuate(level, opacity[ni]);
+            if nlevel > levels[ni] {
+                levels[ni] = nlevel;
+                queue.push_back(ni as u32);
+            }
+        }
+    }
+}
+
+/// Calculates the light of the chunk at `cpos`.
+/// `neighbors` are the 27 chunks in `iter_neighbors(cpos, true)` order, `above` is the light of the 9 chunks
+/// one layer above `cpos` (ZX order), used to find out how much sky light enters the region from the top.
+/// Columns without loaded light above are assumed to be open to the sky.
+pub fn calculate_chunk_light(
+    registry: &VoxelRegistry,
+    cpos: ChunkPosition,
+    neighbors: &[Arc<VChunk>],
+    above: &[Option<Arc<ChunkLight>>],
+) -> ChunkLight {
+    assert_eq!(neighbors.len(), 27);
+    assert_eq!(above.len(), 9);
+    let margin = vec3(LIGHT_MARGIN, LIGHT_MARGIN, LIGHT_MARGIN);
+    let mut opacity = vec![MAX_LIGHT_LEVEL; REGION_DIM3];
+    let mut sky = vec![0u8; REGION_DIM3];
+    let mut block = vec![0u8; REGION_DIM3];
+    let mut queue: VecDeque<u32> = VecDeque::with_capacity(4096);
+
+    for (npos, chunk) in iter_neighbors(cpos, true).zip(neighbors.iter()) {
+        let offset = (npos - cpos).0 * CHUNK_DIM as i32 + margin;
+        for (datum, bpos, _) in chunk.iter() {
+            let rpos = bpos.0 + offset;
+            if !region_contains(rpos) {
+                continue;
+            }
+            let ri = region_index(rpos);
+            let vdef = registry.get_definition_from_datum(datum);
+            opacity[ri] = vdef.light_opacity;
+            if vdef.light_emission > 0 {
+                block[ri] = vdef.light_emission;
+                queue.push_back(ri as u32);
+            }
+        }
+    }
+    propagate_light(&mut block, &opacity, &mut queue);
+
+    // Full-strength sky light goes straight down without losing intensity, then spreads out like block light
+    let seed_y = REGION_DIM - LIGHT_MARGIN - CHUNK_DIM as i32;
+    for (rz, rx) in iproduct!(0..REGION_DIM, 0..REGION_DIM) {
+        let cx = rx - LIGHT_MARGIN;
+        let cz = rz - LIGHT_MARGIN;
+        let ax = cx.div_floor(&(CHUNK_DIM as i32));
+        let az = cz.div_floor(&(CHUNK_DIM as i32));
+        let mut level = match &above[((az + 1) * 3 + (ax + 1)) as usize] {
+            Some(light) => light.sky_light(BlockPosition::new(cx, seed_y, cz)),
+            None => MAX_LIGHT_LEVEL,
+        };
+        for ry in (0..REGION_DIM).rev() {
+            if level == 0 {
+                break;
+            }
+            let ri = region_index(vec3(rx, ry, rz));
+            let op = opacity[ri];
+            level = if level == MAX_LIGHT_LEVEL && op == 0 {
+                MAX_LIGHT_LEVEL
+            } else {
+                attenuate(level, op)
+            };
+            sky[ri] = level;
+            if level > 1 {
+                queue.push_back(ri as u32);
+            }
+        }
+    }
+    propagate_light(&mut sky, &opacity, &mut queue);
+
+    let mut light = ChunkLight::new(cpos);
+    for (bidx, out) in light.levels.iter_mut().enumerate() {
+        let rpos = BlockPosition::from_blockidx(bidx as u32).0 + margin;
+        let ri = region_index(rpos);
+        *out = (sky[ri] << 4) | block[ri];
+    }
+    light
+}
+
+pub struct WorldLight {
+    pub voxel_registry: Arc<VoxelRegistry>,
+    status_array: Vec<ChunkDataState>,
+    light_storage: Vec<ChunkLightData>,
+    /// Chunks whose light calculation in progress was started before the last change affecting them
+    outdated: Vec<bool>,
+}
+
+impl WorldLight {
+    pub fn new(voxel_registry: Arc<VoxelRegistry>) -> Self {
+        Self {
+            voxel_registry,
+            status_array: Vec::new(),
+            light_storage: Vec::new(),
+            outdated: Vec::new(),
+        }
+    }
+
+    pub fn get_chunk(&self, world: &World, cpos: ChunkPosition) -> ChunkLightData {
+        let idx = world.get_chunk_index(cpos);
+        idx.and_then(|i| Some(self.light_storage[i].as_ref()?.clone()))
+    }
+
+    /// Schedules the light of the chunk to be recalculated by the next load delta pass,
+    /// keeping the old light around until then
+    pub fn mark_outdated(&mut self, index: usize) {
+        match self.status_array[index] {
+            ChunkDataState::Loaded => self.status_array[index] = ChunkDataState::NotInIo,
+            ChunkDataState::Loading | ChunkDataState::Updating => self.outdated[index] = true,
+            _ => {}
+        }
+    }
+}
+
+impl ChunkDataHandler for WorldLight {
+    fn status_array(&self) -> &Vec<ChunkDataState> {
+        &self.status_array
+    }
+
+    fn status_array_mut(&mut self) -> &mut Vec<ChunkDataState> {
+        &mut self.status_array
+    }
+
+    fn get_dependency(&self) -> Option<(usize, bool)> {
+        Some((CHUNK_BLOCK_DATA, true))
+    }
+
+    fn get_data(&self, _world: &World, index: usize) -> AnyChunkData {
+        self.light_storage[index]
+            .clone()
+            .map(|x| x as AnyChunkDataArc)
+    }
+
+    fn swap_data(&mut self, _world: &World, index: usize, new_data: AnyChunkData) -> AnyChunkData {
+        let new_data = new_data.map(|d| d.downcast::<ChunkLight>().unwrap());
+        let old_data = std::mem::replace(&mut self.light_storage[index], new_data);
+        self.outdated[index] = false;
+        old_data.map(|x| x as AnyChunkDataArc)
+    }
+
+    fn resize_data(&mut self, _world: &World, new_size: usize) {
+        self.light_storage.resize(new_size, None);
+        self.outdated.resize(new_size, false);
+    }
+
+    fn create_chunk_update_task(
+        &mut self,
+        world: &World,
+        cpos: ChunkPosition,
+        index: usize,
+    ) -> Option<Task> {
+        let blocks = world.get_handler(CHUNK_BLOCK_DATA).borrow();
+        let blocks = blocks.as_any().downcast_ref::<WorldBlocks>().unwrap();
+        let mut neighbors = Vec::with_capacity(27);
+        for npos in iter_neighbors(cpos, true) {
+            let chunk = match blocks.get_chunk(world, npos) {
+                Some(c) => c,
+                None => return None,
+            };
+            neighbors.push(chunk);
+        }
+        let above = iproduct!(-1..=1, -1..=1)
+            .map(|(dz, dx)| self.get_chunk(world, cpos + ChunkPosition::new(dx, 1, dz)))
+            .collect_vec();
+
+        self.status_array[index] = match self.status_array[index] {
+            ChunkDataState::Unloaded => ChunkDataState::Loading,
+            _ => ChunkDataState::Updating,
+        };
+        let registry = self.voxel_registry.clone();
+        let submit_channel = world.get_sync_task_channel();
+        Some(Task::new(
+            move || {
+                let _p_zone = bxw_util::tracy_client::Span::new(
+                    "Chunk light task",
+                    "mainloop",
+                    file!(),
+                    line!(),
+                    4,
+                );
+                let prelight = Instant::now();
+                let light = Arc::new(calculate_chunk_light(&registry, cpos, &neighbors, &above));
+                drop(neighbors);
+                drop(above);
+                let postlight = Instant::now();
+                let lighttime = postlight.saturating_duration_since(prelight);
+                bxw_util::debug_data::DEBUG_DATA
+                    .wlight_times
+                    .push_ns(lighttime.as_nanos() as i64);
+                submit_channel
+                    .send(Box::new(move |world| {
+                        let index = match world.get_chunk_index(cpos) {
+                            Some(i) => i,
+                            None => return,
+                        };
+                        let mut lights = world.get_handler(CHUNK_LIGHT_DATA).borrow_mut();
+                        let lights: &mut Self = lights.as_any_mut().downcast_mut().unwrap();
+                        // request was cancelled
+                        if lights.status_array[index] == ChunkDataState::Unloaded {
+                            return;
+                        }
+                        lights.status_array[index] = if lights.outdated[index] {
+                            ChunkDataState::NotInIo
+                        } else {
+                            ChunkDataState::Loaded
+                        };
+                        lights.outdated[index] = false;
+                        let new_sky = light.levels[sky_seed_layer()].iter().map(|l| l >> 4);
+                        // chunks below assume open sky until the light above them is known
+                        let sky_changed = match &lights.light_storage[index] {
+                            Some(old) => old.levels[sky_seed_layer()]
+                                .iter()
+                                .map(|l| l >> 4)
+                                .ne(new_sky.clone()),
+                            None => new_sky.clone().any(|l| l != MAX_LIGHT_LEVEL),
+                        };
+                        lights.light_storage[index] = Some(light);
+                        // sky light entering the chunks below changed, so they need to be recalculated
+                        if sky_changed {
+                            for (dz, dx) in iproduct!(-1..=1, -1..=1) {
+                                let below = cpos + ChunkPosition::new(dx, -1, dz);
+                                if let Some(bi) = world.get_chunk_index(below) {
+                                    lights.mark_outdated(bi);
+                                }
+                            }
+                        }
+                    }))
+                    .unwrap_or(());
+            },
+            false,
+            false,
+        ))
+    }
+
+    fn needs_loading_for_anchor(&self, _anchor: &CLoadAnchor) -> bool {
+        true
+    }
+
+    fn serializable(&self) -> bool {
+        true
+    }
+
+    fn serialize_data(&self, _world: &World, index: usize) -> Option<Vec<u8>> {
+        let data = self.light_storage.get(index)?.as_ref()?;
+        Some(storage_zstd_compress(&data.levels))
+    }
+
+    fn deserialize_data(
+        &mut self,
+        world: &World,
+        index: usize,
+        data: &[u8],
+    ) -> Result<AnyChunkData, &'static str> {
+        let levels = storage_zstd_decompress(data, Some(CHUNK_DIM3))
+            .map_err(|_| "Invalid compressed light data")?;
+        if levels.len() != CHUNK_DIM3 {
+            return Err("Invalid serialized light data length");
+        }
+        let new_data = ChunkLight {
+            position: world
+                .get_chunk_position(index)
+                .ok_or("Trying to deserialize light without an assigned position")?,
+            levels,
+        };
+        let old_data = std::mem::replace(&mut self.light_storage[index], Some(Arc::new(new_data)));
+        Ok(old_data.map(|x| x as AnyChunkDataArc))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_registry() -> VoxelRegistry {
+        let mut reg = VoxelRegistry::new();
+        reg.build_definition().name("test:stone").finish().unwrap();
+        reg.build_definition()
+            .name("test:lamp")
+            .light_emission(MAX_LIGHT_LEVEL)
+            .finish()
+            .unwrap();
+        reg
+    }
+
+    fn filled_chunk(cpos: ChunkPosition, datum: VoxelDatum) -> Arc<VChunk> {
+        let mut uc = UncompressedChunk::new();
+        uc.position = cpos;
+        uc.blocks_yzx = [datum; CHUNK_DIM3];
+        let mut vc = VChunk::new();
+        vc.position = cpos;
+        vc.compress(&uc);
+        Arc::new(vc)
+    }
+
+    #[test]
+    fn open_sky_light_test() {
+        let reg = test_registry();
+        let cpos = ChunkPosition::new(0, 0, 0);
+        let neighbors = iter_neighbors(cpos, true)
+            .map(|npos| filled_chunk(npos, VoxelDatum::new(0, 0)))
+            .collect_vec();
+        let light = calculate_chunk_light(&reg, cpos, &neighbors, &vec![None; 9]);
+        assert!(light.levels.iter().all(|&l| l == MAX_LIGHT_LEVEL << 4));
+    }
+
+    #[test]
+    fn buried_lamp_light_test() {
+        let reg = test_registry();
+        let stone = reg.get_definition_from_name("test:stone").unwrap().id;
+        let lamp = reg.get_definition_from_name("test:lamp").unwrap().id;
+        let air = reg.get_definition_from_name("core:void").unwrap().id;
+        let cpos = ChunkPosition::new(0, 0, 0);
+        let mut neighbors = iter_neighbors(cpos, true)
+            .map(|npos| filled_chunk(npos, VoxelDatum::new(stone, 0)))
+            .collect_vec();
+        // a horizontal air tunnel along X through the middle of the chunk with a lamp at x=0
+        let mut uc = UncompressedChunk::new();
+        uc.blocks_yzx = [VoxelDatum::new(stone, 0); CHUNK_DIM3];
+        for x in 0..CHUNK_DIM as i32 {
+            uc.blocks_yzx[BlockPosition::new(x, 16, 16).as_blockidx()] = VoxelDatum::new(air, 0);
+        }
+        uc.blocks_yzx[BlockPosition::new(0, 16, 16).as_blockidx()] = VoxelDatum::new(lamp, 0);
+        let mut vc = VChunk::new();
+        vc.compress(&uc);
+        neighbors[13] = Arc::new(vc);
+        let light = calculate_chunk_light(&reg, cpos, &neighbors, &vec![None; 9]);
+        for x in 0..CHUNK_DIM as i32 {
+            let bpos = BlockPosition::new(x, 16, 16);
+            assert_eq!(light.sky_light(bpos), 0);
+            assert_eq!(
+                light.block_light(bpos),
+                MAX_LIGHT_LEVEL.saturating_sub(x as u8)
+            );
+        }
+        assert_eq!(light.light(BlockPosition::new(0, 17, 16)), 0);
+    }
+}
@@ -5,6 +5,7 @@ use crate::worldmgr::*;
 use crate::Direction;
 use bxw_util::change::Change;
 use bxw_util::collider::AABB;
+use bxw_util::fnv::FnvHashSet;
 use bxw_util::math::*;
 use bxw_util::*;
 use itertools::Itertools;
@@ -41,6 +42,20 @@ fn drag_force(loc: &CLocation, velocity: Vector3<f64>) -> Vector3<f64> {
 }
 
 pub fn world_physics_tick(world: &mut World) {
+    physics_tick_filtered(world, |_| true);
+}
+
+/// Physics tick of all entities except the given ones, e.g. players moved by their own input commands
+pub fn world_physics_tick_excluding(world: &mut World, excluded: &FnvHashSet<ValidEntityID>) {
+    physics_tick_filtered(world, |eid| !excluded.contains(&eid));
+}
+
+/// Physics tick of a single entity, used to replay or process player input commands one tick at a time
+pub fn entity_physics_tick(world: &mut World, entity: ValidEntityID) {
+    physics_tick_filtered(world, |eid| eid == entity);
+}
+
+fn physics_tick_filtered<F: Fn(ValidEntityID) -> bool>(world: &mut World, filter: F) {
     let voxels_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow();
     let voxels = voxels_ref.as_any().downcast_ref::<WorldBlocks>().unwrap();
     let entities = world.ecs();
@@ -49,6 +64,9 @@ pub fn world_physics_tick(world: &mut World) {
     let mut changes: Vec<EntityChange> = Vec::new();
     for old_phys in ECSHandler::<CPhysics>::iter(entities) {
         let eid = old_phys.entity_id();
+        if !filter(eid) {
+            continue;
+        }
         let old_loc: &CLocation = match entities.get_component(eid) {
             Some(loc) => loc,
             None => continue,
@@ -1,3 +1,4 @@
+use crate::generation::WorldGenerator;
 use crate::voxregistry::VoxelRegistry;
 use crate::{UncompressedChunk, VoxelDatum, CHUNK_DIM};
 use bxw_util::math::*;
@@ -300,8 +301,25 @@ impl StdGenerator {
             cell_gen: ThreadLocal::default(),
         }
     }
+}
+
+/// Name of the [`StdGenerator`] in save files
+pub const STD_GENERATOR_NAME: &str = "std";
+
+impl WorldGenerator for StdGenerator {
+    fn name(&self) -> &'static str {
+        STD_GENERATOR_NAME
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn settings(&self) -> String {
+        String::new()
+    }
 
-    pub fn generate_chunk(&self, chunk: &mut UncompressedChunk, registry: &VoxelRegistry) {
+    fn generate_chunk(&self, chunk: &mut UncompressedChunk, registry: &VoxelRegistry) {
         let _p_frame = bxw_util::tracy_client::start_noncontinuous_frame!("StdGenerator");
         let _p_zone = bxw_util::tracy_client::Span::new(
             "Generate chunk",
@@ -1,4 +1,5 @@
-use crate::ChunkPosition;
+use crate::{ChunkPosition, VoxelId};
+use bxw_util::fnv::FnvHashMap;
 use bxw_util::itertools::Itertools;
 use bxw_util::log;
 use bxw_util::parking_lot::{Mutex, MutexGuard};
@@ -11,9 +12,44 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
 
+pub mod palette;
 mod schemas;
 pub mod serializer;
 
+pub use schemas::{SaveFormatError, CURRENT_SAVE_FORMAT};
+
+/// Save metadata field holding the name of the world generator
+pub const META_GENERATOR_NAME: &str = "generator_name";
+/// Save metadata field holding the version of the world generator that created the world
+pub const META_GENERATOR_VERSION: &str = "generator_version";
+/// Save metadata field holding the serialized settings of the world generator
+pub const META_GENERATOR_SETTINGS: &str = "generator_settings";
+/// Save metadata field holding the world generation seed as a decimal number
+pub const META_WORLD_SEED: &str = "world_seed";
+/// Save metadata field holding the highest entity sub-ID used in each domain, see [`format_entity_id_marks`]
+pub const META_ENTITY_ID_MARKS: &str = "entity_id_marks";
+
+/// Comma-separated decimal sub-IDs, in the order of [`crate::ecs::EntityDomain::number`]
+pub fn format_entity_id_marks(marks: &[u64; 4]) -> String {
+    marks
+        .iter()
+        .map(|m| m.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn parse_entity_id_marks(text: &str) -> Option<[u64; 4]> {
+    let mut marks = [0u64; 4];
+    let mut parts = text.split(',');
+    for mark in marks.iter_mut() {
+        *mark = parts.next()?.trim().parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(marks)
+}
+
 pub fn saves_folder_path() -> PathBuf {
     PathBuf::from("saves")
 }
@@ -111,6 +147,10 @@ pub enum ChunkIoRequest {
     Write {
         positions: Vec<(ChunkPosition, Vec<u8>, Vec<u8>)>,
     },
+    /// Replaces the whole global (omnipresent) entity table with the given (raw id, entity data) pairs
+    WriteGlobalEntities {
+        entities: Vec<(u64, Vec<u8>)>,
+    },
     Close,
 }
 
@@ -118,6 +158,7 @@ pub enum ChunkIoRequest {
 enum ChunkIoRequestKind {
     TryRead,
     Write,
+    WriteGlobalEntities,
     Close,
 }
 
@@ -136,6 +177,7 @@ pub enum ChunkIoResponse {
     WriteOk {
         cpos: ChunkPosition,
     },
+    GlobalEntitiesWriteOk,
     ClosedOk,
 }
 
@@ -145,6 +187,7 @@ impl ChunkIoRequest {
         match self {
             Self::TryRead { .. } => TryRead,
             Self::Write { .. } => Write,
+            Self::WriteGlobalEntities { .. } => WriteGlobalEntities,
             Self::Close => Close,
         }
     }
@@ -169,7 +212,7 @@ struct WDSWorkerData {
 }
 
 impl WorldDiskStorage {
-    pub fn open(save: &WorldSave) -> rusqlite::Result<Self> {
+    pub fn open(save: &WorldSave) -> Result<Self, SaveFormatError> {
         let db_path = save.0.clone();
         use rusqlite::OpenFlags;
         let mut db = Connection::open_with_flags(
@@ -217,6 +260,16 @@ pub trait WorldStorageBackend {
     fn lock_requests(&mut self) -> MutexGuard<ChunkIoQueue>;
     fn lock_responses(&mut self) -> MutexGuard<ChunkIoResponseQueue>;
     fn notify_worker(&mut self);
+    /// Synchronously reads all the (raw id, entity data) pairs of the global entity table, used when opening a world
+    fn read_global_entities(&mut self) -> Vec<(u64, Vec<u8>)>;
+    /// Synchronously reads a field of the save metadata table
+    fn read_meta(&mut self, field_name: &str) -> rusqlite::Result<Option<String>>;
+    /// Synchronously writes a field of the save metadata table
+    fn write_meta(&mut self, field_name: &str, field_value: &str) -> rusqlite::Result<()>;
+    /// Synchronously reads the (saved voxel id, voxel name) pairs of the voxel palette
+    fn read_voxel_palette(&mut self) -> rusqlite::Result<Vec<(VoxelId, String)>>;
+    /// Synchronously adds new entries to the voxel palette
+    fn add_voxel_palette_entries(&mut self, entries: &[(VoxelId, String)]) -> rusqlite::Result<()>;
 }
 
 impl WorldStorageBackend for WorldDiskStorage {
@@ -233,6 +286,127 @@ impl WorldStorageBackend for WorldDiskStorage {
     fn notify_worker(&mut self) {
         self.worker.thread().unpark();
     }
+
+    fn read_global_entities(&mut self) -> Vec<(u64, Vec<u8>)> {
+        schemas::db_load_global_entities(&mut self.db.lock_traced(
+            "Disk database lock",
+            file!(),
+            line!(),
+        ))
+        .unwrap_or_else(|e| {
+            log::error!("Error loading global entity data: {}", e);
+            panic!("Load error");
+        })
+    }
+
+    fn read_meta(&mut self, field_name: &str) -> rusqlite::Result<Option<String>> {
+        schemas::db_read_meta(
+            &mut self.db.lock_traced("Disk database lock", file!(), line!()),
+            field_name,
+        )
+    }
+
+    fn write_meta(&mut self, field_name: &str, field_value: &str) -> rusqlite::Result<()> {
+        schemas::db_write_meta(
+            &mut self.db.lock_traced("Disk database lock", file!(), line!()),
+            field_name,
+            field_value,
+        )
+    }
+
+    fn read_voxel_palette(&mut self) -> rusqlite::Result<Vec<(VoxelId, String)>> {
+        schemas::db_load_voxel_palette(&mut self.db.lock_traced(
+            "Disk database lock",
+            file!(),
+            line!(),
+        ))
+    }
+
+    fn add_voxel_palette_entries(&mut self, entries: &[(VoxelId, String)]) -> rusqlite::Result<()> {
+        schemas::db_add_voxel_palette_entries(
+            &mut self.db.lock_traced("Disk database lock", file!(), line!()),
+            entries,
+        )
+    }
+}
+
+/// Storage backend that doesn't keep any chunk data, used for worlds streamed from a server.
+/// Reads always report missing chunks and writes are discarded, all requests complete immediately.
+#[derive(Default)]
+pub struct WorldNullStorage {
+    io_requests: Mutex<ChunkIoQueue>,
+    io_responses: Mutex<ChunkIoResponseQueue>,
+    meta: FnvHashMap<String, String>,
+}
+
+impl WorldNullStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl WorldStorageBackend for WorldNullStorage {
+    fn lock_requests(&mut self) -> MutexGuard<ChunkIoQueue> {
+        self.io_requests
+            .lock_traced("Null io requests lock", file!(), line!())
+    }
+
+    fn lock_responses(&mut self) -> MutexGuard<ChunkIoResponseQueue> {
+        self.io_responses
+            .lock_traced("Null io responses lock", file!(), line!())
+    }
+
+    fn notify_worker(&mut self) {
+        let mut responses =
+            self.io_responses
+                .lock_traced("Null io responses lock", file!(), line!());
+        let mut requests = self
+            .io_requests
+            .lock_traced("Null io requests lock", file!(), line!());
+        for request in requests.drain(..) {
+            match request {
+                ChunkIoRequest::TryRead { positions } => responses.extend(
+                    positions
+                        .into_iter()
+                        .map(|cpos| ChunkIoResponse::ReadMissing { cpos }),
+                ),
+                ChunkIoRequest::Write { positions } => responses.extend(
+                    positions
+                        .into_iter()
+                        .map(|(cpos, _, _)| ChunkIoResponse::WriteOk { cpos }),
+                ),
+                ChunkIoRequest::WriteGlobalEntities { .. } => {
+                    responses.push_back(ChunkIoResponse::GlobalEntitiesWriteOk)
+                }
+                ChunkIoRequest::Close => responses.push_back(ChunkIoResponse::ClosedOk),
+            }
+        }
+    }
+
+    fn read_global_entities(&mut self) -> Vec<(u64, Vec<u8>)> {
+        Vec::new()
+    }
+
+    fn read_meta(&mut self, field_name: &str) -> rusqlite::Result<Option<String>> {
+        Ok(self.meta.get(field_name).cloned())
+    }
+
+    fn write_meta(&mut self, field_name: &str, field_value: &str) -> rusqlite::Result<()> {
+        self.meta
+            .insert(String::from(field_name), String::from(field_value));
+        Ok(())
+    }
+
+    fn read_voxel_palette(&mut self) -> rusqlite::Result<Vec<(VoxelId, String)>> {
+        Ok(Vec::new())
+    }
+
+    fn add_voxel_palette_entries(
+        &mut self,
+        _entries: &[(VoxelId, String)],
+    ) -> rusqlite::Result<()> {
+        Ok(())
+    }
 }
 
 fn wds_worker(data: WDSWorkerData) {
@@ -317,6 +491,20 @@ fn wds_worker(data: WDSWorkerData) {
                         store_chunk_data_buf.clear();
                         io_responses.lock().extend(out_responses.drain(..));
                     }
+                    ChunkIoRequestKind::WriteGlobalEntities => {
+                        for r in requests {
+                            if let ChunkIoRequest::WriteGlobalEntities { entities } = r {
+                                schemas::db_replace_global_entities(&mut db.lock(), &entities)
+                                    .unwrap_or_else(|e| {
+                                        log::error!("Error storing global entity data: {}", e);
+                                    });
+                                out_responses.push(ChunkIoResponse::GlobalEntitiesWriteOk);
+                            } else {
+                                unreachable!();
+                            }
+                        }
+                        io_responses.lock().extend(out_responses.drain(..));
+                    }
                     ChunkIoRequestKind::Close => {
                         schemas::db_on_exit(&mut db.lock()).unwrap_or_else(|e| {
                             log::warn!("Error on database pre-close optimization: {}", e);
@@ -0,0 +1,238 @@
+use super::WorldStorageBackend;
+use crate::voxregistry::VoxelRegistry;
+use crate::{UncompressedChunk, VoxelDatum, VoxelId};
+use bxw_util::fnv::{FnvHashMap, FnvHashSet};
+use bxw_util::log;
+
+/// Name of the placeholder voxel that replaces saved voxels which are no longer registered
+pub const MISSING_VOXEL_NAME: &str = "core:missing";
+
+/// Mapping between the voxel IDs stored in a save and the IDs assigned by the current [`VoxelRegistry`],
+/// which depend on the registration order
+#[derive(Clone, Debug, Default)]
+pub struct VoxelPalette {
+    /// Indexed by saved id
+    from_saved: Vec<VoxelId>,
+    /// Indexed by registry id
+    to_saved: Vec<VoxelId>,
+    /// Registry id of the voxel used for saved ids without a registered voxel
+    missing_id: VoxelId,
+    identity: bool,
+}
+
+impl VoxelPalette {
+    /// A palette that doesn't change any IDs
+    pub fn identity() -> Self {
+        Self {
+            identity: true,
+            ..Default::default()
+        }
+    }
+
+    /// Builds the palette from the saved (id, name) pairs.
+    /// Registered voxels missing from the save get new saved ids, returned as the second tuple element.
+    /// A new voxel keeps its registry id if that one is still free, so saves from before palettes existed load unchanged.
+    pub fn from_saved_entries(
+        registry: &VoxelRegistry,
+        entries: &[(VoxelId, String)],
+    ) -> (Self, Vec<(VoxelId, String)>) {
+        let missing_id = registry
+            .get_definition_from_name(MISSING_VOXEL_NAME)
+            .map_or(0, |d| d.id);
+        let saved_ids: FnvHashMap<&str, VoxelId> = entries
+            .iter()
+            .map(|(id, name)| (name.as_str(), *id))
+            .collect();
+        let mut used_ids: FnvHashSet<VoxelId> = entries.iter().map(|(id, _)| *id).collect();
+        let mut new_entries = Vec::new();
+        let mut next_free_id: VoxelId = 0;
+        let mut to_saved = Vec::new();
+        for def in registry.iter() {
+            let saved_id = if let Some(&id) = saved_ids.get(def.name.as_str()) {
+                id
+            } else {
+                let id = if used_ids.contains(&def.id) {
+                    while used_ids.contains(&next_free_id) {
+                        next_free_id += 1;
+                    }
+                    next_free_id
+                } else {
+                    def.id
+                };
+                used_ids.insert(id);
+                new_entries.push((id, def.name.clone()));
+                id
+            };
+            let idx = usize::from(def.id);
+            if to_saved.len() <= idx {
+                to_saved.resize(idx + 1, 0);
+            }
+            to_saved[idx] = saved_id;
+        }
+        let mut from_saved = Vec::new();
+        for (saved_id, name) in entries.iter().chain(new_entries.iter()) {
+            let idx = usize::from(*saved_id);
+            if from_saved.len() <= idx {
+                from_saved.resize(idx + 1, missing_id);
+            }
+            from_saved[idx] = match registry.get_definition_from_name(name) {
+                Some(def) => def.id,
+                None => {
+                    log::warn!(
+                        "Saved voxel {} is not registered, replacing it with {}",
+                        name,
+                        MISSING_VOXEL_NAME
+                    );
+                    missing_id
+                }
+            };
+        }
+        let identity = from_saved.iter().enumerate().all(|(i, &v)| i == v as usize)
+            && to_saved.iter().enumerate().all(|(i, &v)| i == v as usize);
+        (
+            Self {
+                from_saved,
+                to_saved,
+                missing_id,
+                identity,
+            },
+            new_entries,
+        )
+    }
+
+    /// Reads the palette of the save, adding entries for newly registered voxels
+    pub fn load(
+        storage: &mut dyn WorldStorageBackend,
+        registry: &VoxelRegistry,
+    ) -> rusqlite::Result<Self> {
+        let entries = storage.read_voxel_palette()?;
+        let (palette, new_entries) = Self::from_saved_entries(registry, &entries);
+        if !new_entries.is_empty() {
+            storage.add_voxel_palette_entries(&new_entries)?;
+        }
+        Ok(palette)
+    }
+
+    /// True if saved ids are the same as registry ids, so chunks don't need to be remapped
+    pub fn is_identity(&self) -> bool {
+        self.identity
+    }
+
+    pub fn saved_to_registry(&self, saved_id: VoxelId) -> VoxelId {
+        if self.identity {
+            return saved_id;
+        }
+        self.from_saved
+            .get(usize::from(saved_id))
+            .copied()
+            .unwrap_or(self.missing_id)
+    }
+
+    pub fn registry_to_saved(&self, id: VoxelId) -> VoxelId {
+        if self.identity {
+            return id;
+        }
+        self.to_saved[usize::from(id)]
+    }
+
+    pub fn datum_from_saved(&self, datum: VoxelDatum) -> VoxelDatum {
+        VoxelDatum::new(self.saved_to_registry(datum.id()), datum.meta())
+    }
+
+    pub fn datum_to_saved(&self, datum: VoxelDatum) -> VoxelDatum {
+        VoxelDatum::new(self.registry_to_saved(datum.id()), datum.meta())
+    }
+
+    pub fn chunk_from_saved(&self, chunk: &mut UncompressedChunk) {
+        if self.identity {
+            return;
+        }
+        for v in chunk.blocks_yzx.iter_mut() {
+            *v = self.datum_from_saved(*v);
+        }
+    }
+
+    pub fn chunk_to_saved(&self, chunk: &mut UncompressedChunk) {
+        if self.identity {
+            return;
+        }
+        for v in chunk.blocks_yzx.iter_mut() {
+            *v = self.datum_to_saved(*v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_registry(names: &[&str]) -> VoxelRegistry {
+        let mut reg = VoxelRegistry::new();
+        for name in names {
+            reg.build_definition().name(name).finish().unwrap();
+        }
+        reg
+    }
+
+    #[test]
+    fn palette_legacy_save_test() {
+        // a save without a palette keeps the current registry ids
+        let reg = test_registry(&["core:stone", "core:dirt"]);
+        let (palette, new_entries) = VoxelPalette::from_saved_entries(&reg, &[]);
+        assert!(palette.is_identity());
+        assert_eq!(new_entries.len(), 3);
+        assert!(new_entries.contains(&(2, String::from("core:dirt"))));
+    }
+
+    #[test]
+    fn palette_remap_test() {
+        let saved = vec![
+            (0, String::from("core:void")),
+            (1, String::from("core:stone")),
+            (2, String::from("core:removed")),
+            (3, String::from("core:dirt")),
+        ];
+        let reg = test_registry(&["core:dirt", "core:grass", "core:stone", MISSING_VOXEL_NAME]);
+        let (palette, new_entries) = VoxelPalette::from_saved_entries(&reg, &saved);
+        assert!(!palette.is_identity());
+        // core:grass has registry id 2 which is taken in the save, so it gets the first free one
+        assert_eq!(
+            new_entries,
+            vec![
+                (4, String::from("core:grass")),
+                (5, String::from(MISSING_VOXEL_NAME))
+            ]
+        );
+        let dirt = reg.get_definition_from_name("core:dirt").unwrap().id;
+        let stone = reg.get_definition_from_name("core:stone").unwrap().id;
+        let missing = reg.get_definition_from_name(MISSING_VOXEL_NAME).unwrap().id;
+        assert_eq!(palette.saved_to_registry(0), 0);
+        assert_eq!(palette.saved_to_registry(1), stone);
+        assert_eq!(palette.saved_to_registry(2), missing);
+        assert_eq!(palette.saved_to_registry(3), dirt);
+        assert_eq!(palette.saved_to_registry(100), missing);
+        for def in reg.iter() {
+            assert_eq!(
+                palette.saved_to_registry(palette.registry_to_saved(def.id)),
+                def.id
+            );
+        }
+        let mut chunk = UncompressedChunk::new();
+        chunk.blocks_yzx[0] = VoxelDatum::new(1, 5);
+        chunk.blocks_yzx[1] = VoxelDatum::new(2, 0);
+        palette.chunk_from_saved(&mut chunk);
+        assert_eq!(chunk.blocks_yzx[0], VoxelDatum::new(stone, 5));
+        assert_eq!(chunk.blocks_yzx[1], VoxelDatum::new(missing, 0));
+        palette.chunk_to_saved(&mut chunk);
+        assert_eq!(chunk.blocks_yzx[0], VoxelDatum::new(1, 5));
+        assert_eq!(chunk.blocks_yzx[1], VoxelDatum::new(5, 0));
+        assert_eq!(
+            palette.datum_to_saved(VoxelDatum::new(dirt, 7)),
+            VoxelDatum::new(3, 7)
+        );
+        assert_eq!(
+            palette.datum_from_saved(VoxelDatum::new(3, 7)),
+            VoxelDatum::new(dirt, 7)
+        );
+    }
+}
@@ -1,7 +1,8 @@
-use crate::ChunkPosition;
+use crate::{ChunkPosition, VoxelId};
 use bxw_util::fnv::FnvHashSet;
 use bxw_util::itertools::Itertools;
-use rusqlite::{named_params, Connection, OptionalExtension};
+use bxw_util::log;
+use rusqlite::{named_params, Connection, OptionalExtension, Transaction};
 use std::fmt::Write;
 use std::sync::atomic::{AtomicI64, Ordering};
 
@@ -22,7 +23,128 @@ pub fn db_on_exit(db: &mut Connection) -> rusqlite::Result<()> {
     db.execute_batch(include_str!("sql/00_conn_on_exit.sql"))
 }
 
-pub fn db_setup_schema(db: &mut Connection) -> rusqlite::Result<()> {
+/// Version of the save format written by this build, saves with older versions are migrated on opening
+pub const CURRENT_SAVE_FORMAT: u32 = 4;
+
+#[derive(Debug)]
+pub enum SaveFormatError {
+    Sqlite(rusqlite::Error),
+    /// The save was written by a newer version of the game
+    NewerSaveFormat {
+        found: u32,
+        supported: u32,
+    },
+    /// The save_format field is missing or not a number
+    InvalidSaveFormat(Option<String>),
+}
+
+impl From<rusqlite::Error> for SaveFormatError {
+    fn from(e: rusqlite::Error) -> Self {
+        SaveFormatError::Sqlite(e)
+    }
+}
+
+enum Migration {
+    Sql(&'static str),
+    Rust(fn(&Transaction) -> rusqlite::Result<()>),
+}
+
+/// Migrations in order, the one at index `i` upgrades a save from format `i + 1` to `i + 2`.
+/// Each one runs in its own transaction together with the save_format update.
+const MIGRATIONS: [Migration; (CURRENT_SAVE_FORMAT - 1) as usize] = [
+    Migration::Rust(migrate_v2_generator_meta),
+    Migration::Sql(include_str!("sql/03_voxel_palette.sql")),
+    Migration::Rust(migrate_v4_entity_id_marks),
+];
+
+/// v2: worlds created before the generator was stored in the metadata were all generated by the standard generator with seed 0
+fn migrate_v2_generator_meta(tx: &Transaction) -> rusqlite::Result<()> {
+    let has_chunks = tx
+        .query_row("SELECT 1 FROM bxw_chunk_storage LIMIT 1;", [], |_| Ok(()))
+        .optional()?
+        .is_some();
+    let has_generator = tx
+        .query_row(
+            "SELECT 1 FROM bxw_save_meta WHERE field_name = :name;",
+            named_params! {":name": super::META_GENERATOR_NAME},
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    // a world without any chunks gets its generator assigned on first open
+    if has_chunks && !has_generator {
+        for (field, value) in &[
+            (
+                super::META_GENERATOR_NAME,
+                crate::stdgen::STD_GENERATOR_NAME,
+            ),
+            (super::META_GENERATOR_VERSION, "1"),
+            (super::META_GENERATOR_SETTINGS, ""),
+            (super::META_WORLD_SEED, "0"),
+        ] {
+            tx.execute(
+                "INSERT OR REPLACE INTO bxw_save_meta(field_name, field_value) VALUES (:name, :value);",
+                named_params! {":name": field, ":value": value},
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// v4: the entity ID allocation state is saved, older saves get it from the IDs of all the stored entities
+fn migrate_v4_entity_id_marks(tx: &Transaction) -> rusqlite::Result<()> {
+    let mut marks = [0u64; 4];
+    let mut mark_id = |raw_id: u64| {
+        if let Some(id) = crate::ecs::ValidEntityID::from_raw(raw_id) {
+            let mark = &mut marks[id.domain().number()];
+            *mark = (*mark).max(id.sub_id());
+        }
+    };
+    {
+        let mut stmt = tx.prepare(
+            "SELECT x, y, z, entity_data FROM bxw_chunk_storage WHERE entity_data IS NOT NULL;",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let entity_data: Vec<u8> = row.get(3)?;
+            match super::serializer::deserialize_entities(&entity_data) {
+                Ok(snapshots) => snapshots.iter().for_each(|s| mark_id(s.raw_id)),
+                Err(err) => log::warn!(
+                    "Skipping entities of chunk ({}, {}, {}) while migrating: {}",
+                    row.get::<_, i32>(0)?,
+                    row.get::<_, i32>(1)?,
+                    row.get::<_, i32>(2)?,
+                    err
+                ),
+            }
+        }
+        let mut stmt = tx.prepare("SELECT entity_id FROM bxw_global_entity_storage;")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            mark_id(row.get::<_, i64>(0)? as u64);
+        }
+    }
+    tx.execute(
+        "INSERT OR REPLACE INTO bxw_save_meta(field_name, field_value) VALUES (:name, :value);",
+        named_params! {
+            ":name": super::META_ENTITY_ID_MARKS,
+            ":value": super::format_entity_id_marks(&marks),
+        },
+    )?;
+    Ok(())
+}
+
+pub fn db_read_save_format(db: &mut Connection) -> Result<u32, SaveFormatError> {
+    let format = db_read_meta(db, "save_format")?;
+    format
+        .as_deref()
+        .and_then(|f| f.parse::<u32>().ok())
+        .filter(|&f| f >= 1)
+        .ok_or(SaveFormatError::InvalidSaveFormat(format))
+}
+
+/// Creates the tables for a new save, or migrates an existing save to the current format
+pub fn db_setup_schema(db: &mut Connection) -> Result<(), SaveFormatError> {
     let _p_section = bxw_util::tracy_client::Span::new(
         "db_setup_schema",
         "db_setup_schema",
@@ -41,9 +163,81 @@ pub fn db_setup_schema(db: &mut Connection) -> rusqlite::Result<()> {
     if needs_initial_tables {
         db.execute_batch(include_str!("sql/01_initial_tables.sql"))?;
     }
+    db_migrate(db)
+}
+
+/// Applies all the migrations needed to bring the save up to [`CURRENT_SAVE_FORMAT`]
+pub fn db_migrate(db: &mut Connection) -> Result<(), SaveFormatError> {
+    let mut format = db_read_save_format(db)?;
+    if format > CURRENT_SAVE_FORMAT {
+        return Err(SaveFormatError::NewerSaveFormat {
+            found: format,
+            supported: CURRENT_SAVE_FORMAT,
+        });
+    }
+    while format < CURRENT_SAVE_FORMAT {
+        let _p_section =
+            bxw_util::tracy_client::Span::new("db_migrate step", "db_migrate", file!(), line!(), 8);
+        log::info!("Migrating save from format {} to {}", format, format + 1);
+        let tx = db.transaction_with_behavior(rusqlite::TransactionBehavior::Exclusive)?;
+        match &MIGRATIONS[(format - 1) as usize] {
+            Migration::Sql(sql) => tx.execute_batch(sql)?,
+            Migration::Rust(migration) => migration(&tx)?,
+        }
+        format += 1;
+        tx.execute(
+            "UPDATE bxw_save_meta SET field_value = :format WHERE field_name = 'save_format';",
+            named_params! {":format": format.to_string()},
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub fn db_read_meta(db: &mut Connection, field_name: &str) -> rusqlite::Result<Option<String>> {
+    db.query_row(
+        "SELECT field_value FROM bxw_save_meta WHERE field_name = :name;",
+        named_params! {":name": field_name},
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+pub fn db_write_meta(
+    db: &mut Connection,
+    field_name: &str,
+    field_value: &str,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT OR REPLACE INTO bxw_save_meta(field_name, field_value) VALUES (:name, :value);",
+        named_params! {":name": field_name, ":value": field_value},
+    )?;
     Ok(())
 }
 
+/// Returns all the (saved voxel id, voxel name) pairs of the save's voxel palette
+pub fn db_load_voxel_palette(db: &mut Connection) -> rusqlite::Result<Vec<(VoxelId, String)>> {
+    let mut stmt = db.prepare("SELECT voxel_id, voxel_name FROM bxw_voxel_palette;")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+pub fn db_add_voxel_palette_entries(
+    db: &mut Connection,
+    entries: &[(VoxelId, String)],
+) -> rusqlite::Result<()> {
+    let transaction = db.transaction()?;
+    {
+        let mut stmt = transaction.prepare_cached(
+            "INSERT INTO bxw_voxel_palette(voxel_id, voxel_name) VALUES (:id, :name);",
+        )?;
+        for (id, name) in entries.iter() {
+            stmt.execute(named_params! {":id": id, ":name": name})?;
+        }
+    }
+    transaction.commit()
+}
+
 /// chunk_data: `&[(position, serialized voxel data, serialized entity data)]`
 pub fn db_store_chunk_data(
     db: &mut Connection,
@@ -159,6 +353,59 @@ pub fn db_load_chunk_data(
     Ok(out_table)
 }
 
+/// entities: `&[(raw entity id, serialized entity data)]`, replaces all the previously stored global entities
+pub fn db_replace_global_entities(
+    db: &mut Connection,
+    entities: &[(u64, Vec<u8>)],
+) -> rusqlite::Result<()> {
+    let _p_section = bxw_util::tracy_client::Span::new(
+        "db_replace_global_entities",
+        "db_replace_global_entities",
+        file!(),
+        line!(),
+        8,
+    );
+    let transaction = db.transaction_with_behavior(rusqlite::TransactionBehavior::Exclusive)?;
+    transaction.execute("DELETE FROM bxw_global_entity_storage;", [])?;
+    {
+        let mut stmt = transaction
+            .prepare_cached(
+                r#"INSERT INTO bxw_global_entity_storage
+        (entity_id, entity_data)
+        VALUES
+        (:id, :ent)
+        ;"#,
+            )
+            .expect("Invalid SQL insert statement for bxw_global_entity_storage rows");
+        for (raw_id, entity_data) in entities.iter() {
+            // SQLite integers are signed, the domain bits of omnipresent IDs wrap around to negative numbers
+            stmt.execute(named_params! {
+                ":id": &(*raw_id as i64),
+                ":ent": entity_data,
+            })?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+pub fn db_load_global_entities(db: &mut Connection) -> rusqlite::Result<Vec<(u64, Vec<u8>)>> {
+    let _p_section = bxw_util::tracy_client::Span::new(
+        "db_load_global_entities",
+        "db_load_global_entities",
+        file!(),
+        line!(),
+        8,
+    );
+    let mut stmt = db.prepare("SELECT entity_id, entity_data FROM bxw_global_entity_storage;")?;
+    let rows = stmt.query_map([], |row| {
+        let raw_id: i64 = row.get(0)?;
+        let entity_data: Vec<u8> = row.get(1)?;
+        Ok((raw_id as u64, entity_data))
+    })?;
+    rows.collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -265,4 +512,136 @@ mod test {
         // Test finalization SQL
         db_on_exit(&mut inmem).expect("db_on_exit failed");
     }
+
+    #[test]
+    pub fn db_global_entities_test() {
+        let mut inmem = Connection::open_in_memory().unwrap();
+        db_configure_conn(&mut inmem).expect("db_configure_conn failed");
+        db_setup_schema(&mut inmem).expect("setup_db_schema failed");
+        assert!(db_load_global_entities(&mut inmem).unwrap().is_empty());
+        // IDs with the highest bit set have to survive the round trip through signed SQLite integers
+        let sample_data_1 = vec![(1u64 << 63 | 1, vec![1, 2, 3]), (3u64 << 62 | 7, vec![4])];
+        db_replace_global_entities(&mut inmem, &sample_data_1)
+            .expect("Couldn't store global entities 1");
+        let mut loaded_1 = db_load_global_entities(&mut inmem).unwrap();
+        loaded_1.sort();
+        assert_eq!(loaded_1, sample_data_1);
+        // Entities missing from the new set are removed
+        let sample_data_2 = vec![(3u64 << 62 | 7, vec![5, 6])];
+        db_replace_global_entities(&mut inmem, &sample_data_2)
+            .expect("Couldn't store global entities 2");
+        assert_eq!(db_load_global_entities(&mut inmem).unwrap(), sample_data_2);
+    }
+
+    #[test]
+    pub fn db_meta_test() {
+        let mut inmem = Connection::open_in_memory().unwrap();
+        db_configure_conn(&mut inmem).expect("db_configure_conn failed");
+        db_setup_schema(&mut inmem).expect("setup_db_schema failed");
+        assert_eq!(
+            db_read_meta(&mut inmem, "save_format").unwrap(),
+            Some(CURRENT_SAVE_FORMAT.to_string())
+        );
+        assert_eq!(db_read_meta(&mut inmem, "generator_name").unwrap(), None);
+        db_write_meta(&mut inmem, "generator_name", "flat").unwrap();
+        db_write_meta(&mut inmem, "generator_name", "void").unwrap();
+        assert_eq!(
+            db_read_meta(&mut inmem, "generator_name")
+                .unwrap()
+                .as_deref(),
+            Some("void")
+        );
+    }
+
+    fn open_v1_fixture() -> Connection {
+        let mut inmem = Connection::open_in_memory().unwrap();
+        db_configure_conn(&mut inmem).expect("db_configure_conn failed");
+        inmem
+            .execute_batch(include_str!("sql/fixtures/v1_save.sql"))
+            .expect("Couldn't load the v1 save fixture");
+        inmem
+    }
+
+    #[test]
+    pub fn db_migrate_v1_test() {
+        let mut db = open_v1_fixture();
+        assert_eq!(db_read_save_format(&mut db).unwrap(), 1);
+        db_setup_schema(&mut db).expect("Couldn't migrate the v1 save");
+        assert_eq!(db_read_save_format(&mut db).unwrap(), CURRENT_SAVE_FORMAT);
+        // existing data is preserved
+        let counter = AtomicI64::new(0);
+        let chunks = db_load_chunk_data(&mut db, &[ChunkPosition::new(0, 0, 0)], &counter)
+            .expect("Couldn't load chunks after migration");
+        assert_eq!(chunks[0].1, Some((vec![0, 1, 2], vec![3])));
+        assert_eq!(
+            db_load_global_entities(&mut db).unwrap(),
+            vec![(1u64 << 63 | 1, vec![7, 8, 9])]
+        );
+        // v2: the implicit generator of old worlds is made explicit
+        assert_eq!(
+            db_read_meta(&mut db, "generator_name").unwrap().as_deref(),
+            Some("std")
+        );
+        assert_eq!(
+            db_read_meta(&mut db, "world_seed").unwrap().as_deref(),
+            Some("0")
+        );
+        // v3: the voxel palette starts out empty
+        assert!(db_load_voxel_palette(&mut db).unwrap().is_empty());
+        // v4: the fixture's chunk entity blob isn't valid, only the global entity counts
+        let marks = db_read_meta(&mut db, "entity_id_marks").unwrap().unwrap();
+        assert_eq!(
+            crate::storage::parse_entity_id_marks(&marks),
+            Some([0, 0, 1, 0])
+        );
+        assert_eq!(crate::storage::parse_entity_id_marks("1,2,3"), None);
+        assert_eq!(crate::storage::parse_entity_id_marks("1,2,3,4,5"), None);
+        // migrating an up to date save is a no-op
+        db_setup_schema(&mut db).expect("Couldn't reopen the migrated save");
+        assert_eq!(db_read_save_format(&mut db).unwrap(), CURRENT_SAVE_FORMAT);
+    }
+
+    #[test]
+    pub fn db_migrate_new_save_test() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db_configure_conn(&mut db).expect("db_configure_conn failed");
+        db_setup_schema(&mut db).expect("setup_db_schema failed");
+        assert_eq!(db_read_save_format(&mut db).unwrap(), CURRENT_SAVE_FORMAT);
+        // the generator of an empty world is chosen when it's first opened
+        assert_eq!(db_read_meta(&mut db, "generator_name").unwrap(), None);
+        let palette = vec![
+            (0, String::from("core:void")),
+            (7, String::from("core:stone")),
+        ];
+        db_add_voxel_palette_entries(&mut db, &palette).unwrap();
+        let mut loaded = db_load_voxel_palette(&mut db).unwrap();
+        loaded.sort();
+        assert_eq!(loaded, palette);
+        // names and ids are unique
+        assert!(db_add_voxel_palette_entries(&mut db, &[(1, String::from("core:stone"))]).is_err());
+        assert!(db_add_voxel_palette_entries(&mut db, &[(7, String::from("core:dirt"))]).is_err());
+    }
+
+    #[test]
+    pub fn db_newer_format_test() {
+        let mut db = open_v1_fixture();
+        db_write_meta(
+            &mut db,
+            "save_format",
+            &(CURRENT_SAVE_FORMAT + 1).to_string(),
+        )
+        .unwrap();
+        match db_setup_schema(&mut db) {
+            Err(SaveFormatError::NewerSaveFormat { found, supported }) => {
+                assert_eq!(found, CURRENT_SAVE_FORMAT + 1);
+                assert_eq!(supported, CURRENT_SAVE_FORMAT);
+            }
+            other => panic!("Newer save format not refused: {:?}", other),
+        }
+        db_write_meta(&mut db, "save_format", "abc").unwrap();
+        assert!(matches!(
+            db_setup_schema(&mut db),
+            Err(SaveFormatError::InvalidSaveFormat(_))
+        ));
+    }
 }
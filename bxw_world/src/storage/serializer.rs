@@ -1,4 +1,6 @@
+use crate::ecs::EntitySnapshot;
 use crate::ChunkPosition;
+use bxw_util::rmp_serde;
 use bxw_util::zstd;
 use std::cell::RefCell;
 
@@ -40,3 +42,73 @@ pub fn storage_zstd_decompress(
         })
         .map_err(|_| DecompressError::UnknownError)
 }
+
+/// Marks a voxel_data blob as a list of per-handler sections, see `pack_chunk_sections`
+const CHUNK_SECTIONS_MAGIC: [u8; 4] = *b"BXWS";
+
+/// Packs the serialized data of several chunk data handlers into one voxel_data blob.
+/// Layout: magic, then for each section `kind: u8, length: u32 LE, data`
+pub fn pack_chunk_sections(sections: &[(usize, Vec<u8>)]) -> Vec<u8> {
+    let total_len: usize = sections.iter().map(|(_, d)| d.len() + 5).sum();
+    let mut out = Vec::with_capacity(CHUNK_SECTIONS_MAGIC.len() + total_len);
+    out.extend_from_slice(&CHUNK_SECTIONS_MAGIC);
+    for (kind, data) in sections.iter() {
+        out.push(*kind as u8);
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// Inverse of `pack_chunk_sections`, blobs written before sections existed are returned as block data only
+pub fn unpack_chunk_sections(data: &[u8]) -> Result<Vec<(usize, &[u8])>, &'static str> {
+    if !data.starts_with(&CHUNK_SECTIONS_MAGIC) {
+        return Ok(vec![(crate::worldmgr::CHUNK_BLOCK_DATA, data)]);
+    }
+    let mut sections = Vec::with_capacity(2);
+    let mut rest = &data[CHUNK_SECTIONS_MAGIC.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            return Err("Truncated chunk section header");
+        }
+        let kind = rest[0] as usize;
+        let len = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        rest = &rest[5..];
+        if rest.len() < len {
+            return Err("Truncated chunk section data");
+        }
+        sections.push((kind, &rest[..len]));
+        rest = &rest[len..];
+    }
+    Ok(sections)
+}
+
+/// Serializes the entities stored alongside a chunk into an entity_data blob
+pub fn serialize_entities(entities: &[EntitySnapshot]) -> Vec<u8> {
+    if entities.is_empty() {
+        return Vec::new();
+    }
+    let raw = rmp_serde::to_vec(entities).expect("Unexpected entity serialization error");
+    storage_zstd_compress(&raw)
+}
+
+/// Inverse of `serialize_entities`, an empty blob means no entities
+pub fn deserialize_entities(data: &[u8]) -> Result<Vec<EntitySnapshot>, &'static str> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let raw = storage_zstd_decompress(data, None).map_err(|_| "Invalid compressed entity data")?;
+    rmp_serde::from_read_ref(&raw).map_err(|_| "Invalid serialized entity data")
+}
+
+/// Serializes a single entity for the global entity table
+pub fn serialize_entity(entity: &EntitySnapshot) -> Vec<u8> {
+    let raw = rmp_serde::to_vec(entity).expect("Unexpected entity serialization error");
+    storage_zstd_compress(&raw)
+}
+
+/// Inverse of `serialize_entity`
+pub fn deserialize_entity(data: &[u8]) -> Result<EntitySnapshot, &'static str> {
+    let raw = storage_zstd_decompress(data, None).map_err(|_| "Invalid compressed entity data")?;
+    rmp_serde::from_read_ref(&raw).map_err(|_| "Invalid serialized entity data")
+}
@@ -0,0 +1,4 @@
+CREATE TABLE bxw_voxel_palette (
+    voxel_id INTEGER NOT NULL UNIQUE PRIMARY KEY,
+    voxel_name VARCHAR(256) NOT NULL UNIQUE
+);
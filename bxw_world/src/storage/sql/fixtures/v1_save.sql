@@ -0,0 +1,38 @@
+-- A save in format version 1 with some chunk and entity data, used by the migration tests
+BEGIN TRANSACTION;
+
+CREATE TABLE bxw_save_meta (
+    field_name VARCHAR(64) UNIQUE PRIMARY KEY,
+    field_value VARCHAR(1024)
+);
+
+INSERT INTO bxw_save_meta(field_name, field_value) VALUES
+    ('save_format', '1'),
+    ('date_created', '2021-05-01 12:00:00')
+;
+
+CREATE TABLE bxw_chunk_storage (
+    chunk_id INTEGER NOT NULL UNIQUE PRIMARY KEY,
+    x INTEGER NOT NULL,
+    y INTEGER NOT NULL,
+    z INTEGER NOT NULL,
+    voxel_data BLOB,
+    entity_data BLOB,
+    UNIQUE (x, y, z)
+);
+
+INSERT INTO bxw_chunk_storage(chunk_id, x, y, z, voxel_data, entity_data) VALUES
+    (1, 0, 0, 0, X'000102', X'03'),
+    (2, 0, 1, 0, X'040506', NULL)
+;
+
+CREATE TABLE bxw_global_entity_storage (
+    entity_id INTEGER NOT NULL UNIQUE PRIMARY KEY,
+    entity_data BLOB
+);
+
+INSERT INTO bxw_global_entity_storage(entity_id, entity_data) VALUES
+    (-9223372036854775807, X'070809')
+;
+
+COMMIT TRANSACTION;
@@ -0,0 +1,329 @@
+//! Simple world generators for testing and debugging
+
+use crate::generation::{
+    parse_generator_settings, serialize_generator_settings, WorldGenerator, WorldGeneratorError,
+};
+use crate::voxregistry::VoxelRegistry;
+use crate::{UncompressedChunk, VoxelDatum, CHUNK_DIM};
+use serde::{Deserialize, Serialize};
+
+pub const VOID_GENERATOR_NAME: &str = "void";
+pub const FLAT_GENERATOR_NAME: &str = "flat";
+pub const DEBUG_GENERATOR_NAME: &str = "debug";
+
+/// Y coordinate of the block at index `vidx` of a yzx-ordered chunk
+fn block_y(chunk: &UncompressedChunk, vidx: usize) -> i32 {
+    chunk.position.0.y * CHUNK_DIM as i32 + (vidx / CHUNK_DIM / CHUNK_DIM) as i32
+}
+
+/// Generates only empty space
+#[derive(Clone, Debug, Default)]
+pub struct VoidGenerator;
+
+impl WorldGenerator for VoidGenerator {
+    fn name(&self) -> &'static str {
+        VOID_GENERATOR_NAME
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn settings(&self) -> String {
+        String::new()
+    }
+
+    fn generate_chunk(&self, chunk: &mut UncompressedChunk, _registry: &VoxelRegistry) {
+        chunk.blocks_yzx.fill(VoxelDatum::default());
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FlatLayer {
+    /// Voxel name, eg. core:stone
+    pub block: String,
+    pub thickness: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlatGeneratorSettings {
+    /// Y coordinate of the bottom of the first layer, everything below is filled with the first layer's block
+    pub base_height: i32,
+    /// Listed from the bottom up
+    pub layers: Vec<FlatLayer>,
+}
+
+impl Default for FlatGeneratorSettings {
+    fn default() -> Self {
+        let layer = |block: &str, thickness| FlatLayer {
+            block: String::from(block),
+            thickness,
+        };
+        Self {
+            base_height: -64,
+            layers: vec![
+                layer("core:stone", 60),
+                layer("core:dirt", 3),
+                layer("core:grass", 1),
+            ],
+        }
+    }
+}
+
+/// Generates horizontal layers of blocks, the same in every column
+#[derive(Clone, Debug, Default)]
+pub struct FlatGenerator {
+    settings: FlatGeneratorSettings,
+}
+
+impl FlatGenerator {
+    pub fn new(settings: FlatGeneratorSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn from_settings(settings: &str) -> Result<Self, WorldGeneratorError> {
+        Ok(Self::new(parse_generator_settings(settings)?))
+    }
+}
+
+impl WorldGenerator for FlatGenerator {
+    fn name(&self) -> &'static str {
+        FLAT_GENERATOR_NAME
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn settings(&self) -> String {
+        serialize_generator_settings(&self.settings)
+    }
+
+    fn generate_chunk(&self, chunk: &mut UncompressedChunk, registry: &VoxelRegistry) {
+        // (top y exclusive, voxel) for each layer
+        let mut layers = Vec::with_capacity(self.settings.layers.len());
+        let mut top = self.settings.base_height;
+        for layer in self.settings.layers.iter() {
+            top = top.saturating_add(layer.thickness as i32);
+            // unknown blocks are generated as empty space
+            let id = registry
+                .get_definition_from_name(&layer.block)
+                .map_or(0, |d| d.id);
+            layers.push((top, VoxelDatum::new(id, 0)));
+        }
+        for vidx in 0..chunk.blocks_yzx.len() {
+            let y = block_y(chunk, vidx);
+            chunk.blocks_yzx[vidx] = layers
+                .iter()
+                .find(|(top, _)| y < *top)
+                .map_or(VoxelDatum::default(), |(_, v)| *v);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebugGeneratorSettings {
+    /// Y coordinate of the plane the blocks are placed on
+    pub height: i32,
+    /// Distance between neighbouring blocks of the grid
+    pub spacing: u32,
+}
+
+impl Default for DebugGeneratorSettings {
+    fn default() -> Self {
+        Self {
+            height: 0,
+            spacing: 2,
+        }
+    }
+}
+
+/// Places one of every registered voxel type in a grid on a single plane, starting at x=z=0
+#[derive(Clone, Debug, Default)]
+pub struct DebugPatternGenerator {
+    settings: DebugGeneratorSettings,
+}
+
+impl DebugPatternGenerator {
+    pub fn new(settings: DebugGeneratorSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn from_settings(settings: &str) -> Result<Self, WorldGeneratorError> {
+        Ok(Self::new(parse_generator_settings(settings)?))
+    }
+}
+
+impl WorldGenerator for DebugPatternGenerator {
+    fn name(&self) -> &'static str {
+        DEBUG_GENERATOR_NAME
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn settings(&self) -> String {
+        serialize_generator_settings(&self.settings)
+    }
+
+    fn generate_chunk(&self, chunk: &mut UncompressedChunk, registry: &VoxelRegistry) {
+        chunk.blocks_yzx.fill(VoxelDatum::default());
+        let cdim = CHUNK_DIM as i32;
+        let local_y = self.settings.height - chunk.position.0.y * cdim;
+        if local_y < 0 || local_y >= cdim {
+            return;
+        }
+        let voxels: Vec<_> = registry
+            .iter()
+            .filter(|d| d.id != 0)
+            .map(|d| d.id)
+            .collect();
+        if voxels.is_empty() {
+            return;
+        }
+        let spacing = self.settings.spacing.max(1) as i32;
+        let row_length = (voxels.len() as f64).sqrt().ceil() as i32;
+        for zc in 0..cdim {
+            for xc in 0..cdim {
+                let x = chunk.position.0.x * cdim + xc;
+                let z = chunk.position.0.z * cdim + zc;
+                if x < 0 || z < 0 || x % spacing != 0 || z % spacing != 0 {
+                    continue;
+                }
+                let (gx, gz) = (x / spacing, z / spacing);
+                if gx >= row_length {
+                    continue;
+                }
+                let idx = (gx + gz * row_length) as usize;
+                if let Some(&id) = voxels.get(idx) {
+                    let vidx = (xc + zc * cdim + local_y * cdim * cdim) as usize;
+                    chunk.blocks_yzx[vidx] = VoxelDatum::new(id, 0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlockPosition, ChunkPosition};
+
+    fn test_registry() -> VoxelRegistry {
+        let mut reg = VoxelRegistry::new();
+        for name in &["core:stone", "core:dirt", "core:grass"] {
+            reg.build_definition().name(name).finish().unwrap();
+        }
+        reg
+    }
+
+    fn id(reg: &VoxelRegistry, name: &str) -> u16 {
+        reg.get_definition_from_name(name).unwrap().id
+    }
+
+    fn generate(
+        gen: &dyn WorldGenerator,
+        reg: &VoxelRegistry,
+        cpos: ChunkPosition,
+    ) -> UncompressedChunk {
+        let mut chunk = UncompressedChunk::new();
+        chunk.position = cpos;
+        // garbage that the generators have to overwrite
+        chunk.blocks_yzx.fill(VoxelDatum::new(1, 0));
+        gen.generate_chunk(&mut chunk, reg);
+        chunk
+    }
+
+    #[test]
+    fn void_generator_test() {
+        let reg = test_registry();
+        let chunk = generate(&VoidGenerator, &reg, ChunkPosition::new(0, -1, 3));
+        assert!(chunk.blocks_yzx.iter().all(|v| v.id() == 0));
+    }
+
+    #[test]
+    fn flat_generator_test() {
+        let reg = test_registry();
+        let gen = FlatGenerator::default();
+        // default layers: stone below y=-4, dirt up to y=-2, grass at y=-1
+        let chunk = generate(&gen, &reg, ChunkPosition::new(2, -1, -5));
+        for (vidx, v) in chunk.blocks_yzx.iter().enumerate() {
+            let y = block_y(&chunk, vidx);
+            let expected = match y {
+                y if y < -4 => "core:stone",
+                y if y < -1 => "core:dirt",
+                _ => "core:grass",
+            };
+            assert_eq!(v.id(), id(&reg, expected), "at y={}", y);
+        }
+        let above = generate(&gen, &reg, ChunkPosition::new(0, 0, 0));
+        assert!(above.blocks_yzx.iter().all(|v| v.id() == 0));
+        // below the base the first layer continues
+        let below = generate(&gen, &reg, ChunkPosition::new(0, -5, 0));
+        assert!(below
+            .blocks_yzx
+            .iter()
+            .all(|v| v.id() == id(&reg, "core:stone")));
+
+        let gen = FlatGenerator::new(FlatGeneratorSettings {
+            base_height: 0,
+            layers: vec![FlatLayer {
+                block: String::from("test:missing"),
+                thickness: 10,
+            }],
+        });
+        let chunk = generate(&gen, &reg, ChunkPosition::new(0, 0, 0));
+        assert!(chunk.blocks_yzx.iter().all(|v| v.id() == 0));
+    }
+
+    #[test]
+    fn debug_generator_test() {
+        let reg = test_registry();
+        let gen = DebugPatternGenerator::default();
+        // 3 voxels in rows of 2, 2 blocks apart
+        let chunk = generate(&gen, &reg, ChunkPosition::new(0, 0, 0));
+        let expected = [
+            (BlockPosition::new(0, 0, 0), "core:stone"),
+            (BlockPosition::new(2, 0, 0), "core:dirt"),
+            (BlockPosition::new(0, 0, 2), "core:grass"),
+        ];
+        for &(bpos, name) in expected.iter() {
+            assert_eq!(chunk.blocks_yzx[bpos.as_blockidx()].id(), id(&reg, name));
+        }
+        let placed = chunk.blocks_yzx.iter().filter(|v| v.id() != 0).count();
+        assert_eq!(placed, expected.len());
+        for &cpos in &[ChunkPosition::new(0, 1, 0), ChunkPosition::new(-1, 0, 0)] {
+            let chunk = generate(&gen, &reg, cpos);
+            assert!(chunk.blocks_yzx.iter().all(|v| v.id() == 0));
+        }
+    }
+
+    #[test]
+    fn settings_roundtrip_test() {
+        let gen = FlatGenerator::new(FlatGeneratorSettings {
+            base_height: 12,
+            layers: vec![FlatLayer {
+                block: String::from("core:dirt"),
+                thickness: 7,
+            }],
+        });
+        let parsed = FlatGenerator::from_settings(&gen.settings()).unwrap();
+        assert_eq!(parsed.settings.base_height, 12);
+        assert_eq!(parsed.settings.layers.len(), 1);
+        assert_eq!(parsed.settings.layers[0].block, "core:dirt");
+        assert_eq!(parsed.settings.layers[0].thickness, 7);
+
+        let gen = DebugPatternGenerator::new(DebugGeneratorSettings {
+            height: -3,
+            spacing: 5,
+        });
+        let parsed = DebugPatternGenerator::from_settings(&gen.settings()).unwrap();
+        assert_eq!(parsed.settings.height, -3);
+        assert_eq!(parsed.settings.spacing, 5);
+
+        assert!(FlatGenerator::from_settings("layers = 3").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::light::MAX_LIGHT_LEVEL;
 use crate::{TextureMapping, VoxelDatum, VoxelDefinition, VoxelId, VoxelMesh};
 use bxw_util::collider::AABB;
 use bxw_util::lazy_static::lazy_static;
@@ -20,6 +21,8 @@ pub struct VoxelDefinitionBuilder<'a> {
     selection_shape: Option<AABB>,
     debug_color: [f32; 3],
     texture_mapping: TextureMapping<u32>,
+    light_emission: u8,
+    light_opacity: u8,
 }
 
 #[derive(Clone)]
@@ -32,6 +35,19 @@ pub struct VoxelRegistry {
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum VoxelDefinitionError {
     AlreadyExists,
+    InvalidLightLevel,
+    InvalidName,
+    /// A collision or selection box with mins greater than maxs
+    InvalidShape,
+    /// Unknown mesh or shape kind name in a definition file
+    UnknownKind(String),
+    /// The definition file couldn't be read or parsed
+    InvalidFile(String),
+    /// Wraps an error of a specific definition from a definition file
+    InDefinition {
+        name: String,
+        error: Box<VoxelDefinitionError>,
+    },
 }
 
 impl<'a> VoxelDefinitionBuilder<'a> {
@@ -60,7 +76,34 @@ impl<'a> VoxelDefinitionBuilder<'a> {
         self
     }
 
+    pub fn light_emission(mut self, level: u8) -> Self {
+        self.light_emission = level;
+        self
+    }
+
+    pub fn light_opacity(mut self, level: u8) -> Self {
+        self.light_opacity = level;
+        self
+    }
+
     pub fn finish(self) -> Result<(), VoxelDefinitionError> {
+        if self.light_emission > MAX_LIGHT_LEVEL || self.light_opacity > MAX_LIGHT_LEVEL {
+            return Err(VoxelDefinitionError::InvalidLightLevel);
+        }
+        if self.name.is_empty() {
+            return Err(VoxelDefinitionError::InvalidName);
+        }
+        let valid_shape = |shape: &Option<AABB>| {
+            shape.map_or(true, |s| {
+                s.mins.iter().zip(s.maxs.iter()).all(|(a, b)| a <= b)
+            })
+        };
+        if !valid_shape(&self.collision_shape) || !valid_shape(&self.selection_shape) {
+            return Err(VoxelDefinitionError::InvalidShape);
+        }
+        if self.registry.name_lut.contains_key(&self.name) {
+            return Err(VoxelDefinitionError::AlreadyExists);
+        }
         let def = VoxelDefinition {
             id: self.id,
             name: self.name,
@@ -69,6 +112,8 @@ impl<'a> VoxelDefinitionBuilder<'a> {
             selection_shape: self.selection_shape,
             debug_color: self.debug_color,
             texture_mapping: self.texture_mapping,
+            light_emission: self.light_emission,
+            light_opacity: self.light_opacity,
         };
         let idx = def.id as usize;
         if self.registry.definitions.len() <= idx {
@@ -81,6 +126,11 @@ impl<'a> VoxelDefinitionBuilder<'a> {
         Ok(())
     }
 
+    pub fn texture_mapping(mut self, t: TextureMapping<u32>) -> Self {
+        self.texture_mapping = t;
+        self
+    }
+
     pub fn texture_names(
         mut self,
         mapper_fn: &dyn Fn(&str) -> u32,
@@ -104,6 +154,7 @@ impl Default for VoxelRegistry {
             .set_mesh(VoxelMesh::None)
             .set_collision_shape(None)
             .set_selection_shape(None)
+            .light_opacity(0)
             .finish()
             .unwrap();
         reg
@@ -129,6 +180,8 @@ impl VoxelRegistry {
             selection_shape: Some(*VOXEL_CUBE_SHAPE),
             debug_color: [1.0, 1.0, 1.0],
             texture_mapping: TextureMapping::new_single(0),
+            light_emission: 0,
+            light_opacity: MAX_LIGHT_LEVEL,
         }
     }
 
@@ -142,9 +195,18 @@ impl VoxelRegistry {
         self.definitions[usize::from(id)].as_ref().unwrap()
     }
 
+    /// Like [`Self::get_definition_from_id`], but doesn't panic on ids that aren't registered
+    pub fn try_get_definition_from_id(&self, id: VoxelId) -> Option<&VoxelDefinition> {
+        self.definitions.get(usize::from(id))?.as_ref()
+    }
+
     pub fn get_definition_from_name(&self, name: &str) -> Option<&VoxelDefinition> {
         self.name_lut
             .get(name)
             .and_then(|x| self.definitions.get(*x)?.as_ref())
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VoxelDefinition> {
+        self.definitions.iter().filter_map(Option::as_ref)
+    }
 }
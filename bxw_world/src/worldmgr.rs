@@ -1,6 +1,6 @@
 use crate::ecs::*;
 use crate::generation::WorldBlocks;
-use crate::storage::WorldStorageBackend;
+use crate::storage::{serializer, WorldStorageBackend};
 use crate::*;
 use bxw_util::fnv::*;
 use bxw_util::itertools::*;
@@ -40,7 +40,7 @@ pub const CHUNK_LIGHT_DATA: usize = 1;
 pub const CHUNK_MESH_DATA: usize = 2;
 
 pub type ChunkBlockData = Option<Arc<VChunk>>;
-pub type ChunkLightData = Option<Arc<()>>;
+pub type ChunkLightData = Option<Arc<crate::light::ChunkLight>>;
 type KindsSmallVec = SmallVec<[usize; 6]>;
 
 pub type AnyChunkData = Option<Arc<dyn Any + Send + Sync>>;
@@ -168,6 +168,8 @@ pub struct World {
         Receiver<SynchronousUpdateTask>,
     ),
     storage: Box<dyn WorldStorageBackend>,
+    /// Entity ID high water marks last written to the storage metadata
+    stored_entity_id_marks: [u64; 4],
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -274,6 +276,7 @@ impl World {
             remaining_deltas: Vec::new(),
             sync_task_queue: (tx, rx),
             storage,
+            stored_entity_id_marks: [0; 4],
         }
     }
 
@@ -327,13 +330,28 @@ impl World {
         }
         drop(blocks_ref);
         let mut chunks_to_update: FnvHashSet<ChunkPosition> = Default::default();
+        let mut light_chunks_to_update: FnvHashSet<ChunkPosition> = Default::default();
         for (_, change) in changes.iter() {
             for upos in change.bpos.touching_chunks() {
                 chunks_to_update.insert(upos);
             }
+            for upos in light::chunks_affected_by_change(change.bpos) {
+                light_chunks_to_update.insert(upos);
+            }
+        }
+        // relit on the task pool through the load deltas instead of stalling the caller
+        {
+            let mut lights = self.get_handler(CHUNK_LIGHT_DATA).borrow_mut();
+            if let Some(lights) = lights.as_any_mut().downcast_mut::<light::WorldLight>() {
+                for cpos in light_chunks_to_update {
+                    if let Some(cid) = self.get_chunk_index(cpos) {
+                        lights.mark_outdated(cid);
+                    }
+                }
+            }
         }
         for handler_i in 0..self.handlers.len() {
-            if handler_i == CHUNK_BLOCK_DATA {
+            if handler_i == CHUNK_BLOCK_DATA || handler_i == CHUNK_LIGHT_DATA {
                 continue;
             }
             let handler = &self.handlers[handler_i];
@@ -423,7 +441,15 @@ impl World {
                     } => {
                         let cid = self.allocation.get(&cpos).copied();
                         if let Some(cid) = cid {
-                            for kind in self.handlers.iter() {
+                            // entities are stored together with the block data, so only restore them once
+                            let blocks_from_io = self.handlers[CHUNK_BLOCK_DATA]
+                                .borrow()
+                                .status_array()
+                                .get(cid)
+                                .copied()
+                                == Some(ChunkDataState::WaitingOnIo);
+                            let sections = serializer::unpack_chunk_sections(&voxel_data);
+                            for (kind_id, kind) in self.handlers.iter().enumerate() {
                                 let mut kind = kind.borrow_mut();
                                 if !kind.serializable() {
                                     continue;
@@ -433,7 +459,27 @@ impl World {
                                 {
                                     continue;
                                 }
-                                let r = kind.deserialize_data(self, cid, &voxel_data);
+                                let section = match &sections {
+                                    Ok(sections) => sections.iter().find(|(id, _)| *id == kind_id),
+                                    Err(err) => {
+                                        log::error!(
+                                            "Error loading chunk {} from storage: {}",
+                                            cpos,
+                                            err
+                                        );
+                                        kind.status_array_mut()[cid] = ChunkDataState::Errored;
+                                        continue;
+                                    }
+                                };
+                                let section = match section {
+                                    Some((_, data)) => *data,
+                                    None => {
+                                        // saved before this kind of data existed, calculate it from scratch
+                                        kind.status_array_mut()[cid] = ChunkDataState::NotInIo;
+                                        continue;
+                                    }
+                                };
+                                let r = kind.deserialize_data(self, cid, section);
                                 if let Err(err) = r {
                                     log::error!(
                                         "Error loading chunk {} from storage: {}",
@@ -444,7 +490,12 @@ impl World {
                                 } else {
                                     kind.status_array_mut()[cid] = ChunkDataState::Loaded;
                                 }
-                                let _ = entity_data;
+                            }
+                            let blocks_loaded =
+                                self.handlers[CHUNK_BLOCK_DATA].borrow().status_array()[cid]
+                                    == ChunkDataState::Loaded;
+                            if blocks_from_io && blocks_loaded {
+                                self.restore_chunk_entities(cpos, &entity_data);
                             }
                         }
                     }
@@ -466,6 +517,7 @@ impl World {
                         }
                     }
                     WriteOk { .. } => {}
+                    GlobalEntitiesWriteOk => {}
                     ClosedOk => {}
                 }
             }
@@ -483,15 +535,30 @@ impl World {
                     }
                 };
                 if delta.unload {
-                    for kind in delta.handlers {
+                    // All serializable kinds are stored together in one sectioned blob, so they are unloaded together
+                    let mut unload_kinds = delta.handlers.clone();
+                    let unloads_serializable = delta
+                        .handlers
+                        .iter()
+                        .any(|&kind| self.handlers[kind].borrow().serializable());
+                    if unloads_serializable {
+                        for (kind_id, kind) in self.handlers.iter().enumerate() {
+                            if kind.borrow().serializable() && !unload_kinds.contains(&kind_id) {
+                                unload_kinds.push(kind_id);
+                            }
+                        }
+                        if let Some((voxel_data, entity_data, entity_ids)) =
+                            self.serialize_chunk(delta.cpos, cid)
+                        {
+                            storage_write_requests.push((delta.cpos, voxel_data, entity_data));
+                            for eid in entity_ids {
+                                self.entities.delete_entity(eid);
+                            }
+                        }
+                    }
+                    for kind in unload_kinds {
                         let mut kind = self.handlers[kind].borrow_mut();
                         if kind.status_array()[cid] != ChunkDataState::Unloaded {
-                            if kind.serializable() {
-                                let data = kind.serialize_data(self, cid);
-                                if let Some(data) = data {
-                                    storage_write_requests.push((delta.cpos, data, Vec::new()));
-                                }
-                            }
                             let _arc = kind.swap_data(self, cid, None);
                             kind.status_array_mut()[cid] = ChunkDataState::Unloaded;
                         }
@@ -546,6 +613,10 @@ impl World {
         if !tasks.is_empty() {
             task_pool.push_tasks(tasks.into_iter());
         }
+        if !storage_write_requests.is_empty() {
+            // before the written entities could be loaded back and collide with newly allocated IDs
+            self.store_entity_id_marks();
+        }
         if !storage_read_requests.is_empty() || !storage_write_requests.is_empty() {
             let mut storage_requests = self.storage.lock_requests();
             if !storage_read_requests.is_empty() {
@@ -566,6 +637,175 @@ impl World {
             .store(self.remaining_deltas.len() as i32, Ordering::Release);
     }
 
+    /// Returns the sectioned voxel data blob and the entity data blob of the given chunk,
+    /// together with the IDs of the chunked entities stored in it.
+    /// None if no serializable chunk data is loaded.
+    fn serialize_chunk(
+        &self,
+        cpos: ChunkPosition,
+        cid: usize,
+    ) -> Option<(Vec<u8>, Vec<u8>, Vec<ValidEntityID>)> {
+        let mut sections: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (kind_id, kind) in self.handlers.iter().enumerate() {
+            let kind = kind.borrow();
+            if !kind.serializable() || !kind.status_array()[cid].is_loaded() {
+                continue;
+            }
+            if let Some(data) = kind.serialize_data(self, cid) {
+                sections.push((kind_id, data));
+            }
+        }
+        if sections.is_empty() {
+            return None;
+        }
+        let entity_ids = self.chunked_entities_in(cpos);
+        let snapshots = entity_ids
+            .iter()
+            .filter_map(|&eid| self.entities.snapshot_entity(eid))
+            .collect_vec();
+        Some((
+            serializer::pack_chunk_sections(&sections),
+            serializer::serialize_entities(&snapshots),
+            entity_ids,
+        ))
+    }
+
+    /// Chunked entities with a location inside the given chunk
+    fn chunked_entities_in(&self, cpos: ChunkPosition) -> Vec<ValidEntityID> {
+        ECSHandler::<CLocation>::iter(&self.entities)
+            .filter(|loc| {
+                matches!(
+                    loc.entity_id().domain(),
+                    EntityDomain::LocalChunked | EntityDomain::SharedChunked
+                ) && ChunkPosition::from(loc.position) == cpos
+            })
+            .map(|loc| loc.entity_id())
+            .collect_vec()
+    }
+
+    fn restore_chunk_entities(&mut self, cpos: ChunkPosition, entity_data: &[u8]) {
+        let snapshots = match serializer::deserialize_entities(entity_data) {
+            Ok(s) => s,
+            Err(err) => {
+                log::error!(
+                    "Error loading entities of chunk {} from storage: {}",
+                    cpos,
+                    err
+                );
+                return;
+            }
+        };
+        for snapshot in snapshots {
+            let raw_id = snapshot.raw_id;
+            if let Err(err) = self.entities.restore_entity(snapshot) {
+                log::warn!(
+                    "Couldn't restore entity {:x} of chunk {}: {:?}",
+                    raw_id,
+                    cpos,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Writes the entity ID high water marks to the storage metadata if they changed,
+    /// so that entities in stored chunks keep their IDs to themselves once the world is reopened
+    fn store_entity_id_marks(&mut self) {
+        let marks = self.entities.id_high_water_marks();
+        if marks == self.stored_entity_id_marks {
+            return;
+        }
+        match self.storage.write_meta(
+            storage::META_ENTITY_ID_MARKS,
+            &storage::format_entity_id_marks(&marks),
+        ) {
+            Ok(()) => self.stored_entity_id_marks = marks,
+            Err(err) => log::error!("Couldn't save the entity ID allocation state: {}", err),
+        }
+    }
+
+    /// Restores the omnipresent entities saved in the world storage and reserves the IDs
+    /// of the entities stored in chunks, should be called right after creating the world
+    pub fn load_global_entities(&mut self) {
+        match self.storage.read_meta(storage::META_ENTITY_ID_MARKS) {
+            Ok(Some(text)) => match storage::parse_entity_id_marks(&text) {
+                Some(marks) => {
+                    self.entities.reserve_ids_up_to(marks);
+                    self.stored_entity_id_marks = marks;
+                }
+                None => log::error!("Invalid saved entity ID allocation state `{}`", text),
+            },
+            Ok(None) => {}
+            Err(err) => log::error!("Couldn't read the entity ID allocation state: {}", err),
+        }
+        for (raw_id, data) in self.storage.read_global_entities() {
+            let r = serializer::deserialize_entity(&data)
+                .map_err(|e| format!("{}", e))
+                .and_then(|snapshot| {
+                    self.entities
+                        .restore_entity(snapshot)
+                        .map_err(|e| format!("{:?}", e))
+                });
+            if let Err(err) = r {
+                log::error!("Couldn't restore global entity {:x}: {}", raw_id, err);
+            }
+        }
+    }
+
+    /// Writes all the loaded chunks, chunked entities and omnipresent entities to storage,
+    /// and blocks until the storage backend finishes writing them
+    pub fn save_and_close(&mut self) {
+        let _p_zone = tracy_client::Span::new(
+            "World save and close",
+            "save_and_close",
+            file!(),
+            line!(),
+            4,
+        );
+        let mut chunk_writes = Vec::with_capacity(self.allocation.len());
+        for (&cpos, &cid) in self.allocation.iter() {
+            if let Some((voxel_data, entity_data, _)) = self.serialize_chunk(cpos, cid) {
+                chunk_writes.push((cpos, voxel_data, entity_data));
+            }
+        }
+        let global_entities = self
+            .entities
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e.id.domain(),
+                    EntityDomain::LocalOmnipresent | EntityDomain::SharedOmnipresent
+                )
+            })
+            .filter_map(|e| self.entities.snapshot_entity(e.id))
+            .map(|snapshot| (snapshot.raw_id, serializer::serialize_entity(&snapshot)))
+            .collect_vec();
+        self.store_entity_id_marks();
+        let mut storage_requests = self.storage.lock_requests();
+        if !chunk_writes.is_empty() {
+            storage_requests.push_back(storage::ChunkIoRequest::Write {
+                positions: chunk_writes,
+            });
+        }
+        storage_requests.push_back(storage::ChunkIoRequest::WriteGlobalEntities {
+            entities: global_entities,
+        });
+        storage_requests.push_back(storage::ChunkIoRequest::Close);
+        drop(storage_requests);
+        self.storage.notify_worker();
+        loop {
+            let closed = self
+                .storage
+                .lock_responses()
+                .drain(..)
+                .any(|r| r == storage::ChunkIoResponse::ClosedOk);
+            if closed {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+    }
+
     fn check_load_deltas(&mut self, task_pool: &TaskPool) {
         if !self.load_data_busy.load(Ordering::Acquire) {
             let mut load_data = self
@@ -11,13 +11,14 @@ use crate::client::render::ui::{
 };
 use crate::client::render::{RenderingContext, VoxelRenderer};
 use crate::client::world::{CameraSettings, ClientWorld};
-use crate::config::Config;
-use bxw_util::debug_data::DEBUG_DATA;
+use crate::config::{Config, ConfigHandle};
+use bxw_util::debug_data::{FmtBytes, DEBUG_DATA};
 use bxw_util::math::*;
 use bxw_util::*;
 use bxw_world::blocks::register_standard_blocks;
 use bxw_world::ecs::*;
-use bxw_world::entities::player::PLAYER_EYE_HEIGHT;
+use bxw_world::entities::player::{PlayerInput, PLAYER_EYE_HEIGHT, PLAYER_REACH};
+use bxw_world::itemregistry::ItemRegistry;
 use bxw_world::BlockPosition;
 use std::borrow::Cow;
 use std::cell::RefCell;
@@ -30,12 +31,16 @@ use std::time::{Duration, Instant};
 use crate::client::render::voxrender::MeshDataHandler;
 use crate::client::screens::player_inventory::UiPlayerInventory;
 use crate::client::screens::UiScreen;
-use crate::network::client::{ClientControlMessage, NetClient};
+use crate::network::client::{ClientControlMessage, ClientEvent, NetClient};
+use crate::network::discovery::discover_lan_servers;
+use crate::network::known_servers::{KnownServers, ServerTrust};
+use crate::network::packets::game::*;
+use crate::network::protocol::net_mpack_serialize;
+use crate::network::reliability::Channel;
 use bxw_util::change::Change;
 use bxw_util::collider::AABB;
 use bxw_util::direction::OctahedralOrientation;
 use bxw_world::blocks::stdshapes::StdMeta;
-use bxw_world::physics::SMALL_V_CUTOFF;
 use bxw_world::physics::TIMESTEP as PHYSICS_FRAME_TIME;
 use bxw_world::storage::WorldSave;
 
@@ -48,6 +53,56 @@ struct InputState {
     do_normal: bool,
 }
 
+/// How long to wait for the LAN servers to answer a discovery request
+const LAN_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The server given with `-connect <address>`, otherwise the nearest compatible LAN server whose identity key
+/// is already in the known servers file. Anyone on the LAN can answer discovery requests, so new servers
+/// are only listed and have to be chosen explicitly with `-connect`.
+fn choose_server_address(cfg: &ConfigHandle) -> std::net::SocketAddr {
+    let mut args = std::env::args();
+    if args.any(|a| a == "-connect") {
+        return args
+            .next()
+            .expect("Missing value for the -connect argument")
+            .parse()
+            .expect("Invalid value for the -connect argument, expected an IP:Port address");
+    }
+    let servers = discover_lan_servers(cfg.clone(), LAN_DISCOVERY_TIMEOUT).unwrap_or_else(|e| {
+        log::error!("LAN server discovery failed: {}", e);
+        Vec::new()
+    });
+    log::info!("Discovered {} LAN servers", servers.len());
+    for server in servers.iter() {
+        log::info!("  {}", server);
+    }
+    let known_servers_path = cfg.read().client_known_servers_file.clone();
+    let known_servers = KnownServers::load(std::path::Path::new(&known_servers_path))
+        .unwrap_or_else(|e| {
+            log::error!("Couldn't read {}: {:?}", known_servers_path, e);
+            KnownServers::in_memory()
+        });
+    let trusted = servers.iter().find(|s| {
+        s.is_compatible() && known_servers.check(&s.address, &s.server_id) == ServerTrust::Trusted
+    });
+    match trusted {
+        Some(server) => {
+            log::info!("Connecting to {}", server.name);
+            server.address
+        }
+        None => {
+            if servers.iter().any(|s| s.is_compatible()) {
+                log::warn!(
+                    "None of the discovered LAN servers are known, connect to one of them with -connect <address>"
+                );
+            }
+            let port = cfg.read().client_discovery_port;
+            log::warn!("No known compatible LAN servers found, trying the local machine");
+            std::net::SocketAddr::new(std::net::Ipv4Addr::LOCALHOST.into(), port)
+        }
+    }
+}
+
 pub fn client_main() {
     let sdl_ctx = sdl2::init().unwrap();
     let sdl_vid = sdl_ctx.video().unwrap();
@@ -74,23 +129,36 @@ pub fn client_main() {
     let mut vxreg: Box<bxw_world::voxregistry::VoxelRegistry> = Box::default();
     {
         let vctx = vctx.borrow();
-        register_standard_blocks(&mut vxreg, &|nm| vctx.get_texture_id(nm));
+        register_standard_blocks(&mut vxreg, &|nm| vctx.get_texture_id(nm))
+            .expect("Couldn't register the standard blocks");
     }
     let vxreg: Arc<bxw_world::voxregistry::VoxelRegistry> = Arc::from(vxreg);
-    let savefile = {
-        let name = "clientworld";
-        if let Some(ws) = WorldSave::list_existing()
-            .expect("Couldn't list world savefiles")
-            .into_iter()
-            .find(|ws| ws.name() == name)
-        {
-            ws
-        } else {
-            WorldSave::new(name).expect("Couldn't create a new world savefile")
-        }
+    let mut itemreg = ItemRegistry::new();
+    itemreg
+        .register_block_items(&vxreg)
+        .expect("Couldn't register the block items");
+    let (mut world, mut client_world) = if use_netclient {
+        ClientWorld::new_remote_world(vxreg.clone())
+    } else {
+        let savefile = {
+            let name = "clientworld";
+            if let Some(ws) = WorldSave::list_existing()
+                .expect("Couldn't list world savefiles")
+                .into_iter()
+                .find(|ws| ws.name() == name)
+            {
+                ws
+            } else {
+                WorldSave::new(name).expect("Couldn't create a new world savefile")
+            }
+        };
+        ClientWorld::new_local_world(
+            vxreg.clone(),
+            &savefile,
+            &cfg.read().world_generator_request(),
+        )
+        .expect("Couldn't create a new world")
     };
-    let (mut world, mut client_world) = ClientWorld::new_local_world(vxreg.clone(), &savefile)
-        .expect("Couldn't create a new world");
     {
         let lp = client_world.local_player;
         let ents = world.ecs();
@@ -128,24 +196,34 @@ pub fn client_main() {
     let mut event_pump = sdl_ctx.event_pump().unwrap();
 
     let i_placeable = [
-        vxreg.get_definition_from_name("core:grass").unwrap(),
-        vxreg.get_definition_from_name("core:snow_grass").unwrap(),
-        vxreg.get_definition_from_name("core:dirt").unwrap(),
-        vxreg.get_definition_from_name("core:stone").unwrap(),
-        vxreg.get_definition_from_name("core:diamond_ore").unwrap(),
-        vxreg.get_definition_from_name("core:debug").unwrap(),
-        vxreg.get_definition_from_name("core:table").unwrap(),
-    ];
+        "core:grass",
+        "core:snow_grass",
+        "core:dirt",
+        "core:stone",
+        "core:diamond_ore",
+        "core:debug",
+        "core:table",
+        "core:lava",
+    ]
+    .iter()
+    .map(|&name| {
+        let item = itemreg
+            .get_definition_from_name(name)
+            .expect("Missing placeable block item");
+        vxreg.get_definition_from_id(item.placed_voxel.expect("Item doesn't place a block"))
+    })
+    .collect::<Vec<_>>();
     let mut i_place = 4;
     let mut i_orientation = 0;
     let i_destroy = vxreg.get_definition_from_name("core:void").unwrap();
 
     let netclient = if use_netclient {
-        let addr = std::net::SocketAddr::V4("127.0.0.1:20138".parse().unwrap());
+        let addr = choose_server_address(&cfg);
         Some(NetClient::new(cfg.clone(), &addr).expect("Couldn't create netclient"))
     } else {
         None
     };
+    let mut net_connected = false;
 
     'running: loop {
         let current_frame_time = Instant::now();
@@ -229,7 +307,14 @@ pub fn client_main() {
                     from: click_datum,
                     to: bxw_world::VoxelDatum::new(i_used.id(), meta),
                 };
-                world.apply_voxel_changes(&[change]);
+                match &netclient {
+                    // edits are only made on the server's copy of the world
+                    Some(nc) if net_connected => {
+                        client_world.request_block_edit(&mut world, nc, change)
+                    }
+                    Some(_) => {}
+                    None => world.apply_voxel_changes(&[change]),
+                }
             }
 
             for _pfrm in 0..physics_frames {
@@ -241,63 +326,20 @@ pub fn client_main() {
                     4,
                 );
                 // do physics tick
-                let entities = world.ecs();
-                let lp_loc: &CLocation = entities.get_component(local_player).unwrap();
-                let mut new_loc: CLocation = lp_loc.clone();
-                // position
                 let &CameraSettings::FPS { pitch, yaw } = &client_world.camera_settings;
-                let qyaw = Quaternion::from_polar_decomposition(1.0, yaw, Vector3::y_axis());
-                let qpitch = Quaternion::from_polar_decomposition(1.0, pitch, Vector3::x_axis());
-                new_loc.orientation = UnitQuaternion::new_normalize(qpitch * qyaw);
-
-                let mview = glm::quat_to_mat3(&new_loc.orientation).transpose();
-
-                let mut wvel = Vector3::new(
-                    input_mgr.input_state.walk.x as f64,
-                    0.0,
-                    input_mgr.input_state.walk.y as f64,
-                );
-                wvel *= 6.0; // Walk 6m/s
-                if input_mgr.input_state.noclip {
-                    wvel *= 10.0;
-                }
-                if input_mgr.input_state.sprint.is_active() {
-                    wvel *= 3.0;
-                }
-                let mut tvel = mview * wvel;
-                let tspeed = tvel.magnitude();
-
-                if input_mgr.input_state.noclip {
-                    new_loc.position += tvel * PHYSICS_FRAME_TIME;
-                    new_loc.velocity = tvel;
-                } else {
-                    tvel.y = 0.0;
-                }
-                let lp_phys: &CPhysics = entities.get_component(local_player).unwrap();
-                let mut new_phys: CPhysics = lp_phys.clone();
-                new_phys.control_target_velocity = if tspeed < SMALL_V_CUTOFF {
-                    zero()
-                } else {
-                    tvel.normalize() * tspeed
+                let input = PlayerInput {
+                    walk: [input_mgr.input_state.walk.x, input_mgr.input_state.walk.y],
+                    pitch,
+                    yaw,
+                    jump: input_mgr.input_state.jump.is_active(),
+                    sprint: input_mgr.input_state.sprint.is_active(),
+                    noclip: input_mgr.input_state.noclip,
                 };
-                new_phys.frozen = input_mgr.input_state.noclip;
-                if input_mgr.input_state.jump.is_active() && new_phys.against_wall[2] {
-                    new_phys.control_frame_impulse.y = 300.0;
-                }
-                let change = [EntityChange {
-                    kind: EntityChangeKind::UpdateEntity(lp_loc.entity_id()),
-                    location: Change::Update {
-                        old: lp_loc.clone(),
-                        new: new_loc,
-                    },
-                    physics: Change::Update {
-                        old: lp_phys.clone(),
-                        new: new_phys,
-                    },
-                    ..Default::default()
-                }];
-                world.apply_entity_changes(&change);
-                bxw_world::physics::world_physics_tick(&mut world);
+                client_world.tick_local_player(&mut world, input);
+                bxw_world::physics::world_physics_tick_excluding(
+                    &mut world,
+                    &std::iter::once(local_player).collect(),
+                );
             }
         }
 
@@ -321,6 +363,46 @@ pub fn client_main() {
             );
             task_pool.main_thread_tick();
         }
+        if let Some(nc) = &netclient {
+            for event in nc.poll_events() {
+                match event {
+                    ClientEvent::Message { packet_id, data } => {
+                        if let Err(e) =
+                            client_world.handle_server_message(&mut world, nc, packet_id, &data)
+                        {
+                            log::warn!("Invalid message {} from the server: {:?}", packet_id, e);
+                        }
+                    }
+                    // connection state changes are logged by the network thread
+                    ClientEvent::Connected { .. } => {
+                        net_connected = true;
+                        let settings = PktClientSettingsPayload {
+                            load_radius: cfg.read().performance_load_distance,
+                        };
+                        nc.send_message(
+                            Channel::ReliableOrdered,
+                            PacketTypeGameMessage::ClientSettings.into(),
+                            net_mpack_serialize(&settings),
+                        );
+                    }
+                    ClientEvent::ConnectionFailed { .. } | ClientEvent::Disconnected(_) => {
+                        net_connected = false;
+                    }
+                }
+            }
+            let commands = client_world
+                .prediction
+                .as_mut()
+                .filter(|_| net_connected)
+                .and_then(|p| p.commands_to_send());
+            if let Some(commands) = commands {
+                nc.send_message(
+                    Channel::UnreliableSequenced,
+                    PacketTypeGameMessage::PlayerInputs.into(),
+                    net_mpack_serialize(&PktPlayerInputsPayload { commands }),
+                );
+            }
+        }
 
         let _p_span_prepass =
             bxw_util::tracy_client::Span::new("Render prepass", "mainloop", file!(), line!(), 4);
@@ -363,9 +445,27 @@ pub fn client_main() {
                     pl_chunk,
                     pl_cidx,
                 );
+                if let Some(stats) = netclient.as_ref().and_then(|nc| nc.stats()) {
+                    debug_panel_text.push_str(&format!(
+                        "Net RTT: {:.1} ms ±{:.1}, loss {:.1}%\nNet rate: {}/s, in flight {}, queued {}\n",
+                        stats.smoothed_rtt.as_secs_f64() * 1000.0,
+                        stats.rtt_variance.as_secs_f64() * 1000.0,
+                        stats.loss_rate * 100.0,
+                        FmtBytes(stats.send_rate as i64),
+                        FmtBytes(stats.bytes_in_flight as i64),
+                        FmtBytes(stats.queued_bytes as i64),
+                    ));
+                }
+                if let Some(replay) = netclient.as_ref().and_then(|nc| nc.replay_stats()) {
+                    debug_panel_text.push_str(&format!(
+                        "Net replay protection: {} replayed, {} too old\n",
+                        replay.replayed, replay.too_old,
+                    ));
+                }
                 if let Some(pl_cidx) = pl_cidx {
                     let handler_statuses = std::array::IntoIter::new([
                         bxw_world::worldmgr::CHUNK_BLOCK_DATA,
+                        bxw_world::worldmgr::CHUNK_LIGHT_DATA,
                         bxw_world::worldmgr::CHUNK_MESH_DATA,
                     ])
                     .map(|hid| world.get_handler(hid).borrow().status_array()[pl_cidx]);
@@ -522,7 +622,7 @@ pub fn client_main() {
             let rc = raycast::RaycastQuery::new_directed(
                 player_pos + vec3(0.0, PLAYER_EYE_HEIGHT / 2.0, 0.0),
                 fwd,
-                32.0,
+                PLAYER_REACH,
                 &world,
                 true,
                 false,
@@ -587,6 +687,8 @@ pub fn client_main() {
         log::info!("Done netclient shutdown");
     }
 
+    log::info!("Saving the world");
+    world.save_and_close();
     drop(world);
     drop(task_pool);
     let vctx = Rc::try_unwrap(vctx)
@@ -1,11 +1,32 @@
+use crate::network::client::NetClient;
+use crate::network::packets::game::*;
+use crate::network::protocol::{net_mpack_deserialize, net_mpack_serialize, PacketProcessingError};
+use crate::network::reliability::Channel;
+use bxw_terragen::worldgen::{
+    load_or_create_world_generator, WorldGeneratorLoadError, WorldGeneratorRequest,
+};
 use bxw_util::change::Change;
+use bxw_util::fnv::FnvHashSet;
+use bxw_util::log;
 use bxw_world::ecs::*;
-use bxw_world::generation::WorldBlocks;
-use bxw_world::storage::{WorldDiskStorage, WorldSave};
+use bxw_world::entities::player::{PlayerInput, PLAYER_SPAWN_POSITION};
+use bxw_world::generation::{
+    forget_remote_chunk, receive_remote_chunk, set_remote_blocks, WorldBlocks, WorldGeneratorError,
+};
+use bxw_world::light::WorldLight;
+use bxw_world::storage::palette::VoxelPalette;
+use bxw_world::storage::{SaveFormatError, WorldDiskStorage, WorldNullStorage, WorldSave};
 use bxw_world::worldmgr::*;
-use bxw_world::VoxelRegistry;
+use bxw_world::{BlockPosition, ChunkPosition, VoxelDatum, VoxelRegistry};
+use std::convert::TryFrom;
 use std::sync::Arc;
 
+pub mod prediction;
+
+use prediction::{step_player, PlayerPrediction};
+
+const LOCAL_PLAYER_NAME: &str = "@local_player";
+
 #[derive(Clone, Debug)]
 pub enum CameraSettings {
     FPS { pitch: f64, yaw: f64 },
@@ -15,47 +36,292 @@ pub enum CameraSettings {
 pub struct ClientWorld {
     pub local_player: ValidEntityID,
     pub camera_settings: CameraSettings,
+    /// Replicated entities that diverged from the server, updates are ignored until they're sent again in full
+    entity_resyncs: FnvHashSet<ValidEntityID>,
+    /// Present in worlds streamed from a server
+    pub prediction: Option<PlayerPrediction>,
 }
 
 #[derive(Debug)]
 pub enum WorldOpenError {
     StorageError(bxw_world::storage::rusqlite::Error),
+    SaveFormatError(SaveFormatError),
+    GeneratorError(WorldGeneratorError),
+}
+
+impl From<SaveFormatError> for WorldOpenError {
+    fn from(e: SaveFormatError) -> Self {
+        match e {
+            SaveFormatError::Sqlite(e) => WorldOpenError::StorageError(e),
+            e => WorldOpenError::SaveFormatError(e),
+        }
+    }
+}
+
+impl From<WorldGeneratorLoadError> for WorldOpenError {
+    fn from(e: WorldGeneratorLoadError) -> Self {
+        match e {
+            WorldGeneratorLoadError::StorageError(e) => WorldOpenError::StorageError(e),
+            WorldGeneratorLoadError::GeneratorError(e) => WorldOpenError::GeneratorError(e),
+        }
+    }
 }
 
 impl ClientWorld {
     pub fn new_local_world(
         registry: Arc<VoxelRegistry>,
         save: &WorldSave,
+        generator_request: &WorldGeneratorRequest,
     ) -> Result<(World, ClientWorld), WorldOpenError> {
-        let world_disk_storage =
-            Box::new(WorldDiskStorage::open(save).map_err(WorldOpenError::StorageError)?);
+        let mut world_disk_storage = Box::new(WorldDiskStorage::open(save)?);
+        let (generator, seed) =
+            load_or_create_world_generator(world_disk_storage.as_mut(), generator_request)?;
+        log::info!(
+            "Opening world {} with generator {} v{}, seed {}",
+            save.name(),
+            generator.name(),
+            generator.version(),
+            seed
+        );
+        let palette = VoxelPalette::load(world_disk_storage.as_mut(), &registry)
+            .map_err(WorldOpenError::StorageError)?;
         let mut world = World::new(save.name(), registry.clone(), world_disk_storage);
-        world.replace_handler(CHUNK_BLOCK_DATA, Box::new(WorldBlocks::new(registry, 0)));
+        world.replace_handler(
+            CHUNK_BLOCK_DATA,
+            Box::new(WorldBlocks::new(registry.clone(), generator, palette)),
+        );
+        world.replace_handler(CHUNK_LIGHT_DATA, Box::new(WorldLight::new(registry)));
+        world.load_global_entities();
+        let saved_player = ECSHandler::<CDebugInfo>::iter(world.ecs())
+            .find(|info| {
+                info.entity_id().domain() == EntityDomain::LocalOmnipresent
+                    && info.ent_name == LOCAL_PLAYER_NAME
+            })
+            .map(|info| info.entity_id());
+        let eid = if let Some(eid) = saved_player {
+            eid
+        } else {
+            Self::spawn_local_player(&mut world)
+        };
+        Ok((world, Self::with_player(eid, None)))
+    }
+
+    /// A world streamed from a server, its chunks are filled in by [`Self::handle_server_message`]
+    pub fn new_remote_world(registry: Arc<VoxelRegistry>) -> (World, ClientWorld) {
+        let mut world = World::new(
+            String::from("remote"),
+            registry.clone(),
+            Box::new(WorldNullStorage::new()),
+        );
+        world.replace_handler(
+            CHUNK_BLOCK_DATA,
+            Box::new(WorldBlocks::new_remote(registry.clone())),
+        );
+        world.replace_handler(CHUNK_LIGHT_DATA, Box::new(WorldLight::new(registry)));
+        let eid = Self::spawn_local_player(&mut world);
+        (world, Self::with_player(eid, Some(PlayerPrediction::new())))
+    }
+
+    fn with_player(local_player: ValidEntityID, prediction: Option<PlayerPrediction>) -> Self {
+        ClientWorld {
+            local_player,
+            camera_settings: CameraSettings::FPS {
+                pitch: 0.0,
+                yaw: 0.0,
+            },
+            entity_resyncs: Default::default(),
+            prediction,
+        }
+    }
+
+    fn spawn_local_player(world: &mut World) -> ValidEntityID {
         let entities = world.ecs();
         let mut local_player = bxw_world::entities::player::create_player(
             entities,
             true,
-            String::from("@local_player"),
+            String::from(LOCAL_PLAYER_NAME),
         );
         let eid;
         match local_player.location {
             Change::Create { ref mut new } => {
-                new.position.x = 300.0;
-                new.position.y = 32.0;
-                new.position.z = 28.0;
+                new.position = PLAYER_SPAWN_POSITION.into();
                 eid = new.entity_id();
             }
             _ => unreachable!(),
         };
-
         world.apply_entity_changes(&[local_player]);
-        let cw = ClientWorld {
-            local_player: eid,
-            camera_settings: CameraSettings::FPS {
-                pitch: 0.0,
-                yaw: 0.0,
-            },
+        eid
+    }
+
+    /// Moves the local player by one physics tick of input, predicting the server's movement in remote worlds
+    pub fn tick_local_player(&mut self, world: &mut World, input: PlayerInput) {
+        match &mut self.prediction {
+            Some(prediction) => prediction.predict(world, self.local_player, input),
+            None => step_player(world, self.local_player, &input),
+        }
+    }
+
+    /// Applies a block edit right away and asks the server to make it, it's rolled back if the server rejects it
+    pub fn request_block_edit(&self, world: &mut World, net: &NetClient, change: VoxelChange) {
+        let edit = {
+            let blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow();
+            let blocks: &WorldBlocks = blocks_ref.as_any().downcast_ref().unwrap();
+            PktBlockEditPayload {
+                position: change.bpos.0.into(),
+                from: blocks.palette.datum_to_saved(change.from).repr(),
+                to: blocks.palette.datum_to_saved(change.to).repr(),
+            }
         };
-        Ok((world, cw))
+        world.apply_voxel_changes(&[change]);
+        net.send_message(
+            Channel::ReliableOrdered,
+            PacketTypeGameMessage::BlockEdit.into(),
+            net_mpack_serialize(&edit),
+        );
+    }
+
+    pub fn handle_server_message(
+        &mut self,
+        world: &mut World,
+        net: &NetClient,
+        packet_id: u8,
+        data: &[u8],
+    ) -> Result<(), PacketProcessingError> {
+        match PacketTypeGameMessage::try_from(packet_id)
+            .map_err(|_| PacketProcessingError::UnexpectedPacket)?
+        {
+            PacketTypeGameMessage::VoxelRegistry => {
+                let payload: PktVoxelRegistryPayload = net_mpack_deserialize(data)?;
+                let mut blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow_mut();
+                let blocks: &mut WorldBlocks = blocks_ref.as_any_mut().downcast_mut().unwrap();
+                // the server's ids are treated like the saved ids of a local world
+                blocks.palette =
+                    VoxelPalette::from_saved_entries(&blocks.voxel_registry, &payload.voxels).0;
+            }
+            PacketTypeGameMessage::ChunkData => {
+                let payload: PktChunkDataPayload = net_mpack_deserialize(data)?;
+                let cpos = payload.chunk_position();
+                if let Err(e) = receive_remote_chunk(world, cpos, payload.voxel_words()?) {
+                    log::warn!("Invalid data of {} received from the server: {}", cpos, e);
+                }
+            }
+            PacketTypeGameMessage::ChunkUnload => {
+                let payload: PktChunkUnloadPayload = net_mpack_deserialize(data)?;
+                for [x, y, z] in payload.positions {
+                    forget_remote_chunk(world, ChunkPosition::new(x, y, z));
+                }
+            }
+            PacketTypeGameMessage::EntityUpdates => {
+                let payload: PktEntityUpdatesPayload = net_mpack_deserialize(data)?;
+                let resync = self.apply_entity_updates(world, payload.changes)?;
+                if !resync.is_empty() {
+                    net.send_message(
+                        Channel::ReliableOrdered,
+                        PacketTypeGameMessage::EntityResync.into(),
+                        net_mpack_serialize(&PktEntityResyncPayload { entities: resync }),
+                    );
+                }
+            }
+            PacketTypeGameMessage::PlayerState => {
+                let payload: PktPlayerStatePayload = net_mpack_deserialize(data)?;
+                if let Some(prediction) = &mut self.prediction {
+                    prediction.reconcile(
+                        world,
+                        self.local_player,
+                        payload.last_input,
+                        &payload.state,
+                    );
+                }
+            }
+            PacketTypeGameMessage::BlockChanges => {
+                let payload: PktBlockChangesPayload = net_mpack_deserialize(data)?;
+                let blocks: Vec<(BlockPosition, VoxelDatum)> = payload
+                    .blocks
+                    .iter()
+                    .map(|&(position, datum)| {
+                        (BlockPosition(position.into()), VoxelDatum::from_repr(datum))
+                    })
+                    .collect();
+                if let Err(e) = set_remote_blocks(world, &blocks) {
+                    log::warn!("Invalid block changes received from the server: {}", e);
+                }
+            }
+            PacketTypeGameMessage::BlockEditRejected => {
+                let payload: PktBlockEditRejectedPayload = net_mpack_deserialize(data)?;
+                log::info!(
+                    "Block edit at {:?} rejected by the server: {:?}",
+                    payload.position,
+                    payload.reason
+                );
+                let block = (
+                    BlockPosition(payload.position.into()),
+                    VoxelDatum::from_repr(payload.datum),
+                );
+                if let Err(e) = set_remote_blocks(world, &[block]) {
+                    log::warn!(
+                        "Invalid block edit rollback received from the server: {}",
+                        e
+                    );
+                }
+            }
+            PacketTypeGameMessage::ClientSettings
+            | PacketTypeGameMessage::PlayerInputs
+            | PacketTypeGameMessage::EntityResync
+            | PacketTypeGameMessage::BlockEdit => {
+                return Err(PacketProcessingError::UnexpectedPacket)
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies replicated entity changes, returning the entities that have to be resynchronized
+    fn apply_entity_updates(
+        &mut self,
+        world: &mut World,
+        changes: Vec<EntityChange>,
+    ) -> Result<Vec<ValidEntityID>, PacketProcessingError> {
+        let mut resync = Vec::new();
+        for change in changes {
+            let id = change.kind.entity_id();
+            let shared = matches!(
+                id.domain(),
+                EntityDomain::SharedChunked | EntityDomain::SharedOmnipresent
+            );
+            if !shared
+                || ValidEntityID::from_raw(id.u64()).is_none()
+                || !change.component_ids_match()
+            {
+                return Err(PacketProcessingError::UnexpectedPacket);
+            }
+            let delete = EntityChange {
+                kind: EntityChangeKind::DeleteEntity(id),
+                ..Default::default()
+            };
+            match change.kind {
+                EntityChangeKind::NewEntity(_) => {
+                    // a full copy replaces whatever the client had
+                    self.entity_resyncs.remove(&id);
+                    world.apply_entity_changes(&[delete, change]);
+                }
+                EntityChangeKind::DeleteEntity(_) => {
+                    self.entity_resyncs.remove(&id);
+                    world.apply_entity_changes(&[change]);
+                }
+                EntityChangeKind::UpdateEntity(_) => {
+                    if self.entity_resyncs.contains(&id) {
+                        continue;
+                    }
+                    if world.ecs().is_change_valid(&change) {
+                        world.apply_entity_changes(&[change]);
+                    } else {
+                        log::debug!("Entity {:?} diverged from the server, resyncing it", id);
+                        world.apply_entity_changes(&[delete]);
+                        self.entity_resyncs.insert(id);
+                        resync.push(id);
+                    }
+                }
+            }
+        }
+        Ok(resync)
     }
 }
@@ -0,0 +1,131 @@
+//! Client-side prediction of the local player's movement in worlds streamed from a server.
+//!
+//! Input commands are applied locally right away and kept until the server acknowledges them.
+//! If the server's state after an acknowledged command differs from the predicted one,
+//! the player is reset to the server's state and the remaining commands are replayed on top of it.
+
+use crate::network::packets::game::PlayerInputCommand;
+use bxw_util::math::*;
+use bxw_world::ecs::*;
+use bxw_world::entities::player::*;
+use bxw_world::physics::entity_physics_tick;
+use bxw_world::worldmgr::World;
+use std::collections::VecDeque;
+
+/// About 4 seconds of input at the physics tick rate, older unacknowledged commands are forgotten
+pub const MAX_PENDING_INPUTS: usize = 256;
+/// How many of the newest unacknowledged commands are sent in every input message
+pub const INPUT_REDUNDANCY: usize = 8;
+/// Predicted positions and velocities further than this from the server's are corrected
+pub const PREDICTION_TOLERANCE: f64 = 0.01;
+
+#[derive(Clone, Debug, Default)]
+pub struct PlayerPrediction {
+    next_sequence: u32,
+    last_sent_sequence: Option<u32>,
+    last_acknowledged: Option<u32>,
+    /// Unacknowledged commands with the predicted state after each of them, oldest first
+    pending: VecDeque<(PlayerInputCommand, PlayerMovementState)>,
+    corrections: u64,
+}
+
+/// Applies the input and runs the player's physics for one tick
+pub fn step_player(world: &mut World, player: ValidEntityID, input: &PlayerInput) {
+    if let Some(change) = apply_player_input(world.ecs(), player, input) {
+        world.apply_entity_changes(&[change]);
+    }
+    entity_physics_tick(world, player);
+}
+
+fn states_differ(a: &PlayerMovementState, b: &PlayerMovementState) -> bool {
+    // !(a <= b) is true when there are NaNs present
+    !((a.position - b.position).magnitude() <= PREDICTION_TOLERANCE)
+        || !((a.velocity - b.velocity).magnitude() <= PREDICTION_TOLERANCE)
+}
+
+impl PlayerPrediction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of mispredictions corrected so far
+    pub fn corrections(&self) -> u64 {
+        self.corrections
+    }
+
+    /// Moves the player by one tick of input and remembers the command for sending and replaying
+    pub fn predict(&mut self, world: &mut World, player: ValidEntityID, input: PlayerInput) {
+        step_player(world, player, &input);
+        let state = match PlayerMovementState::of_entity(world.ecs(), player) {
+            Some(s) => s,
+            None => return,
+        };
+        let command = PlayerInputCommand {
+            sequence: self.next_sequence,
+            input,
+        };
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        if self.pending.len() >= MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((command, state));
+    }
+
+    /// The newest unacknowledged commands, if any of them weren't sent yet
+    pub fn commands_to_send(&mut self) -> Option<Vec<PlayerInputCommand>> {
+        let newest = self.pending.back()?.0.sequence;
+        if self.last_sent_sequence == Some(newest) {
+            return None;
+        }
+        self.last_sent_sequence = Some(newest);
+        let skip = self.pending.len().saturating_sub(INPUT_REDUNDANCY);
+        Some(
+            self.pending
+                .iter()
+                .skip(skip)
+                .map(|(command, _)| command.clone())
+                .collect(),
+        )
+    }
+
+    /// Checks the server's state after command `last_input` against the prediction, rewinding and replaying on a mismatch
+    pub fn reconcile(
+        &mut self,
+        world: &mut World,
+        player: ValidEntityID,
+        last_input: u32,
+        server_state: &PlayerMovementState,
+    ) {
+        if self
+            .last_acknowledged
+            .map_or(false, |last| last_input <= last)
+        {
+            return;
+        }
+        self.last_acknowledged = Some(last_input);
+        let mut predicted = None;
+        while let Some((command, _)) = self.pending.front() {
+            if command.sequence > last_input {
+                break;
+            }
+            let (command, state) = self.pending.pop_front().unwrap();
+            if command.sequence == last_input {
+                predicted = Some(state);
+            }
+        }
+        let mispredicted = predicted.map_or(true, |state| states_differ(&state, server_state));
+        if !mispredicted {
+            return;
+        }
+        self.corrections += 1;
+        if let Some(change) = server_state.change_for(world.ecs(), player) {
+            world.apply_entity_changes(&[change]);
+        }
+        for (command, state) in self.pending.iter_mut() {
+            step_player(world, player, &command.input);
+            if let Some(new_state) = PlayerMovementState::of_entity(world.ecs(), player) {
+                *state = new_state;
+            }
+        }
+    }
+}
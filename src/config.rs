@@ -1,3 +1,4 @@
+use bxw_terragen::worldgen::WorldGeneratorRequest;
 use bxw_util::itertools::Itertools;
 use bxw_util::parking_lot::RwLock;
 use bxw_util::*;
@@ -23,12 +24,42 @@ pub struct Config {
 
     pub server_listen_addresses: Vec<SocketAddr>,
     pub server_mtu: u16,
+    /// Path of the server's identity key file
+    pub server_identity_file: String,
+    pub server_max_players: u32,
+    /// Path of the file listing the banned player keys and networks
+    pub server_bans_file: String,
+    /// Only the players listed in `server_allowlist_file` can connect if enabled
+    pub server_enable_allowlist: bool,
+    pub server_allowlist_file: String,
+    /// Answer LAN discovery requests with the server's name and player count
+    pub server_enable_discovery: bool,
+    /// Maximum number of discovery responses sent per second, see `network::discovery::DiscoveryRateLimiter`
+    pub server_discovery_rate: u32,
+
+    /// Path of the player's identity key file
+    pub client_identity_file: String,
+    /// Path of the file remembering the identity keys of servers connected to before
+    pub client_known_servers_file: String,
+    /// Port the LAN discovery requests are broadcast to, the servers' listen port
+    pub client_discovery_port: u16,
+
+    /// Name of the terrain generator used for newly created worlds, see bxw_terragen::worldgen
+    pub world_generator: String,
+    /// TOML settings of the generator for newly created worlds, empty for the defaults
+    pub world_generator_settings: String,
+    /// Seed for newly created worlds, random if not set
+    pub world_seed: Option<u64>,
 
     pub debug_logging: bool,
     pub vk_debug_layers: bool,
 
     /// not in TOML
     pub dbg_renderdoc: bool,
+    /// not in TOML, replace the identity keys with new ones on startup
+    pub regenerate_identity_keys: bool,
+    /// not in TOML, connect even if a known server presents a different identity key than before
+    pub accept_changed_server_key: bool,
 
     toml_doc: Option<toml_edit::Document>,
 }
@@ -59,11 +90,28 @@ impl Config {
                 20138,
             ))],
             server_mtu: 1400,
+            server_identity_file: String::from("server_identity.toml"),
+            server_max_players: 32,
+            server_bans_file: String::from("bans.toml"),
+            server_enable_allowlist: false,
+            server_allowlist_file: String::from("allowlist.toml"),
+            server_enable_discovery: true,
+            server_discovery_rate: 20,
+
+            client_identity_file: String::from("client_identity.toml"),
+            client_known_servers_file: String::from("known_servers.toml"),
+            client_discovery_port: 20138,
+
+            world_generator: String::from("std"),
+            world_generator_settings: String::new(),
+            world_seed: None,
 
             debug_logging: true,
             vk_debug_layers: false,
 
             dbg_renderdoc: false,
+            regenerate_identity_keys: false,
+            accept_changed_server_key: false,
 
             toml_doc: None,
         }
@@ -90,9 +138,51 @@ impl Config {
             .expect("Couldn't open settings.toml for writing")
             .write_all(cfg_text.as_bytes())
             .expect("Couldn't write to settings.toml");
+        // command line overrides are not saved to settings.toml
+        cfg.apply_command_line(std::env::args().skip(1));
         Arc::new(RwLock::new(cfg))
     }
 
+    /// Applies `-seed <number>`, `-generator <name>`, `-generator-settings <toml>`, `-regenerate-keys` and `-accept-server-key` overrides
+    pub fn apply_command_line<I: Iterator<Item = String>>(&mut self, mut args: I) {
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-seed" => {
+                    let seed = args.next().expect("Missing value for the -seed argument");
+                    self.world_seed = Some(seed.parse().expect(
+                        "Invalid value for the -seed argument, expected an unsigned number",
+                    ));
+                }
+                "-generator" => {
+                    self.world_generator = args
+                        .next()
+                        .expect("Missing value for the -generator argument");
+                }
+                "-generator-settings" => {
+                    self.world_generator_settings = args
+                        .next()
+                        .expect("Missing value for the -generator-settings argument");
+                }
+                "-regenerate-keys" => {
+                    self.regenerate_identity_keys = true;
+                }
+                "-accept-server-key" => {
+                    self.accept_changed_server_key = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The generator choice for worlds created with this configuration
+    pub fn world_generator_request(&self) -> WorldGeneratorRequest {
+        WorldGeneratorRequest {
+            name: self.world_generator.clone(),
+            settings: self.world_generator_settings.clone(),
+            seed: self.world_seed,
+        }
+    }
+
     pub fn load_from_toml(&mut self, config: &str) {
         use toml_edit::*;
         let toml_doc: Document = config
@@ -156,6 +246,55 @@ impl Config {
             .map_or(self.server_mtu, |v| v as u16)
             .max(1000)
             .min(9216);
+        self.server_identity_file = toml_doc["server"]["identity_file"]
+            .as_str()
+            .map_or(std::mem::take(&mut self.server_identity_file), String::from);
+        self.server_max_players = toml_doc["server"]["max_players"]
+            .as_integer()
+            .map_or(self.server_max_players, |v| v as u32);
+        self.server_bans_file = toml_doc["server"]["bans_file"]
+            .as_str()
+            .map_or(std::mem::take(&mut self.server_bans_file), String::from);
+        self.server_enable_allowlist = toml_doc["server"]["enable_allowlist"]
+            .as_bool()
+            .unwrap_or(self.server_enable_allowlist);
+        self.server_allowlist_file = toml_doc["server"]["allowlist_file"].as_str().map_or(
+            std::mem::take(&mut self.server_allowlist_file),
+            String::from,
+        );
+        self.server_enable_discovery = toml_doc["server"]["enable_discovery"]
+            .as_bool()
+            .unwrap_or(self.server_enable_discovery);
+        self.server_discovery_rate = toml_doc["server"]["discovery_rate"]
+            .as_integer()
+            .map_or(self.server_discovery_rate, |v| v as u32);
+
+        self.client_identity_file = toml_doc["client"]["identity_file"]
+            .as_str()
+            .map_or(std::mem::take(&mut self.client_identity_file), String::from);
+        self.client_known_servers_file = toml_doc["client"]["known_servers_file"].as_str().map_or(
+            std::mem::take(&mut self.client_known_servers_file),
+            String::from,
+        );
+        self.client_discovery_port = toml_doc["client"]["discovery_port"]
+            .as_integer()
+            .map_or(self.client_discovery_port, |v| v as u16);
+
+        self.world_generator = toml_doc["world"]["generator"]
+            .as_str()
+            .map_or(std::mem::take(&mut self.world_generator), String::from);
+        self.world_generator_settings = toml_doc["world"]["generator_settings"].as_str().map_or(
+            std::mem::take(&mut self.world_generator_settings),
+            String::from,
+        );
+        // written as a string, TOML integers can't hold every u64
+        self.world_seed = match toml_doc["world"]["seed"].as_str() {
+            Some("") => None,
+            Some(seed) => Some(seed.parse().expect(
+                "Invalid value for world.seed in config, expected an unsigned number or an empty string",
+            )),
+            None => self.world_seed,
+        };
 
         self.debug_logging = toml_doc["debug"]["enable_logging"]
             .as_bool()
@@ -172,7 +311,15 @@ impl Config {
         use toml_edit::*;
         let mut toml_doc = std::mem::replace(&mut self.toml_doc, None).unwrap_or_default();
 
-        for rootkey in &["window", "render", "performance", "server", "debug"] {
+        for rootkey in &[
+            "window",
+            "render",
+            "performance",
+            "server",
+            "client",
+            "world",
+            "debug",
+        ] {
             if toml_doc[rootkey].is_none() {
                 toml_doc[rootkey] = Item::Table(Table::new());
             }
@@ -204,6 +351,33 @@ impl Config {
                 .collect(),
         );
         toml_doc["server"]["mtu"] = Item::Value(Value::from(self.server_mtu as i64));
+        toml_doc["server"]["identity_file"] =
+            Item::Value(Value::from(self.server_identity_file.as_str()));
+        toml_doc["server"]["max_players"] =
+            Item::Value(Value::from(self.server_max_players as i64));
+        toml_doc["server"]["bans_file"] = Item::Value(Value::from(self.server_bans_file.as_str()));
+        toml_doc["server"]["enable_allowlist"] =
+            Item::Value(Value::from(self.server_enable_allowlist));
+        toml_doc["server"]["allowlist_file"] =
+            Item::Value(Value::from(self.server_allowlist_file.as_str()));
+        toml_doc["server"]["enable_discovery"] =
+            Item::Value(Value::from(self.server_enable_discovery));
+        toml_doc["server"]["discovery_rate"] =
+            Item::Value(Value::from(self.server_discovery_rate as i64));
+
+        toml_doc["client"]["identity_file"] =
+            Item::Value(Value::from(self.client_identity_file.as_str()));
+        toml_doc["client"]["known_servers_file"] =
+            Item::Value(Value::from(self.client_known_servers_file.as_str()));
+        toml_doc["client"]["discovery_port"] =
+            Item::Value(Value::from(self.client_discovery_port as i64));
+
+        toml_doc["world"]["generator"] = Item::Value(Value::from(self.world_generator.as_str()));
+        toml_doc["world"]["generator_settings"] =
+            Item::Value(Value::from(self.world_generator_settings.as_str()));
+        toml_doc["world"]["seed"] = Item::Value(Value::from(
+            self.world_seed.map_or_else(String::new, |s| s.to_string()),
+        ));
 
         toml_doc["debug"]["enable_logging"] = Item::Value(Value::from(self.debug_logging));
         toml_doc["debug"]["enable_vk_layers"] = Item::Value(Value::from(self.vk_debug_layers));
@@ -218,3 +392,30 @@ impl Default for Config {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_toml_roundtrip() {
+        let mut cfg = Config::new();
+        cfg.world_seed = Some(u64::MAX);
+        cfg.world_generator = String::from("flat");
+        cfg.server_max_players = 7;
+        let text = cfg.save_toml();
+        let mut loaded = Config::new();
+        loaded.load_from_toml(&text);
+        assert_eq!(loaded.world_seed, Some(u64::MAX));
+        assert_eq!(loaded.world_generator, "flat");
+        assert_eq!(loaded.server_max_players, 7);
+
+        // an empty seed means a random one
+        loaded.world_seed = None;
+        let text = loaded.save_toml();
+        let mut reloaded = Config::new();
+        reloaded.world_seed = Some(5);
+        reloaded.load_from_toml(&text);
+        assert_eq!(reloaded.world_seed, None);
+    }
+}
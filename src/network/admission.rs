@@ -0,0 +1,352 @@
+//! Server-side decision which connection requests are accepted, based on the player slot limit,
+//! bans by identity key or network address, and an optional allowlist of identity keys.
+
+use crate::config::Config;
+use crate::network::packets::auth::ConnectionResponse;
+use bxw_util::log;
+use bxw_util::sodiumoxide::crypto::box_;
+use bxw_util::sodiumoxide::hex;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum AdmissionError {
+    Io(std::io::Error),
+    InvalidFile(String),
+}
+
+impl From<std::io::Error> for AdmissionError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// An IP network in CIDR notation, a plain address is a network with only that address
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+/// IPv4-mapped IPv6 addresses are compared as the IPv4 addresses they represent
+fn canonical_ip(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+fn ip_bits(address: IpAddr) -> (u128, u8) {
+    match address {
+        IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl IpNetwork {
+    pub fn new(address: IpAddr, prefix_len: u8) -> Option<Self> {
+        let address = canonical_ip(address);
+        if prefix_len > ip_bits(address).1 {
+            return None;
+        }
+        Some(Self {
+            address,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (net_bits, width) = ip_bits(self.address);
+        let (addr_bits, addr_width) = ip_bits(canonical_ip(address));
+        if width != addr_width {
+            return false;
+        }
+        // the address is in the low `width` bits, the mask has to skip the unused high bits too
+        let host_bits = u32::from(width - self.prefix_len);
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        (net_bits & mask) == (addr_bits & mask)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.find('/') {
+            Some(slash) => (
+                &s[..slash],
+                Some(
+                    s[slash + 1..]
+                        .parse::<u8>()
+                        .map_err(|e| format!("Invalid prefix length in `{}`: {}", s, e))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|e| format!("Invalid address in `{}`: {}", s, e))?;
+        let prefix_len = match (prefix_len, address, canonical_ip(address)) {
+            (None, _, canonical) => ip_bits(canonical).1,
+            // the prefix of an IPv4-mapped address also counts the 96 bits of the mapping
+            (Some(len), IpAddr::V6(_), IpAddr::V4(_)) => len
+                .checked_sub(96)
+                .ok_or_else(|| format!("Prefix too short for a mapped IPv4 address in `{}`", s))?,
+            (Some(len), _, _) => len,
+        };
+        Self::new(address, prefix_len).ok_or_else(|| format!("Prefix too long in `{}`", s))
+    }
+}
+
+impl std::fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlayerEntry {
+    /// Hex encoded public identity key
+    key: String,
+    /// Informative only
+    #[serde(default)]
+    note: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkEntry {
+    /// An address or a network in CIDR notation
+    network: String,
+    /// Informative only
+    #[serde(default)]
+    note: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BansFile {
+    #[serde(default, rename = "player")]
+    players: Vec<PlayerEntry>,
+    #[serde(default, rename = "address")]
+    networks: Vec<NetworkEntry>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AllowlistFile {
+    #[serde(default, rename = "player")]
+    players: Vec<PlayerEntry>,
+}
+
+/// Reads a TOML file, a missing file is treated as an empty one
+fn load_toml<T: Default + serde::de::DeserializeOwned>(path: &Path) -> Result<T, AdmissionError> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => bxw_util::toml::from_str(&text)
+            .map_err(|e| AdmissionError::InvalidFile(format!("{}: {}", path.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_keys(
+    entries: &[PlayerEntry],
+    path: &Path,
+) -> Result<Vec<box_::PublicKey>, AdmissionError> {
+    entries
+        .iter()
+        .map(|entry| {
+            hex::decode(&entry.key)
+                .ok()
+                .and_then(|k| box_::PublicKey::from_slice(&k))
+                .ok_or_else(|| {
+                    AdmissionError::InvalidFile(format!(
+                        "{}: invalid player key `{}`",
+                        path.display(),
+                        entry.key
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AdmissionPolicy {
+    pub max_players: u32,
+    pub banned_players: Vec<box_::PublicKey>,
+    pub banned_networks: Vec<IpNetwork>,
+    /// Only these players can connect if present
+    pub allowlist: Option<Vec<box_::PublicKey>>,
+}
+
+impl AdmissionPolicy {
+    /// A policy with only the slot limit
+    pub fn new(max_players: u32) -> Self {
+        Self {
+            max_players,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the ban list and the allowlist files named in the configuration, missing files are treated as empty
+    pub fn load(cfg: &Config) -> Result<Self, AdmissionError> {
+        let bans_path = Path::new(&cfg.server_bans_file);
+        let bans: BansFile = load_toml(bans_path)?;
+        let banned_networks = bans
+            .networks
+            .iter()
+            .map(|entry| {
+                entry.network.parse().map_err(|e| {
+                    AdmissionError::InvalidFile(format!("{}: {}", bans_path.display(), e))
+                })
+            })
+            .collect::<Result<Vec<IpNetwork>, _>>()?;
+        let allowlist = if cfg.server_enable_allowlist {
+            let allowlist_path = Path::new(&cfg.server_allowlist_file);
+            let allowlist: AllowlistFile = load_toml(allowlist_path)?;
+            Some(parse_keys(&allowlist.players, allowlist_path)?)
+        } else {
+            None
+        };
+        let policy = Self {
+            max_players: cfg.server_max_players,
+            banned_players: parse_keys(&bans.players, bans_path)?,
+            banned_networks,
+            allowlist,
+        };
+        log::info!(
+            "Admission policy: {} slots, {} banned players, {} banned networks, allowlist {}",
+            policy.max_players,
+            policy.banned_players.len(),
+            policy.banned_networks.len(),
+            policy.allowlist.as_ref().map_or_else(
+                || String::from("disabled"),
+                |a| format!("of {} players", a.len())
+            )
+        );
+        Ok(policy)
+    }
+
+    /// Decides on a connection request given the identity keys of the players already connected.
+    /// Only the players that proved they hold the secret keys belong in `connected`, otherwise anyone
+    /// could lock a player out or fill the slots by sending handshakes with made up keys.
+    pub fn check<'a, I: IntoIterator<Item = &'a box_::PublicKey>>(
+        &self,
+        player_id: &box_::PublicKey,
+        address: IpAddr,
+        connected: I,
+    ) -> ConnectionResponse {
+        let banned = self.banned_players.contains(player_id)
+            || self.banned_networks.iter().any(|n| n.contains(address));
+        let allowed = self
+            .allowlist
+            .as_ref()
+            .map_or(true, |allowlist| allowlist.contains(player_id));
+        if banned || !allowed {
+            return ConnectionResponse::Blocked;
+        }
+        let mut count: usize = 0;
+        for other in connected {
+            if other == player_id {
+                return ConnectionResponse::AlreadyPresent;
+            }
+            count += 1;
+        }
+        if count >= self.max_players as usize {
+            return ConnectionResponse::NoSlots;
+        }
+        ConnectionResponse::Accepted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_ip_network() {
+        let net: IpNetwork = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains("192.168.44.1".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!net.contains("192.169.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+        let single: IpNetwork = "10.0.0.1".parse().unwrap();
+        assert!(single.contains("10.0.0.1".parse().unwrap()));
+        assert!(!single.contains("10.0.0.2".parse().unwrap()));
+        let all: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("8.8.8.8".parse().unwrap()));
+        let v6: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::5".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::5".parse().unwrap()));
+        let mapped: IpNetwork = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("not an address".parse::<IpNetwork>().is_err());
+        assert_eq!(
+            IpNetwork::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 128)
+                .unwrap()
+                .to_string(),
+            "::1/128"
+        );
+    }
+
+    #[test]
+    fn test_admission_check() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let (key_a, _) = box_::gen_keypair();
+        let (key_b, _) = box_::gen_keypair();
+        let (key_c, _) = box_::gen_keypair();
+        let home: IpAddr = "10.1.2.3".parse().unwrap();
+        let mut policy = AdmissionPolicy::new(2);
+        assert_eq!(
+            policy.check(&key_a, home, &[]),
+            ConnectionResponse::Accepted
+        );
+        assert_eq!(
+            policy.check(&key_a, home, &[key_b]),
+            ConnectionResponse::Accepted
+        );
+        assert_eq!(
+            policy.check(&key_a, home, &[key_b, key_a]),
+            ConnectionResponse::AlreadyPresent
+        );
+        assert_eq!(
+            policy.check(&key_a, home, &[key_b, key_c]),
+            ConnectionResponse::NoSlots
+        );
+        policy.banned_networks.push("10.0.0.0/8".parse().unwrap());
+        assert_eq!(policy.check(&key_a, home, &[]), ConnectionResponse::Blocked);
+        policy.banned_networks.clear();
+        policy.banned_players.push(key_b);
+        assert_eq!(policy.check(&key_b, home, &[]), ConnectionResponse::Blocked);
+        policy.allowlist = Some(vec![key_a]);
+        assert_eq!(
+            policy.check(&key_a, home, &[]),
+            ConnectionResponse::Accepted
+        );
+        assert_eq!(policy.check(&key_c, home, &[]), ConnectionResponse::Blocked);
+    }
+
+    #[test]
+    fn test_admission_files() {
+        let text = r#"
+            [[player]]
+            key = "00"
+            note = "griefer"
+
+            [[address]]
+            network = "203.0.113.0/24"
+        "#;
+        let bans: BansFile = bxw_util::toml::from_str(text).unwrap();
+        assert_eq!(bans.players.len(), 1);
+        assert_eq!(bans.networks[0].network, "203.0.113.0/24");
+        assert!(parse_keys(&bans.players, Path::new("bans.toml")).is_err());
+    }
+}
@@ -1,18 +1,27 @@
 use crate::config::ConfigHandle;
+use crate::network::congestion::ConnectionStats;
 use crate::network::get_tokio_runtime;
+use crate::network::keystore;
+use crate::network::known_servers::{KnownServers, ServerTrust};
 use crate::network::packets;
 use crate::network::packets::auth::{ClientConnectionType, ConnectionResponse};
+use crate::network::packets::control::DisconnectReason;
 use crate::network::protocol::{
     authflow_client_handshake_packet, authflow_client_try_accept_handshake_ack,
+    ClientsideConnectionCryptoState, ReplayStats,
 };
+use crate::network::reliability::{Channel, RELIABLE_ACK_DELAY};
+use crate::network::session::{EstablishedSession, SessionEvent, SessionPoll, SessionSide};
+use crate::network::transport::DatagramTransport;
 use bxw_util::log;
+use bxw_util::parking_lot::Mutex;
 use bxw_util::sodiumoxide::crypto::box_;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
 use std::time;
 use tokio::net;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::timeout_at;
 
 #[derive(Debug)]
@@ -26,6 +35,7 @@ pub enum ClientCreationError {
     ConnectionRejected {
         reason: packets::auth::ConnectionResponse,
     },
+    IdentityKeyError(keystore::KeyStoreError),
 }
 
 #[derive(Clone, Debug, Hash)]
@@ -33,37 +43,71 @@ pub enum ClientControlMessage {
     Disconnect,
 }
 
+/// Notifications from the network thread to the game loop, see [`NetClient::poll_events`]
+#[derive(Clone, Debug)]
+pub enum ClientEvent {
+    Connected {
+        server_name: String,
+        server_id: box_::PublicKey,
+    },
+    /// The handshake didn't succeed, the client thread terminates
+    ConnectionFailed {
+        error: String,
+    },
+    Message {
+        packet_id: u8,
+        data: Vec<u8>,
+    },
+    Disconnected(DisconnectReason),
+}
+
 pub struct NetClient {
     client_thread: thread::JoinHandle<()>,
     client_control: broadcast::Sender<ClientControlMessage>,
     shared_state: Arc<NetClientSharedState>,
+    outgoing: mpsc::Sender<(Channel, u8, Vec<u8>)>,
+    events: std::sync::mpsc::Receiver<ClientEvent>,
 }
 
 pub struct NetClientSharedState {
     client_id_keys: (box_::PublicKey, box_::SecretKey),
     server_address: SocketAddr,
+    events: Mutex<std::sync::mpsc::Sender<ClientEvent>>,
+    /// Updated by the network thread while the session is established
+    stats: Mutex<Option<ConnectionStats>>,
+    replay_stats: Mutex<Option<ReplayStats>>,
 }
 
 impl NetClientSharedState {
     pub fn new(
         client_id_keys: (box_::PublicKey, box_::SecretKey),
         server_address: SocketAddr,
+        events: std::sync::mpsc::Sender<ClientEvent>,
     ) -> Self {
         Self {
             client_id_keys,
             server_address,
+            events: Mutex::new(events),
+            stats: Mutex::new(None),
+            replay_stats: Mutex::new(None),
         }
     }
+
+    fn send_event(&self, event: ClientEvent) {
+        // the receiver is only gone while the client is shutting down
+        let _ = self.events.lock().send(event);
+    }
 }
 
 const CLIENT_CONTROL_CHANNEL_BOUND: usize = 1024;
+const CLIENT_OUTGOING_CHANNEL_BOUND: usize = 1024;
+/// How often the session keepalive, timeout and retransmission timers are checked
+const CLIENT_SESSION_TIMER_INTERVAL: time::Duration = RELIABLE_ACK_DELAY;
 
 impl NetClient {
+    /// Binds a UDP socket and connects to the server at `address` with it
     pub fn new(cfg: ConfigHandle, address: &SocketAddr) -> Result<Self, ClientCreationError> {
         let tokrt = get_tokio_runtime(Some(cfg.clone()));
-        let (ccon_tx, ccon_rx) = broadcast::channel(CLIENT_CONTROL_CHANNEL_BOUND);
-        drop(ccon_rx);
-        let ccon_tx2 = ccon_tx.clone();
         use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
         let bindaddr = match address {
             SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
@@ -71,22 +115,61 @@ impl NetClient {
         };
         let socket = std::net::UdpSocket::bind(bindaddr)
             .map_err(|error| ClientCreationError::SocketConnectionError { error })?;
-        socket
-            .connect(address)
-            .map_err(|error| ClientCreationError::SocketConnectionError { error })?;
         socket
             .set_nonblocking(true)
             .map_err(|error| ClientCreationError::SocketConnectionError { error })?;
-        // TODO: Save/load identifying keys
-        let shared_state = Arc::new(NetClientSharedState::new(box_::gen_keypair(), *address));
+        let transport = {
+            // tokio sockets can only be created within the runtime
+            let _rt_guard = tokrt.enter();
+            net::UdpSocket::from_std(socket)
+                .map_err(|error| ClientCreationError::SocketConnectionError { error })?
+        };
+        Self::with_transport(cfg, Arc::new(transport), address)
+    }
+
+    /// Connects to the server at `address` through the given transport instead of a new UDP socket
+    pub fn with_transport(
+        cfg: ConfigHandle,
+        transport: Arc<dyn DatagramTransport>,
+        address: &SocketAddr,
+    ) -> Result<Self, ClientCreationError> {
+        let tokrt = get_tokio_runtime(Some(cfg.clone()));
+        let (ccon_tx, ccon_rx) = broadcast::channel(CLIENT_CONTROL_CHANNEL_BOUND);
+        drop(ccon_rx);
+        let ccon_tx2 = ccon_tx.clone();
+        let id_keys = {
+            let mut cfg = cfg.write();
+            let keys = keystore::load_or_create_identity_from_env(
+                std::path::Path::new(&cfg.client_identity_file),
+                cfg.regenerate_identity_keys,
+            )
+            .map_err(ClientCreationError::IdentityKeyError)?;
+            // only regenerate once per run, not on every connection
+            cfg.regenerate_identity_keys = false;
+            keys
+        };
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(CLIENT_OUTGOING_CHANNEL_BOUND);
+        let shared_state = Arc::new(NetClientSharedState::new(id_keys, *address, events_tx));
         let shared_state_copy = Arc::clone(&shared_state);
         let client_thread = thread::Builder::new()
             .name("bxw-client-netio-main".to_owned())
             .stack_size(2 * 1024 * 1024)
             .spawn(move || {
                 tokrt.block_on(async move {
-                    if let Err(e) = client_netmain(cfg, ccon_tx2, socket, shared_state_copy).await {
+                    if let Err(e) = client_netmain(
+                        cfg,
+                        ccon_tx2,
+                        transport,
+                        Arc::clone(&shared_state_copy),
+                        outgoing_rx,
+                    )
+                    .await
+                    {
                         log::error!("Client netmain terminated with error: {:?}", e);
+                        shared_state_copy.send_event(ClientEvent::ConnectionFailed {
+                            error: e.to_string(),
+                        });
                     }
                 });
             })
@@ -95,6 +178,8 @@ impl NetClient {
             client_thread,
             client_control: ccon_tx,
             shared_state,
+            outgoing: outgoing_tx,
+            events: events_rx,
         })
     }
 
@@ -110,6 +195,30 @@ impl NetClient {
         }
     }
 
+    /// Returns all the events that happened since the last call, doesn't block
+    pub fn poll_events(&self) -> Vec<ClientEvent> {
+        self.events.try_iter().collect()
+    }
+
+    /// Sends a message on the `GameMessages` stream with the delivery guarantees of the given channel,
+    /// queued until the session is established
+    pub fn send_message(&self, channel: Channel, packet_id: u8, data: Vec<u8>) {
+        if let Err(e) = self.outgoing.try_send((channel, packet_id, data)) {
+            log::warn!("Couldn't queue message to the server: {}", e);
+        }
+    }
+
+    /// Round-trip time, loss and send rate estimates of the connection, if it's established
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        *self.shared_state.stats.lock()
+    }
+
+    /// Counters of the packets from the server accepted and rejected by the replay protection,
+    /// if the connection is established
+    pub fn replay_stats(&self) -> Option<ReplayStats> {
+        *self.shared_state.replay_stats.lock()
+    }
+
     pub fn wait_for_shutdown(self) {
         self.client_thread
             .join()
@@ -117,6 +226,75 @@ impl NetClient {
     }
 }
 
+/// Checks the server's identity key against the known servers file, remembering it on the first connection
+fn verify_server_identity(
+    cfg: &ConfigHandle,
+    address: &SocketAddr,
+    cccstate: &ClientsideConnectionCryptoState,
+) -> std::io::Result<()> {
+    let (known_servers_path, accept_changed) = {
+        let cfg = cfg.read();
+        (
+            cfg.client_known_servers_file.clone(),
+            cfg.accept_changed_server_key,
+        )
+    };
+    let mut known_servers =
+        KnownServers::load(std::path::Path::new(&known_servers_path)).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Couldn't read {}: {:?}", known_servers_path, e),
+            )
+        })?;
+    let server_key = bxw_util::sodiumoxide::hex::encode(&cccstate.server_id);
+    let known_key = match known_servers.check(address, &cccstate.server_id) {
+        ServerTrust::Trusted => return Ok(()),
+        ServerTrust::Unknown => {
+            log::info!(
+                "First connection to {:?}, remembering its identity key {}",
+                address,
+                server_key
+            );
+            None
+        }
+        ServerTrust::KeyChanged { known_key } => {
+            Some(bxw_util::sodiumoxide::hex::encode(&known_key))
+        }
+        // can't tell if it's the same key, so it's treated as a changed one
+        ServerTrust::KnownKeyInvalid { known_key } => {
+            Some(format!("an invalid key {:?}", known_key))
+        }
+    };
+    if let Some(known_key) = known_key {
+        if !accept_changed {
+            log::error!(
+                "The identity key of {:?} changed from {} to {}, someone could be impersonating the server! \
+                If the change is expected, reconnect with the -accept-server-key flag.",
+                address,
+                known_key,
+                server_key
+            );
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Server identity key changed, check log for details",
+            ));
+        }
+        log::warn!(
+            "Accepting the changed identity key of {:?}: {} replaces {}",
+            address,
+            server_key,
+            known_key
+        );
+    }
+    known_servers.trust(address, &cccstate.server_name, &cccstate.server_id);
+    known_servers.save().map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Couldn't write {}: {:?}", known_servers_path, e),
+        )
+    })
+}
+
 const NET_CLIENT_CONNECTION_RETRIES: u32 = 5;
 /// Effective timeout is this multiplied by `NET_CLIENT_CONNECTION_RETRIES`
 const NET_CLIENT_CONNECTION_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_millis(500);
@@ -124,12 +302,13 @@ const NET_CLIENT_CONNECTION_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::
 async fn client_netmain(
     cfg: ConfigHandle,
     control: broadcast::Sender<ClientControlMessage>,
-    socket: std::net::UdpSocket,
+    socket: Arc<dyn DatagramTransport>,
     shared_state: Arc<NetClientSharedState>,
+    mut outgoing_rx: mpsc::Receiver<(Channel, u8, Vec<u8>)>,
 ) -> std::io::Result<()> {
-    let socket = Arc::new(net::UdpSocket::from_std(socket).unwrap());
+    let server_address = shared_state.server_address;
     let mtu = cfg.read().server_mtu;
-    let mut _control_rx = control.subscribe();
+    let mut control_rx = control.subscribe();
     let mut msgbuf = vec![0u8; mtu as usize * 2];
     log::info!(
         "Attempting connection to {:?} from {:?}",
@@ -143,16 +322,20 @@ async fn client_netmain(
     .expect("Couldn't encode handshake packet");
     let mut hs0resp = None;
     'hsloop: for _retries in 0..NET_CLIENT_CONNECTION_RETRIES {
-        socket.send(&hs0pkt).await?;
+        socket.send_to(&hs0pkt, server_address).await?;
         let sendtime = time::Instant::now();
         let timeout_t = sendtime + NET_CLIENT_CONNECTION_HANDSHAKE_TIMEOUT;
         loop {
-            match timeout_at(timeout_t.into(), socket.recv(&mut msgbuf)).await {
+            match timeout_at(timeout_t.into(), socket.recv_from(&mut msgbuf)).await {
                 Err(_) => {
                     break;
                 }
-                Ok(bytes_received) => {
-                    let msg = &msgbuf[0..bytes_received?];
+                Ok(received) => {
+                    let (bytes_received, source) = received?;
+                    if source != server_address {
+                        continue;
+                    }
+                    let msg = &msgbuf[0..bytes_received];
                     match authflow_client_try_accept_handshake_ack(
                         &hs0state,
                         msg,
@@ -183,6 +366,7 @@ async fn client_netmain(
             "Couldn't connect to server, check log for details",
         )
     })?;
+    verify_server_identity(&cfg, &shared_state.server_address, &cccstate)?;
     if connresp != ConnectionResponse::Accepted {
         log::error!("Server didn't accept our connection: {:?}", connresp);
         return Err(std::io::Error::new(
@@ -197,62 +381,105 @@ async fn client_netmain(
         &cccstate.server_version_id,
         bxw_util::sodiumoxide::hex::encode(&cccstate.server_id)
     );
-    /*'sockloop: loop {
+    shared_state.send_event(ClientEvent::Connected {
+        server_name: cccstate.server_name.clone(),
+        server_id: cccstate.server_id,
+    });
+    let mut session = EstablishedSession::new(
+        SessionSide::Client,
+        cccstate.rx_key.clone(),
+        cccstate.tx_key.clone(),
+        mtu,
+        time::Instant::now(),
+    );
+    // lets the server know the handshake ack arrived, so it stops answering handshake retries
+    socket
+        .send_to(
+            &session.encode_keepalive(time::Instant::now()),
+            server_address,
+        )
+        .await?;
+    let mut timer = tokio::time::interval(CLIENT_SESSION_TIMER_INTERVAL);
+    let reason = 'sockloop: loop {
         tokio::select! {
             ctrl_msg = control_rx.recv() => {
                 use broadcast::error::RecvError;
                 match ctrl_msg {
-                    Ok(msg) => {
-                        match msg {
-                            ServerControlMessage::Stop => {
-                                break 'sockloop;
-                            }
-                        }
-                    }
-                    Err(RecvError::Closed) => {
-                        break 'sockloop;
+                    Ok(ClientControlMessage::Disconnect) | Err(RecvError::Closed) => {
+                        let disconnect = session.encode_disconnect(DisconnectReason::Quit, time::Instant::now());
+                        socket.send_to(&disconnect, server_address).await?;
+                        break 'sockloop DisconnectReason::Quit;
                     }
                     Err(RecvError::Lagged(n)) => {
                         log::error!("Client socket handler lagged {} control messages!", n);
                     }
                 }
             }
-            recv_result = sock.recv_from(&mut msgbuf) => {
-                let (pkt_len, pkt_src_addr) = recv_result?;
-                if pkt_len > msgbuf.len() || pkt_len < 32 {
+            msg = outgoing_rx.recv() => {
+                match msg {
+                    Some((channel, packet_id, data)) => {
+                        if let Err(e) = session.send_message(channel, packet_id, data) {
+                            log::warn!("Couldn't send message {} to the server: {:?}", packet_id, e);
+                        }
+                    }
+                    None => {
+                        // the NetClient was dropped without disconnecting
+                        let disconnect = session.encode_disconnect(DisconnectReason::Quit, time::Instant::now());
+                        socket.send_to(&disconnect, server_address).await?;
+                        break 'sockloop DisconnectReason::Quit;
+                    }
+                }
+            }
+            recv_result = socket.recv_from(&mut msgbuf) => {
+                let (pkt_len, source) = recv_result?;
+                if source != server_address || pkt_len > msgbuf.len() || pkt_len < 32 {
                     continue 'sockloop;
                 }
-                let pkt_data_ref: &[u8] = &msgbuf[0 .. pkt_len];
-                let pkt_src: (usize, std::net::SocketAddr) = (sid, pkt_src_addr);
-                let conn = conntable.entry(pkt_src.1);
-                let mut processed = false;
-                if let std::collections::hash_map::Entry::Occupied(conn) = conn {
-                    match conn.get().try_send(RawPacket{source: pkt_src, data: Box::from(pkt_data_ref)}) {
-                        Ok(()) => {processed = true;}
-                        Err(mpsc::error::TrySendError::Closed(_)) => {
-                            conn.remove_entry();
-                        }
-                        Err(mpsc::error::TrySendError::Full(_)) => {
-                            log::warn!("Clientside packet handler lagging behind on packet processing (on incoming packet from {:?})", pkt_src);
-                            processed = true;
+                match session.receive(&msgbuf[0..pkt_len], time::Instant::now()) {
+                    Ok(events) => {
+                        for event in events {
+                            match event {
+                                SessionEvent::GameMessage { packet_id, data } => {
+                                    shared_state.send_event(ClientEvent::Message { packet_id, data });
+                                }
+                                SessionEvent::Disconnected(reason) => {
+                                    break 'sockloop reason;
+                                }
+                            }
                         }
                     }
+                    Err(e) => {
+                        // stray handshake acks from retries end up here too
+                        log::debug!("Dropping invalid packet from the server: {:?}", e);
+                    }
                 }
-                if !processed {
-                    let shs1 = match protocol::authflow_server_try_accept_handshake_packet(pkt_data_ref) {
-                        Ok(x) => x,
-                        Err(_) => continue 'sockloop
-                    };
-                    let (packets_tx, packets_rx) = mpsc::channel(SERVER_PACKET_CHANNEL_BOUND);
-                    let shared_state_clone = shared_state.clone();
-                    let sock_clone = sock.clone();
-                    conntable.insert(pkt_src_addr, packets_tx);
-                    shared_state.connection_raw_count.fetch_add(1, SeqCst);
-                    tokio::spawn(async move {server_connection_handler(pkt_src, shs1, packets_rx, shared_state_clone, sock_clone).await});
+            }
+            _ = timer.tick() => {}
+        }
+        match session.poll(time::Instant::now()) {
+            SessionPoll::Idle => {}
+            SessionPoll::Send(packets) => {
+                for pkt in packets {
+                    socket.send_to(&pkt, server_address).await?;
                 }
             }
+            SessionPoll::TimedOut => {
+                break 'sockloop DisconnectReason::Timeout;
+            }
         }
-    }*/
+        *shared_state.stats.lock() = Some(session.stats());
+        *shared_state.replay_stats.lock() = Some(session.replay_stats());
+    };
+    *shared_state.stats.lock() = None;
+    *shared_state.replay_stats.lock() = None;
+    log::info!(
+        "Disconnected from server at {:?}: {:?}",
+        shared_state.server_address,
+        reason
+    );
+    log::debug!("Replay protection: {}", session.replay_stats());
+    log::debug!("Connection: {}", session.stats());
+    shared_state.send_event(ClientEvent::Disconnected(reason));
     log::info!("Client socket handler terminating");
     Ok(())
 }
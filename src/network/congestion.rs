@@ -0,0 +1,402 @@
+//! Round-trip time estimation, packet loss tracking and congestion control of a [`ReliableStream`].
+//!
+//! The round-trip time is smoothed the same way as TCP's (RFC 6298), and the resend timeout is derived from it.
+//! The send window follows TCP NewReno: it grows by the acked bytes in slow start and by about one packet
+//! per round trip afterwards, and is halved at most once per round trip when packets get lost.
+//! Only packets with messages take up the window, packets with just an ack aren't acked by the peer.
+//!
+//! [`ReliableStream`]: crate::network::reliability::ReliableStream
+
+use crate::network::reliability::{
+    SeqBuffer, SeqNumber, RELIABLE_ACK_DELAY, RELIABLE_RESEND_TIMEOUT,
+};
+use bxw_util::debug_data::FmtBytes;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Lower limit of the resend timeout, so that a few quick acks don't cause spurious retransmissions
+pub const MIN_RESEND_TIMEOUT: Duration = Duration::from_millis(100);
+/// Upper limit of the resend timeout
+pub const MAX_RESEND_TIMEOUT: Duration = Duration::from_secs(2);
+/// Send window of a new connection, in packets
+pub const INITIAL_WINDOW_PACKETS: usize = 32;
+/// The send window never shrinks below this many packets, so that the connection keeps making progress
+pub const MIN_WINDOW_PACKETS: usize = 2;
+/// A packet is considered lost once a packet sent this many packets after it is acked
+const LOSS_REORDER_THRESHOLD: u32 = 3;
+/// Weight of a single packet's fate in the smoothed loss rate
+const LOSS_RATE_GAIN: f64 = 1.0 / 32.0;
+/// The delivery rate is measured over at least this long, or a round trip if that's longer
+const DELIVERY_RATE_MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Smoothed round-trip time and its variation
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RttEstimator {
+    smoothed: Option<Duration>,
+    variance: Duration,
+    min: Option<Duration>,
+    latest: Duration,
+}
+
+impl RttEstimator {
+    /// Samples include the time the peer waited before acking, up to `RELIABLE_ACK_DELAY`
+    pub fn sample(&mut self, rtt: Duration) {
+        self.latest = rtt;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variance = rtt / 2;
+            }
+            Some(smoothed) => {
+                let deviation = if smoothed > rtt {
+                    smoothed - rtt
+                } else {
+                    rtt - smoothed
+                };
+                self.variance = (self.variance * 3 + deviation) / 4;
+                self.smoothed = Some((smoothed * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    pub fn has_samples(&self) -> bool {
+        self.smoothed.is_some()
+    }
+
+    /// The smoothed round-trip time, `RELIABLE_RESEND_TIMEOUT` until the first sample
+    pub fn smoothed(&self) -> Duration {
+        self.smoothed.unwrap_or(RELIABLE_RESEND_TIMEOUT)
+    }
+
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    pub fn min(&self) -> Duration {
+        self.min.unwrap_or_default()
+    }
+
+    pub fn latest(&self) -> Duration {
+        self.latest
+    }
+
+    /// How long to wait for an ack before the packet is considered lost
+    pub fn resend_timeout(&self) -> Duration {
+        match self.smoothed {
+            None => RELIABLE_RESEND_TIMEOUT,
+            Some(smoothed) => (smoothed + (self.variance * 4).max(RELIABLE_ACK_DELAY))
+                .max(MIN_RESEND_TIMEOUT)
+                .min(MAX_RESEND_TIMEOUT),
+        }
+    }
+}
+
+/// A snapshot of the state of a connection, for diagnostics and for adapting how much is sent over it
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub smoothed_rtt: Duration,
+    pub rtt_variance: Duration,
+    pub min_rtt: Duration,
+    /// Smoothed fraction of the packets lost recently, 0 to 1
+    pub loss_rate: f64,
+    /// Bytes allowed in flight by the congestion controller
+    pub congestion_window: usize,
+    pub bytes_in_flight: usize,
+    /// Bytes per second the congestion window allows at the current round-trip time
+    pub send_rate: f64,
+    /// Bytes per second acked by the peer recently
+    pub delivery_rate: f64,
+    /// Bytes of messages waiting to be sent, held back by the congestion window
+    pub queued_bytes: usize,
+    pub packets_sent: u64,
+    pub packets_lost: u64,
+}
+
+impl std::fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "RTT {:.1} ms (±{:.1}, min {:.1}), loss {:.1}% ({}/{}), window {} ({} in flight), rate {}/s (delivered {}/s), queued {}",
+            self.smoothed_rtt.as_secs_f64() * 1000.0,
+            self.rtt_variance.as_secs_f64() * 1000.0,
+            self.min_rtt.as_secs_f64() * 1000.0,
+            self.loss_rate * 100.0,
+            self.packets_lost,
+            self.packets_sent,
+            FmtBytes(self.congestion_window as i64),
+            FmtBytes(self.bytes_in_flight as i64),
+            FmtBytes(self.send_rate as i64),
+            FmtBytes(self.delivery_rate as i64),
+            FmtBytes(self.queued_bytes as i64),
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SentPacket {
+    seq: SeqNumber,
+    send_time: Instant,
+    bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct CongestionController {
+    max_packet: usize,
+    window: usize,
+    slow_start_threshold: usize,
+    /// Losses of packets sent before this don't shrink the window again
+    recovery_start: Option<Instant>,
+    /// Packets with messages not acked or declared lost yet, oldest first
+    in_flight: VecDeque<SentPacket>,
+    bytes_in_flight: usize,
+    rtt: RttEstimator,
+    loss_rate: f64,
+    packets_sent: u64,
+    packets_lost: u64,
+    delivery_rate: f64,
+    delivery_sample_start: Option<Instant>,
+    delivery_sample_bytes: usize,
+}
+
+impl CongestionController {
+    pub fn new(max_packet: usize) -> Self {
+        Self {
+            max_packet,
+            window: INITIAL_WINDOW_PACKETS * max_packet,
+            slow_start_threshold: usize::MAX,
+            recovery_start: None,
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            rtt: RttEstimator::default(),
+            loss_rate: 0.0,
+            packets_sent: 0,
+            packets_lost: 0,
+            delivery_rate: 0.0,
+            delivery_sample_start: None,
+            delivery_sample_bytes: 0,
+        }
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    /// Bytes that can be sent now without exceeding the window
+    pub fn available(&self) -> usize {
+        self.window.saturating_sub(self.bytes_in_flight)
+    }
+
+    /// Bytes per second the window allows at the current round-trip time
+    pub fn send_rate(&self) -> f64 {
+        self.window as f64 / self.rtt.smoothed().as_secs_f64().max(1.0e-3)
+    }
+
+    /// Records a sent packet containing messages, it takes up the window until it's acked or lost
+    pub fn on_packet_sent(&mut self, seq: SeqNumber, bytes: usize, now: Instant) {
+        self.in_flight.push_back(SentPacket {
+            seq,
+            send_time: now,
+            bytes,
+        });
+        self.bytes_in_flight += bytes;
+        self.packets_sent += 1;
+    }
+
+    /// Processes the packets marked as acked in `sent` after an ack header with `largest_acked` arrived,
+    /// declaring the packets sent well before the acked ones lost
+    pub fn on_ack(&mut self, sent: &SeqBuffer, largest_acked: SeqNumber, now: Instant) {
+        let mut newly_acked = Vec::new();
+        let mut lost = Vec::new();
+        let mut largest_newly_acked = None;
+        for pkt in std::mem::take(&mut self.in_flight) {
+            let data = &sent[pkt.seq];
+            if data.seq != pkt.seq {
+                // too old to be tracked anymore
+                lost.push(pkt);
+            } else if data.acked() {
+                if pkt.seq == largest_acked {
+                    largest_newly_acked = Some(pkt);
+                }
+                newly_acked.push(pkt);
+            } else if pkt.seq + LOSS_REORDER_THRESHOLD <= largest_acked {
+                lost.push(pkt);
+            } else {
+                self.in_flight.push_back(pkt);
+            }
+        }
+        // only the newest packet of an ack is acked without much delay, the rest could've been acked earlier
+        if let Some(pkt) = largest_newly_acked {
+            self.rtt
+                .sample(now.saturating_duration_since(pkt.send_time));
+        }
+        for pkt in newly_acked {
+            self.on_packet_acked(pkt);
+        }
+        for pkt in lost {
+            self.on_packet_lost(pkt, now);
+        }
+        self.update_delivery_rate(now);
+    }
+
+    /// Declares the packets not acked within the resend timeout lost, should be called before sending
+    pub fn detect_timeouts(&mut self, now: Instant) {
+        let timeout = self.rtt.resend_timeout();
+        while let Some(&pkt) = self.in_flight.front() {
+            if now.saturating_duration_since(pkt.send_time) < timeout {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.on_packet_lost(pkt, now);
+        }
+        self.update_delivery_rate(now);
+    }
+
+    fn on_packet_acked(&mut self, pkt: SentPacket) {
+        self.bytes_in_flight -= pkt.bytes;
+        self.loss_rate -= self.loss_rate * LOSS_RATE_GAIN;
+        self.delivery_sample_bytes += pkt.bytes;
+        let in_recovery = self
+            .recovery_start
+            .map_or(false, |start| pkt.send_time <= start);
+        if in_recovery {
+            return;
+        }
+        if self.window < self.slow_start_threshold {
+            self.window += pkt.bytes;
+        } else {
+            self.window += (self.max_packet * pkt.bytes / self.window).max(1);
+        }
+    }
+
+    fn on_packet_lost(&mut self, pkt: SentPacket, now: Instant) {
+        self.bytes_in_flight -= pkt.bytes;
+        self.packets_lost += 1;
+        self.loss_rate += (1.0 - self.loss_rate) * LOSS_RATE_GAIN;
+        let in_recovery = self
+            .recovery_start
+            .map_or(false, |start| pkt.send_time <= start);
+        if in_recovery {
+            return;
+        }
+        self.recovery_start = Some(now);
+        self.window = (self.window / 2).max(MIN_WINDOW_PACKETS * self.max_packet);
+        self.slow_start_threshold = self.window;
+    }
+
+    fn update_delivery_rate(&mut self, now: Instant) {
+        let start = *self.delivery_sample_start.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < self.rtt.smoothed().max(DELIVERY_RATE_MIN_INTERVAL) {
+            return;
+        }
+        self.delivery_rate = self.delivery_sample_bytes as f64 / elapsed.as_secs_f64();
+        self.delivery_sample_start = Some(now);
+        self.delivery_sample_bytes = 0;
+    }
+
+    /// The stats of the connection, except for `queued_bytes`
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            smoothed_rtt: self.rtt.smoothed(),
+            rtt_variance: self.rtt.variance(),
+            min_rtt: self.rtt.min(),
+            loss_rate: self.loss_rate,
+            congestion_window: self.window,
+            bytes_in_flight: self.bytes_in_flight,
+            send_rate: self.send_rate(),
+            delivery_rate: self.delivery_rate,
+            queued_bytes: 0,
+            packets_sent: self.packets_sent,
+            packets_lost: self.packets_lost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::reliability::ReliablePeerState;
+
+    #[test]
+    fn test_rtt_estimator() {
+        let mut rtt = RttEstimator::default();
+        assert!(!rtt.has_samples());
+        assert_eq!(rtt.resend_timeout(), RELIABLE_RESEND_TIMEOUT);
+        rtt.sample(Duration::from_millis(80));
+        assert_eq!(rtt.smoothed(), Duration::from_millis(80));
+        assert_eq!(rtt.variance(), Duration::from_millis(40));
+        assert_eq!(rtt.resend_timeout(), Duration::from_millis(240));
+        for _ in 0..100 {
+            rtt.sample(Duration::from_millis(40));
+        }
+        assert!(rtt.smoothed() < Duration::from_millis(41));
+        assert_eq!(rtt.min(), Duration::from_millis(40));
+        assert_eq!(rtt.resend_timeout(), MIN_RESEND_TIMEOUT);
+        rtt.sample(Duration::from_secs(30));
+        assert_eq!(rtt.resend_timeout(), MAX_RESEND_TIMEOUT);
+    }
+
+    fn send(
+        peer: &mut ReliablePeerState,
+        cc: &mut CongestionController,
+        now: Instant,
+    ) -> SeqNumber {
+        let seq = peer.get_next_send_seq(now);
+        cc.on_packet_sent(seq, 1000, now);
+        seq
+    }
+
+    #[test]
+    fn test_congestion_window() {
+        let mut now = Instant::now();
+        let mut peer = ReliablePeerState::default();
+        let mut cc = CongestionController::new(1000);
+        let initial = cc.window();
+        let seqs: Vec<SeqNumber> = (0..8).map(|_| send(&mut peer, &mut cc, now)).collect();
+        assert_eq!(cc.bytes_in_flight(), 8000);
+        assert_eq!(cc.available(), initial - 8000);
+
+        // slow start grows the window by the acked bytes
+        now += Duration::from_millis(50);
+        for &seq in &seqs[..4] {
+            peer.sent[seq].first_ack_time = Some(now);
+        }
+        cc.on_ack(&peer.sent, seqs[3], now);
+        assert_eq!(cc.rtt().smoothed(), Duration::from_millis(50));
+        assert_eq!(cc.window(), initial + 4000);
+        assert_eq!(cc.bytes_in_flight(), 4000);
+
+        // packet 4 was overtaken by enough acked packets to be lost, 5 and 6 could still be just reordered
+        peer.sent[seqs[7]].first_ack_time = Some(now);
+        cc.on_ack(&peer.sent, seqs[7], now);
+        let stats = cc.stats();
+        assert_eq!(stats.packets_lost, 1);
+        assert_eq!(stats.congestion_window, (initial + 5000) / 2);
+        assert!(stats.loss_rate > 0.0);
+        assert_eq!(cc.bytes_in_flight(), 2000);
+
+        // the remaining packets time out, they were sent before the first loss so the window only halved once
+        now += MAX_RESEND_TIMEOUT;
+        cc.detect_timeouts(now);
+        assert_eq!(cc.bytes_in_flight(), 0);
+        assert_eq!(cc.stats().packets_lost, 3);
+        assert_eq!(cc.window(), (initial + 5000) / 2);
+
+        // losses of newer packets shrink it again, down to the minimum at most
+        for _ in 0..10 {
+            send(&mut peer, &mut cc, now);
+            now += MAX_RESEND_TIMEOUT;
+            cc.detect_timeouts(now);
+        }
+        assert_eq!(cc.window(), MIN_WINDOW_PACKETS * 1000);
+    }
+}
@@ -0,0 +1,331 @@
+//! Unauthenticated LAN server discovery: clients broadcast a small request to the servers' listen port,
+//! servers answer with their name, identity key fingerprint, version and player counts.
+//! The requests are padded to be bigger than the responses and the responses are rate limited,
+//! so a spoofed source address can't turn servers into traffic amplifiers.
+
+use crate::config::ConfigHandle;
+use crate::network::get_tokio_runtime;
+use crate::network::packets::discovery::*;
+use crate::network::packets::PACKET_PROTOCOL_CURRENT_VERSION;
+use crate::network::protocol::{
+    net_mpack_deserialize, net_mpack_serialize, PacketDecodeError, PacketFormat,
+    PacketProcessingError,
+};
+use crate::network::transport::DatagramTransport;
+use bxw_util::fnv::FnvHashMap;
+use bxw_util::log;
+use bxw_util::sodiumoxide::crypto::box_;
+use bxw_util::sodiumoxide::crypto::hash::sha256;
+use bxw_util::sodiumoxide::hex;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Size of the padded discovery requests, servers ignore shorter ones
+pub const DISCOVERY_REQUEST_SIZE: usize = 256;
+/// Longer server names are cut off in discovery responses to keep them smaller than the requests
+pub const DISCOVERY_MAX_NAME_LEN: usize = 64;
+/// Responses to a single network address allowed within `DISCOVERY_ADDRESS_INTERVAL`
+pub const DISCOVERY_RESPONSES_PER_ADDRESS: u32 = 2;
+pub const DISCOVERY_ADDRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Requests from more addresses than this within `DISCOVERY_ADDRESS_INTERVAL` go unanswered
+const DISCOVERY_MAX_TRACKED_ADDRESSES: usize = 1024;
+
+pub fn is_discovery_request(raw: &[u8]) -> bool {
+    raw.first() == Some(&u8::from(PacketFormat::DiscoveryRequestV1))
+}
+
+fn decode_discovery_packet<'a, M: serde::Deserialize<'a>>(
+    raw: &'a [u8],
+    format: PacketFormat,
+    min_len: usize,
+) -> Result<M, PacketProcessingError> {
+    if raw.len() < min_len.max(2) {
+        return Err(PacketDecodeError::TooShort.into());
+    }
+    if raw[0] != u8::from(format) {
+        return Err(
+            PacketDecodeError::UnexpectedFieldValue("format@discovery", raw[0] as u64).into(),
+        );
+    }
+    Ok(net_mpack_deserialize(&raw[1..])?)
+}
+
+pub fn encode_discovery_request(request: &PktDiscoveryRequestPayload) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(DISCOVERY_REQUEST_SIZE);
+    packet.push(PacketFormat::DiscoveryRequestV1.into());
+    packet.extend_from_slice(&net_mpack_serialize(request));
+    packet.resize(packet.len().max(DISCOVERY_REQUEST_SIZE), 0);
+    packet
+}
+
+pub fn try_decode_discovery_request(
+    raw: &[u8],
+) -> Result<PktDiscoveryRequestPayload, PacketProcessingError> {
+    decode_discovery_packet(
+        raw,
+        PacketFormat::DiscoveryRequestV1,
+        DISCOVERY_REQUEST_SIZE,
+    )
+}
+
+pub fn encode_discovery_response(response: &PktDiscoveryResponsePayload) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(128);
+    packet.push(PacketFormat::DiscoveryResponseV1.into());
+    packet.extend_from_slice(&net_mpack_serialize(response));
+    packet
+}
+
+pub fn try_decode_discovery_response(
+    raw: &[u8],
+) -> Result<PktDiscoveryResponsePayload, PacketProcessingError> {
+    decode_discovery_packet(raw, PacketFormat::DiscoveryResponseV1, 0)
+}
+
+/// Cuts the name down to at most `DISCOVERY_MAX_NAME_LEN` bytes on a character boundary
+pub fn truncate_server_name(name: &str) -> String {
+    let mut end = name.len().min(DISCOVERY_MAX_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    String::from(&name[..end])
+}
+
+/// Short human-readable digest of an identity key, like `1a2b:3c4d:5e6f:7081`
+pub fn key_fingerprint(key: &box_::PublicKey) -> String {
+    let digest = sha256::hash(&key.0);
+    digest.0[..8]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Limits the discovery responses of a server socket, both in total and to each network address
+#[derive(Clone, Debug)]
+pub struct DiscoveryRateLimiter {
+    /// Responses per second in total, also the burst size
+    rate: f64,
+    tokens: f64,
+    last_refill: Option<Instant>,
+    /// Start of the current interval and responses sent within it to each address
+    addresses: FnvHashMap<IpAddr, (Instant, u32)>,
+}
+
+impl DiscoveryRateLimiter {
+    pub fn new(responses_per_second: u32) -> Self {
+        Self {
+            rate: f64::from(responses_per_second),
+            tokens: f64::from(responses_per_second),
+            last_refill: None,
+            addresses: FnvHashMap::default(),
+        }
+    }
+
+    /// Returns true if a response to `address` can be sent now, counting it as sent
+    pub fn allow(&mut self, address: IpAddr, now: Instant) -> bool {
+        if let Some(last) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        }
+        self.last_refill = Some(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        if self.addresses.len() >= DISCOVERY_MAX_TRACKED_ADDRESSES {
+            self.addresses.retain(|_, (start, _)| {
+                now.saturating_duration_since(*start) < DISCOVERY_ADDRESS_INTERVAL
+            });
+        }
+        if self.addresses.len() >= DISCOVERY_MAX_TRACKED_ADDRESSES
+            && !self.addresses.contains_key(&address)
+        {
+            return false;
+        }
+        let entry = self.addresses.entry(address).or_insert((now, 0));
+        if now.saturating_duration_since(entry.0) >= DISCOVERY_ADDRESS_INTERVAL {
+            *entry = (now, 0);
+        }
+        if entry.1 >= DISCOVERY_RESPONSES_PER_ADDRESS {
+            return false;
+        }
+        entry.1 += 1;
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// A server that answered a discovery request, nothing here is verified until connecting to it
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    /// Where the response came from, the address to connect to
+    pub address: SocketAddr,
+    pub server_id: box_::PublicKey,
+    pub name: String,
+    pub version: u32,
+    pub players: u32,
+    pub max_players: u32,
+    /// Time between sending the request and receiving the response
+    pub ping: Duration,
+}
+
+impl DiscoveredServer {
+    pub fn fingerprint(&self) -> String {
+        key_fingerprint(&self.server_id)
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.version == PACKET_PROTOCOL_CURRENT_VERSION
+    }
+}
+
+impl std::fmt::Display for DiscoveredServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at {} [{}] {}/{} players, ping {} ms",
+            self.name,
+            self.address,
+            self.fingerprint(),
+            self.players,
+            self.max_players,
+            self.ping.as_millis()
+        )?;
+        if !self.is_compatible() {
+            write!(f, ", incompatible version {}", self.version)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends a discovery request to each of the `targets` and collects the responses arriving within `timeout`,
+/// one per server identity key, ordered by ping
+pub async fn discover_servers(
+    transport: &dyn DatagramTransport,
+    targets: &[SocketAddr],
+    timeout: Duration,
+) -> io::Result<Vec<DiscoveredServer>> {
+    let request = PktDiscoveryRequestPayload {
+        c_version_id: PACKET_PROTOCOL_CURRENT_VERSION,
+        nonce: bxw_util::rand::random(),
+    };
+    let packet = encode_discovery_request(&request);
+    let start = Instant::now();
+    for &target in targets {
+        if let Err(e) = transport.send_to(&packet, target).await {
+            // e.g. no route to the broadcast address, the other targets can still work
+            log::warn!("Couldn't send a discovery request to {}: {}", target, e);
+        }
+    }
+
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut buf = vec![0u8; 2048];
+    let deadline = tokio::time::Instant::from_std(start + timeout);
+    while let Ok(received) = tokio::time::timeout_at(deadline, transport.recv_from(&mut buf)).await
+    {
+        let (len, source) = received?;
+        let response = match try_decode_discovery_response(&buf[..len.min(buf.len())]) {
+            Ok(response) if response.nonce == request.nonce => response,
+            _ => continue,
+        };
+        if servers.iter().any(|s| s.server_id == response.s_server_id) {
+            continue;
+        }
+        servers.push(DiscoveredServer {
+            address: source,
+            server_id: response.s_server_id,
+            name: truncate_server_name(&response.server_name),
+            version: response.s_version_id,
+            players: response.players,
+            max_players: response.max_players,
+            ping: start.elapsed(),
+        });
+    }
+    servers.sort_by_key(|s| s.ping);
+    Ok(servers)
+}
+
+/// Broadcasts a discovery request on the local network and to the local machine, waiting `timeout` for the answers
+pub fn discover_lan_servers(
+    cfg: ConfigHandle,
+    timeout: Duration,
+) -> io::Result<Vec<DiscoveredServer>> {
+    let tokrt = get_tokio_runtime(Some(cfg.clone()));
+    let port = cfg.read().client_discovery_port;
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    let targets = [
+        SocketAddr::new(Ipv4Addr::BROADCAST.into(), port),
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+    ];
+    tokrt.block_on(async move {
+        let transport = tokio::net::UdpSocket::from_std(socket)?;
+        discover_servers(&transport, &targets, timeout).await
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_discovery_packets() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let request = PktDiscoveryRequestPayload {
+            c_version_id: PACKET_PROTOCOL_CURRENT_VERSION,
+            nonce: 0x1234_5678_9abc,
+        };
+        let request_packet = encode_discovery_request(&request);
+        assert_eq!(request_packet.len(), DISCOVERY_REQUEST_SIZE);
+        assert!(is_discovery_request(&request_packet));
+        assert_eq!(
+            try_decode_discovery_request(&request_packet).unwrap(),
+            request
+        );
+        assert!(try_decode_discovery_request(&request_packet[..100]).is_err());
+
+        let response = PktDiscoveryResponsePayload {
+            nonce: request.nonce,
+            s_version_id: PACKET_PROTOCOL_CURRENT_VERSION,
+            s_server_id: box_::gen_keypair().0,
+            server_name: truncate_server_name(&"ż".repeat(100)),
+            players: 3,
+            max_players: 32,
+        };
+        assert_eq!(response.server_name.len(), DISCOVERY_MAX_NAME_LEN);
+        let response_packet = encode_discovery_response(&response);
+        // no amplification even with the longest name
+        assert!(response_packet.len() < request_packet.len());
+        assert!(!is_discovery_request(&response_packet));
+        assert_eq!(
+            try_decode_discovery_response(&response_packet).unwrap(),
+            response
+        );
+        assert!(try_decode_discovery_response(&request_packet).is_err());
+        assert_eq!(key_fingerprint(&response.s_server_id).len(), 19);
+    }
+
+    #[test]
+    fn test_discovery_rate_limiter() {
+        let start = Instant::now();
+        let a: IpAddr = "192.168.1.10".parse().unwrap();
+        let b: IpAddr = "192.168.1.11".parse().unwrap();
+        let mut limiter = DiscoveryRateLimiter::new(4);
+        assert!(limiter.allow(a, start));
+        assert!(limiter.allow(a, start));
+        assert!(!limiter.allow(a, start));
+        assert!(limiter.allow(b, start));
+        assert!(limiter.allow(b, start));
+        // the global limit is exhausted
+        let c: IpAddr = "192.168.1.12".parse().unwrap();
+        assert!(!limiter.allow(c, start));
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow(c, later));
+        assert!(limiter.allow(c, later));
+        assert!(!limiter.allow(a, later));
+        let much_later = start + DISCOVERY_ADDRESS_INTERVAL;
+        assert!(limiter.allow(a, much_later));
+    }
+}
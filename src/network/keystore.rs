@@ -0,0 +1,288 @@
+//! On-disk storage of the long-term identity keypairs of servers and clients
+//!
+//! The secret key is stored in plain hex unless the `BXW_IDENTITY_PASSPHRASE` environment variable is set,
+//! in which case it is encrypted with a key derived from the passphrase.
+
+use bxw_util::log;
+use bxw_util::sodiumoxide::crypto::pwhash::argon2id13;
+use bxw_util::sodiumoxide::crypto::{box_, secretbox};
+use bxw_util::sodiumoxide::hex;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+pub type IdentityKeys = (box_::PublicKey, box_::SecretKey);
+
+/// Environment variable holding the passphrase used to encrypt newly created key files and decrypt existing ones
+pub const IDENTITY_PASSPHRASE_ENV: &str = "BXW_IDENTITY_PASSPHRASE";
+
+const KEY_FILE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum KeyStoreError {
+    Io(std::io::Error),
+    InvalidFile(String),
+    /// The key file can be read or written by other users
+    InsecurePermissions {
+        path: String,
+        mode: u32,
+    },
+    PassphraseRequired,
+    WrongPassphrase,
+}
+
+impl From<std::io::Error> for KeyStoreError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    version: u32,
+    public_key: String,
+    /// Hex encoded, present only in key files without a passphrase
+    secret_key: Option<String>,
+    encrypted_secret_key: Option<EncryptedSecretKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncryptedSecretKey {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>, KeyStoreError> {
+    hex::decode(value).map_err(|_| KeyStoreError::InvalidFile(format!("Invalid hex in {}", field)))
+}
+
+fn passphrase_key(
+    passphrase: &str,
+    salt: &argon2id13::Salt,
+) -> Result<secretbox::Key, KeyStoreError> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    argon2id13::derive_key(
+        &mut key.0,
+        passphrase.as_bytes(),
+        salt,
+        argon2id13::OPSLIMIT_INTERACTIVE,
+        argon2id13::MEMLIMIT_INTERACTIVE,
+    )
+    .map_err(|_| KeyStoreError::InvalidFile(String::from("Couldn't derive the passphrase key")))?;
+    Ok(key)
+}
+
+fn encode_key_file(keys: &IdentityKeys, passphrase: Option<&str>) -> Result<String, KeyStoreError> {
+    let mut file = KeyFile {
+        version: KEY_FILE_VERSION,
+        public_key: hex::encode(&keys.0),
+        secret_key: None,
+        encrypted_secret_key: None,
+    };
+    match passphrase {
+        None => {
+            file.secret_key = Some(hex::encode(&keys.1 .0));
+        }
+        Some(passphrase) => {
+            let salt = argon2id13::gen_salt();
+            let nonce = secretbox::gen_nonce();
+            let key = passphrase_key(passphrase, &salt)?;
+            let ciphertext = secretbox::seal(&keys.1 .0, &nonce, &key);
+            file.encrypted_secret_key = Some(EncryptedSecretKey {
+                salt: hex::encode(&salt),
+                nonce: hex::encode(&nonce),
+                ciphertext: hex::encode(&ciphertext),
+            });
+        }
+    }
+    bxw_util::toml::to_string(&file).map_err(|e| KeyStoreError::InvalidFile(e.to_string()))
+}
+
+fn decode_key_file(text: &str, passphrase: Option<&str>) -> Result<IdentityKeys, KeyStoreError> {
+    let file: KeyFile =
+        bxw_util::toml::from_str(text).map_err(|e| KeyStoreError::InvalidFile(e.to_string()))?;
+    if file.version != KEY_FILE_VERSION {
+        return Err(KeyStoreError::InvalidFile(format!(
+            "Unsupported key file version {}",
+            file.version
+        )));
+    }
+    let public_key = box_::PublicKey::from_slice(&decode_hex("public_key", &file.public_key)?)
+        .ok_or_else(|| KeyStoreError::InvalidFile(String::from("Invalid public key length")))?;
+    let secret_bytes = match (&file.secret_key, &file.encrypted_secret_key) {
+        (Some(secret), None) => decode_hex("secret_key", secret)?,
+        (None, Some(encrypted)) => {
+            let passphrase = passphrase.ok_or(KeyStoreError::PassphraseRequired)?;
+            let salt = argon2id13::Salt::from_slice(&decode_hex("salt", &encrypted.salt)?)
+                .ok_or_else(|| KeyStoreError::InvalidFile(String::from("Invalid salt length")))?;
+            let nonce = secretbox::Nonce::from_slice(&decode_hex("nonce", &encrypted.nonce)?)
+                .ok_or_else(|| KeyStoreError::InvalidFile(String::from("Invalid nonce length")))?;
+            let ciphertext = decode_hex("ciphertext", &encrypted.ciphertext)?;
+            let key = passphrase_key(passphrase, &salt)?;
+            secretbox::open(&ciphertext, &nonce, &key)
+                .map_err(|_| KeyStoreError::WrongPassphrase)?
+        }
+        _ => {
+            return Err(KeyStoreError::InvalidFile(String::from(
+                "Exactly one of secret_key and encrypted_secret_key must be present",
+            )))
+        }
+    };
+    let secret_key = box_::SecretKey::from_slice(&secret_bytes)
+        .ok_or_else(|| KeyStoreError::InvalidFile(String::from("Invalid secret key length")))?;
+    if secret_key.public_key() != public_key {
+        return Err(KeyStoreError::InvalidFile(String::from(
+            "Public key doesn't match the secret key",
+        )));
+    }
+    Ok((public_key, secret_key))
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), KeyStoreError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(KeyStoreError::InsecurePermissions {
+            path: path.to_string_lossy().into_owned(),
+            mode,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), KeyStoreError> {
+    Ok(())
+}
+
+/// Writes the file readable only by the current user, replacing it atomically
+fn write_private_file(path: &Path, contents: &str) -> Result<(), KeyStoreError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Loads the identity keypair from `path`, creating a new one if the file doesn't exist or `regenerate` is set.
+/// A regenerated key file's previous version is kept with an `.old` extension.
+pub fn load_or_create_identity(
+    path: &Path,
+    regenerate: bool,
+    passphrase: Option<&str>,
+) -> Result<IdentityKeys, KeyStoreError> {
+    if path.exists() && !regenerate {
+        check_permissions(path)?;
+        let text = std::fs::read_to_string(path)?;
+        let keys = decode_key_file(&text, passphrase)?;
+        log::info!(
+            "Loaded identity key {} from {}",
+            hex::encode(&keys.0),
+            path.to_string_lossy()
+        );
+        return Ok(keys);
+    }
+    if path.exists() {
+        let backup = path.with_extension("old");
+        log::warn!(
+            "Regenerating identity key {}, the previous one is moved to {}",
+            path.to_string_lossy(),
+            backup.to_string_lossy()
+        );
+        std::fs::rename(path, &backup)?;
+    }
+    let keys = box_::gen_keypair();
+    write_private_file(path, &encode_key_file(&keys, passphrase)?)?;
+    log::info!(
+        "Created identity key {} in {}",
+        hex::encode(&keys.0),
+        path.to_string_lossy()
+    );
+    Ok(keys)
+}
+
+/// [`load_or_create_identity`] with the passphrase taken from [`IDENTITY_PASSPHRASE_ENV`]
+pub fn load_or_create_identity_from_env(
+    path: &Path,
+    regenerate: bool,
+) -> Result<IdentityKeys, KeyStoreError> {
+    let passphrase = std::env::var(IDENTITY_PASSPHRASE_ENV)
+        .ok()
+        .filter(|p| !p.is_empty());
+    load_or_create_identity(path, regenerate, passphrase.as_deref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_file_roundtrip() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let keys = box_::gen_keypair();
+        let plain = encode_key_file(&keys, None).unwrap();
+        let decoded = decode_key_file(&plain, None).unwrap();
+        assert_eq!(decoded.0, keys.0);
+        assert_eq!(decoded.1, keys.1);
+        // a passphrase isn't needed for unencrypted files
+        assert!(decode_key_file(&plain, Some("unused")).is_ok());
+
+        let encrypted = encode_key_file(&keys, Some("hunter2")).unwrap();
+        assert!(!encrypted.contains(&hex::encode(&keys.1 .0)));
+        let decoded = decode_key_file(&encrypted, Some("hunter2")).unwrap();
+        assert_eq!(decoded.1, keys.1);
+        assert!(matches!(
+            decode_key_file(&encrypted, None),
+            Err(KeyStoreError::PassphraseRequired)
+        ));
+        assert!(matches!(
+            decode_key_file(&encrypted, Some("hunter3")),
+            Err(KeyStoreError::WrongPassphrase)
+        ));
+
+        let other_keys = box_::gen_keypair();
+        let mismatched = plain.replace(&hex::encode(&keys.0), &hex::encode(&other_keys.0));
+        assert!(matches!(
+            decode_key_file(&mismatched, None),
+            Err(KeyStoreError::InvalidFile(_))
+        ));
+    }
+
+    #[test]
+    fn test_identity_persistence() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let dir = std::env::temp_dir().join(format!("bxw-keystore-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("identity.toml");
+        let _ = std::fs::remove_file(&path);
+        let created = load_or_create_identity(&path, false, None).unwrap();
+        let loaded = load_or_create_identity(&path, false, None).unwrap();
+        assert_eq!(created.0, loaded.0);
+        let regenerated = load_or_create_identity(&path, true, None).unwrap();
+        assert_ne!(created.0, regenerated.0);
+        assert!(path.with_extension("old").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(matches!(
+                load_or_create_identity(&path, false, None),
+                Err(KeyStoreError::InsecurePermissions { .. })
+            ));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
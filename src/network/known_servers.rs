@@ -0,0 +1,162 @@
+//! Trust-on-first-use storage of server identity keys, the client remembers the key each server presented
+//! on the first connection and refuses to connect if it later changes.
+
+use bxw_util::sodiumoxide::crypto::box_;
+use bxw_util::sodiumoxide::hex;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum KnownServersError {
+    Io(std::io::Error),
+    InvalidFile(String),
+}
+
+impl From<std::io::Error> for KnownServersError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ServerTrust {
+    /// Never connected to this address before
+    Unknown,
+    /// The key matches the remembered one
+    Trusted,
+    /// The server presented a different key than the remembered one, possibly a man-in-the-middle attack
+    KeyChanged { known_key: box_::PublicKey },
+    /// The remembered key can't be decoded, so the presented one can't be checked against it
+    KnownKeyInvalid { known_key: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KnownServerEntry {
+    address: String,
+    /// The name last announced by the server, informative only
+    name: String,
+    /// Hex encoded public identity key
+    key: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KnownServersFile {
+    #[serde(default, rename = "server")]
+    servers: Vec<KnownServerEntry>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KnownServers {
+    path: Option<PathBuf>,
+    file: KnownServersFile,
+}
+
+impl KnownServers {
+    /// A list that isn't backed by a file
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Reads the list from the given path, a missing file is treated as an empty list
+    pub fn load(path: &Path) -> Result<Self, KnownServersError> {
+        let file = match std::fs::read_to_string(path) {
+            Ok(text) => bxw_util::toml::from_str(&text)
+                .map_err(|e| KnownServersError::InvalidFile(e.to_string()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => KnownServersFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            file,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), KnownServersError> {
+        if let Some(path) = &self.path {
+            let text = bxw_util::toml::to_string(&self.file)
+                .map_err(|e| KnownServersError::InvalidFile(e.to_string()))?;
+            std::fs::write(path, text)?;
+        }
+        Ok(())
+    }
+
+    fn entry(&self, address: &SocketAddr) -> Option<&KnownServerEntry> {
+        let address = address.to_string();
+        self.file.servers.iter().find(|e| e.address == address)
+    }
+
+    pub fn check(&self, address: &SocketAddr, key: &box_::PublicKey) -> ServerTrust {
+        match self.entry(address) {
+            None => ServerTrust::Unknown,
+            Some(entry) => {
+                let known_key = hex::decode(&entry.key)
+                    .ok()
+                    .and_then(|k| box_::PublicKey::from_slice(&k));
+                match known_key {
+                    Some(known_key) if known_key == *key => ServerTrust::Trusted,
+                    Some(known_key) => ServerTrust::KeyChanged { known_key },
+                    None => ServerTrust::KnownKeyInvalid {
+                        known_key: entry.key.clone(),
+                    },
+                }
+            }
+        }
+    }
+
+    /// Remembers the key for the address, replacing any previous one
+    pub fn trust(&mut self, address: &SocketAddr, name: &str, key: &box_::PublicKey) {
+        let address = address.to_string();
+        let entry = KnownServerEntry {
+            address: address.clone(),
+            name: String::from(name),
+            key: hex::encode(key),
+        };
+        match self.file.servers.iter_mut().find(|e| e.address == address) {
+            Some(existing) => *existing = entry,
+            None => self.file.servers.push(entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_known_servers_tofu() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let (key_a, _) = box_::gen_keypair();
+        let (key_b, _) = box_::gen_keypair();
+        let addr_a: SocketAddr = "127.0.0.1:20138".parse().unwrap();
+        let addr_b: SocketAddr = "[::1]:20138".parse().unwrap();
+        let mut known = KnownServers::in_memory();
+        assert_eq!(known.check(&addr_a, &key_a), ServerTrust::Unknown);
+        known.trust(&addr_a, "A", &key_a);
+        assert_eq!(known.check(&addr_a, &key_a), ServerTrust::Trusted);
+        assert_eq!(
+            known.check(&addr_a, &key_b),
+            ServerTrust::KeyChanged { known_key: key_a }
+        );
+        assert_eq!(known.check(&addr_b, &key_a), ServerTrust::Unknown);
+        known.trust(&addr_a, "A", &key_b);
+        assert_eq!(known.check(&addr_a, &key_b), ServerTrust::Trusted);
+        assert_eq!(known.file.servers.len(), 1);
+        // a damaged entry doesn't make the address count as never seen
+        known.file.servers[0].key = String::from("not a key");
+        assert_eq!(
+            known.check(&addr_a, &key_b),
+            ServerTrust::KnownKeyInvalid {
+                known_key: String::from("not a key")
+            }
+        );
+        known.trust(&addr_a, "A", &key_b);
+        assert_eq!(known.check(&addr_a, &key_b), ServerTrust::Trusted);
+
+        let text = bxw_util::toml::to_string(&known.file).unwrap();
+        let reloaded: KnownServersFile = bxw_util::toml::from_str(&text).unwrap();
+        assert_eq!(reloaded.servers[0].key, hex::encode(&key_b));
+    }
+}
@@ -1,8 +1,15 @@
+pub mod admission;
 pub mod client;
+pub mod congestion;
+pub mod discovery;
+pub mod keystore;
+pub mod known_servers;
 pub mod packets;
 pub mod protocol;
 pub mod reliability;
 pub mod server;
+pub mod session;
+pub mod transport;
 
 use crate::config::ConfigHandle;
 
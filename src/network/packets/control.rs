@@ -0,0 +1,47 @@
+use bxw_util::sodiumoxide::crypto::kx;
+use num_enum::*;
+use serde::*;
+
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, Hash, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Deserialize, Serialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum PacketTypeConnectionControl {
+    /// Sent when nothing else was sent for a while, keeps the session from timing out
+    Keepalive = 1,
+    /// Graceful end of the session, carries a `PktDisconnectPayload`
+    Disconnect = 2,
+    /// Client->Server request to switch to new session keys, carries a `PktRekeyPayload`
+    RekeyRequest = 3,
+    /// Server->Client answer to `RekeyRequest`, carries a `PktRekeyPayload`
+    RekeyResponse = 4,
+}
+
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, Hash, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Deserialize, Serialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum DisconnectReason {
+    /// The client left
+    Quit = 1,
+    ServerShutdown,
+    /// Nothing was received from the peer for too long, never sent over the network
+    Timeout,
+    Kicked,
+    /// The peer sent something it shouldn't have
+    ProtocolError,
+}
+
+/// Either direction disconnect notification
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktDisconnectPayload {
+    pub reason: DisconnectReason,
+}
+
+/// Key exchange public key for the next session keys, derived like the keys of the handshake
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktRekeyPayload {
+    pub kx_public: kx::PublicKey,
+}
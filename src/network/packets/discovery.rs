@@ -0,0 +1,25 @@
+use bxw_util::sodiumoxide::crypto::box_;
+use serde::*;
+
+/// Client->Server LAN discovery request, broadcast to the servers' listen port
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktDiscoveryRequestPayload {
+    /// Version of the client, servers answer even if it doesn't match theirs
+    pub c_version_id: u32,
+    /// Random number identifying this specific request, echoed back in the responses
+    pub nonce: u64,
+}
+
+/// Server->Client answer to a discovery request, nothing in it is authenticated
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktDiscoveryResponsePayload {
+    /// The `nonce` of the request
+    pub nonce: u64,
+    /// Version of the server, see `super::PACKET_PROTOCOL_CURRENT_VERSION`
+    pub s_version_id: u32,
+    /// Server's permanent identifying public key, the handshake proves the server actually has it
+    pub s_server_id: box_::PublicKey,
+    pub server_name: String,
+    pub players: u32,
+    pub max_players: u32,
+}
@@ -0,0 +1,172 @@
+use crate::network::packets::byte_vec;
+use crate::network::protocol::{net_zstd_compress, net_zstd_decompress, PacketProcessingError};
+use bxw_util::itertools::Itertools;
+use bxw_world::ecs::{EntityChange, ValidEntityID};
+use bxw_world::entities::player::{PlayerInput, PlayerMovementState};
+use bxw_world::{ChunkPosition, VChunk, VChunkData, VoxelId};
+use num_enum::*;
+use serde::*;
+
+/// Message ids of the `GameMessages` stream
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, Hash, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Deserialize, Serialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum PacketTypeGameMessage {
+    /// Server->Client `PktVoxelRegistryPayload`, sent before any chunk data
+    VoxelRegistry = 1,
+    /// Server->Client `PktChunkDataPayload`
+    ChunkData,
+    /// Server->Client `PktChunkUnloadPayload`
+    ChunkUnload,
+    /// Client->Server `PktClientSettingsPayload`
+    ClientSettings,
+    /// Client->Server `PktPlayerInputsPayload`
+    PlayerInputs,
+    /// Server->Client `PktEntityUpdatesPayload`
+    EntityUpdates,
+    /// Client->Server `PktEntityResyncPayload`
+    EntityResync,
+    /// Server->Client `PktPlayerStatePayload`
+    PlayerState,
+    /// Client->Server `PktBlockEditPayload`
+    BlockEdit,
+    /// Server->Client `PktBlockChangesPayload`
+    BlockChanges,
+    /// Server->Client `PktBlockEditRejectedPayload`
+    BlockEditRejected,
+}
+
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, Hash, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Deserialize, Serialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum BlockEditRejection {
+    /// The block is too far from the player or hidden behind other blocks
+    OutOfReach = 1,
+    /// The block isn't what the client thought it was anymore
+    Mismatch,
+    /// The block's chunk isn't loaded on the server
+    NotLoaded,
+    UnknownVoxel,
+    /// The client sent too many edits recently
+    RateLimited,
+}
+
+/// The server's voxel ids, chunk data uses them
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktVoxelRegistryPayload {
+    pub voxels: Vec<(VoxelId, String)>,
+}
+
+/// Block data of a chunk within the player's load radius, sent again whenever it changes
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktChunkDataPayload {
+    pub position: [i32; 3],
+    /// zstd-compressed little-endian RLE words of the chunk
+    #[serde(with = "byte_vec")]
+    pub data: Vec<u8>,
+}
+
+/// Chunks that left the player's load radius, the server won't send updates for them anymore
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktChunkUnloadPayload {
+    pub positions: Vec<[i32; 3]>,
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktClientSettingsPayload {
+    /// Requested chunk streaming radius, the server can limit it further
+    pub load_radius: u32,
+}
+
+/// A player input command, applied for a single physics tick
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlayerInputCommand {
+    /// Increases by one every client physics tick, so it also timestamps the command
+    pub sequence: u32,
+    pub input: PlayerInput,
+}
+
+/// The most recent input commands of the client, older unacknowledged ones are repeated in case of packet loss
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PktPlayerInputsPayload {
+    pub commands: Vec<PlayerInputCommand>,
+}
+
+/// The authoritative state of the client's player after processing its input commands
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PktPlayerStatePayload {
+    /// Sequence number of the last processed input command
+    pub last_input: u32,
+    pub state: PlayerMovementState,
+}
+
+/// Changes of the shared entities near the player since the previous update, in the order they have to be applied
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PktEntityUpdatesPayload {
+    pub changes: Vec<EntityChange>,
+}
+
+/// Entities whose client copy didn't match an update, the server sends them again in full
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktEntityResyncPayload {
+    pub entities: Vec<ValidEntityID>,
+}
+
+/// A block the player broke or placed, the client applies it right away and the server either confirms it
+/// with `PktBlockChangesPayload` or rolls it back with `PktBlockEditRejectedPayload`.
+/// Voxel data uses the server's voxel ids.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktBlockEditPayload {
+    pub position: [i32; 3],
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Blocks changed in chunks the client has, in the server's voxel ids
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktBlockChangesPayload {
+    pub blocks: Vec<([i32; 3], u32)>,
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PktBlockEditRejectedPayload {
+    pub position: [i32; 3],
+    pub reason: BlockEditRejection,
+    /// The block's data on the server that the client rolls back to, or the edit's `from` if the server doesn't have it loaded
+    pub datum: u32,
+}
+
+impl PktChunkDataPayload {
+    pub fn new(chunk: &VChunk) -> Self {
+        let VChunkData::QuickCompressed { vox } = &chunk.data;
+        let mut raw: Vec<u8> = Vec::with_capacity(vox.len() * 4);
+        vox.iter()
+            .for_each(|word| raw.extend_from_slice(&word.to_le_bytes()));
+        Self {
+            position: chunk.position.0.into(),
+            data: net_zstd_compress(&raw),
+        }
+    }
+
+    pub fn chunk_position(&self) -> ChunkPosition {
+        let [x, y, z] = self.position;
+        ChunkPosition::new(x, y, z)
+    }
+
+    /// Decompresses the RLE words, they still have to be validated
+    pub fn voxel_words(&self) -> Result<Vec<u32>, PacketProcessingError> {
+        let raw = net_zstd_decompress(&self.data, None)?;
+        if raw.len() % 4 != 0 {
+            return Err(PacketProcessingError::UnexpectedPacket);
+        }
+        Ok(raw
+            .iter()
+            .tuples()
+            .map(|(&a, &b, &c, &d)| u32::from_le_bytes([a, b, c, d]))
+            .collect())
+    }
+}
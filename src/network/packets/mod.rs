@@ -2,6 +2,9 @@ use num_enum::*;
 use serde::*;
 
 pub mod auth;
+pub mod control;
+pub mod discovery;
+pub mod game;
 
 pub const PACKET_PROTOCOL_CURRENT_VERSION: u32 = 1;
 
@@ -13,4 +16,47 @@ pub const PACKET_PROTOCOL_CURRENT_VERSION: u32 = 1;
 pub enum MessagePartType {
     AckInfo = 0x00,
     MultipartFragment = 0x01,
+    ChannelMessage = 0x02,
+}
+
+/// Serializes `Vec<u8>` fields as a msgpack binary blob instead of an array of integers,
+/// use with `#[serde(with = "byte_vec")]`
+pub mod byte_vec {
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt::Formatter;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(data)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(ByteVecVisitor)
+    }
+
+    struct ByteVecVisitor;
+
+    impl<'de> Visitor<'de> for ByteVecVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+            formatter.write_str("a byte array")
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(Vec::from(v))
+        }
+
+        fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut out = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(byte) = seq.next_element()? {
+                out.push(byte);
+            }
+            Ok(out)
+        }
+    }
 }
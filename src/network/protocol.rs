@@ -113,6 +113,10 @@ pub enum PacketFormat {
     EncryptedV1 = 0xB2,
     /// All other packets use this format (or the uncompressed variant), encrypted with the established session key, the message field is also zstd-compressed
     EncryptedCompressedV1 = 0xB3,
+    /// Unencrypted and unauthenticated LAN discovery request, padded to at least the size of the response
+    DiscoveryRequestV1 = 0xB4,
+    /// Unencrypted server->client answer to a discovery request
+    DiscoveryResponseV1 = 0xB5,
 }
 
 impl PacketFormat {
@@ -166,6 +170,10 @@ pub enum PacketDecodeError {
     UnexpectedFieldValue(&'static str, u64),
     TooBigTimeDelta,
     DecryptionError,
+    /// A packet with the same sequence number was already received
+    Replayed,
+    /// The sequence number is too far behind the newest received one to tell if it's a replay
+    TooOld,
 }
 
 #[derive(Debug)]
@@ -177,6 +185,8 @@ pub enum PacketProcessingError {
     UntrustedCrypto,
     /// Invalid identifying information, possibly from a previous connection attempt
     StrayPacket,
+    /// Correctly decoded packet of a kind not allowed in the current connection state
+    UnexpectedPacket,
 }
 
 impl From<PacketEncodeError> for PacketProcessingError {
@@ -203,7 +213,133 @@ impl From<DecompressError> for PacketProcessingError {
     }
 }
 
+/// How many sequence numbers before the newest received one are tracked by a `ReplayWindow`
+pub const REPLAY_WINDOW_LEN: u32 = 1024;
+
+/// Sliding window over the recently received sequence numbers of a stream, like the IPsec and DTLS anti-replay windows
+#[derive(Clone, Debug)]
+pub struct ReplayWindow {
+    newest: Option<u32>,
+    /// Bit `seq % REPLAY_WINDOW_LEN` is set if `seq` was received, for the sequence numbers inside the window
+    bitmap: [u64; (REPLAY_WINDOW_LEN / 64) as usize],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self {
+            newest: None,
+            bitmap: [0; (REPLAY_WINDOW_LEN / 64) as usize],
+        }
+    }
+}
+
+impl ReplayWindow {
+    fn bit(seq: u32) -> (usize, u64) {
+        let idx = seq % REPLAY_WINDOW_LEN;
+        ((idx / 64) as usize, 1u64 << (idx % 64))
+    }
+
+    /// Checks if the sequence number wasn't received before and is still inside the window
+    pub fn check(&self, seq: u32) -> Result<(), PacketDecodeError> {
+        let newest = match self.newest {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+        // sequence numbers wrap around, so anything up to half the range ahead counts as newer
+        if (seq.wrapping_sub(newest) as i32) > 0 {
+            return Ok(());
+        }
+        if newest.wrapping_sub(seq) >= REPLAY_WINDOW_LEN {
+            return Err(PacketDecodeError::TooOld);
+        }
+        let (word, mask) = Self::bit(seq);
+        if self.bitmap[word] & mask != 0 {
+            Err(PacketDecodeError::Replayed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks a sequence number that passed [`Self::check`] as received, sliding the window forward if it's the newest
+    pub fn accept(&mut self, seq: u32) {
+        match self.newest {
+            Some(newest) if (seq.wrapping_sub(newest) as i32) <= 0 => {}
+            Some(newest) => {
+                let advance = seq.wrapping_sub(newest);
+                if advance >= REPLAY_WINDOW_LEN {
+                    self.bitmap.iter_mut().for_each(|w| *w = 0);
+                } else {
+                    // forget the sequence numbers that left the window, their slots are reused
+                    for skipped in 1..=advance {
+                        let (word, mask) = Self::bit(newest.wrapping_add(skipped));
+                        self.bitmap[word] &= !mask;
+                    }
+                }
+                self.newest = Some(seq);
+            }
+            None => {
+                self.newest = Some(seq);
+            }
+        }
+        let (word, mask) = Self::bit(seq);
+        self.bitmap[word] |= mask;
+    }
+}
+
+/// Counters of the packets checked by a `ReplayProtection`, for diagnostics
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ReplayStats {
+    pub accepted: u64,
+    pub replayed: u64,
+    pub too_old: u64,
+}
+
+impl std::fmt::Display for ReplayStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} accepted, {} replayed, {} too old",
+            self.accepted, self.replayed, self.too_old
+        )
+    }
+}
+
+/// Anti-replay state of one direction of an established connection, with a separate window for each stream
+#[derive(Clone, Debug, Default)]
+pub struct ReplayProtection {
+    windows: [ReplayWindow; 3],
+    stats: ReplayStats,
+}
+
+impl ReplayProtection {
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
+    fn window(&mut self, stream: PacketStream) -> &mut ReplayWindow {
+        &mut self.windows[u8::from(stream) as usize - 1]
+    }
+
+    fn check(&mut self, stream: PacketStream, seq: u32) -> Result<(), PacketDecodeError> {
+        let result = self.window(stream).check(seq);
+        match result {
+            Ok(()) => {}
+            Err(PacketDecodeError::Replayed) => self.stats.replayed += 1,
+            Err(_) => self.stats.too_old += 1,
+        }
+        result
+    }
+
+    fn accept(&mut self, stream: PacketStream, seq: u32) {
+        self.window(stream).accept(seq);
+        self.stats.accepted += 1;
+    }
+}
+
 const HANDSHAKE_MSG_PADSIZE: usize = 1024;
+/// Bytes added by `PacketV1::encode_established` on top of the message length
+pub const ESTABLISHED_PACKET_OVERHEAD: usize =
+    1 + secretbox::NONCEBYTES + secretbox::MACBYTES + PacketV1::minimum_fields_len();
 
 impl<'d> PacketV1<'d> {
     fn to_owned_message(&self) -> PacketV1<'static> {
@@ -430,9 +566,11 @@ impl<'d> PacketV1<'d> {
     }
 
     /// Returns a `PacketV1` with an unencrypted, decompressed `message` field.
+    /// Packets already received or too old to tell are rejected using the connection's `replay` state.
     pub fn decode_established(
         raw: &[u8],
         rx_key: &secretbox::Key,
+        replay: &mut ReplayProtection,
     ) -> Result<PacketV1<'static>, PacketProcessingError> {
         if raw.len() < 1 + PacketV1::minimum_fields_len() + secretbox::NONCEBYTES {
             return Err(PacketDecodeError::TooShort.into());
@@ -451,10 +589,13 @@ impl<'d> PacketV1<'d> {
         if net_timestamp_delta(pkt.sent_time, current_net_timestamp()) > MAX_NET_TIMESTAMP_DELTA {
             return Err(PacketDecodeError::TooBigTimeDelta.into());
         }
+        replay.check(pkt.stream, pkt.seq_id)?;
         if format == PacketFormat::EncryptedCompressedV1 {
             let compressed = std::mem::replace(&mut pkt.message, Cow::Borrowed(&[])).into_owned();
             pkt.message = Cow::Owned(net_zstd_decompress(&compressed, None)?);
         }
+        // only authenticated and fully decoded packets move the window
+        replay.accept(pkt.stream, pkt.seq_id);
         Ok(pkt)
     }
 
@@ -698,8 +839,9 @@ mod test {
             let packet_id = 13;
             let seq_id = 24;
             let encoded = PacketV1::encode_established(stream, packet_id, seq_id, msg, &skey);
-            let decoded =
-                PacketV1::decode_established(&encoded, &skey).expect("Couldn't decode packet");
+            let mut replay = ReplayProtection::default();
+            let decoded = PacketV1::decode_established(&encoded, &skey, &mut replay)
+                .expect("Couldn't decode packet");
             assert_eq!(decoded.stream, stream);
             assert_eq!(decoded.packet_id, packet_id);
             assert_eq!(decoded.seq_id, seq_id);
@@ -709,6 +851,71 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        for &seq in &[5, 7, 6, 2] {
+            window.check(seq).unwrap();
+            window.accept(seq);
+        }
+        assert_eq!(window.check(7), Err(PacketDecodeError::Replayed));
+        assert_eq!(window.check(2), Err(PacketDecodeError::Replayed));
+        assert_eq!(window.check(3), Ok(()));
+        window.accept(2000);
+        assert_eq!(window.check(7), Err(PacketDecodeError::TooOld));
+        assert_eq!(window.check(2000 - REPLAY_WINDOW_LEN + 1), Ok(()));
+        assert_eq!(window.check(2000), Err(PacketDecodeError::Replayed));
+        // the slots of sequence numbers that left the window are cleared for reuse
+        window.accept(2000 + REPLAY_WINDOW_LEN - 1);
+        assert_eq!(window.check(2000 + 5), Ok(()));
+        assert_eq!(window.check(2000), Err(PacketDecodeError::Replayed));
+        // sequence numbers wrap around
+        let mut window = ReplayWindow::default();
+        window.accept(u32::MAX - 1);
+        assert_eq!(window.check(1), Ok(()));
+        window.accept(1);
+        assert_eq!(window.check(u32::MAX - 1), Err(PacketDecodeError::Replayed));
+        assert_eq!(window.check(u32::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_packet_v1_replay_protection() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let skey = secretbox::gen_key();
+        let mut replay = ReplayProtection::default();
+        let control =
+            PacketV1::encode_established(PacketStream::ConnectionControl, 1, 3, b"", &skey);
+        let game = PacketV1::encode_established(PacketStream::GameMessages, 0, 3, b"", &skey);
+        PacketV1::decode_established(&control, &skey, &mut replay).unwrap();
+        // the same sequence number on another stream isn't a replay
+        PacketV1::decode_established(&game, &skey, &mut replay).unwrap();
+        assert!(matches!(
+            PacketV1::decode_established(&game, &skey, &mut replay),
+            Err(PacketProcessingError::Decode(PacketDecodeError::Replayed))
+        ));
+        let newer = PacketV1::encode_established(
+            PacketStream::GameMessages,
+            0,
+            3 + REPLAY_WINDOW_LEN,
+            b"",
+            &skey,
+        );
+        PacketV1::decode_established(&newer, &skey, &mut replay).unwrap();
+        let old = PacketV1::encode_established(PacketStream::GameMessages, 0, 2, b"", &skey);
+        assert!(matches!(
+            PacketV1::decode_established(&old, &skey, &mut replay),
+            Err(PacketProcessingError::Decode(PacketDecodeError::TooOld))
+        ));
+        assert_eq!(
+            replay.stats(),
+            ReplayStats {
+                accepted: 3,
+                replayed: 1,
+                too_old: 1
+            }
+        );
+    }
+
     #[test]
     fn test_packet_v1_handshake_symmetry() {
         let hs_msg = b"Hello, world\0!";
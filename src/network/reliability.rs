@@ -1,7 +1,16 @@
+//! Acks, retransmission and ordering of messages sent over the unreliable datagram transport
+
+use crate::network::congestion::{CongestionController, ConnectionStats};
+use crate::network::packets::MessagePartType;
+use crate::network::protocol::{PacketDecodeError, PacketEncodeError};
+use bxw_util::log;
+use num_enum::*;
 use serde::*;
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::{TryFrom, TryInto};
 use std::ops::{Add, AddAssign, Index, IndexMut};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 const SEQUENCE_BUFFER_LEN: usize = 2048;
 
@@ -59,7 +68,7 @@ impl PacketData {
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct AckHeader {
     pub last_recv_seq: SeqNumber,
     pub ack_bitvec: u64,
@@ -84,7 +93,9 @@ impl ReliablePeerState {
 
     pub fn accept_ack_header(&mut self, header: &AckHeader, time: Instant) {
         let rsq = header.last_recv_seq.0;
-        self.sent[rsq].on_ack(time);
+        if self.sent[rsq].seq.0 == rsq {
+            self.sent[rsq].on_ack(time);
+        }
         for seq_offset in 0..64u32 {
             let acked = (header.ack_bitvec & (1u64 << seq_offset)) != 0;
             if acked {
@@ -108,14 +119,19 @@ impl ReliablePeerState {
         seq
     }
 
+    /// Received packets are acked at the moment of arrival, so that they show up in [`Self::generate_ack_header`]
     pub fn mark_received(&mut self, seq: SeqNumber, time: Instant) {
         self.received.most_recent_seq = self.received.most_recent_seq.max(seq);
         self.received[seq] = PacketData {
             seq,
             send_time: time,
-            first_ack_time: None,
+            first_ack_time: Some(time),
         };
     }
+
+    pub fn was_received(&self, seq: SeqNumber) -> bool {
+        self.received[seq].seq == seq
+    }
 }
 
 impl Index<u32> for SeqBuffer {
@@ -193,3 +209,1015 @@ impl AddAssign<u32> for SeqNumber {
         self.0 = self.0.wrapping_add(rhs);
     }
 }
+
+/// Delivery guarantees of a message sent with [`ReliableStream`]
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, Hash, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Deserialize, Serialize,
+)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Channel {
+    /// Retransmitted until acked, delivered exactly once in the order of sending
+    ReliableOrdered = 0,
+    /// Retransmitted until acked, delivered exactly once as soon as it arrives
+    ReliableUnordered = 1,
+    /// Never retransmitted, messages older than the newest one received are dropped
+    UnreliableSequenced = 2,
+}
+
+const CHANNEL_COUNT: usize = 3;
+
+impl Channel {
+    pub fn is_reliable(self) -> bool {
+        self != Channel::UnreliableSequenced
+    }
+
+    fn index(self) -> usize {
+        u8::from(self) as usize
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ChannelMessage {
+    pub channel: Channel,
+    /// Sequential number of the message within its channel
+    pub message_id: u32,
+    /// Value interpretation dependent on the stream
+    pub packet_id: u8,
+    pub data: Vec<u8>,
+}
+
+/// A piece of a message too long to fit in a single packet, each fragment is acked and resent on its own
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct MessageFragment {
+    pub channel: Channel,
+    pub message_id: u32,
+    pub packet_id: u8,
+    pub fragment_index: u16,
+    pub fragment_count: u16,
+    pub data: Vec<u8>,
+}
+
+/// Part of the `message` field of a packet on a reliable stream, a packet contains any number of these
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum MessagePart {
+    AckInfo(AckHeader),
+    Fragment(MessageFragment),
+    Message(ChannelMessage),
+}
+
+const ACK_INFO_PART_LEN: usize = 1 + 4 + 8;
+const CHANNEL_MESSAGE_HEADER_LEN: usize = 1 + 1 + 4 + 1 + 4;
+const FRAGMENT_HEADER_LEN: usize = 1 + 1 + 4 + 1 + 2 + 2 + 4;
+/// Fragments are never made smaller than this, even if the MTU is configured to be tiny
+const MIN_FRAGMENT_DATA_LEN: usize = 64;
+
+/// Reads the length-prefixed data at the end of a part header, returning it with the remaining input
+fn split_part_data(data: &[u8], header_len: usize) -> Result<(Vec<u8>, &[u8]), PacketDecodeError> {
+    let len = u32::from_le_bytes(data[header_len - 4..header_len].try_into().unwrap()) as usize;
+    let end = header_len
+        .checked_add(len)
+        .filter(|&end| end <= data.len())
+        .ok_or(PacketDecodeError::TooShort)?;
+    Ok((data[header_len..end].to_vec(), &data[end..]))
+}
+
+impl MessagePart {
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            MessagePart::AckInfo(_) => ACK_INFO_PART_LEN,
+            MessagePart::Fragment(frag) => FRAGMENT_HEADER_LEN + frag.data.len(),
+            MessagePart::Message(msg) => CHANNEL_MESSAGE_HEADER_LEN + msg.data.len(),
+        }
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            MessagePart::AckInfo(ack) => {
+                out.push(MessagePartType::AckInfo.into());
+                out.extend_from_slice(&ack.last_recv_seq.0.to_le_bytes());
+                out.extend_from_slice(&ack.ack_bitvec.to_le_bytes());
+            }
+            MessagePart::Fragment(frag) => {
+                out.push(MessagePartType::MultipartFragment.into());
+                out.push(frag.channel.into());
+                out.extend_from_slice(&frag.message_id.to_le_bytes());
+                out.push(frag.packet_id);
+                out.extend_from_slice(&frag.fragment_index.to_le_bytes());
+                out.extend_from_slice(&frag.fragment_count.to_le_bytes());
+                out.extend_from_slice(&(frag.data.len() as u32).to_le_bytes());
+                out.extend_from_slice(&frag.data);
+            }
+            MessagePart::Message(msg) => {
+                out.push(MessagePartType::ChannelMessage.into());
+                out.push(msg.channel.into());
+                out.extend_from_slice(&msg.message_id.to_le_bytes());
+                out.push(msg.packet_id);
+                out.extend_from_slice(&(msg.data.len() as u32).to_le_bytes());
+                out.extend_from_slice(&msg.data);
+            }
+        }
+    }
+
+    pub fn decode_all(mut data: &[u8]) -> Result<Vec<MessagePart>, PacketDecodeError> {
+        let mut parts = Vec::new();
+        while !data.is_empty() {
+            let ptype = MessagePartType::try_from(data[0]).map_err(|_| {
+                PacketDecodeError::UnexpectedFieldValue("message_part_type", data[0] as u64)
+            })?;
+            match ptype {
+                MessagePartType::AckInfo => {
+                    if data.len() < ACK_INFO_PART_LEN {
+                        return Err(PacketDecodeError::TooShort);
+                    }
+                    parts.push(MessagePart::AckInfo(AckHeader {
+                        last_recv_seq: SeqNumber(u32::from_le_bytes(
+                            data[1..5].try_into().unwrap(),
+                        )),
+                        ack_bitvec: u64::from_le_bytes(data[5..13].try_into().unwrap()),
+                    }));
+                    data = &data[ACK_INFO_PART_LEN..];
+                }
+                MessagePartType::ChannelMessage => {
+                    if data.len() < CHANNEL_MESSAGE_HEADER_LEN {
+                        return Err(PacketDecodeError::TooShort);
+                    }
+                    let channel = Channel::try_from(data[1]).map_err(|_| {
+                        PacketDecodeError::UnexpectedFieldValue("channel", data[1] as u64)
+                    })?;
+                    let message_id = u32::from_le_bytes(data[2..6].try_into().unwrap());
+                    let packet_id = data[6];
+                    let (msg_data, rest) = split_part_data(data, CHANNEL_MESSAGE_HEADER_LEN)?;
+                    parts.push(MessagePart::Message(ChannelMessage {
+                        channel,
+                        message_id,
+                        packet_id,
+                        data: msg_data,
+                    }));
+                    data = rest;
+                }
+                MessagePartType::MultipartFragment => {
+                    if data.len() < FRAGMENT_HEADER_LEN {
+                        return Err(PacketDecodeError::TooShort);
+                    }
+                    let channel = Channel::try_from(data[1]).map_err(|_| {
+                        PacketDecodeError::UnexpectedFieldValue("channel", data[1] as u64)
+                    })?;
+                    let message_id = u32::from_le_bytes(data[2..6].try_into().unwrap());
+                    let packet_id = data[6];
+                    let fragment_index = u16::from_le_bytes(data[7..9].try_into().unwrap());
+                    let fragment_count = u16::from_le_bytes(data[9..11].try_into().unwrap());
+                    if fragment_index >= fragment_count {
+                        return Err(PacketDecodeError::UnexpectedFieldValue(
+                            "fragment_index",
+                            fragment_index as u64,
+                        ));
+                    }
+                    let (frag_data, rest) = split_part_data(data, FRAGMENT_HEADER_LEN)?;
+                    parts.push(MessagePart::Fragment(MessageFragment {
+                        channel,
+                        message_id,
+                        packet_id,
+                        fragment_index,
+                        fragment_count,
+                        data: frag_data,
+                    }));
+                    data = rest;
+                }
+            }
+        }
+        Ok(parts)
+    }
+}
+
+/// Unacked reliable messages are sent again after this long until the round-trip time is measured,
+/// the timeout follows the round-trip time from then on, see `RttEstimator::resend_timeout`
+pub const RELIABLE_RESEND_TIMEOUT: Duration = Duration::from_millis(250);
+/// Received packets are acked at most this late if there's nothing else to send
+pub const RELIABLE_ACK_DELAY: Duration = Duration::from_millis(20);
+/// Received packets are acked without waiting for `RELIABLE_ACK_DELAY` once this many are pending,
+/// so that the 64 packets covered by an ack header are enough during bursts
+const RELIABLE_ACK_EVERY_PACKETS: u32 = 32;
+/// Limits bursts of fragments, the rest of the queued messages wait for the next poll
+const MAX_PACKETS_PER_POLL: usize = 64;
+/// Reliable messages further ahead than this of the next expected one are dropped, to be resent later
+const RECEIVE_WINDOW: u32 = 4096;
+/// Longest message accepted by [`ReliableStream::queue_message`], before fragmentation
+pub const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+/// Maximum total size of the fragments buffered for reassembly, packets with fragments over the limit are
+/// dropped without acking them so that the reliable ones get resent later
+const MAX_REASSEMBLY_BYTES: usize = 2 * MAX_MESSAGE_LEN;
+/// Maximum total size of the ordered messages waiting for the earlier ones, packets that would go over the limit
+/// are dropped without acking them like the ones over `MAX_REASSEMBLY_BYTES`.
+/// Completing a reassembled message can overshoot it by at most what was being reassembled.
+const MAX_HELD_BACK_BYTES: usize = 2 * MAX_MESSAGE_LEN;
+/// Maximum number of messages being reassembled at once
+const MAX_PENDING_REASSEMBLIES: usize = 64;
+/// Incomplete unreliable messages are dropped after this long, reliable ones are kept as their missing
+/// fragments are always resent
+pub const FRAGMENT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// (channel, message_id, fragment_index), whole messages use the fragment index 0
+type PartKey = (u8, u32, u16);
+
+impl MessagePart {
+    fn key(&self) -> Option<PartKey> {
+        match self {
+            MessagePart::AckInfo(_) => None,
+            MessagePart::Fragment(frag) => {
+                Some((frag.channel.into(), frag.message_id, frag.fragment_index))
+            }
+            MessagePart::Message(msg) => Some((msg.channel.into(), msg.message_id, 0)),
+        }
+    }
+
+    fn is_reliable(&self) -> bool {
+        match self {
+            MessagePart::AckInfo(_) => false,
+            MessagePart::Fragment(frag) => frag.channel.is_reliable(),
+            MessagePart::Message(msg) => msg.channel.is_reliable(),
+        }
+    }
+}
+
+struct UnackedPart {
+    part: MessagePart,
+    /// The last packet the part was sent in
+    packet_seq: SeqNumber,
+}
+
+#[derive(Default)]
+struct ChannelReceiveState {
+    /// Lowest message id not yet delivered
+    next_id: u32,
+    /// Ordered: messages waiting for the earlier ones. Unordered: delivered messages above `next_id`
+    pending: BTreeMap<u32, Option<ChannelMessage>>,
+    /// Sequenced: the newest delivered message id
+    last_id: Option<u32>,
+}
+
+impl ChannelReceiveState {
+    /// Whether a message with the given id would be dropped if it arrived now
+    fn is_stale(&self, channel: Channel, id: u32) -> bool {
+        match channel {
+            Channel::ReliableOrdered | Channel::ReliableUnordered => {
+                id.wrapping_sub(self.next_id) >= RECEIVE_WINDOW || self.pending.contains_key(&id)
+            }
+            Channel::UnreliableSequenced => self
+                .last_id
+                .map_or(false, |last| SeqNumber(id) <= SeqNumber(last)),
+        }
+    }
+}
+
+/// A message whose fragments are being collected
+struct PartialMessage {
+    packet_id: u8,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    first_received: Instant,
+}
+
+/// Packs messages of a single `PacketStream` into packets, retransmitting reliable ones until they are acked.
+/// Every packet sent starts with an ack for the packets received from the peer.
+/// Messages too long for a single packet are split into fragments and reassembled on the receiving side.
+/// Packets are only sent while the congestion window allows it, the other messages wait in the queue.
+pub struct ReliableStream {
+    peer: Box<ReliablePeerState>,
+    congestion: CongestionController,
+    /// Maximum length of the `message` field of a single packet
+    max_payload: usize,
+    received_any: bool,
+    ack_pending_since: Option<Instant>,
+    ack_pending_count: u32,
+    next_message_id: [u32; CHANNEL_COUNT],
+    outgoing: VecDeque<MessagePart>,
+    /// Encoded length of the parts in `outgoing`
+    outgoing_bytes: usize,
+    unacked: BTreeMap<PartKey, UnackedPart>,
+    receive_state: [ChannelReceiveState; CHANNEL_COUNT],
+    reassembly: HashMap<(u8, u32), PartialMessage>,
+    reassembly_bytes: usize,
+    /// Size of the ordered messages in `receive_state` waiting for the earlier ones
+    held_back_bytes: usize,
+}
+
+impl ReliableStream {
+    pub fn new(max_payload: usize) -> Self {
+        Self {
+            peer: Box::default(),
+            congestion: CongestionController::new(max_payload),
+            max_payload,
+            received_any: false,
+            ack_pending_since: None,
+            ack_pending_count: 0,
+            next_message_id: [0; CHANNEL_COUNT],
+            outgoing: VecDeque::new(),
+            outgoing_bytes: 0,
+            unacked: BTreeMap::new(),
+            receive_state: Default::default(),
+            reassembly: HashMap::new(),
+            reassembly_bytes: 0,
+            held_back_bytes: 0,
+        }
+    }
+
+    pub fn peer_state(&self) -> &ReliablePeerState {
+        &self.peer
+    }
+
+    pub fn congestion(&self) -> &CongestionController {
+        &self.congestion
+    }
+
+    /// Round-trip time, loss and send rate estimates, with the bytes waiting in the queue
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            queued_bytes: self.outgoing_bytes,
+            ..self.congestion.stats()
+        }
+    }
+
+    /// Number of reliable messages and fragments sent but not acked yet
+    pub fn unacked_count(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Number of messages with some of their fragments still missing
+    pub fn reassembly_count(&self) -> usize {
+        self.reassembly.len()
+    }
+
+    /// Queues a message, it's sent with the next [`Self::poll`].
+    /// Messages that don't fit in a single packet are split into fragments.
+    pub fn queue_message(
+        &mut self,
+        channel: Channel,
+        packet_id: u8,
+        data: Vec<u8>,
+    ) -> Result<(), PacketEncodeError> {
+        if data.len() > MAX_MESSAGE_LEN {
+            return Err(PacketEncodeError::MessageTooLong);
+        }
+        // leave space for the ack part, the peer might be first heard from before a retransmission
+        let available = self.max_payload.saturating_sub(ACK_INFO_PART_LEN);
+        let fragment_len = available
+            .saturating_sub(FRAGMENT_HEADER_LEN)
+            .max(MIN_FRAGMENT_DATA_LEN);
+        let fragment_count = if CHANNEL_MESSAGE_HEADER_LEN + data.len() <= available {
+            1
+        } else {
+            (data.len() + fragment_len - 1) / fragment_len
+        };
+        let fragment_count =
+            u16::try_from(fragment_count).map_err(|_| PacketEncodeError::MessageTooLong)?;
+        let id = &mut self.next_message_id[channel.index()];
+        let message_id = *id;
+        *id = id.wrapping_add(1);
+        if fragment_count == 1 {
+            self.push_outgoing(MessagePart::Message(ChannelMessage {
+                channel,
+                message_id,
+                packet_id,
+                data,
+            }));
+        } else {
+            for (fragment_index, chunk) in data.chunks(fragment_len).enumerate() {
+                self.push_outgoing(MessagePart::Fragment(MessageFragment {
+                    channel,
+                    message_id,
+                    packet_id,
+                    fragment_index: fragment_index as u16,
+                    fragment_count,
+                    data: chunk.to_vec(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    fn push_outgoing(&mut self, part: MessagePart) {
+        self.outgoing_bytes += part.encoded_len();
+        self.outgoing.push_back(part);
+    }
+
+    /// Returns the packets to send as (seq_id, message field) pairs, each at most `max_payload` bytes long.
+    pub fn poll(&mut self, now: Instant) -> Vec<(SeqNumber, Vec<u8>)> {
+        self.prune_acked();
+        self.congestion.detect_timeouts(now);
+        let resend_timeout = self.congestion.rtt().resend_timeout();
+        let mut resend: VecDeque<MessagePart> = VecDeque::new();
+        for unacked in self.unacked.values() {
+            let pkt = &self.peer.sent[unacked.packet_seq];
+            // a packet too old to be tracked anymore is assumed to be lost
+            if pkt.seq != unacked.packet_seq
+                || now.saturating_duration_since(pkt.send_time) >= resend_timeout
+            {
+                resend.push_back(unacked.part.clone());
+            }
+        }
+
+        let ack_due = self.ack_pending_count >= RELIABLE_ACK_EVERY_PACKETS
+            || self.ack_pending_since.map_or(false, |t| {
+                now.saturating_duration_since(t) >= RELIABLE_ACK_DELAY
+            });
+        if resend.is_empty() && self.outgoing.is_empty() && !ack_due {
+            return Vec::new();
+        }
+
+        let base_len = if self.received_any {
+            ACK_INFO_PART_LEN
+        } else {
+            0
+        };
+        let mut payloads: Vec<Vec<MessagePart>> = Vec::new();
+        let mut current: Vec<MessagePart> = Vec::new();
+        let mut current_len = base_len;
+        let mut budget = self.congestion.available();
+        // resent parts go first, parts that don't fit in this poll's packets or the congestion window stay queued
+        loop {
+            let part_len = match resend.front().or_else(|| self.outgoing.front()) {
+                Some(part) => part.encoded_len(),
+                None => break,
+            };
+            if !current.is_empty() && current_len + part_len > self.max_payload {
+                payloads.push(std::mem::take(&mut current));
+                current_len = base_len;
+                if payloads.len() >= MAX_PACKETS_PER_POLL {
+                    break;
+                }
+            }
+            let cost = if current.is_empty() {
+                base_len + part_len
+            } else {
+                part_len
+            };
+            if cost > budget {
+                break;
+            }
+            budget -= cost;
+            let part = match resend.pop_front() {
+                Some(part) => part,
+                None => {
+                    let part = self.outgoing.pop_front().unwrap();
+                    self.outgoing_bytes -= part_len;
+                    part
+                }
+            };
+            current_len += part_len;
+            current.push(part);
+        }
+        if !current.is_empty() {
+            payloads.push(current);
+        }
+        if payloads.is_empty() {
+            if !ack_due {
+                return Vec::new();
+            }
+            // the ack is sent even if the window is full, it doesn't take up any of it
+            payloads.push(Vec::new());
+        }
+
+        let mut packets = Vec::with_capacity(payloads.len());
+        for parts in payloads {
+            let seq = self.peer.get_next_send_seq(now);
+            let mut payload = Vec::with_capacity(self.max_payload);
+            if self.received_any {
+                MessagePart::AckInfo(self.peer.generate_ack_header()).encode_into(&mut payload);
+            }
+            let has_messages = !parts.is_empty();
+            for part in parts {
+                part.encode_into(&mut payload);
+                if let (true, Some(key)) = (part.is_reliable(), part.key()) {
+                    self.unacked.insert(
+                        key,
+                        UnackedPart {
+                            part,
+                            packet_seq: seq,
+                        },
+                    );
+                }
+            }
+            if has_messages {
+                self.congestion.on_packet_sent(seq, payload.len(), now);
+            }
+            packets.push((seq, payload));
+        }
+        self.ack_pending_since = None;
+        self.ack_pending_count = 0;
+        packets
+    }
+
+    /// Processes a packet received from the peer, returning the messages ready for delivery
+    pub fn receive(
+        &mut self,
+        seq: SeqNumber,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Vec<ChannelMessage>, PacketDecodeError> {
+        let parts = MessagePart::decode_all(payload)?;
+        let duplicate = self.received_any && self.peer.was_received(seq);
+        self.prune_reassembly(now);
+        if !duplicate && !self.can_buffer_parts(&parts)? {
+            log::debug!(
+                "Receive buffers full, dropping packet {} to be resent later",
+                seq.0
+            );
+            return Ok(Vec::new());
+        }
+        self.peer.mark_received(seq, now);
+        self.received_any = true;
+        // only packets with messages need to be acked, otherwise acks would be acked forever
+        if parts.iter().any(|p| p.key().is_some()) {
+            self.ack_pending_since.get_or_insert(now);
+            self.ack_pending_count += 1;
+        }
+        let mut delivered = Vec::new();
+        if duplicate {
+            return Ok(delivered);
+        }
+        for part in parts {
+            match part {
+                MessagePart::AckInfo(ack) => {
+                    self.peer.accept_ack_header(&ack, now);
+                    self.congestion
+                        .on_ack(&self.peer.sent, ack.last_recv_seq, now);
+                }
+                MessagePart::Fragment(frag) => {
+                    if let Some(msg) = self.receive_fragment(frag, now) {
+                        self.receive_message(msg, &mut delivered);
+                    }
+                }
+                MessagePart::Message(msg) => {
+                    self.receive_message(msg, &mut delivered);
+                }
+            }
+        }
+        self.prune_acked();
+        Ok(delivered)
+    }
+
+    fn prune_acked(&mut self) {
+        let peer = &self.peer;
+        self.unacked.retain(|_, unacked| {
+            let pkt = &peer.sent[unacked.packet_seq];
+            !(pkt.seq == unacked.packet_seq && pkt.acked())
+        });
+    }
+
+    /// Drops timed out unreliable partial messages and the ones made obsolete by newer messages
+    fn prune_reassembly(&mut self, now: Instant) {
+        let receive_state = &self.receive_state;
+        let reassembly_bytes = &mut self.reassembly_bytes;
+        self.reassembly.retain(|&(channel, id), partial| {
+            let channel = Channel::try_from(channel).unwrap();
+            let keep = !receive_state[channel.index()].is_stale(channel, id)
+                && (channel.is_reliable()
+                    || now.saturating_duration_since(partial.first_received)
+                        < FRAGMENT_REASSEMBLY_TIMEOUT);
+            if !keep {
+                *reassembly_bytes -= partial
+                    .fragments
+                    .iter()
+                    .flatten()
+                    .map(|f| f.len())
+                    .sum::<usize>();
+            }
+            keep
+        });
+    }
+
+    /// Whether the ordered message would have to wait for earlier ones, counting towards `MAX_HELD_BACK_BYTES`
+    fn would_hold_back(&self, channel: Channel, message_id: u32) -> bool {
+        let state = &self.receive_state[channel.index()];
+        channel == Channel::ReliableOrdered
+            && message_id != state.next_id
+            && !state.is_stale(channel, message_id)
+    }
+
+    /// Checks that the fragments in a packet are consistent with the ones already received
+    /// and that there's enough space to buffer them and the messages that have to wait for earlier ones
+    fn can_buffer_parts(&self, parts: &[MessagePart]) -> Result<bool, PacketDecodeError> {
+        let mut extra_bytes = 0usize;
+        let mut extra_messages = 0usize;
+        let mut extra_held_back = 0usize;
+        for part in parts {
+            match part {
+                MessagePart::Message(msg) if self.would_hold_back(msg.channel, msg.message_id) => {
+                    extra_held_back += msg.data.len();
+                }
+                // may complete a message that has to wait, the rest of it was checked when it arrived
+                MessagePart::Fragment(frag)
+                    if self.would_hold_back(frag.channel, frag.message_id) =>
+                {
+                    extra_held_back += frag.data.len();
+                }
+                _ => {}
+            }
+        }
+
+        // fragment counts of the messages this packet starts, its fragments have to agree on them too
+        let mut new_messages: HashMap<(u8, u32), u16> = HashMap::new();
+        for part in parts {
+            let frag = match part {
+                MessagePart::Fragment(frag) => frag,
+                _ => continue,
+            };
+            let key = (frag.channel.into(), frag.message_id);
+            let fragment_count = match self.reassembly.get(&key) {
+                Some(partial) => partial.fragments.len(),
+                None => {
+                    if self.receive_state[frag.channel.index()]
+                        .is_stale(frag.channel, frag.message_id)
+                    {
+                        continue;
+                    }
+                    let count = *new_messages.entry(key).or_insert_with(|| {
+                        extra_messages += 1;
+                        frag.fragment_count
+                    });
+                    count as usize
+                }
+            };
+            if fragment_count != frag.fragment_count as usize {
+                return Err(PacketDecodeError::UnexpectedFieldValue(
+                    "fragment_count",
+                    frag.fragment_count as u64,
+                ));
+            }
+            let already_received = self
+                .reassembly
+                .get(&key)
+                .and_then(|partial| partial.fragments.get(frag.fragment_index as usize))
+                .map_or(false, Option::is_some);
+            if !already_received {
+                extra_bytes += frag.data.len();
+            }
+        }
+        Ok(self.reassembly_bytes + extra_bytes <= MAX_REASSEMBLY_BYTES
+            && self.reassembly.len() + extra_messages <= MAX_PENDING_REASSEMBLIES
+            && self.held_back_bytes + extra_held_back <= MAX_HELD_BACK_BYTES)
+    }
+
+    /// Stores the fragment, returning the whole message once all of its fragments arrived
+    fn receive_fragment(&mut self, frag: MessageFragment, now: Instant) -> Option<ChannelMessage> {
+        if self.receive_state[frag.channel.index()].is_stale(frag.channel, frag.message_id) {
+            return None;
+        }
+        let key = (frag.channel.into(), frag.message_id);
+        let partial = self
+            .reassembly
+            .entry(key)
+            .or_insert_with(|| PartialMessage {
+                packet_id: frag.packet_id,
+                fragments: vec![None; frag.fragment_count as usize],
+                missing: frag.fragment_count as usize,
+                first_received: now,
+            });
+        // checked by `can_buffer_parts` already, but a bad index must never bring the connection down
+        let slot = match partial.fragments.get_mut(frag.fragment_index as usize) {
+            Some(slot) if partial.fragments.len() == frag.fragment_count as usize => slot,
+            _ => return None,
+        };
+        if slot.is_none() {
+            self.reassembly_bytes += frag.data.len();
+            *slot = Some(frag.data);
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+        let partial = self.reassembly.remove(&key).unwrap();
+        let data: Vec<u8> = partial.fragments.into_iter().flatten().flatten().collect();
+        self.reassembly_bytes -= data.len();
+        Some(ChannelMessage {
+            channel: frag.channel,
+            message_id: frag.message_id,
+            packet_id: partial.packet_id,
+            data,
+        })
+    }
+
+    fn receive_message(&mut self, msg: ChannelMessage, delivered: &mut Vec<ChannelMessage>) {
+        let state = &mut self.receive_state[msg.channel.index()];
+        if state.is_stale(msg.channel, msg.message_id) {
+            return;
+        }
+        let id = msg.message_id;
+        match msg.channel {
+            Channel::ReliableOrdered => {
+                self.held_back_bytes += msg.data.len();
+                state.pending.insert(id, Some(msg));
+                while let Some(Some(msg)) = state.pending.remove(&state.next_id) {
+                    self.held_back_bytes -= msg.data.len();
+                    delivered.push(msg);
+                    state.next_id = state.next_id.wrapping_add(1);
+                }
+            }
+            Channel::ReliableUnordered => {
+                state.pending.insert(id, None);
+                delivered.push(msg);
+                while state.pending.remove(&state.next_id).is_some() {
+                    state.next_id = state.next_id.wrapping_add(1);
+                }
+            }
+            Channel::UnreliableSequenced => {
+                state.last_id = Some(id);
+                delivered.push(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::congestion::INITIAL_WINDOW_PACKETS;
+
+    const TEST_PAYLOAD: usize = 1200;
+
+    /// Delivers all the packets from `a` to `b` except the ones for which `drop` returns true
+    fn transfer(
+        a: &mut ReliableStream,
+        b: &mut ReliableStream,
+        now: Instant,
+        drop: impl Fn(usize) -> bool,
+    ) -> Vec<ChannelMessage> {
+        let mut delivered = Vec::new();
+        for (i, (seq, payload)) in a.poll(now).into_iter().enumerate() {
+            if !drop(i) {
+                delivered.extend(b.receive(seq, &payload, now).unwrap());
+            }
+        }
+        delivered
+    }
+
+    #[test]
+    fn test_message_part_encoding() {
+        let parts = vec![
+            MessagePart::AckInfo(AckHeader {
+                last_recv_seq: SeqNumber(12),
+                ack_bitvec: 0xF0F0,
+            }),
+            MessagePart::Message(ChannelMessage {
+                channel: Channel::ReliableOrdered,
+                message_id: 7,
+                packet_id: 3,
+                data: vec![1, 2, 3],
+            }),
+        ];
+        let mut encoded = Vec::new();
+        for part in parts.iter() {
+            part.encode_into(&mut encoded);
+        }
+        assert_eq!(
+            encoded.len(),
+            parts.iter().map(|p| p.encoded_len()).sum::<usize>()
+        );
+        assert_eq!(MessagePart::decode_all(&encoded).unwrap(), parts);
+        assert_eq!(
+            MessagePart::decode_all(&encoded[..encoded.len() - 1]),
+            Err(PacketDecodeError::TooShort)
+        );
+    }
+
+    #[test]
+    fn test_reliable_ordered_retransmission() {
+        let mut a = ReliableStream::new(TEST_PAYLOAD);
+        let mut b = ReliableStream::new(TEST_PAYLOAD);
+        let mut now = Instant::now();
+        // large enough to need one packet per message
+        for i in 0..4u8 {
+            a.queue_message(Channel::ReliableOrdered, i, vec![i; 1000])
+                .unwrap();
+        }
+        // the second packet is lost, so only the first message can be delivered
+        let delivered = transfer(&mut a, &mut b, now, |i| i == 1);
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(a.unacked_count(), 4);
+        now += RELIABLE_ACK_DELAY;
+        assert!(transfer(&mut b, &mut a, now, |_| false).is_empty());
+        assert_eq!(a.unacked_count(), 1);
+        now += RELIABLE_RESEND_TIMEOUT;
+        let delivered = transfer(&mut a, &mut b, now, |_| false);
+        assert_eq!(
+            delivered.iter().map(|m| m.packet_id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        now += RELIABLE_ACK_DELAY;
+        transfer(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.poll(now).len(), 0);
+        assert_eq!(a.unacked_count(), 0);
+    }
+
+    #[test]
+    fn test_unordered_and_sequenced() {
+        let mut a = ReliableStream::new(TEST_PAYLOAD);
+        let mut b = ReliableStream::new(TEST_PAYLOAD);
+        let now = Instant::now();
+        for i in 0..3u8 {
+            a.queue_message(Channel::ReliableUnordered, i, vec![i; 1000])
+                .unwrap();
+        }
+        let packets = a.poll(now);
+        assert_eq!(packets.len(), 3);
+        // out of order and duplicated delivery
+        let mut delivered = Vec::new();
+        for &i in &[2, 0, 2, 1, 0] {
+            let (seq, payload) = &packets[i];
+            delivered.extend(b.receive(*seq, payload, now).unwrap());
+        }
+        assert_eq!(
+            delivered.iter().map(|m| m.packet_id).collect::<Vec<_>>(),
+            vec![2, 0, 1]
+        );
+
+        for i in 0..3u8 {
+            a.queue_message(Channel::UnreliableSequenced, i, vec![i; 1000])
+                .unwrap();
+        }
+        let packets = a.poll(now);
+        let mut delivered = Vec::new();
+        for &i in &[0, 2, 1] {
+            let (seq, payload) = &packets[i];
+            delivered.extend(b.receive(*seq, payload, now).unwrap());
+        }
+        assert_eq!(
+            delivered.iter().map(|m| m.packet_id).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        // unreliable messages aren't resent
+        assert_eq!(a.unacked_count(), 3);
+    }
+
+    #[test]
+    fn test_congestion_window_limit() {
+        let mut a = ReliableStream::new(TEST_PAYLOAD);
+        let mut b = ReliableStream::new(TEST_PAYLOAD);
+        let mut now = Instant::now();
+        let packet_len = CHANNEL_MESSAGE_HEADER_LEN + 1000;
+        for i in 0..100u8 {
+            a.queue_message(Channel::ReliableOrdered, i, vec![i; 1000])
+                .unwrap();
+        }
+        // only the initial window is sent, the rest waits for acks
+        let window_packets = INITIAL_WINDOW_PACKETS * TEST_PAYLOAD / packet_len;
+        assert_eq!(
+            transfer(&mut a, &mut b, now, |_| false).len(),
+            window_packets
+        );
+        assert_eq!(a.stats().queued_bytes, (100 - window_packets) * packet_len);
+        assert!(a.poll(now).is_empty());
+        now += RELIABLE_ACK_DELAY;
+        transfer(&mut b, &mut a, now, |_| false);
+        let stats = a.stats();
+        assert_eq!(stats.smoothed_rtt, RELIABLE_ACK_DELAY);
+        assert_eq!(stats.bytes_in_flight, 0);
+        assert_eq!(stats.packets_lost, 0);
+        // slow start doubled the window
+        assert_eq!(
+            transfer(&mut a, &mut b, now, |_| false).len(),
+            100 - window_packets
+        );
+        assert_eq!(a.stats().queued_bytes, 0);
+    }
+
+    #[test]
+    fn test_inconsistent_fragments() {
+        let fragment = |index: u16, count: u16| {
+            MessagePart::Fragment(MessageFragment {
+                channel: Channel::ReliableOrdered,
+                message_id: 0,
+                packet_id: 1,
+                fragment_index: index,
+                fragment_count: count,
+                data: vec![index as u8; 16],
+            })
+        };
+        let encode = |parts: &[MessagePart]| {
+            let mut encoded = Vec::new();
+            parts.iter().for_each(|p| p.encode_into(&mut encoded));
+            encoded
+        };
+        let now = Instant::now();
+        let mut b = ReliableStream::new(TEST_PAYLOAD);
+        // fragments of a new message disagreeing on its length within a single packet
+        assert_eq!(
+            b.receive(
+                SeqNumber(0),
+                &encode(&[fragment(0, 2), fragment(5, 10)]),
+                now
+            ),
+            Err(PacketDecodeError::UnexpectedFieldValue(
+                "fragment_count",
+                10
+            ))
+        );
+        assert_eq!(b.reassembly_count(), 0);
+        // and with a message already being reassembled
+        assert!(b
+            .receive(SeqNumber(1), &encode(&[fragment(0, 2)]), now)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            b.receive(SeqNumber(2), &encode(&[fragment(5, 10)]), now),
+            Err(PacketDecodeError::UnexpectedFieldValue(
+                "fragment_count",
+                10
+            ))
+        );
+        let delivered = b
+            .receive(SeqNumber(3), &encode(&[fragment(1, 2)]), now)
+            .unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].data.len(), 32);
+        assert_eq!(b.reassembly_bytes, 0);
+    }
+
+    #[test]
+    fn test_held_back_limit() {
+        let packet = |message_id: u32, len: usize| {
+            let mut encoded = Vec::new();
+            MessagePart::Message(ChannelMessage {
+                channel: Channel::ReliableOrdered,
+                message_id,
+                packet_id: message_id as u8,
+                data: vec![message_id as u8; len],
+            })
+            .encode_into(&mut encoded);
+            encoded
+        };
+        let now = Instant::now();
+        let mut b = ReliableStream::new(TEST_PAYLOAD);
+        let len = MAX_HELD_BACK_BYTES / 8;
+        // the first message is missing, so the following ones wait for it until the limit
+        for id in 1..=8u32 {
+            assert!(b
+                .receive(SeqNumber(100 + id), &packet(id, len), now)
+                .unwrap()
+                .is_empty());
+        }
+        assert_eq!(b.held_back_bytes, MAX_HELD_BACK_BYTES);
+        let over_limit = packet(9, len);
+        assert!(b
+            .receive(SeqNumber(109), &over_limit, now)
+            .unwrap()
+            .is_empty());
+        assert!(!b.peer_state().was_received(SeqNumber(109)));
+        assert_eq!(b.held_back_bytes, MAX_HELD_BACK_BYTES);
+
+        let delivered = b.receive(SeqNumber(100), &packet(0, 1), now).unwrap();
+        assert_eq!(delivered.len(), 9);
+        assert_eq!(b.held_back_bytes, 0);
+        // the dropped packet wasn't acked, so its resend is accepted
+        let delivered = b.receive(SeqNumber(109), &over_limit, now).unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].message_id, 9);
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let mut a = ReliableStream::new(TEST_PAYLOAD);
+        let mut b = ReliableStream::new(TEST_PAYLOAD);
+        let mut now = Instant::now();
+        let big: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        a.queue_message(Channel::ReliableOrdered, 1, big.clone())
+            .unwrap();
+        a.queue_message(Channel::ReliableOrdered, 2, vec![1, 2, 3])
+            .unwrap();
+        // a lost fragment holds back the whole message and the ones after it
+        assert!(transfer(&mut a, &mut b, now, |i| i == 3).is_empty());
+        assert_eq!(b.reassembly_count(), 1);
+        now += RELIABLE_ACK_DELAY;
+        transfer(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.unacked_count(), 1);
+        now += RELIABLE_RESEND_TIMEOUT;
+        let delivered = transfer(&mut a, &mut b, now, |_| false);
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[0].packet_id, 1);
+        assert_eq!(delivered[0].data, big);
+        assert_eq!(delivered[1].data, vec![1, 2, 3]);
+        assert_eq!(b.reassembly_count(), 0);
+
+        // incomplete unreliable messages are dropped
+        a.queue_message(Channel::UnreliableSequenced, 3, big.clone())
+            .unwrap();
+        assert!(transfer(&mut a, &mut b, now, |i| i == 0).is_empty());
+        assert_eq!(b.reassembly_count(), 1);
+        now += FRAGMENT_REASSEMBLY_TIMEOUT;
+        a.queue_message(Channel::UnreliableSequenced, 4, vec![4])
+            .unwrap();
+        let delivered = transfer(&mut a, &mut b, now, |_| false);
+        assert_eq!(
+            delivered.iter().map(|m| m.packet_id).collect::<Vec<_>>(),
+            vec![4]
+        );
+        assert_eq!(b.reassembly_count(), 0);
+        assert_eq!(b.reassembly_bytes, 0);
+
+        assert_eq!(
+            a.queue_message(Channel::ReliableOrdered, 5, vec![0; MAX_MESSAGE_LEN + 1]),
+            Err(PacketEncodeError::MessageTooLong)
+        );
+    }
+}
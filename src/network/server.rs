@@ -1,24 +1,40 @@
 use crate::config::ConfigHandle;
+use crate::network::admission::{AdmissionError, AdmissionPolicy};
+use crate::network::congestion::ConnectionStats;
+use crate::network::discovery;
+use crate::network::discovery::DiscoveryRateLimiter;
 use crate::network::get_tokio_runtime;
+use crate::network::keystore;
 use crate::network::packets;
+use crate::network::packets::auth::ConnectionResponse;
+use crate::network::packets::control::DisconnectReason;
+use crate::network::packets::discovery::PktDiscoveryResponsePayload;
 use crate::network::protocol;
-use crate::network::protocol::authflow_server_respond_to_handshake_packet;
+use crate::network::protocol::{authflow_server_respond_to_handshake_packet, ReplayStats};
+use crate::network::reliability::{Channel, RELIABLE_ACK_DELAY};
+use crate::network::session::{
+    is_handshake_packet, EstablishedSession, SessionEvent, SessionPoll, SessionSide,
+};
+use crate::network::transport::DatagramTransport;
 use bxw_util::itertools::Itertools;
 use bxw_util::log;
-use bxw_util::parking_lot::RwLock;
+use bxw_util::parking_lot::{Mutex, RwLock};
 use bxw_util::sodiumoxide::crypto::box_;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicI32;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicI32, AtomicU64};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use tokio::net;
 use tokio::sync::{broadcast, mpsc};
 
 #[derive(Debug)]
 pub enum ServerCreationError {
     SocketBindError { addr: String, error: std::io::Error },
+    IdentityKeyError(keystore::KeyStoreError),
+    AdmissionPolicyError(AdmissionError),
 }
 
 #[derive(Clone, Debug, Hash)]
@@ -26,42 +42,121 @@ pub enum ServerControlMessage {
     Stop,
 }
 
+/// Unique for every connection during a single server run
+pub type ClientId = u64;
+
+/// Notifications from the network threads to the game loop, see [`NetServer::poll_events`]
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    ClientConnected {
+        client: ClientId,
+        player_id: box_::PublicKey,
+        address: SocketAddr,
+    },
+    Message {
+        client: ClientId,
+        packet_id: u8,
+        data: Vec<u8>,
+    },
+    ClientDisconnected {
+        client: ClientId,
+        reason: DisconnectReason,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum ConnectionCommand {
+    Send {
+        channel: Channel,
+        packet_id: u8,
+        data: Vec<u8>,
+    },
+    Disconnect(DisconnectReason),
+}
+
 pub struct NetServer {
+    cfg: ConfigHandle,
     server_thread: thread::JoinHandle<()>,
     server_control: broadcast::Sender<ServerControlMessage>,
     shared_state: Arc<NetServerSharedState>,
+    events: std::sync::mpsc::Receiver<ServerEvent>,
 }
 
 pub struct ConnectedClient {
-    cryptostate: protocol::ServersideConnectionCryptoState,
+    pub player_id: box_::PublicKey,
+    pub address: SocketAddr,
+    commands: mpsc::Sender<ConnectionCommand>,
+    /// Updated by the connection handler after every poll of the session
+    stats: Arc<Mutex<ConnectionStats>>,
+    replay_stats: Arc<Mutex<ReplayStats>>,
 }
 
 pub struct NetServerSharedState {
     connection_raw_count: Arc<AtomicI32>,
     server_id_keys: (box_::PublicKey, box_::SecretKey),
     server_name: RwLock<String>,
+    admission: RwLock<AdmissionPolicy>,
+    next_client_id: AtomicU64,
+    /// Clients with an established session, that proved they hold the secret key of their identity
+    clients: RwLock<HashMap<ClientId, ConnectedClient>>,
+    /// Accepted handshakes whose client hasn't proved its identity yet, locked after `clients`
+    pending_handshakes: Mutex<HashSet<ClientId>>,
+    events: Mutex<std::sync::mpsc::Sender<ServerEvent>>,
 }
 
 impl NetServerSharedState {
-    pub fn new(server_id_keys: (box_::PublicKey, box_::SecretKey), server_name: String) -> Self {
+    pub fn new(
+        server_id_keys: (box_::PublicKey, box_::SecretKey),
+        server_name: String,
+        admission: AdmissionPolicy,
+        events: std::sync::mpsc::Sender<ServerEvent>,
+    ) -> Self {
         Self {
             connection_raw_count: Arc::new(AtomicI32::new(0)),
             server_id_keys,
             server_name: RwLock::new(server_name),
+            admission: RwLock::new(admission),
+            next_client_id: AtomicU64::new(1),
+            clients: RwLock::new(HashMap::with_capacity(32)),
+            pending_handshakes: Mutex::new(HashSet::with_capacity(32)),
+            events: Mutex::new(events),
         }
     }
+
+    fn discovery_response(&self, nonce: u64) -> PktDiscoveryResponsePayload {
+        PktDiscoveryResponsePayload {
+            nonce,
+            s_version_id: packets::PACKET_PROTOCOL_CURRENT_VERSION,
+            s_server_id: self.server_id_keys.0,
+            server_name: discovery::truncate_server_name(&self.server_name.read()),
+            players: self.clients.read().len() as u32,
+            max_players: self.admission.read().max_players,
+        }
+    }
+
+    fn send_event(&self, event: ServerEvent) {
+        // the receiver is only gone while the server is shutting down
+        let _ = self.events.lock().send(event);
+    }
 }
 
 const SERVER_CONTROL_CHANNEL_BOUND: usize = 1024;
 const SERVER_PACKET_CHANNEL_BOUND: usize = 1024;
+const SERVER_CONNECTION_COMMAND_BOUND: usize = 1024;
+/// How often the connection handlers check their keepalive, timeout and retransmission timers
+const SERVER_SESSION_TIMER_INTERVAL: Duration = RELIABLE_ACK_DELAY;
+/// Handshakes are answered with `NoSlots` while this many clients are yet to prove their identity
+const SERVER_MAX_PENDING_HANDSHAKES: usize = 64;
+/// Clients that don't send a valid packet with the session keys in this long after the handshake are dropped,
+/// the handshake request is unauthenticated so they might never have asked for the connection
+const SERVER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for connection handlers to notify their clients on shutdown
+const SERVER_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 impl NetServer {
+    /// Binds UDP sockets to the configured listen addresses and starts the server on them
     pub fn new(cfg: ConfigHandle) -> Result<Self, ServerCreationError> {
         let tokrt = get_tokio_runtime(Some(cfg.clone()));
-        let cfg_clone = cfg.clone();
-        let (scon_tx, scon_rx) = broadcast::channel(SERVER_CONTROL_CHANNEL_BOUND);
-        drop(scon_rx);
-        let scon_tx2 = scon_tx.clone();
         let sockets = {
             let mut v: Vec<std::net::UdpSocket> =
                 Vec::with_capacity(cfg.read().server_listen_addresses.len());
@@ -90,10 +185,45 @@ impl NetServer {
             }
             v
         };
-        // TODO: Save/load identifying keys
+        let transports = {
+            // tokio sockets can only be created within the runtime
+            let _rt_guard = tokrt.enter();
+            sockets
+                .into_iter()
+                .map(|s| {
+                    Arc::new(net::UdpSocket::from_std(s).unwrap()) as Arc<dyn DatagramTransport>
+                })
+                .collect_vec()
+        };
+        Self::with_transports(cfg, transports)
+    }
+
+    /// Starts the server on the given transports instead of the configured listen addresses
+    pub fn with_transports(
+        cfg: ConfigHandle,
+        transports: Vec<Arc<dyn DatagramTransport>>,
+    ) -> Result<Self, ServerCreationError> {
+        let tokrt = get_tokio_runtime(Some(cfg.clone()));
+        let cfg_clone = cfg.clone();
+        let (scon_tx, scon_rx) = broadcast::channel(SERVER_CONTROL_CHANNEL_BOUND);
+        drop(scon_rx);
+        let scon_tx2 = scon_tx.clone();
+        let id_keys = {
+            let cfg = cfg.read();
+            keystore::load_or_create_identity_from_env(
+                std::path::Path::new(&cfg.server_identity_file),
+                cfg.regenerate_identity_keys,
+            )
+            .map_err(ServerCreationError::IdentityKeyError)?
+        };
+        let admission = AdmissionPolicy::load(&cfg.read())
+            .map_err(ServerCreationError::AdmissionPolicyError)?;
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
         let shared_state = Arc::new(NetServerSharedState::new(
-            box_::gen_keypair(),
+            id_keys,
             String::from("BXW Server"),
+            admission,
+            events_tx,
         ));
         let shared_state_copy = Arc::clone(&shared_state);
         let server_thread = thread::Builder::new()
@@ -101,14 +231,16 @@ impl NetServer {
             .stack_size(2 * 1024 * 1024)
             .spawn(move || {
                 tokrt.block_on(async move {
-                    server_netmain(cfg_clone, scon_tx2, sockets, shared_state_copy).await
+                    server_netmain(cfg_clone, scon_tx2, transports, shared_state_copy).await
                 });
             })
             .expect("Couldn't start main server network thread");
         Ok(Self {
+            cfg,
             server_thread,
             server_control: scon_tx,
             shared_state,
+            events: events_rx,
         })
     }
 
@@ -124,6 +256,83 @@ impl NetServer {
         }
     }
 
+    /// Returns all the events that happened since the last call, doesn't block
+    pub fn poll_events(&self) -> Vec<ServerEvent> {
+        self.events.try_iter().collect()
+    }
+
+    fn send_command(&self, client: ClientId, cmd: ConnectionCommand) {
+        let clients = self.shared_state.clients.read();
+        let conn = match clients.get(&client) {
+            Some(conn) => conn,
+            None => {
+                log::debug!("Dropping command for disconnected client {}", client);
+                return;
+            }
+        };
+        if let Err(e) = conn.commands.try_send(cmd) {
+            log::warn!("Couldn't queue command for client {}: {}", client, e);
+        }
+    }
+
+    /// Sends a message on the `GameMessages` stream with the delivery guarantees of the given channel
+    pub fn send_message(&self, client: ClientId, channel: Channel, packet_id: u8, data: Vec<u8>) {
+        self.send_command(
+            client,
+            ConnectionCommand::Send {
+                channel,
+                packet_id,
+                data,
+            },
+        );
+    }
+
+    pub fn disconnect_client(&self, client: ClientId, reason: DisconnectReason) {
+        self.send_command(client, ConnectionCommand::Disconnect(reason));
+    }
+
+    /// Rereads the ban list and the allowlist, already connected clients aren't affected
+    pub fn reload_admission_policy(&self) -> Result<(), AdmissionError> {
+        let policy = AdmissionPolicy::load(&self.cfg.read())?;
+        *self.shared_state.admission.write() = policy;
+        Ok(())
+    }
+
+    /// Ids of the clients with an established session
+    pub fn connected_clients(&self) -> Vec<ClientId> {
+        self.shared_state.clients.read().keys().copied().collect()
+    }
+
+    /// Round-trip time, loss and send rate estimates of the connection to the client
+    pub fn client_stats(&self, client: ClientId) -> Option<ConnectionStats> {
+        self.shared_state
+            .clients
+            .read()
+            .get(&client)
+            .map(|c| *c.stats.lock())
+    }
+
+    /// Counters of the packets from the client accepted and rejected by the replay protection
+    pub fn client_replay_stats(&self, client: ClientId) -> Option<ReplayStats> {
+        self.shared_state
+            .clients
+            .read()
+            .get(&client)
+            .map(|c| *c.replay_stats.lock())
+    }
+
+    /// Addresses, connection and replay protection stats of all the clients with an established session,
+    /// ordered by id
+    pub fn all_client_stats(&self) -> Vec<(ClientId, SocketAddr, ConnectionStats, ReplayStats)> {
+        self.shared_state
+            .clients
+            .read()
+            .iter()
+            .map(|(&id, c)| (id, c.address, *c.stats.lock(), *c.replay_stats.lock()))
+            .sorted_by_key(|&(id, _, _, _)| id)
+            .collect()
+    }
+
     pub fn wait_for_shutdown(self) {
         self.server_thread
             .join()
@@ -137,26 +346,84 @@ struct RawPacket {
     data: Box<[u8]>,
 }
 
+/// State of a single listening socket shared with its connection handlers
+struct SocketContext {
+    socket: Arc<dyn DatagramTransport>,
+    mtu: u16,
+    /// Connection handlers report here when they finish, so that the socket task can remove them from its table
+    closed_tx: mpsc::UnboundedSender<(SocketAddr, ClientId)>,
+}
+
+async fn send_packet(ctx: &SocketContext, packet: &[u8], target: SocketAddr) -> bool {
+    match ctx.socket.send_to(packet, target).await {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("Error sending packet to {:?}: {:?}", target, e);
+            false
+        }
+    }
+}
+
 async fn server_connection_handler(
+    client_id: ClientId,
     source: (usize, SocketAddr),
     initial_hs_state: protocol::ServerHandshakeState1,
     mut packet_stream: mpsc::Receiver<RawPacket>,
     shared_state: Arc<NetServerSharedState>,
-    socket: Arc<net::UdpSocket>,
+    socket_ctx: Arc<SocketContext>,
+    mut control_rx: broadcast::Receiver<ServerControlMessage>,
 ) {
     let target = source.1;
-    let _connguard =
-        bxw_util::scopeguard::guard(Arc::clone(&shared_state.connection_raw_count), |cc| {
-            cc.fetch_sub(1, SeqCst);
-        });
+    let _connguard = {
+        let socket_ctx = Arc::clone(&socket_ctx);
+        bxw_util::scopeguard::guard(Arc::clone(&shared_state), move |ss| {
+            ss.clients.write().remove(&client_id);
+            ss.pending_handshakes.lock().remove(&client_id);
+            let _ = socket_ctx.closed_tx.send((target, client_id));
+            ss.connection_raw_count.fetch_sub(1, SeqCst);
+        })
+    };
     log::info!(
         "New connection from {:?} - version {}, id {}",
         source,
         initial_hs_state.get_request().c_version_id,
         bxw_util::sodiumoxide::hex::encode(&initial_hs_state.get_request().c_player_id)
     );
-    let connresponse = packets::auth::ConnectionResponse::Accepted;
-    let (hsack_packet, _ssccs) = match authflow_server_respond_to_handshake_packet(
+    let player_id = initial_hs_state.get_request().c_player_id;
+    let (cmd_tx, mut cmd_rx) = mpsc::channel(SERVER_CONNECTION_COMMAND_BOUND);
+    let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+    let replay_stats = Arc::new(Mutex::new(ReplayStats::default()));
+    let connresponse = {
+        // anyone can send a handshake with any key, so only the clients that proved theirs take slots,
+        // the check is repeated once this one does too
+        let clients = shared_state.clients.read();
+        let mut pending = shared_state.pending_handshakes.lock();
+        let response = match shared_state.admission.read().check(
+            &player_id,
+            target.ip(),
+            clients.values().map(|c| &c.player_id),
+        ) {
+            ConnectionResponse::Accepted if pending.len() >= SERVER_MAX_PENDING_HANDSHAKES => {
+                ConnectionResponse::NoSlots
+            }
+            response => response,
+        };
+        if response == ConnectionResponse::Accepted {
+            pending.insert(client_id);
+        } else {
+            log::info!("Rejecting connection from {:?}: {:?}", source, response);
+        }
+        response
+    };
+    // registered once the client proves its identity by sending something encrypted with the session keys
+    let mut unverified_client = Some(ConnectedClient {
+        player_id,
+        address: target,
+        commands: cmd_tx,
+        stats: Arc::clone(&stats),
+        replay_stats: Arc::clone(&replay_stats),
+    });
+    let (hsack_packet, ssccs) = match authflow_server_respond_to_handshake_packet(
         initial_hs_state,
         &shared_state.server_id_keys.0,
         &shared_state.server_id_keys.1,
@@ -170,41 +437,211 @@ async fn server_connection_handler(
             return;
         }
     };
-    match socket.send_to(&hsack_packet, target).await {
-        Ok(_) => {}
-        Err(e) => {
-            log::warn!(
-                "Error sending handshake ack to {:?}, terminating connection: {:?}",
-                source,
-                e
-            );
-            packet_stream.close();
-            return;
+    if !send_packet(&socket_ctx, &hsack_packet, target).await {
+        log::warn!(
+            "Couldn't send handshake ack to {:?}, terminating connection",
+            source
+        );
+        packet_stream.close();
+        return;
+    }
+    if connresponse != ConnectionResponse::Accepted {
+        packet_stream.close();
+        return;
+    }
+
+    let handshake_time = Instant::now();
+    let mut verified = false;
+    let mut session = EstablishedSession::new(
+        SessionSide::Server,
+        ssccs.rx_key,
+        ssccs.tx_key,
+        socket_ctx.mtu,
+        Instant::now(),
+    );
+    let mut timer = tokio::time::interval(SERVER_SESSION_TIMER_INTERVAL);
+    let reason = 'session: loop {
+        let mut outgoing: Option<Vec<u8>> = None;
+        tokio::select! {
+            pkt = packet_stream.recv() => {
+                let pkt = match pkt {
+                    Some(pkt) => pkt,
+                    None => {
+                        // the socket task stopped
+                        let disconnect = session.encode_disconnect(DisconnectReason::ServerShutdown, Instant::now());
+                        send_packet(&socket_ctx, &disconnect, target).await;
+                        break 'session DisconnectReason::ServerShutdown;
+                    }
+                };
+                if is_handshake_packet(&pkt.data) {
+                    // the client didn't get our ack and retried the handshake
+                    if !session.received_any() {
+                        outgoing = Some(hsack_packet.clone());
+                    }
+                } else {
+                    match session.receive(&pkt.data, Instant::now()) {
+                        Ok(events) => {
+                            if let Some(client) = unverified_client.take() {
+                                let response = admit_verified_client(&shared_state, client_id, client);
+                                verified = response == ConnectionResponse::Accepted;
+                                if !verified {
+                                    log::info!("Dropping client {} at {:?} after the handshake: {:?}", client_id, source, response);
+                                    let disconnect = session.encode_disconnect(DisconnectReason::Kicked, Instant::now());
+                                    send_packet(&socket_ctx, &disconnect, target).await;
+                                    break 'session DisconnectReason::Kicked;
+                                }
+                            }
+                            for event in events {
+                                match event {
+                                    SessionEvent::GameMessage { packet_id, data } => {
+                                        shared_state.send_event(ServerEvent::Message { client: client_id, packet_id, data });
+                                    }
+                                    SessionEvent::Disconnected(reason) => {
+                                        break 'session reason;
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log::debug!("Dropping invalid packet from {:?}: {:?}", source, e);
+                        }
+                    }
+                }
+            }
+            cmd = cmd_rx.recv() => {
+                match cmd {
+                    Some(ConnectionCommand::Send { channel, packet_id, data }) => {
+                        if let Err(e) = session.send_message(channel, packet_id, data) {
+                            log::warn!("Couldn't send message {} to client {}: {:?}", packet_id, client_id, e);
+                        }
+                    }
+                    Some(ConnectionCommand::Disconnect(reason)) => {
+                        let disconnect = session.encode_disconnect(reason, Instant::now());
+                        send_packet(&socket_ctx, &disconnect, target).await;
+                        break 'session reason;
+                    }
+                    None => {
+                        break 'session DisconnectReason::Kicked;
+                    }
+                }
+            }
+            ctrl_msg = control_rx.recv() => {
+                use broadcast::error::RecvError;
+                match ctrl_msg {
+                    Ok(ServerControlMessage::Stop) | Err(RecvError::Closed) => {
+                        let disconnect = session.encode_disconnect(DisconnectReason::ServerShutdown, Instant::now());
+                        send_packet(&socket_ctx, &disconnect, target).await;
+                        break 'session DisconnectReason::ServerShutdown;
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        log::error!("Connection handler {:?} lagged {} control messages!", source, n);
+                    }
+                }
+            }
+            _ = timer.tick() => {}
+        }
+        if let Some(pkt) = outgoing {
+            send_packet(&socket_ctx, &pkt, target).await;
+        }
+        if unverified_client.is_some() && handshake_time.elapsed() >= SERVER_HANDSHAKE_TIMEOUT {
+            break 'session DisconnectReason::Timeout;
         }
+        match session.poll(Instant::now()) {
+            SessionPoll::Idle => {}
+            SessionPoll::Send(packets) => {
+                for pkt in packets {
+                    send_packet(&socket_ctx, &pkt, target).await;
+                }
+            }
+            SessionPoll::TimedOut => {
+                break 'session DisconnectReason::Timeout;
+            }
+        }
+        *stats.lock() = session.stats();
+        *replay_stats.lock() = session.replay_stats();
+    };
+    packet_stream.close();
+    log::info!(
+        "Client {} at {:?} disconnected: {:?}",
+        client_id,
+        source,
+        reason
+    );
+    log::debug!(
+        "Replay protection of client {}: {}",
+        client_id,
+        session.replay_stats()
+    );
+    log::debug!("Connection to client {}: {}", client_id, session.stats());
+    // the game never heard of clients that didn't prove their identity
+    if verified {
+        shared_state.send_event(ServerEvent::ClientDisconnected {
+            client: client_id,
+            reason,
+        });
     }
 }
 
+/// Registers a client that proved its identity key, if the admission policy still allows it
+/// now that the other clients could have taken the slots or connected with the same key
+fn admit_verified_client(
+    shared_state: &NetServerSharedState,
+    client_id: ClientId,
+    client: ConnectedClient,
+) -> ConnectionResponse {
+    let (player_id, address) = (client.player_id, client.address);
+    let response = {
+        let mut clients = shared_state.clients.write();
+        shared_state.pending_handshakes.lock().remove(&client_id);
+        let response = shared_state.admission.read().check(
+            &player_id,
+            address.ip(),
+            clients.values().map(|c| &c.player_id),
+        );
+        if response == ConnectionResponse::Accepted {
+            clients.insert(client_id, client);
+        }
+        response
+    };
+    if response == ConnectionResponse::Accepted {
+        shared_state.send_event(ServerEvent::ClientConnected {
+            client: client_id,
+            player_id,
+            address,
+        });
+    }
+    response
+}
+
 async fn server_netmain(
     cfg: ConfigHandle,
     control: broadcast::Sender<ServerControlMessage>,
-    sockets: Vec<std::net::UdpSocket>,
+    sockets: Vec<Arc<dyn DatagramTransport>>,
     shared_state: Arc<NetServerSharedState>,
 ) {
-    let sockets: Vec<net::UdpSocket> = sockets
-        .into_iter()
-        .map(|s| net::UdpSocket::from_std(s).unwrap())
-        .collect_vec();
     let mtu = cfg.read().server_mtu;
+    let (discovery_enabled, discovery_rate) = {
+        let cfg = cfg.read();
+        (cfg.server_enable_discovery, cfg.server_discovery_rate)
+    };
     let tasks = sockets
         .into_iter()
         .enumerate()
         .map(|(sid, sock)| {
-            let sock = Arc::new(sock);
+            let control = control.clone();
             let mut control_rx = control.subscribe();
             let shared_state = Arc::clone(&shared_state);
+            let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+            let socket_ctx = Arc::new(SocketContext {
+                socket: Arc::clone(&sock),
+                mtu,
+                closed_tx,
+            });
             tokio::spawn(async move {
                 let mut msgbuf = vec![0u8; mtu as usize * 2];
-                let mut conntable: HashMap<SocketAddr, mpsc::Sender<RawPacket>> = HashMap::with_capacity(32);
+                let mut conntable: HashMap<SocketAddr, (ClientId, mpsc::Sender<RawPacket>)> = HashMap::with_capacity(32);
+                // each listen address answers up to `discovery_rate` discovery requests per second
+                let mut discovery_limiter = DiscoveryRateLimiter::new(discovery_rate);
                 'sockloop: loop {
                     tokio::select! {
                         ctrl_msg = control_rx.recv() => {
@@ -225,17 +662,39 @@ async fn server_netmain(
                                 }
                             }
                         }
+                        closed = closed_rx.recv() => {
+                            if let Some((addr, client_id)) = closed {
+                                // a new connection from the same address could have replaced the closed one already
+                                if conntable.get(&addr).map(|c| c.0) == Some(client_id) {
+                                    conntable.remove(&addr);
+                                }
+                            }
+                        }
                         recv_result = sock.recv_from(&mut msgbuf) => {
                             let (pkt_len, pkt_src_addr) = recv_result?;
                             if pkt_len > msgbuf.len() || pkt_len < 32 {
                                 continue 'sockloop;
                             }
                             let pkt_data_ref: &[u8] = &msgbuf[0 .. pkt_len];
+                            if discovery::is_discovery_request(pkt_data_ref) {
+                                let request = match discovery::try_decode_discovery_request(pkt_data_ref) {
+                                    Ok(request) if discovery_enabled => request,
+                                    _ => continue 'sockloop
+                                };
+                                if !discovery_limiter.allow(pkt_src_addr.ip(), Instant::now()) {
+                                    continue 'sockloop;
+                                }
+                                let response = discovery::encode_discovery_response(&shared_state.discovery_response(request.nonce));
+                                if let Err(e) = sock.send_to(&response, pkt_src_addr).await {
+                                    log::debug!("Couldn't answer a discovery request from {}: {}", pkt_src_addr, e);
+                                }
+                                continue 'sockloop;
+                            }
                             let pkt_src: (usize, std::net::SocketAddr) = (sid, pkt_src_addr);
                             let conn = conntable.entry(pkt_src.1);
                             let mut processed = false;
                             if let std::collections::hash_map::Entry::Occupied(conn) = conn {
-                                match conn.get().try_send(RawPacket{source: pkt_src, data: Box::from(pkt_data_ref)}) {
+                                match conn.get().1.try_send(RawPacket{source: pkt_src, data: Box::from(pkt_data_ref)}) {
                                     Ok(()) => {processed = true;}
                                     Err(mpsc::error::TrySendError::Closed(_)) => {
                                         conn.remove_entry();
@@ -252,11 +711,13 @@ async fn server_netmain(
                                     Err(_) => continue 'sockloop
                                 };
                                 let (packets_tx, packets_rx) = mpsc::channel(SERVER_PACKET_CHANNEL_BOUND);
+                                let client_id = shared_state.next_client_id.fetch_add(1, SeqCst);
                                 let shared_state_clone = shared_state.clone();
-                                let sock_clone = sock.clone();
-                                conntable.insert(pkt_src_addr, packets_tx);
+                                let socket_ctx_clone = socket_ctx.clone();
+                                let control_rx_clone = control.subscribe();
+                                conntable.insert(pkt_src_addr, (client_id, packets_tx));
                                 shared_state.connection_raw_count.fetch_add(1, SeqCst);
-                                tokio::spawn(async move {server_connection_handler(pkt_src, shs1, packets_rx, shared_state_clone, sock_clone).await});
+                                tokio::spawn(async move {server_connection_handler(client_id, pkt_src, shs1, packets_rx, shared_state_clone, socket_ctx_clone, control_rx_clone).await});
                             }
                         }
                     }
@@ -271,4 +732,253 @@ async fn server_netmain(
             .expect("Panic from a network socket handler task")
             .expect("Error in a network socket handler task");
     }
+    // let the connection handlers notify their clients about the shutdown
+    let shutdown_start = Instant::now();
+    while shared_state.connection_raw_count.load(SeqCst) > 0
+        && shutdown_start.elapsed() < SERVER_SHUTDOWN_GRACE_PERIOD
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::network::client::{ClientControlMessage, ClientEvent, NetClient};
+    use crate::network::transport::{NetworkConditions, SimulatedNetwork};
+    use std::path::PathBuf;
+
+    /// Default configuration with all the key files in a new temporary directory
+    fn test_config(name: &str) -> (ConfigHandle, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("bxw-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        let mut cfg = Config::new();
+        cfg.server_identity_file = path("server_identity.toml");
+        cfg.server_bans_file = path("bans.toml");
+        cfg.server_allowlist_file = path("allowlist.toml");
+        cfg.client_identity_file = path("client_identity.toml");
+        cfg.client_known_servers_file = path("known_servers.toml");
+        (Arc::new(RwLock::new(cfg)), dir)
+    }
+
+    /// Calls `f` until it returns something, panics if that takes too long
+    fn wait_for<T>(what: &str, mut f: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            if let Some(result) = f() {
+                return result;
+            }
+            assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn message_data(i: u8) -> Vec<u8> {
+        // one message big enough to be split into many fragments
+        let len = if i == 50 {
+            50_000
+        } else {
+            (i as usize * 397) % 3000 + 1
+        };
+        (0..len).map(|j| (j as u8) ^ i).collect()
+    }
+
+    #[test]
+    fn test_connection_over_simulated_network() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let (cfg, dir) = test_config("simnet");
+        // the lossy network case is covered by the session tests, which don't depend on wall-clock timing
+        let network = SimulatedNetwork::new(
+            NetworkConditions {
+                latency: Duration::from_millis(15),
+                ..Default::default()
+            },
+            0xba11,
+        );
+        let server_address: SocketAddr = "10.0.0.1:20138".parse().unwrap();
+        let client_address: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let server = NetServer::with_transports(
+            cfg.clone(),
+            vec![Arc::new(network.bind(server_address).unwrap())],
+        )
+        .unwrap();
+        let client = NetClient::with_transport(
+            cfg,
+            Arc::new(network.bind(client_address).unwrap()),
+            &server_address,
+        )
+        .unwrap();
+
+        wait_for("the client to connect", || {
+            client
+                .poll_events()
+                .into_iter()
+                .find(|e| matches!(e, ClientEvent::Connected { .. }))
+        });
+        let client_id = wait_for("the server to accept the client", || {
+            server.poll_events().into_iter().find_map(|e| match e {
+                ServerEvent::ClientConnected {
+                    client, address, ..
+                } => {
+                    assert_eq!(address, client_address);
+                    Some(client)
+                }
+                _ => None,
+            })
+        });
+
+        for i in 0..100u8 {
+            server.send_message(client_id, Channel::ReliableOrdered, i, message_data(i));
+        }
+        for i in 0..50u8 {
+            client.send_message(Channel::ReliableUnordered, i, message_data(i));
+        }
+        let mut client_received = Vec::new();
+        wait_for("the messages from the server", || {
+            for event in client.poll_events() {
+                if let ClientEvent::Message { packet_id, data } = event {
+                    assert_eq!(data, message_data(packet_id));
+                    client_received.push(packet_id);
+                }
+            }
+            Some(()).filter(|_| client_received.len() >= 100)
+        });
+        assert_eq!(client_received, (0..100u8).collect::<Vec<_>>());
+        let mut server_received = Vec::new();
+        wait_for("the messages from the client", || {
+            for event in server.poll_events() {
+                if let ServerEvent::Message {
+                    client,
+                    packet_id,
+                    data,
+                } = event
+                {
+                    assert_eq!(client, client_id);
+                    assert_eq!(data, message_data(packet_id));
+                    server_received.push(packet_id);
+                }
+            }
+            Some(()).filter(|_| server_received.len() >= 50)
+        });
+        server_received.sort_unstable();
+        assert_eq!(server_received, (0..50u8).collect::<Vec<_>>());
+
+        assert_eq!(network.stats().lost, 0);
+        // a round trip takes at least twice the latency
+        let server_stats = server.client_stats(client_id).unwrap();
+        assert!(server_stats.min_rtt >= Duration::from_millis(30));
+        assert!(client.stats().unwrap().min_rtt >= Duration::from_millis(30));
+        assert!(server.client_replay_stats(client_id).unwrap().accepted > 0);
+        assert!(client.replay_stats().unwrap().accepted > 0);
+
+        client.send_control_message(ClientControlMessage::Disconnect);
+        let reason = wait_for("the client to disconnect", || {
+            server.poll_events().into_iter().find_map(|e| match e {
+                ServerEvent::ClientDisconnected { reason, .. } => Some(reason),
+                _ => None,
+            })
+        });
+        assert_eq!(reason, DisconnectReason::Quit);
+        client.wait_for_shutdown();
+        server.send_control_message(ServerControlMessage::Stop);
+        server.wait_for_shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_spoofed_handshakes_take_no_slots() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let (cfg, dir) = test_config("spoofed");
+        cfg.write().server_max_players = 1;
+        let network = SimulatedNetwork::new(NetworkConditions::default(), 0x5f00f);
+        let server_address: SocketAddr = "10.0.0.1:20138".parse().unwrap();
+        let server = NetServer::with_transports(
+            cfg.clone(),
+            vec![Arc::new(network.bind(server_address).unwrap())],
+        )
+        .unwrap();
+        let (victim_key, _) = keystore::load_or_create_identity_from_env(
+            std::path::Path::new(&cfg.read().client_identity_file),
+            false,
+        )
+        .unwrap();
+
+        // handshakes with the victim's key and a made up one, the attacker can't continue with either
+        let attacker = network.bind("10.0.0.66:40000".parse().unwrap()).unwrap();
+        for key in [victim_key, box_::gen_keypair().0].iter() {
+            let (handshake, _) = protocol::authflow_client_handshake_packet(
+                key,
+                packets::auth::ClientConnectionType::GameClient,
+            )
+            .unwrap();
+            get_tokio_runtime(Some(cfg.clone()))
+                .block_on(attacker.send_to(&handshake, server_address))
+                .unwrap();
+        }
+        wait_for("the handshakes to be answered", || {
+            Some(()).filter(|_| server.shared_state.pending_handshakes.lock().len() == 2)
+        });
+        assert!(server.connected_clients().is_empty());
+
+        let client = NetClient::with_transport(
+            cfg,
+            Arc::new(network.bind("10.0.0.2:50000".parse().unwrap()).unwrap()),
+            &server_address,
+        )
+        .unwrap();
+        let player = wait_for("the victim to connect", || {
+            server.poll_events().into_iter().find_map(|e| match e {
+                ServerEvent::ClientConnected { player_id, .. } => Some(player_id),
+                _ => None,
+            })
+        });
+        assert_eq!(player, victim_key);
+        assert_eq!(server.connected_clients().len(), 1);
+
+        client.send_control_message(ClientControlMessage::Disconnect);
+        client.wait_for_shutdown();
+        server.send_control_message(ServerControlMessage::Stop);
+        server.wait_for_shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_discovery_over_simulated_network() {
+        bxw_util::sodiumoxide::init().unwrap();
+        let (cfg, dir) = test_config("discovery");
+        let network = SimulatedNetwork::new(NetworkConditions::default(), 0xd15c);
+        let server_address: SocketAddr = "10.0.0.1:20138".parse().unwrap();
+        let server = NetServer::with_transports(
+            cfg.clone(),
+            vec![Arc::new(network.bind(server_address).unwrap())],
+        )
+        .unwrap();
+        let client_socket = network.bind("10.0.0.2:50000".parse().unwrap()).unwrap();
+        let discover = || {
+            get_tokio_runtime(Some(cfg.clone()))
+                .block_on(discovery::discover_servers(
+                    &client_socket,
+                    &[server_address, "10.0.0.3:20138".parse().unwrap()],
+                    Duration::from_millis(100),
+                ))
+                .unwrap()
+        };
+
+        let found = discover();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].address, server_address);
+        assert_eq!(found[0].name, "BXW Server");
+        assert_eq!(found[0].server_id, server.shared_state.server_id_keys.0);
+        assert_eq!((found[0].players, found[0].max_players), (0, 32));
+        assert!(found[0].is_compatible());
+        // only a couple of responses per second to the same address
+        assert_eq!(discover().len(), 1);
+        assert!(discover().is_empty());
+
+        server.send_control_message(ServerControlMessage::Stop);
+        server.wait_for_shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
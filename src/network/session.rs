@@ -0,0 +1,745 @@
+//! Connection state after a successful handshake, shared by the server and client sides.
+//! Doesn't do any IO itself, the caller sends the returned packets and feeds in the received ones.
+//!
+//! The session keys are replaced periodically: the client sends a `RekeyRequest` with a new key exchange key,
+//! and switches to the derived keys once the server answers with a `RekeyResponse`. The server keeps sending
+//! with the old keys until the first packet with the new ones arrives, and both sides accept packets
+//! with the previous keys for a while, so packets in flight during the switch aren't lost.
+
+use crate::network::congestion::ConnectionStats;
+use crate::network::packets::control::{
+    DisconnectReason, PacketTypeConnectionControl, PktDisconnectPayload, PktRekeyPayload,
+};
+use crate::network::protocol::{
+    net_mpack_deserialize, net_mpack_serialize, PacketDecodeError, PacketEncodeError, PacketFormat,
+    PacketProcessingError, PacketStream, PacketV1, ReplayProtection, ReplayStats,
+    ESTABLISHED_PACKET_OVERHEAD,
+};
+use crate::network::reliability::{Channel, ReliableStream, SeqNumber};
+use bxw_util::log;
+use bxw_util::sodiumoxide::crypto::{kx, secretbox};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+/// A keepalive is sent if nothing else was sent for this long
+pub const SESSION_KEEPALIVE_INTERVAL: Duration = Duration::from_millis(1000);
+/// The session is closed if nothing was received from the peer for this long
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+/// The client starts a rekey after using the session keys for this long
+pub const SESSION_REKEY_INTERVAL: Duration = Duration::from_secs(600);
+/// The client starts a rekey after this many packets were sent and received with the session keys.
+/// Far below the `u32` range, so no sequence number of a stream repeats under the same keys.
+pub const SESSION_REKEY_PACKET_LIMIT: u64 = 1 << 24;
+/// How often an unanswered rekey request is repeated
+const SESSION_REKEY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets encrypted with the previous keys are accepted for this long after a rekey
+const SESSION_PREVIOUS_KEY_GRACE: Duration = Duration::from_secs(10);
+
+/// Which end of the connection the session is, only the client starts rekeys
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SessionSide {
+    Client,
+    Server,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SessionEvent {
+    /// A packet on the `GameMessages` stream
+    GameMessage { packet_id: u8, data: Vec<u8> },
+    /// The peer ended the session
+    Disconnected(DisconnectReason),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SessionPoll {
+    Idle,
+    /// Send the contained packets
+    Send(Vec<Vec<u8>>),
+    /// Nothing was heard from the peer for `SESSION_IDLE_TIMEOUT`
+    TimedOut,
+}
+
+/// Packet id of `GameMessages` packets, the actual message ids are in the channel message parts
+const GAME_MESSAGES_PACKET_ID: u8 = 0;
+
+/// Client's key exchange keys of a rekey request waiting for the server's answer
+struct PendingRekey {
+    kx_pk: kx::PublicKey,
+    kx_sk: kx::SecretKey,
+    last_sent: Instant,
+}
+
+/// Server's answer to a rekey request, the keys are used once a packet encrypted with them arrives
+struct AnsweredRekey {
+    client_kx_pk: kx::PublicKey,
+    server_kx_pk: kx::PublicKey,
+    rx_key: secretbox::Key,
+    tx_key: secretbox::Key,
+}
+
+pub struct EstablishedSession {
+    side: SessionSide,
+    rx_key: secretbox::Key,
+    tx_key: secretbox::Key,
+    /// The receive key before the last rekey and until when it's accepted
+    previous_rx_key: Option<(secretbox::Key, Instant)>,
+    keys_since: Instant,
+    /// Packets sent and received with the current keys
+    keys_packet_count: u64,
+    pending_rekey: Option<PendingRekey>,
+    answered_rekey: Option<AnsweredRekey>,
+    rekey_count: u64,
+    /// Control packets to send with the next poll
+    queued_control: Vec<Vec<u8>>,
+    control_seq: u32,
+    replay: ReplayProtection,
+    game: ReliableStream,
+    last_received: Instant,
+    last_sent: Instant,
+    received_any: bool,
+    closed: Option<DisconnectReason>,
+}
+
+impl EstablishedSession {
+    pub fn new(
+        side: SessionSide,
+        rx_key: secretbox::Key,
+        tx_key: secretbox::Key,
+        mtu: u16,
+        now: Instant,
+    ) -> Self {
+        Self {
+            side,
+            rx_key,
+            tx_key,
+            previous_rx_key: None,
+            keys_since: now,
+            keys_packet_count: 0,
+            pending_rekey: None,
+            answered_rekey: None,
+            rekey_count: 0,
+            queued_control: Vec::new(),
+            control_seq: 0,
+            replay: ReplayProtection::default(),
+            // the message field of a packet has to fit in the MTU
+            game: ReliableStream::new((mtu as usize).saturating_sub(ESTABLISHED_PACKET_OVERHEAD)),
+            last_received: now,
+            last_sent: now,
+            received_any: false,
+            closed: None,
+        }
+    }
+
+    /// Whether any established packet was received, until then the peer might still be waiting for the handshake
+    pub fn received_any(&self) -> bool {
+        self.received_any
+    }
+
+    pub fn closed(&self) -> Option<DisconnectReason> {
+        self.closed
+    }
+
+    /// Counters of the received packets that were accepted or dropped as replays
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.replay.stats()
+    }
+
+    /// Number of completed switches to new session keys
+    pub fn rekey_count(&self) -> u64 {
+        self.rekey_count
+    }
+
+    /// Round-trip time, loss and send rate estimates of the `GameMessages` stream
+    pub fn stats(&self) -> ConnectionStats {
+        self.game.stats()
+    }
+
+    pub fn game_stream(&self) -> &ReliableStream {
+        &self.game
+    }
+
+    fn encode_control(
+        &mut self,
+        packet_id: PacketTypeConnectionControl,
+        message: &[u8],
+        now: Instant,
+    ) -> Vec<u8> {
+        let seq_id = self.control_seq;
+        self.control_seq = self.control_seq.wrapping_add(1);
+        self.last_sent = now;
+        self.keys_packet_count += 1;
+        PacketV1::encode_established(
+            PacketStream::ConnectionControl,
+            packet_id.into(),
+            seq_id,
+            message,
+            &self.tx_key,
+        )
+    }
+
+    /// Queues a message on the `GameMessages` stream, it's sent with the next [`Self::poll`],
+    /// fragmented if it doesn't fit in a single packet
+    pub fn send_message(
+        &mut self,
+        channel: Channel,
+        packet_id: u8,
+        data: Vec<u8>,
+    ) -> Result<(), PacketEncodeError> {
+        self.game.queue_message(channel, packet_id, data)
+    }
+
+    pub fn encode_keepalive(&mut self, now: Instant) -> Vec<u8> {
+        self.encode_control(PacketTypeConnectionControl::Keepalive, &[], now)
+    }
+
+    /// Encodes the disconnect notification and marks the session as closed
+    pub fn encode_disconnect(&mut self, reason: DisconnectReason, now: Instant) -> Vec<u8> {
+        self.closed.get_or_insert(reason);
+        let msg = net_mpack_serialize(&PktDisconnectPayload { reason });
+        self.encode_control(PacketTypeConnectionControl::Disconnect, &msg, now)
+    }
+
+    /// Switches to the new keys and starts accepting the old receive key only for a while
+    fn switch_keys(&mut self, rx_key: secretbox::Key, tx_key: secretbox::Key, now: Instant) {
+        let old_rx_key = std::mem::replace(&mut self.rx_key, rx_key);
+        self.previous_rx_key = Some((old_rx_key, now + SESSION_PREVIOUS_KEY_GRACE));
+        self.tx_key = tx_key;
+        self.keys_since = now;
+        self.keys_packet_count = 0;
+        self.rekey_count += 1;
+        log::debug!("Switched to new session keys, rekey #{}", self.rekey_count);
+    }
+
+    /// Decrypts the packet with the current keys, the server's answered rekey keys or the previous keys
+    fn decode(
+        &mut self,
+        raw: &[u8],
+        now: Instant,
+    ) -> Result<PacketV1<'static>, PacketProcessingError> {
+        let decryption_failed = |r: &Result<PacketV1<'static>, PacketProcessingError>| {
+            matches!(
+                r,
+                Err(PacketProcessingError::Decode(
+                    PacketDecodeError::DecryptionError
+                ))
+            )
+        };
+        let result = PacketV1::decode_established(raw, &self.rx_key, &mut self.replay);
+        if !decryption_failed(&result) {
+            return result;
+        }
+        if let Some(answered) = &self.answered_rekey {
+            let new_result = PacketV1::decode_established(raw, &answered.rx_key, &mut self.replay);
+            if !decryption_failed(&new_result) {
+                // the client switched to the new keys, so the server can too
+                let answered = self.answered_rekey.take().unwrap();
+                self.switch_keys(answered.rx_key, answered.tx_key, now);
+                return new_result;
+            }
+        }
+        let previous_valid = self
+            .previous_rx_key
+            .as_ref()
+            .map_or(false, |(_, until)| now < *until);
+        if !previous_valid {
+            self.previous_rx_key = None;
+            return result;
+        }
+        let (key, _) = self.previous_rx_key.as_ref().unwrap();
+        PacketV1::decode_established(raw, key, &mut self.replay)
+    }
+
+    fn encode_rekey(
+        &mut self,
+        packet_id: PacketTypeConnectionControl,
+        kx_public: kx::PublicKey,
+        now: Instant,
+    ) {
+        let msg = net_mpack_serialize(&PktRekeyPayload { kx_public });
+        let pkt = self.encode_control(packet_id, &msg, now);
+        self.queued_control.push(pkt);
+    }
+
+    fn receive_rekey_request(
+        &mut self,
+        payload: PktRekeyPayload,
+        now: Instant,
+    ) -> Result<(), PacketProcessingError> {
+        if self.side != SessionSide::Server {
+            return Err(PacketProcessingError::UnexpectedPacket);
+        }
+        // a repeated request if the response got lost
+        let repeated = self
+            .answered_rekey
+            .as_ref()
+            .filter(|answered| answered.client_kx_pk == payload.kx_public)
+            .map(|answered| answered.server_kx_pk);
+        let server_kx_pk = match repeated {
+            Some(kx_pk) => kx_pk,
+            None => {
+                let (kx_pk, kx_sk) = kx::gen_keypair();
+                let (rx, tx) = kx::server_session_keys(&kx_pk, &kx_sk, &payload.kx_public)
+                    .map_err(|_| PacketProcessingError::UntrustedCrypto)?;
+                self.answered_rekey = Some(AnsweredRekey {
+                    client_kx_pk: payload.kx_public,
+                    server_kx_pk: kx_pk,
+                    rx_key: secretbox::Key::from_slice(&rx.0).unwrap(),
+                    tx_key: secretbox::Key::from_slice(&tx.0).unwrap(),
+                });
+                kx_pk
+            }
+        };
+        self.encode_rekey(
+            PacketTypeConnectionControl::RekeyResponse,
+            server_kx_pk,
+            now,
+        );
+        Ok(())
+    }
+
+    fn receive_rekey_response(
+        &mut self,
+        payload: PktRekeyPayload,
+        now: Instant,
+    ) -> Result<(), PacketProcessingError> {
+        if self.side != SessionSide::Client {
+            return Err(PacketProcessingError::UnexpectedPacket);
+        }
+        // responses to repeated requests arrive after the switch already happened
+        let pending = match self.pending_rekey.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let (rx, tx) = kx::client_session_keys(&pending.kx_pk, &pending.kx_sk, &payload.kx_public)
+            .map_err(|_| PacketProcessingError::UntrustedCrypto)?;
+        self.switch_keys(
+            secretbox::Key::from_slice(&rx.0).unwrap(),
+            secretbox::Key::from_slice(&tx.0).unwrap(),
+            now,
+        );
+        // the server switches once it receives something with the new keys
+        let keepalive = self.encode_keepalive(now);
+        self.queued_control.push(keepalive);
+        Ok(())
+    }
+
+    /// Decodes a packet from the peer, returning the events it caused
+    pub fn receive(
+        &mut self,
+        raw: &[u8],
+        now: Instant,
+    ) -> Result<Vec<SessionEvent>, PacketProcessingError> {
+        let pkt = self.decode(raw, now)?;
+        self.last_received = now;
+        self.received_any = true;
+        self.keys_packet_count += 1;
+        match pkt.stream {
+            PacketStream::Handshake => Err(PacketProcessingError::UnexpectedPacket),
+            PacketStream::ConnectionControl => {
+                match PacketTypeConnectionControl::try_from(pkt.packet_id)
+                    .map_err(|_| PacketProcessingError::UnexpectedPacket)?
+                {
+                    PacketTypeConnectionControl::Keepalive => Ok(Vec::new()),
+                    PacketTypeConnectionControl::Disconnect => {
+                        let payload: PktDisconnectPayload = net_mpack_deserialize(&pkt.message)?;
+                        self.closed.get_or_insert(payload.reason);
+                        Ok(vec![SessionEvent::Disconnected(payload.reason)])
+                    }
+                    PacketTypeConnectionControl::RekeyRequest => {
+                        self.receive_rekey_request(net_mpack_deserialize(&pkt.message)?, now)?;
+                        Ok(Vec::new())
+                    }
+                    PacketTypeConnectionControl::RekeyResponse => {
+                        self.receive_rekey_response(net_mpack_deserialize(&pkt.message)?, now)?;
+                        Ok(Vec::new())
+                    }
+                }
+            }
+            PacketStream::GameMessages => Ok(self
+                .game
+                .receive(SeqNumber(pkt.seq_id), &pkt.message, now)?
+                .into_iter()
+                .map(|msg| SessionEvent::GameMessage {
+                    packet_id: msg.packet_id,
+                    data: msg.data,
+                })
+                .collect()),
+        }
+    }
+
+    /// Starts a rekey when the keys were used for long enough, and repeats unanswered requests
+    fn poll_rekey(&mut self, now: Instant) {
+        let kx_pk = match &mut self.pending_rekey {
+            Some(pending) => {
+                if now.saturating_duration_since(pending.last_sent) < SESSION_REKEY_RETRY_INTERVAL {
+                    return;
+                }
+                pending.last_sent = now;
+                pending.kx_pk
+            }
+            None => {
+                let due = now.saturating_duration_since(self.keys_since) >= SESSION_REKEY_INTERVAL
+                    || self.keys_packet_count >= SESSION_REKEY_PACKET_LIMIT;
+                if !due {
+                    return;
+                }
+                let (kx_pk, kx_sk) = kx::gen_keypair();
+                self.pending_rekey = Some(PendingRekey {
+                    kx_pk,
+                    kx_sk,
+                    last_sent: now,
+                });
+                kx_pk
+            }
+        };
+        self.encode_rekey(PacketTypeConnectionControl::RekeyRequest, kx_pk, now);
+    }
+
+    /// Checks the timers and collects the queued messages, retransmissions and acks into packets.
+    /// Should be called after queueing messages and at least every `RELIABLE_ACK_DELAY`.
+    pub fn poll(&mut self, now: Instant) -> SessionPoll {
+        if now.saturating_duration_since(self.last_received) >= SESSION_IDLE_TIMEOUT {
+            self.closed.get_or_insert(DisconnectReason::Timeout);
+            return SessionPoll::TimedOut;
+        }
+        if self.side == SessionSide::Client {
+            self.poll_rekey(now);
+        }
+        let mut packets: Vec<Vec<u8>> = std::mem::take(&mut self.queued_control);
+        for (seq, payload) in self.game.poll(now) {
+            packets.push(PacketV1::encode_established(
+                PacketStream::GameMessages,
+                GAME_MESSAGES_PACKET_ID,
+                seq.0,
+                &payload,
+                &self.tx_key,
+            ));
+            self.keys_packet_count += 1;
+        }
+        if !packets.is_empty() {
+            self.last_sent = now;
+        } else if now.saturating_duration_since(self.last_sent) >= SESSION_KEEPALIVE_INTERVAL {
+            packets.push(self.encode_keepalive(now));
+        }
+        if packets.is_empty() {
+            SessionPoll::Idle
+        } else {
+            SessionPoll::Send(packets)
+        }
+    }
+}
+
+/// Quick check if the datagram could be a retransmitted handshake request rather than an established packet
+pub fn is_handshake_packet(raw: &[u8]) -> bool {
+    raw.first() == Some(&u8::from(PacketFormat::ConnectionHandshakeV1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::transport::{NetworkConditions, SimulatedNetwork};
+    use std::net::SocketAddr;
+
+    /// A client session and a server session connected to it
+    fn session_pair(now: Instant) -> (EstablishedSession, EstablishedSession) {
+        bxw_util::sodiumoxide::init().unwrap();
+        let k1 = secretbox::gen_key();
+        let k2 = secretbox::gen_key();
+        (
+            EstablishedSession::new(SessionSide::Client, k1.clone(), k2.clone(), 1400, now),
+            EstablishedSession::new(SessionSide::Server, k2, k1, 1400, now),
+        )
+    }
+
+    fn poll_packets(session: &mut EstablishedSession, now: Instant) -> Vec<Vec<u8>> {
+        match session.poll(now) {
+            SessionPoll::Send(packets) => packets,
+            other => panic!("Expected packets to send, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_session_messages() {
+        let now = Instant::now();
+        let (mut a, mut b) = session_pair(now);
+        assert!(!b.received_any());
+        a.send_message(Channel::ReliableOrdered, 7, Vec::from(&b"hello"[..]))
+            .unwrap();
+        a.send_message(Channel::UnreliableSequenced, 8, Vec::from(&b"world"[..]))
+            .unwrap();
+        let packets = poll_packets(&mut a, now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            b.receive(&packets[0], now).unwrap(),
+            vec![
+                SessionEvent::GameMessage {
+                    packet_id: 7,
+                    data: Vec::from(&b"hello"[..])
+                },
+                SessionEvent::GameMessage {
+                    packet_id: 8,
+                    data: Vec::from(&b"world"[..])
+                }
+            ]
+        );
+        assert!(b.received_any());
+        // a captured packet can't be replayed
+        assert!(b.receive(&packets[0], now).is_err());
+        assert_eq!(b.replay_stats().accepted, 1);
+        assert_eq!(b.replay_stats().replayed, 1);
+        // a packet encrypted for the other direction doesn't decode
+        b.send_message(Channel::ReliableOrdered, 7, Vec::from(&b"hello"[..]))
+            .unwrap();
+        let packets = poll_packets(&mut b, now);
+        assert!(b.receive(&packets[0], now).is_err());
+        let pkt = a.encode_disconnect(DisconnectReason::Quit, now);
+        assert_eq!(a.closed(), Some(DisconnectReason::Quit));
+        assert_eq!(
+            b.receive(&pkt, now).unwrap(),
+            vec![SessionEvent::Disconnected(DisconnectReason::Quit)]
+        );
+        assert_eq!(b.closed(), Some(DisconnectReason::Quit));
+    }
+
+    #[test]
+    fn test_session_large_message() {
+        let now = Instant::now();
+        let (mut a, mut b) = session_pair(now);
+        let data: Vec<u8> = (0..50_000)
+            .map(|_| bxw_util::rand::random::<u8>())
+            .collect();
+        a.send_message(Channel::ReliableOrdered, 9, data.clone())
+            .unwrap();
+        let packets = poll_packets(&mut a, now);
+        assert!(packets.len() > 1);
+        let mut events = Vec::new();
+        for pkt in packets.iter() {
+            assert!(pkt.len() <= 1400);
+            events.extend(b.receive(pkt, now).unwrap());
+        }
+        assert_eq!(
+            events,
+            vec![SessionEvent::GameMessage { packet_id: 9, data }]
+        );
+    }
+
+    #[test]
+    fn test_session_timers() {
+        let now = Instant::now();
+        let (mut a, mut b) = session_pair(now);
+        assert_eq!(a.poll(now), SessionPoll::Idle);
+        let later = now + SESSION_KEEPALIVE_INTERVAL;
+        let keepalive = poll_packets(&mut a, later);
+        assert_eq!(keepalive.len(), 1);
+        assert_eq!(a.poll(later), SessionPoll::Idle);
+        assert_eq!(b.receive(&keepalive[0], later).unwrap(), Vec::new());
+        let timeout = now + SESSION_IDLE_TIMEOUT;
+        assert_eq!(a.poll(timeout), SessionPoll::TimedOut);
+        assert_eq!(a.closed(), Some(DisconnectReason::Timeout));
+        // b received the keepalive later, so it doesn't time out yet
+        assert!(matches!(b.poll(timeout), SessionPoll::Send(_)));
+    }
+
+    fn deliver(
+        to: &mut EstablishedSession,
+        packets: &[Vec<u8>],
+        now: Instant,
+    ) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        for pkt in packets.iter() {
+            events.extend(to.receive(pkt, now).unwrap());
+        }
+        events
+    }
+
+    fn message(packet_id: u8, data: &[u8]) -> SessionEvent {
+        SessionEvent::GameMessage {
+            packet_id,
+            data: Vec::from(data),
+        }
+    }
+
+    #[test]
+    fn test_session_rekey() {
+        let now = Instant::now();
+        let (mut client, mut server) = session_pair(now);
+        let old_key = client.tx_key.clone();
+        client.keys_packet_count = SESSION_REKEY_PACKET_LIMIT;
+        client
+            .send_message(Channel::ReliableOrdered, 1, Vec::from(&b"before"[..]))
+            .unwrap();
+        let request = poll_packets(&mut client, now);
+        assert_eq!(request.len(), 2);
+        assert_eq!(
+            deliver(&mut server, &request, now),
+            vec![message(1, b"before")]
+        );
+
+        // the server answers, but keeps using the old keys
+        server
+            .send_message(Channel::ReliableOrdered, 2, Vec::from(&b"old keys"[..]))
+            .unwrap();
+        let response = poll_packets(&mut server, now);
+        let old_keys_packet = response.last().unwrap().clone();
+        assert_eq!(
+            deliver(&mut client, &response[..1], now),
+            Vec::<SessionEvent>::new()
+        );
+        assert_eq!(client.rekey_count(), 1);
+        assert_eq!(server.rekey_count(), 0);
+        // sent before the switch and delivered after it
+        assert_eq!(
+            deliver(&mut client, &[old_keys_packet], now),
+            vec![message(2, b"old keys")]
+        );
+
+        // the client's keepalive with the new keys makes the server switch too
+        client
+            .send_message(Channel::ReliableOrdered, 3, Vec::from(&b"new keys"[..]))
+            .unwrap();
+        let confirm = poll_packets(&mut client, now);
+        assert_eq!(
+            deliver(&mut server, &confirm, now),
+            vec![message(3, b"new keys")]
+        );
+        assert_eq!(server.rekey_count(), 1);
+        server
+            .send_message(Channel::ReliableOrdered, 4, Vec::from(&b"both new"[..]))
+            .unwrap();
+        let packets = poll_packets(&mut server, now);
+        assert_eq!(
+            deliver(&mut client, &packets, now),
+            vec![message(4, b"both new")]
+        );
+
+        // the previous keys are only accepted for a while
+        let old_keys_keepalive = |seq_id| {
+            PacketV1::encode_established(
+                PacketStream::ConnectionControl,
+                PacketTypeConnectionControl::Keepalive.into(),
+                seq_id,
+                &[],
+                &old_key,
+            )
+        };
+        assert!(server.receive(&old_keys_keepalive(1000), now).is_ok());
+        let later = now + SESSION_PREVIOUS_KEY_GRACE;
+        assert!(server.receive(&old_keys_keepalive(1001), later).is_err());
+    }
+
+    fn message_data(i: u8) -> Vec<u8> {
+        // one message big enough to be split into many fragments
+        let len = if i == 50 {
+            50_000
+        } else {
+            (i as usize * 397) % 3000 + 1
+        };
+        (0..len).map(|j| (j as u8) ^ i).collect()
+    }
+
+    /// Delivers the datagrams due at `now` to the session and sends the packets it wants to send,
+    /// collecting the ids of the received game messages
+    fn simulate_step(
+        session: &mut EstablishedSession,
+        network: &SimulatedNetwork,
+        (address, peer): (SocketAddr, SocketAddr),
+        now: Instant,
+        received: &mut Vec<u8>,
+    ) {
+        while let Some((raw, source)) = network.receive_at(address, now) {
+            assert_eq!(source, peer);
+            // duplicated packets are rejected by the replay protection
+            let events = match session.receive(&raw, now) {
+                Ok(events) => events,
+                Err(_) => continue,
+            };
+            for event in events {
+                if let SessionEvent::GameMessage { packet_id, data } = event {
+                    assert_eq!(data, message_data(packet_id));
+                    received.push(packet_id);
+                }
+            }
+        }
+        match session.poll(now) {
+            SessionPoll::Idle => {}
+            SessionPoll::Send(packets) => {
+                for pkt in packets {
+                    network.send_at(&pkt, address, peer, now);
+                }
+            }
+            SessionPoll::TimedOut => panic!("Session timed out"),
+        }
+    }
+
+    /// Exchanges messages over a lossy simulated network, stepping a simulated clock so that the outcome
+    /// only depends on the network seed
+    #[test]
+    fn test_session_over_simulated_network() {
+        let start = Instant::now();
+        let network = SimulatedNetwork::new(
+            NetworkConditions {
+                latency: Duration::from_millis(15),
+                jitter: Duration::from_millis(10),
+                loss: 0.1,
+                duplication: 0.05,
+                reordering: 0.05,
+                reorder_delay: Duration::from_millis(30),
+            },
+            0xba11,
+        );
+        let server_address: SocketAddr = "10.0.0.1:20138".parse().unwrap();
+        let client_address: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        // only bound so that the network queues the datagrams sent to them
+        let _server_socket = network.bind(server_address).unwrap();
+        let _client_socket = network.bind(client_address).unwrap();
+        let (mut client, mut server) = session_pair(start);
+
+        for i in 0..100u8 {
+            server
+                .send_message(Channel::ReliableOrdered, i, message_data(i))
+                .unwrap();
+        }
+        for i in 0..50u8 {
+            client
+                .send_message(Channel::ReliableUnordered, i, message_data(i))
+                .unwrap();
+        }
+        let mut client_received = Vec::new();
+        let mut server_received = Vec::new();
+        let mut now = start;
+        while client_received.len() < 100 || server_received.len() < 50 {
+            assert!(
+                now - start < Duration::from_secs(60),
+                "Messages not delivered in time, {} to the client and {} to the server",
+                client_received.len(),
+                server_received.len()
+            );
+            now += Duration::from_millis(1);
+            simulate_step(
+                &mut client,
+                &network,
+                (client_address, server_address),
+                now,
+                &mut client_received,
+            );
+            simulate_step(
+                &mut server,
+                &network,
+                (server_address, client_address),
+                now,
+                &mut server_received,
+            );
+        }
+        assert_eq!(client_received, (0..100u8).collect::<Vec<_>>());
+        server_received.sort_unstable();
+        assert_eq!(server_received, (0..50u8).collect::<Vec<_>>());
+
+        let net_stats = network.stats();
+        assert!(net_stats.lost > 0 && net_stats.duplicated > 0 && net_stats.reordered > 0);
+        // a round trip takes at least twice the latency
+        assert!(server.stats().min_rtt >= Duration::from_millis(30));
+        assert!(client.stats().min_rtt >= Duration::from_millis(30));
+        let rejected = |stats: ReplayStats| stats.replayed + stats.too_old;
+        assert!(rejected(client.replay_stats()) + rejected(server.replay_stats()) > 0);
+    }
+}
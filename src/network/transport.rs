@@ -0,0 +1,371 @@
+//! Datagram transports the network threads send and receive packets through: UDP sockets,
+//! or an in-memory network simulating latency, jitter, loss, duplication and reordering for tests.
+//!
+//! The simulated network decides the fate of every datagram with a random number generator seeded per flow
+//! (source and target address), so the same sequence of datagrams on a flow always meets the same fate.
+
+use bxw_util::fnv::FnvHasher;
+use bxw_util::parking_lot::Mutex;
+use bxw_util::rand::{Rng, SeedableRng};
+use bxw_util::rand_xoshiro::Xoshiro256PlusPlus;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// An unconnected, unreliable datagram socket
+pub trait DatagramTransport: Send + Sync {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn send_to<'a>(&'a self, data: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize>;
+
+    /// Receives a single datagram, truncated to the buffer's length.
+    /// Has to be cancel safe, the network threads use it within `tokio::select!`.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)>;
+}
+
+impl DatagramTransport for tokio::net::UdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+
+    fn send_to<'a>(&'a self, data: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(tokio::net::UdpSocket::send_to(self, data, target))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(tokio::net::UdpSocket::recv_from(self, buf))
+    }
+}
+
+/// What happens to the datagrams sent over a [`SimulatedNetwork`], the default is a perfect network
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay of every datagram
+    pub latency: Duration,
+    /// Extra delay of up to this much, picked uniformly for every datagram
+    pub jitter: Duration,
+    /// Probability of a datagram getting lost, 0 to 1
+    pub loss: f64,
+    /// Probability of a datagram getting delivered twice, 0 to 1
+    pub duplication: f64,
+    /// Probability of a datagram getting held back by `reorder_delay`, so that later ones overtake it
+    pub reordering: f64,
+    pub reorder_delay: Duration,
+}
+
+/// Counters of the datagrams sent over a [`SimulatedNetwork`]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SimulationStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// Sent to an address without a bound socket
+    pub undeliverable: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct QueuedDatagram {
+    deliver_at: Instant,
+    /// Keeps datagrams due at the same time in the order of sending
+    order: u64,
+    source: SocketAddr,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Inbox {
+    queue: Mutex<BinaryHeap<Reverse<QueuedDatagram>>>,
+    notify: Notify,
+}
+
+struct SimulatedNetworkState {
+    conditions: NetworkConditions,
+    seed: u64,
+    flows: HashMap<(SocketAddr, SocketAddr), Xoshiro256PlusPlus>,
+    endpoints: HashMap<SocketAddr, Arc<Inbox>>,
+    next_order: u64,
+    stats: SimulationStats,
+}
+
+/// An in-memory network connecting the [`SimulatedSocket`]s bound to it
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    state: Arc<Mutex<SimulatedNetworkState>>,
+}
+
+impl SimulatedNetwork {
+    pub fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimulatedNetworkState {
+                conditions,
+                seed,
+                flows: HashMap::new(),
+                endpoints: HashMap::new(),
+                next_order: 0,
+                stats: SimulationStats::default(),
+            })),
+        }
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        self.state.lock().stats
+    }
+
+    pub fn bind(&self, address: SocketAddr) -> io::Result<SimulatedSocket> {
+        let mut state = self.state.lock();
+        if state.endpoints.contains_key(&address) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Simulated address {} already bound", address),
+            ));
+        }
+        let inbox = Arc::new(Inbox::default());
+        state.endpoints.insert(address, Arc::clone(&inbox));
+        Ok(SimulatedSocket {
+            network: self.clone(),
+            address,
+            inbox,
+        })
+    }
+
+    /// Sends a datagram at the given time, for driving sans-IO code with a simulated clock
+    /// instead of through a [`SimulatedSocket`]. The target still has to be bound to receive it.
+    pub fn send_at(&self, data: &[u8], source: SocketAddr, target: SocketAddr, now: Instant) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let conditions = state.conditions;
+        state.stats.sent += 1;
+        let seed = state.seed;
+        let rng = state.flows.entry((source, target)).or_insert_with(|| {
+            let mut hasher = FnvHasher::default();
+            (source, target).hash(&mut hasher);
+            Xoshiro256PlusPlus::seed_from_u64(seed ^ hasher.finish())
+        });
+        // the same number of random values for every datagram, so that one's fate doesn't shift the later ones
+        let lost = rng.gen::<f64>() < conditions.loss;
+        let duplicated = rng.gen::<f64>() < conditions.duplication;
+        let delays: [(f64, bool); 2] = [
+            (rng.gen::<f64>(), rng.gen::<f64>() < conditions.reordering),
+            (rng.gen::<f64>(), rng.gen::<f64>() < conditions.reordering),
+        ];
+        if lost {
+            state.stats.lost += 1;
+            return;
+        }
+        let inbox = match state.endpoints.get(&target) {
+            Some(inbox) => Arc::clone(inbox),
+            None => {
+                state.stats.undeliverable += 1;
+                return;
+            }
+        };
+        let copies = if duplicated {
+            state.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let mut queue = inbox.queue.lock();
+        for &(jitter, reordered) in delays.iter().take(copies) {
+            let mut delay = conditions.latency + conditions.jitter.mul_f64(jitter);
+            if reordered {
+                state.stats.reordered += 1;
+                delay += conditions.reorder_delay;
+            }
+            queue.push(Reverse(QueuedDatagram {
+                deliver_at: now + delay,
+                order: state.next_order,
+                source,
+                data: data.to_vec(),
+            }));
+            state.next_order += 1;
+        }
+        drop(queue);
+        inbox.notify.notify_one();
+    }
+
+    /// Takes the next datagram sent to `address` that's due at the given time, the counterpart of [`Self::send_at`]
+    pub fn receive_at(&self, address: SocketAddr, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        let inbox = Arc::clone(self.state.lock().endpoints.get(&address)?);
+        let datagram = inbox.pop_due(now)?;
+        self.state.lock().stats.delivered += 1;
+        Some((datagram.data, datagram.source))
+    }
+}
+
+impl Inbox {
+    fn pop_due(&self, now: Instant) -> Option<QueuedDatagram> {
+        let mut queue = self.queue.lock();
+        match queue.peek() {
+            Some(Reverse(d)) if d.deliver_at <= now => queue.pop().map(|Reverse(d)| d),
+            _ => None,
+        }
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.queue.lock().peek().map(|Reverse(d)| d.deliver_at)
+    }
+}
+
+/// A socket bound to an address of a [`SimulatedNetwork`], unbound when dropped
+pub struct SimulatedSocket {
+    network: SimulatedNetwork,
+    address: SocketAddr,
+    inbox: Arc<Inbox>,
+}
+
+impl DatagramTransport for SimulatedSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+
+    fn send_to<'a>(&'a self, data: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        self.network
+            .send_at(data, self.address, target, Instant::now());
+        Box::pin(std::future::ready(Ok(data.len())))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            loop {
+                // created before checking the queue, so that a datagram queued in between wakes it up
+                let notified = self.inbox.notify.notified();
+                if let Some(datagram) = self.inbox.pop_due(Instant::now()) {
+                    self.network.state.lock().stats.delivered += 1;
+                    let len = datagram.data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&datagram.data[..len]);
+                    return Ok((len, datagram.source));
+                }
+                let next_due = self.inbox.next_due();
+                match next_due {
+                    Some(due) => {
+                        tokio::select! {
+                            _ = notified => {}
+                            _ = tokio::time::sleep_until(due.into()) => {}
+                        }
+                    }
+                    None => notified.await,
+                }
+            }
+        })
+    }
+}
+
+impl Drop for SimulatedSocket {
+    fn drop(&mut self) {
+        let mut state = self.network.state.lock();
+        let bound_here = state
+            .endpoints
+            .get(&self.address)
+            .map_or(false, |inbox| Arc::ptr_eq(inbox, &self.inbox));
+        if bound_here {
+            state.endpoints.remove(&self.address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Sends `count` numbered datagrams from `a` to `b` and returns the numbers received within `wait`
+    async fn exchange(
+        a: &SimulatedSocket,
+        b: &SimulatedSocket,
+        count: u32,
+        wait: Duration,
+    ) -> Vec<u32> {
+        for i in 0..count {
+            a.send_to(&i.to_le_bytes(), b.address).await.unwrap();
+        }
+        let mut received = Vec::new();
+        let mut buf = [0u8; 16];
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(result) = tokio::time::timeout_at(deadline, b.recv_from(&mut buf)).await {
+            let (len, source) = result.unwrap();
+            assert_eq!(source, a.address);
+            assert_eq!(len, 4);
+            received.push(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]));
+        }
+        received
+    }
+
+    fn run<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn test_simulated_network_perfect() {
+        let net = SimulatedNetwork::new(
+            NetworkConditions {
+                latency: Duration::from_millis(30),
+                ..Default::default()
+            },
+            1,
+        );
+        let a = net.bind(addr("10.0.0.1:1000")).unwrap();
+        let b = net.bind(addr("10.0.0.2:1000")).unwrap();
+        assert!(net.bind(addr("10.0.0.2:1000")).is_err());
+        let start = Instant::now();
+        let received = run(exchange(&a, &b, 100, Duration::from_millis(200)));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(received, (0..100).collect::<Vec<u32>>());
+        assert_eq!(net.stats().delivered, 100);
+        drop(b);
+        run(a.send_to(&[1, 2, 3], addr("10.0.0.2:1000"))).unwrap();
+        assert_eq!(net.stats().undeliverable, 1);
+    }
+
+    #[test]
+    fn test_simulated_network_conditions() {
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            loss: 0.2,
+            duplication: 0.1,
+            reordering: 0.1,
+            reorder_delay: Duration::from_millis(20),
+        };
+        let simulate = |seed: u64| {
+            let net = SimulatedNetwork::new(conditions, seed);
+            let a = net.bind(addr("10.0.0.1:1000")).unwrap();
+            let b = net.bind(addr("[fd00::2]:1000")).unwrap();
+            let mut received = run(exchange(&a, &b, 1000, Duration::from_millis(200)));
+            let in_order = received.windows(2).all(|w| w[0] < w[1]);
+            received.sort_unstable();
+            (received, in_order, net.stats())
+        };
+        let (received, in_order, stats) = simulate(42);
+        assert!(!in_order);
+        assert_eq!(stats.sent, 1000);
+        assert_eq!(stats.delivered, received.len() as u64);
+        assert_eq!(stats.delivered, stats.sent - stats.lost + stats.duplicated);
+        assert!(stats.lost > 100 && stats.lost < 300, "{:?}", stats);
+        assert!(stats.duplicated > 0 && stats.reordered > 0, "{:?}", stats);
+        // the fates of the datagrams only depend on the seed
+        let (same_received, _, same_stats) = simulate(42);
+        assert_eq!(same_stats, stats);
+        assert_eq!(same_received, received);
+        let (_, _, other_stats) = simulate(43);
+        assert_ne!(other_stats, stats);
+    }
+}
@@ -0,0 +1,115 @@
+//! Tracks which chunks each network client has received, deciding what to send and unload next.
+
+use crate::network::congestion::ConnectionStats;
+use bxw_util::fnv::FnvHashMap;
+use bxw_util::itertools::iproduct;
+use bxw_world::generation::WorldBlocks;
+use bxw_world::worldmgr::*;
+use bxw_world::{ChunkPosition, VChunk};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// Maximum number of chunks sent to a single client by one [`ChunkStreamer::update`],
+/// fewer if the connection can't carry that many, see [`chunk_budget`]
+pub const CHUNKS_PER_UPDATE: usize = 16;
+
+#[derive(Clone, Default)]
+pub struct ChunkStreamUpdate {
+    pub send: Vec<Arc<VChunk>>,
+    pub unload: Vec<ChunkPosition>,
+}
+
+#[derive(Clone, Default)]
+pub struct ChunkStreamer {
+    /// The version of each chunk the client has, a changed chunk has new data so the pointers differ
+    sent: FnvHashMap<ChunkPosition, Weak<VChunk>>,
+}
+
+/// How many chunks of about `message_bytes` each the connection can carry within `interval`,
+/// after sending what's already queued. Shrinks as the congestion window does under packet loss.
+pub fn chunk_budget(stats: &ConnectionStats, interval: Duration, message_bytes: f64) -> usize {
+    let capacity = stats.send_rate * interval.as_secs_f64() - stats.queued_bytes as f64;
+    if capacity <= 0.0 {
+        return 0;
+    }
+    ((capacity / message_bytes.max(1.0)).ceil() as usize).min(CHUNKS_PER_UPDATE)
+}
+
+/// Squared distance in chunks, the same metric the world uses to order chunk loading
+pub fn distance_sq(a: ChunkPosition, b: ChunkPosition) -> i32 {
+    (a - b).0.iter().map(|x| x * x).sum()
+}
+
+impl ChunkStreamer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent_count(&self) -> usize {
+        self.sent.len()
+    }
+
+    /// If the client has the `old` version of the chunk, records it as having the `new` one instead.
+    /// Returns true if it did, the client has to be sent the changes between them then.
+    pub fn replace_sent(&mut self, old: &Arc<VChunk>, new: &Arc<VChunk>) -> bool {
+        match self.sent.get_mut(&old.position) {
+            Some(sent) if std::ptr::eq(sent.as_ptr(), Arc::as_ptr(old)) => {
+                *sent = Arc::downgrade(new);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Picks up to `max_chunks` of the loaded chunks within `radius` of `center` the client doesn't have
+    /// the newest version of, nearest first like the world's own chunk loading,
+    /// and the previously sent chunks now out of range
+    pub fn update(
+        &mut self,
+        world: &World,
+        center: ChunkPosition,
+        radius: u32,
+        max_chunks: usize,
+    ) -> ChunkStreamUpdate {
+        let radius = radius as i32;
+        let max_dist = radius * radius;
+        let unload: Vec<ChunkPosition> = self
+            .sent
+            .keys()
+            .copied()
+            .filter(|&cpos| distance_sq(cpos, center) > max_dist)
+            .collect();
+        for cpos in unload.iter() {
+            self.sent.remove(cpos);
+        }
+
+        let blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow();
+        let blocks: &WorldBlocks = blocks_ref.as_any().downcast_ref().unwrap();
+        let mut candidates: Vec<(i32, Arc<VChunk>)> = Vec::new();
+        for (x, y, z) in iproduct!(-radius..=radius, -radius..=radius, -radius..=radius) {
+            let offset = ChunkPosition::new(x, y, z);
+            let dist = distance_sq(offset, ChunkPosition::new(0, 0, 0));
+            if dist > max_dist {
+                continue;
+            }
+            let cpos = center + offset;
+            let chunk = match blocks.get_chunk(world, cpos) {
+                Some(c) => c,
+                None => continue,
+            };
+            let up_to_date = self.sent.get(&cpos).map_or(false, |sent| {
+                std::ptr::eq(sent.as_ptr(), Arc::as_ptr(&chunk))
+            });
+            if !up_to_date {
+                candidates.push((dist, chunk));
+            }
+        }
+        candidates.sort_by_key(|(dist, _)| *dist);
+        candidates.truncate(max_chunks);
+        let send: Vec<Arc<VChunk>> = candidates.into_iter().map(|(_, c)| c).collect();
+        for chunk in send.iter() {
+            self.sent.insert(chunk.position, Arc::downgrade(chunk));
+        }
+        ChunkStreamUpdate { send, unload }
+    }
+}
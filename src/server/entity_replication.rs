@@ -0,0 +1,77 @@
+//! Tracks the state of the shared entities each network client has, turning the differences into entity changes.
+
+use crate::server::chunk_stream::distance_sq;
+use bxw_util::fnv::{FnvHashMap, FnvHashSet};
+use bxw_world::ecs::*;
+use bxw_world::ChunkPosition;
+
+#[derive(Clone, Default)]
+pub struct EntityReplicator {
+    /// The replicated state of each entity as last sent to the client
+    known: FnvHashMap<ValidEntityID, EntitySnapshot>,
+}
+
+/// Physics and load anchors are only simulated by the server, so they aren't replicated
+fn replicated_snapshot(ecs: &ECS, id: ValidEntityID) -> Option<EntitySnapshot> {
+    let mut snapshot = ecs.snapshot_entity(id)?;
+    snapshot.physics = None;
+    snapshot.load_anchor = None;
+    Some(snapshot)
+}
+
+impl EntityReplicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Changes bringing the client's copy up to date with the shared entities located within `radius` chunks
+    /// of `center`, entities that left the area are deleted. `exclude` is the client's own player.
+    pub fn update(
+        &mut self,
+        ecs: &ECS,
+        center: ChunkPosition,
+        radius: u32,
+        exclude: Option<ValidEntityID>,
+    ) -> Vec<EntityChange> {
+        let max_dist = (radius * radius) as i32;
+        let interesting: FnvHashSet<ValidEntityID> = ECSHandler::<CLocation>::iter(ecs)
+            .map(|loc| (loc.entity_id(), ChunkPosition::from(loc.position)))
+            .filter(|&(id, _)| {
+                matches!(
+                    id.domain(),
+                    EntityDomain::SharedChunked | EntityDomain::SharedOmnipresent
+                ) && Some(id) != exclude
+            })
+            .filter(|&(_, cpos)| distance_sq(cpos, center) <= max_dist)
+            .map(|(id, _)| id)
+            .collect();
+        let mut changes = Vec::new();
+        let gone: Vec<ValidEntityID> = self
+            .known
+            .keys()
+            .copied()
+            .filter(|id| !interesting.contains(id))
+            .collect();
+        for id in gone {
+            let old = self.known.remove(&id);
+            changes.extend(EntityChange::between_snapshots(old.as_ref(), None));
+        }
+        for id in interesting {
+            let new = replicated_snapshot(ecs, id);
+            changes.extend(EntityChange::between_snapshots(
+                self.known.get(&id),
+                new.as_ref(),
+            ));
+            match new {
+                Some(snapshot) => self.known.insert(id, snapshot),
+                None => self.known.remove(&id),
+            };
+        }
+        changes
+    }
+
+    /// The client's copy diverged, the entity is sent again in full by the next update
+    pub fn resync(&mut self, id: ValidEntityID) {
+        self.known.remove(&id);
+    }
+}
@@ -1,10 +1,10 @@
 use crate::config::Config;
-use crate::network::server::{NetServer, ServerControlMessage};
+use crate::network::packets::control::DisconnectReason;
+use crate::network::server::{NetServer, ServerControlMessage, ServerEvent};
 use crate::server::world::ServerWorld;
 use bxw_util::debug_data::DEBUG_DATA;
 use bxw_util::log;
 use bxw_world::blocks::register_standard_blocks;
-use bxw_world::generation::WorldBlocks;
 use bxw_world::physics::TIMESTEP as PHYSICS_FRAME_TIME;
 use bxw_world::storage::WorldSave;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -28,7 +28,7 @@ pub fn server_main() {
 
     let task_pool = bxw_util::taskpool::TaskPool::new(cfg.read().performance_threads as usize);
     let mut vxreg: Box<bxw_world::voxregistry::VoxelRegistry> = Box::default();
-    register_standard_blocks(&mut vxreg, &|_| 0);
+    register_standard_blocks(&mut vxreg, &|_| 0).expect("Couldn't register the standard blocks");
     let vxreg: Arc<bxw_world::voxregistry::VoxelRegistry> = Arc::from(vxreg);
     let savefile = {
         let name = "serverworld";
@@ -42,9 +42,13 @@ pub fn server_main() {
             WorldSave::new(name).expect("Couldn't create a new world savefile")
         }
     };
-    let (mut world, mut _server_world) =
-        ServerWorld::new_world(vxreg.clone(), &savefile).expect("Couldn't create a new world");
-    let _wgen = WorldBlocks::new(vxreg, 0);
+    let (mut world, mut server_world) = ServerWorld::new_world(
+        vxreg,
+        &savefile,
+        &cfg.read().world_generator_request(),
+        cfg.read().performance_load_distance,
+    )
+    .expect("Couldn't create a new world");
 
     let mut previous_frame_time = Instant::now();
     let mut physics_accum_time = 0.0f64;
@@ -81,17 +85,69 @@ pub fn server_main() {
             };
 
             for _pfrm in 0..physics_frames {
+                server_world.process_player_inputs(&mut world);
                 // do physics tick
-                bxw_world::physics::world_physics_tick(&mut world);
+                bxw_world::physics::world_physics_tick_excluding(
+                    &mut world,
+                    &server_world.player_entities(),
+                );
             }
         }
 
         world.main_loop_tick(&task_pool);
         task_pool.main_thread_tick();
 
+        for event in netserver.poll_events() {
+            match event {
+                ServerEvent::Message {
+                    client,
+                    packet_id,
+                    data,
+                } => {
+                    if let Err(e) = server_world
+                        .handle_message(&mut world, &netserver, client, packet_id, &data)
+                    {
+                        log::warn!(
+                            "Invalid message {} from client {}: {:?}",
+                            packet_id,
+                            client,
+                            e
+                        );
+                        netserver.disconnect_client(client, DisconnectReason::ProtocolError);
+                    }
+                }
+                // connection state changes are logged by the network threads
+                ServerEvent::ClientConnected { client, .. } => {
+                    server_world.client_connected(&mut world, &netserver, client);
+                }
+                ServerEvent::ClientDisconnected { client, .. } => {
+                    server_world.client_disconnected(&mut world, client);
+                }
+            }
+        }
+        server_world.stream_chunks(&world, &netserver);
+        server_world.replicate_entities(&world, &netserver);
+
         if let Ok(cmd) = stdin.try_recv() {
             if cmd == "quit" || cmd == "stop" {
                 break 'running;
+            } else if cmd == "clients" {
+                let clients = netserver.all_client_stats();
+                log::info!("{} clients connected", clients.len());
+                for (client, address, stats, replay_stats) in clients {
+                    log::info!(
+                        "Client {} at {}: {}, replay protection: {}",
+                        client,
+                        address,
+                        stats,
+                        replay_stats
+                    );
+                }
+            } else if cmd == "reload-admission" {
+                match netserver.reload_admission_policy() {
+                    Ok(()) => log::info!("Reloaded the ban list and allowlist"),
+                    Err(e) => log::error!("Couldn't reload the admission policy: {:?}", e),
+                }
             } else {
                 log::warn!("Unrecognized command: `{}`", cmd);
             }
@@ -107,6 +163,9 @@ pub fn server_main() {
     log::info!("Shutting down, waiting for netserver...");
     netserver.send_control_message(ServerControlMessage::Stop);
     netserver.wait_for_shutdown();
+    log::info!("Saving the world");
+    server_world.remove_all_players(&mut world);
+    world.save_and_close();
 }
 
 fn stdin_reader() -> mpsc::Receiver<String> {
@@ -1,2 +1,4 @@
+pub mod chunk_stream;
+pub mod entity_replication;
 pub mod main;
 pub mod world;
@@ -1,24 +1,470 @@
 use crate::client::world::WorldOpenError;
+use crate::network::packets::game::*;
+use crate::network::protocol::{net_mpack_deserialize, net_mpack_serialize, PacketProcessingError};
+use crate::network::reliability::Channel;
+use crate::network::server::{ClientId, NetServer};
+use crate::server::chunk_stream::{chunk_budget, ChunkStreamer, CHUNKS_PER_UPDATE};
+use crate::server::entity_replication::EntityReplicator;
+use bxw_terragen::worldgen::{load_or_create_world_generator, WorldGeneratorRequest};
+use bxw_util::change::Change;
+use bxw_util::fnv::{FnvHashMap, FnvHashSet};
+use bxw_util::log;
+use bxw_util::math::*;
+use bxw_world::ecs::*;
+use bxw_world::entities::player::*;
 use bxw_world::generation::WorldBlocks;
+use bxw_world::light::WorldLight;
+use bxw_world::physics::entity_physics_tick;
+use bxw_world::raycast::{Hit, RaycastQuery};
+use bxw_world::storage::palette::VoxelPalette;
 use bxw_world::storage::{WorldDiskStorage, WorldSave};
 use bxw_world::worldmgr::*;
-use bxw_world::VoxelRegistry;
+use bxw_world::{BlockPosition, ChunkPosition, VChunk, VoxelDatum, VoxelRegistry};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Clone, Debug)]
-pub struct ServerWorld {}
+/// How often the chunks sent to each client are updated
+pub const CHUNK_STREAM_INTERVAL: Duration = Duration::from_millis(100);
+/// Assumed size of a chunk message before any were sent to the client
+const INITIAL_CHUNK_MESSAGE_BYTES: f64 = 2048.0;
+/// Weight of a new chunk message in the running average of their sizes
+const CHUNK_MESSAGE_BYTES_GAIN: f64 = 1.0 / 8.0;
+/// How often entity changes and player states are sent to the clients
+pub const ENTITY_REPLICATION_INTERVAL: Duration = Duration::from_millis(50);
+/// Received input commands beyond this many waiting ones are dropped
+pub const MAX_QUEUED_INPUTS: usize = 64;
+/// How many physics ticks worth of input commands a client can catch up on at once,
+/// input isn't processed faster than the physics runs beyond that
+pub const MAX_INPUT_BUDGET: u32 = 8;
+/// Block edits of a client are counted over windows of this length
+pub const BLOCK_EDIT_RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Block edits beyond this many within a window are rejected
+pub const MAX_BLOCK_EDITS_PER_WINDOW: u32 = 20;
+/// Extra reach allowed on top of `PLAYER_REACH`, the server's player position can lag behind the client's
+pub const BLOCK_REACH_TOLERANCE: f64 = 2.0;
+/// Distance from a block's center to its corners, rounded up
+const BLOCK_HALF_DIAGONAL: f64 = 0.87;
+
+#[derive(Clone)]
+struct RemoteClient {
+    player: ValidEntityID,
+    load_radius: u32,
+    chunks: ChunkStreamer,
+    /// Running average of the chunk message sizes sent to the client
+    chunk_message_bytes: f64,
+    entities: EntityReplicator,
+    inputs: VecDeque<PlayerInputCommand>,
+    /// Sequence number of the newest queued input command
+    last_queued_input: Option<u32>,
+    /// Sequence number of the last processed input command, echoed to the client
+    last_processed_input: Option<u32>,
+    input_budget: u32,
+    edit_window_start: Instant,
+    edits_in_window: u32,
+}
+
+#[derive(Clone)]
+pub struct ServerWorld {
+    /// Upper limit of the load radius requested by the clients
+    max_load_radius: u32,
+    clients: FnvHashMap<ClientId, RemoteClient>,
+    last_chunk_stream: Instant,
+    last_entity_replication: Instant,
+}
 
 impl ServerWorld {
     pub fn new_world(
         registry: Arc<VoxelRegistry>,
         save: &WorldSave,
+        generator_request: &WorldGeneratorRequest,
+        max_load_radius: u32,
     ) -> Result<(World, ServerWorld), WorldOpenError> {
-        let world_disk_storage =
-            Box::new(WorldDiskStorage::open(save).map_err(WorldOpenError::StorageError)?);
+        let mut world_disk_storage = Box::new(WorldDiskStorage::open(save)?);
+        let (generator, seed) =
+            load_or_create_world_generator(world_disk_storage.as_mut(), generator_request)?;
+        log::info!(
+            "Opening world {} with generator {} v{}, seed {}",
+            save.name(),
+            generator.name(),
+            generator.version(),
+            seed
+        );
+        let palette = VoxelPalette::load(world_disk_storage.as_mut(), &registry)
+            .map_err(WorldOpenError::StorageError)?;
         let mut world = World::new(save.name(), registry.clone(), world_disk_storage);
-        world.replace_handler(CHUNK_BLOCK_DATA, Box::new(WorldBlocks::new(registry, 0)));
+        world.replace_handler(
+            CHUNK_BLOCK_DATA,
+            Box::new(WorldBlocks::new(registry.clone(), generator, palette)),
+        );
+        world.replace_handler(CHUNK_LIGHT_DATA, Box::new(WorldLight::new(registry)));
+        world.load_global_entities();
 
-        let sw = ServerWorld {};
+        let sw = ServerWorld {
+            max_load_radius,
+            clients: Default::default(),
+            last_chunk_stream: Instant::now(),
+            last_entity_replication: Instant::now(),
+        };
         Ok((world, sw))
     }
+
+    /// Sends the voxel registry and spawns the client's player, chunks and entities around it are streamed from then on
+    pub fn client_connected(&mut self, world: &mut World, net: &NetServer, client: ClientId) {
+        let voxels = world
+            .voxel_registry()
+            .iter()
+            .map(|def| (def.id, def.name.clone()))
+            .collect();
+        net.send_message(
+            client,
+            Channel::ReliableOrdered,
+            PacketTypeGameMessage::VoxelRegistry.into(),
+            net_mpack_serialize(&PktVoxelRegistryPayload { voxels }),
+        );
+        let mut player = create_player(world.ecs(), false, format!("client {}", client));
+        let player_id = player.kind.entity_id();
+        match (&mut player.location, &mut player.load_anchor) {
+            (Change::Create { new: location }, Change::Create { new: anchor }) => {
+                location.position = PLAYER_SPAWN_POSITION.into();
+                anchor.radius = self.max_load_radius;
+            }
+            _ => unreachable!(),
+        }
+        world.apply_entity_changes(&[player]);
+        self.clients.insert(
+            client,
+            RemoteClient {
+                player: player_id,
+                load_radius: self.max_load_radius,
+                chunks: ChunkStreamer::new(),
+                chunk_message_bytes: INITIAL_CHUNK_MESSAGE_BYTES,
+                entities: EntityReplicator::new(),
+                inputs: VecDeque::new(),
+                last_queued_input: None,
+                last_processed_input: None,
+                input_budget: 0,
+                edit_window_start: Instant::now(),
+                edits_in_window: 0,
+            },
+        );
+    }
+
+    pub fn client_disconnected(&mut self, world: &mut World, client: ClientId) {
+        if let Some(remote) = self.clients.remove(&client) {
+            world.apply_entity_changes(&[EntityChange {
+                kind: EntityChangeKind::DeleteEntity(remote.player),
+                ..Default::default()
+            }]);
+        }
+    }
+
+    /// Removes the players of all clients, they aren't saved with the world
+    pub fn remove_all_players(&mut self, world: &mut World) {
+        let clients: Vec<ClientId> = self.clients.keys().copied().collect();
+        for client in clients {
+            self.client_disconnected(world, client);
+        }
+    }
+
+    pub fn handle_message(
+        &mut self,
+        world: &mut World,
+        net: &NetServer,
+        client: ClientId,
+        packet_id: u8,
+        data: &[u8],
+    ) -> Result<(), PacketProcessingError> {
+        let remote = self
+            .clients
+            .get_mut(&client)
+            .ok_or(PacketProcessingError::UnexpectedPacket)?;
+        match PacketTypeGameMessage::try_from(packet_id)
+            .map_err(|_| PacketProcessingError::UnexpectedPacket)?
+        {
+            PacketTypeGameMessage::ClientSettings => {
+                let settings: PktClientSettingsPayload = net_mpack_deserialize(data)?;
+                remote.load_radius = settings.load_radius.min(self.max_load_radius);
+                let anchor: &CLoadAnchor = world.ecs().get_component(remote.player).unwrap();
+                let mut new_anchor = anchor.clone();
+                new_anchor.radius = remote.load_radius;
+                let change = EntityChange {
+                    kind: EntityChangeKind::UpdateEntity(remote.player),
+                    load_anchor: Change::Update {
+                        old: anchor.clone(),
+                        new: new_anchor,
+                    },
+                    ..Default::default()
+                };
+                world.apply_entity_changes(&[change]);
+            }
+            PacketTypeGameMessage::PlayerInputs => {
+                let payload: PktPlayerInputsPayload = net_mpack_deserialize(data)?;
+                for command in payload.commands {
+                    let is_new = remote
+                        .last_queued_input
+                        .map_or(true, |last| command.sequence > last);
+                    if !is_new {
+                        continue;
+                    }
+                    if remote.inputs.len() >= MAX_QUEUED_INPUTS {
+                        // the client will be corrected by the next player state
+                        break;
+                    }
+                    remote.last_queued_input = Some(command.sequence);
+                    remote.inputs.push_back(command);
+                }
+            }
+            PacketTypeGameMessage::EntityResync => {
+                let payload: PktEntityResyncPayload = net_mpack_deserialize(data)?;
+                for id in payload.entities {
+                    remote.entities.resync(id);
+                }
+            }
+            PacketTypeGameMessage::BlockEdit => {
+                let edit: PktBlockEditPayload = net_mpack_deserialize(data)?;
+                let now = Instant::now();
+                if now.saturating_duration_since(remote.edit_window_start) >= BLOCK_EDIT_RATE_WINDOW
+                {
+                    remote.edit_window_start = now;
+                    remote.edits_in_window = 0;
+                }
+                remote.edits_in_window = remote.edits_in_window.saturating_add(1);
+                let result = if remote.edits_in_window > MAX_BLOCK_EDITS_PER_WINDOW {
+                    Err(BlockEditRejection::RateLimited)
+                } else {
+                    validate_block_edit(world, remote.player, &edit)
+                };
+                match result {
+                    Ok(change) => self.apply_block_edit(world, net, change),
+                    Err(reason) => {
+                        log::debug!(
+                            "Rejected block edit at {:?} from client {}: {:?}",
+                            edit.position,
+                            client,
+                            reason
+                        );
+                        let position = BlockPosition(edit.position.into());
+                        let datum =
+                            block_datum(world, position).map_or(edit.from, VoxelDatum::repr);
+                        let rejected = PktBlockEditRejectedPayload {
+                            position: edit.position,
+                            reason,
+                            datum,
+                        };
+                        net.send_message(
+                            client,
+                            Channel::ReliableOrdered,
+                            PacketTypeGameMessage::BlockEditRejected.into(),
+                            net_mpack_serialize(&rejected),
+                        );
+                    }
+                }
+            }
+            PacketTypeGameMessage::VoxelRegistry
+            | PacketTypeGameMessage::ChunkData
+            | PacketTypeGameMessage::ChunkUnload
+            | PacketTypeGameMessage::EntityUpdates
+            | PacketTypeGameMessage::PlayerState
+            | PacketTypeGameMessage::BlockChanges
+            | PacketTypeGameMessage::BlockEditRejected => {
+                return Err(PacketProcessingError::UnexpectedPacket)
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a validated block edit and sends it to the clients that have the chunk
+    fn apply_block_edit(&mut self, world: &mut World, net: &NetServer, change: VoxelChange) {
+        let cpos = ChunkPosition::from(change.bpos);
+        let old = loaded_chunk(world, cpos);
+        world.apply_voxel_changes(&[change.clone()]);
+        let new = loaded_chunk(world, cpos);
+        let (old, new) = match (old, new) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return,
+        };
+        let changes = net_mpack_serialize(&PktBlockChangesPayload {
+            blocks: vec![(change.bpos.0.into(), change.to.repr())],
+        });
+        for (&client, remote) in self.clients.iter_mut() {
+            // clients with an older version of the chunk get all of it from the chunk streaming instead
+            if remote.chunks.replace_sent(&old, &new) {
+                net.send_message(
+                    client,
+                    Channel::ReliableOrdered,
+                    PacketTypeGameMessage::BlockChanges.into(),
+                    changes.clone(),
+                );
+            }
+        }
+    }
+
+    /// Player entities are moved only by their input commands, not by the regular physics tick
+    pub fn player_entities(&self) -> FnvHashSet<ValidEntityID> {
+        self.clients.values().map(|c| c.player).collect()
+    }
+
+    /// Runs the queued input commands of each player for one physics tick,
+    /// more if the client fell behind and has some input budget left
+    pub fn process_player_inputs(&mut self, world: &mut World) {
+        for remote in self.clients.values_mut() {
+            remote.input_budget = (remote.input_budget + 1).min(MAX_INPUT_BUDGET);
+            while remote.input_budget > 0 {
+                let command = match remote.inputs.pop_front() {
+                    Some(c) => c,
+                    None => break,
+                };
+                remote.input_budget -= 1;
+                if let Some(change) = apply_player_input(world.ecs(), remote.player, &command.input)
+                {
+                    world.apply_entity_changes(&[change]);
+                }
+                entity_physics_tick(world, remote.player);
+                remote.last_processed_input = Some(command.sequence);
+            }
+        }
+    }
+
+    /// Sends the chunks around each player that the client doesn't have yet, and unloads the ones that left the radius
+    pub fn stream_chunks(&mut self, world: &World, net: &NetServer) {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.last_chunk_stream) < CHUNK_STREAM_INTERVAL {
+            return;
+        }
+        self.last_chunk_stream = now;
+        for (&client, remote) in self.clients.iter_mut() {
+            let player = remote.player;
+            let location: &CLocation = match world.ecs().get_component(player) {
+                Some(l) => l,
+                None => continue,
+            };
+            let center = ChunkPosition::from(location.position);
+            // only as many new chunks as the connection can carry, so they don't pile up in the send queue
+            let max_chunks = net.client_stats(client).map_or(CHUNKS_PER_UPDATE, |stats| {
+                chunk_budget(&stats, CHUNK_STREAM_INTERVAL, remote.chunk_message_bytes)
+            });
+            let update = remote
+                .chunks
+                .update(world, center, remote.load_radius, max_chunks);
+            if !update.unload.is_empty() {
+                let positions = update.unload.iter().map(|cpos| cpos.0.into()).collect();
+                net.send_message(
+                    client,
+                    Channel::ReliableOrdered,
+                    PacketTypeGameMessage::ChunkUnload.into(),
+                    net_mpack_serialize(&PktChunkUnloadPayload { positions }),
+                );
+            }
+            for chunk in update.send.iter() {
+                let msg = net_mpack_serialize(&PktChunkDataPayload::new(chunk));
+                remote.chunk_message_bytes +=
+                    (msg.len() as f64 - remote.chunk_message_bytes) * CHUNK_MESSAGE_BYTES_GAIN;
+                net.send_message(
+                    client,
+                    Channel::ReliableOrdered,
+                    PacketTypeGameMessage::ChunkData.into(),
+                    msg,
+                );
+            }
+        }
+    }
+
+    /// Sends the changes of the shared entities around each player to its client
+    pub fn replicate_entities(&mut self, world: &World, net: &NetServer) {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.last_entity_replication) < ENTITY_REPLICATION_INTERVAL
+        {
+            return;
+        }
+        self.last_entity_replication = now;
+        for (&client, remote) in self.clients.iter_mut() {
+            let player = remote.player;
+            let location: &CLocation = match world.ecs().get_component(player) {
+                Some(l) => l,
+                None => continue,
+            };
+            let center = ChunkPosition::from(location.position);
+            let changes =
+                remote
+                    .entities
+                    .update(world.ecs(), center, remote.load_radius, Some(player));
+            if !changes.is_empty() {
+                net.send_message(
+                    client,
+                    Channel::ReliableOrdered,
+                    PacketTypeGameMessage::EntityUpdates.into(),
+                    net_mpack_serialize(&PktEntityUpdatesPayload { changes }),
+                );
+            }
+            let state = PlayerMovementState::of_entity(world.ecs(), player);
+            if let (Some(last_input), Some(state)) = (remote.last_processed_input, state) {
+                net.send_message(
+                    client,
+                    Channel::UnreliableSequenced,
+                    PacketTypeGameMessage::PlayerState.into(),
+                    net_mpack_serialize(&PktPlayerStatePayload { last_input, state }),
+                );
+            }
+        }
+    }
+}
+
+fn loaded_chunk(world: &World, cpos: ChunkPosition) -> Option<Arc<VChunk>> {
+    let blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow();
+    let blocks: &WorldBlocks = blocks_ref.as_any().downcast_ref().unwrap();
+    blocks.get_chunk(world, cpos)
+}
+
+fn block_datum(world: &World, bpos: BlockPosition) -> Option<VoxelDatum> {
+    let blocks_ref = world.get_handler(CHUNK_BLOCK_DATA).borrow();
+    let blocks: &WorldBlocks = blocks_ref.as_any().downcast_ref().unwrap();
+    let mut vcache = blocks.get_vcache();
+    vcache.get_block(world, blocks, bpos)
+}
+
+/// Checks that the player can make the edit: the block is loaded, still has the data the client saw,
+/// and isn't too far or obstructed by other blocks when looking at it from the player's eyes
+fn validate_block_edit(
+    world: &World,
+    player: ValidEntityID,
+    edit: &PktBlockEditPayload,
+) -> Result<VoxelChange, BlockEditRejection> {
+    let bpos = BlockPosition(edit.position.into());
+    let from = VoxelDatum::from_repr(edit.from);
+    let to = VoxelDatum::from_repr(edit.to);
+    if world
+        .voxel_registry()
+        .try_get_definition_from_id(to.id())
+        .is_none()
+    {
+        return Err(BlockEditRejection::UnknownVoxel);
+    }
+    let current = block_datum(world, bpos).ok_or(BlockEditRejection::NotLoaded)?;
+    if current != from {
+        return Err(BlockEditRejection::Mismatch);
+    }
+    let location: &CLocation = world
+        .ecs()
+        .get_component(player)
+        .ok_or(BlockEditRejection::OutOfReach)?;
+    let eye = location.position + vec3(0.0, PLAYER_EYE_HEIGHT / 2.0, 0.0);
+    let to_block = bpos.0.map(|c| c as f64) - eye;
+    let distance = to_block.magnitude();
+    // !(a <= b) is true when there are NaNs present
+    if !(distance <= PLAYER_REACH + BLOCK_REACH_TOLERANCE) {
+        return Err(BlockEditRejection::OutOfReach);
+    }
+    let rc = RaycastQuery::new_directed(eye, to_block, distance, world, true, false).execute();
+    let reachable = match rc.hit {
+        Hit::Voxel { position, .. } => {
+            position == bpos || rc.distance + BLOCK_HALF_DIAGONAL >= distance
+        }
+        _ => true,
+    };
+    if !reachable {
+        return Err(BlockEditRejection::OutOfReach);
+    }
+    Ok(VoxelChange { bpos, from, to })
 }